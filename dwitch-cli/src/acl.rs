@@ -0,0 +1,212 @@
+use clap::Subcommand;
+use protocol::{mac, AclAction, AclDirection, AclRule, AclVerdict, IpPrefix};
+
+use crate::{
+    mac::vrf_id,
+    vrf::{parse_ethertype, parse_mac, parse_prefix},
+    Connection, Output,
+};
+
+#[derive(Subcommand)]
+pub enum AclCommand {
+    /// List the acl rules of a vrf on this switch, in the order they're matched
+    List {
+        /// Id or name of the vrf
+        #[arg(long)]
+        vrf: String,
+    },
+    /// Add an acl rule to a vrf, a frame matches it when it matches each of the criteria given
+    Add {
+        /// Id or name of the vrf
+        #[arg(long)]
+        vrf: String,
+
+        /// permit or deny
+        #[arg(value_parser = parse_verdict)]
+        verdict: AclVerdict,
+
+        /// ingress for the frames read from the taps, egress for the ones from the peers
+        #[arg(long, value_parser = parse_direction, default_value = "both")]
+        direction: AclDirection,
+
+        #[arg(long, value_parser = parse_mac)]
+        source_mac: Option<[u8; 6]>,
+
+        #[arg(long, value_parser = parse_mac)]
+        destination_mac: Option<[u8; 6]>,
+
+        /// Ethertype after the vlan tags, like 0x0800
+        #[arg(long, value_parser = parse_ethertype)]
+        ethertype: Option<u16>,
+
+        /// Vlan of the outer tag, 0 for untagged frames
+        #[arg(long, value_parser = clap::value_parser!(u16).range(0..4095))]
+        vlan: Option<u16>,
+
+        /// Ip prefix, like 10.0.0.0/24
+        #[arg(long, value_parser = parse_prefix)]
+        source_ip: Option<IpPrefix>,
+
+        #[arg(long, value_parser = parse_prefix)]
+        destination_ip: Option<IpPrefix>,
+
+        /// tcp, udp, sctp, icmp or an ip protocol number
+        #[arg(long, value_parser = parse_protocol)]
+        protocol: Option<u8>,
+
+        #[arg(long)]
+        source_port: Option<u16>,
+
+        #[arg(long)]
+        destination_port: Option<u16>,
+
+        /// Index the rule is inserted at, after the last rule by default
+        #[arg(long)]
+        position: Option<u32>,
+    },
+    /// Remove the acl rule at an index of a vrf
+    Remove {
+        /// Id or name of the vrf
+        #[arg(long)]
+        vrf: String,
+
+        /// Index of the rule, as listed
+        index: u32,
+    },
+}
+
+pub fn command(
+    command: AclCommand,
+    output: Output,
+    mut connection: Connection,
+) -> eyre::Result<()> {
+    match command {
+        AclCommand::List { vrf } => {
+            let vrf_id = vrf_id(&mut connection, &vrf)?;
+            let rules = connection.run(async |client| client.list_acl(vrf_id).await)?;
+
+            output.print(rules, |rules| {
+                for (index, rule) in rules.iter().enumerate() {
+                    println!("{index}: {}", format_rule(rule));
+                }
+            })
+        }
+        AclCommand::Add {
+            vrf,
+            verdict,
+            direction,
+            source_mac,
+            destination_mac,
+            ethertype,
+            vlan,
+            source_ip,
+            destination_ip,
+            protocol,
+            source_port,
+            destination_port,
+            position,
+        } => {
+            let vrf_id = vrf_id(&mut connection, &vrf)?;
+
+            connection.request(AclAction::Add {
+                vrf_id,
+                rule: AclRule {
+                    verdict,
+                    direction,
+                    source_mac,
+                    destination_mac,
+                    ethertype,
+                    vlan,
+                    source_ip,
+                    destination_ip,
+                    protocol,
+                    source_port,
+                    destination_port,
+                },
+                position,
+            })
+        }
+        AclCommand::Remove { vrf, index } => {
+            let vrf_id = vrf_id(&mut connection, &vrf)?;
+
+            connection.request(AclAction::Remove { vrf_id, index })
+        }
+    }
+}
+
+// the verdict, the direction and the criteria set
+fn format_rule(rule: &AclRule) -> String {
+    let mut words = vec![
+        match rule.verdict {
+            AclVerdict::Permit => "permit",
+            AclVerdict::Deny => "deny",
+        }
+        .to_string(),
+        match rule.direction {
+            AclDirection::Ingress => "ingress",
+            AclDirection::Egress => "egress",
+            AclDirection::Both => "both",
+        }
+        .to_string(),
+    ];
+    let prefix = |prefix: &IpPrefix| format!("{}/{}", prefix.address, prefix.prefix_length);
+
+    words.extend(
+        [
+            ("source mac", rule.source_mac.as_ref().map(mac::format)),
+            (
+                "destination mac",
+                rule.destination_mac.as_ref().map(mac::format),
+            ),
+            (
+                "ethertype",
+                rule.ethertype.map(|ethertype| format!("{ethertype:#06x}")),
+            ),
+            ("vlan", rule.vlan.map(|vlan| vlan.to_string())),
+            ("source ip", rule.source_ip.as_ref().map(prefix)),
+            ("destination ip", rule.destination_ip.as_ref().map(prefix)),
+            (
+                "protocol",
+                rule.protocol.map(|protocol| protocol.to_string()),
+            ),
+            ("source port", rule.source_port.map(|port| port.to_string())),
+            (
+                "destination port",
+                rule.destination_port.map(|port| port.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{name} {}", value?))),
+    );
+
+    words.join(", ")
+}
+
+fn parse_verdict(verdict: &str) -> Result<AclVerdict, String> {
+    match verdict {
+        "permit" => Ok(AclVerdict::Permit),
+        "deny" => Ok(AclVerdict::Deny),
+        _ => Err("Expected permit or deny".to_string()),
+    }
+}
+
+fn parse_direction(direction: &str) -> Result<AclDirection, String> {
+    match direction {
+        "ingress" => Ok(AclDirection::Ingress),
+        "egress" => Ok(AclDirection::Egress),
+        "both" => Ok(AclDirection::Both),
+        _ => Err("Expected ingress, egress or both".to_string()),
+    }
+}
+
+fn parse_protocol(protocol: &str) -> Result<u8, String> {
+    match protocol {
+        "icmp" => Ok(1),
+        "tcp" => Ok(6),
+        "udp" => Ok(17),
+        "sctp" => Ok(132),
+        _ => protocol
+            .parse()
+            .map_err(|_| format!("Invalid ip protocol {protocol}")),
+    }
+}
@@ -0,0 +1,69 @@
+use protocol::{Audit, AuditReport, Packet, Response};
+
+use crate::{vrf::list_vrf, Connection};
+
+pub fn command(mut connection: Connection) -> eyre::Result<()> {
+    let vrf_list = list_vrf(&mut connection)?;
+
+    connection.send(Audit::Start)?;
+
+    let mut reports = Vec::new();
+
+    loop {
+        match connection.recv()? {
+            Packet::Audit(Audit::Report(reports_chunk)) => {
+                if reports_chunk.is_empty() {
+                    break;
+                }
+
+                reports.extend(reports_chunk);
+            }
+            Packet::Response(Response::Error(error)) => eyre::bail!(error),
+            _ => {}
+        }
+    }
+
+    let mut leaks = 0;
+
+    for AuditReport {
+        vrf_id,
+        seen_by,
+        leaks: vrf_leaks,
+    } in reports
+    {
+        let missing = vrf_list
+            .iter()
+            .find(|vrf| vrf.id == vrf_id)
+            .map(|vrf| {
+                vrf.members
+                    .iter()
+                    .filter(|member| !seen_by.contains(member))
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        println!("Vrf {vrf_id}: seen by {seen_by:?}, missing {missing:?}");
+
+        for leak in &vrf_leaks {
+            println!(
+                "\tleaked into vrf {} on switch {}, {}",
+                leak.vrf_id,
+                leak.switch_id,
+                if leak.from_tap {
+                    "out of its tap"
+                } else {
+                    "through the overlay"
+                }
+            );
+        }
+
+        leaks += vrf_leaks.len();
+    }
+
+    if leaks > 0 {
+        eyre::bail!("Found {leaks} leaks");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Subcommand;
+use common::{SwitchId, VrfId};
+use eyre::OptionExt;
+use protocol::{mac, MacAction, MacEntry};
+
+use crate::{
+    vrf::{list_vrf, parse_mac},
+    Connection, Output,
+};
+
+#[derive(Subcommand)]
+pub enum MacCommand {
+    /// List the macs pinned and learned in a vrf and the switch each one is behind, the learned
+    /// ones most recently seen first
+    List {
+        /// Id or name of the vrf
+        #[arg(long)]
+        vrf: String,
+    },
+    /// Pin a mac behind a switch in the table of this switch, in every vlan, it's neither learned
+    /// elsewhere nor aged out
+    AddStatic {
+        /// Id or name of the vrf
+        #[arg(long)]
+        vrf: String,
+
+        /// Mac to pin, like 02:00:00:00:00:01
+        #[arg(value_parser = parse_mac)]
+        mac: [u8; 6],
+
+        /// Switch id the mac is behind
+        #[arg(long)]
+        switch: SwitchId,
+    },
+    /// Unpin a mac, it's learned again
+    RemoveStatic {
+        /// Id or name of the vrf
+        #[arg(long)]
+        vrf: String,
+
+        /// Mac to unpin, like 02:00:00:00:00:01
+        #[arg(value_parser = parse_mac)]
+        mac: [u8; 6],
+    },
+}
+
+pub fn command(
+    command: MacCommand,
+    output: Output,
+    mut connection: Connection,
+) -> eyre::Result<()> {
+    match command {
+        MacCommand::List { vrf } => {
+            let vrf_id = vrf_id(&mut connection, &vrf)?;
+            let entries = connection.run(async |client| client.list_macs(vrf_id).await)?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+
+            output.print(entries, |entries| {
+                for MacEntry {
+                    vlan,
+                    mac,
+                    switch_id,
+                    learned,
+                    pinned,
+                } in entries
+                {
+                    if pinned {
+                        println!("{} on switch {switch_id}, pinned", mac::format(&mac));
+                    } else {
+                        println!(
+                            "{} vlan {vlan} on switch {switch_id}, learned {}s ago",
+                            mac::format(&mac),
+                            now.saturating_sub(learned)
+                        );
+                    }
+                }
+            })
+        }
+        MacCommand::AddStatic { vrf, mac, switch } => {
+            let vrf_id = vrf_id(&mut connection, &vrf)?;
+
+            connection.request(MacAction::AddStatic {
+                vrf_id,
+                mac,
+                switch_id: switch,
+            })
+        }
+        MacCommand::RemoveStatic { vrf, mac } => {
+            let vrf_id = vrf_id(&mut connection, &vrf)?;
+
+            connection.request(MacAction::RemoveStatic { vrf_id, mac })
+        }
+    }
+}
+
+// a name is matched first, vrf names can be made of digits
+pub fn vrf_id(connection: &mut Connection, vrf: &str) -> eyre::Result<VrfId> {
+    list_vrf(connection)?
+        .into_iter()
+        .find(|listed| listed.name == vrf)
+        .map(|listed| listed.id)
+        .or_else(|| vrf.parse().ok())
+        .ok_or_eyre("Can't find vrf with this id or name")
+}
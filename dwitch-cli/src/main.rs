@@ -1,19 +1,46 @@
+mod acl;
+mod audit;
+mod mac;
+mod peer;
+mod stats;
+mod status;
+mod trace;
+mod vm;
 mod vrf;
 
-use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream},
-};
+use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
-use common::SwitchId;
-use protocol::{PacketSerializer, CONFIGURATION_SWITCH_ID};
-use vrf::VrfCommand;
+use acl::AclCommand;
+use clap::{Parser, Subcommand, ValueEnum};
+use dwitch_client::{Client, Target};
+use mac::MacCommand;
+use peer::PeerCommand;
+use protocol::{Maintenance, Packet};
+use serde::Serialize;
+use tokio::runtime::{Builder, Runtime};
+use vm::VmCommand;
+use vrf::{VrfCommand, VrfIdArg};
+
+const MANAGEMENT_SOCKET: &str = "/run/dwitch.sock";
+const VM_SOCKET: &str = "/run/dwitch-vm.sock";
 
 #[derive(Parser)]
 struct Args {
-    /// Address of the dwitch daemon
-    address: SocketAddr,
+    /// Address of the dwitch daemon, or the path of its management socket, the local daemon by
+    /// default
+    address: Option<Target>,
+
+    /// Shared key used to sign control packets
+    #[arg(long, env = "DWITCH_KEY", hide_env_values = true)]
+    key: Option<String>,
+
+    /// Token used to authenticate with a remote daemon
+    #[arg(long, env = "DWITCH_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// How the listings and reports are printed
+    #[arg(long, value_enum, default_value_t = Output::Table, global = true)]
+    output: Output,
 
     #[command(subcommand)]
     command: Command,
@@ -24,24 +51,189 @@ enum Command {
     /// Vrf commands
     Vrf {
         #[command(subcommand)]
-        command: VrfCommand,
+        command: Box<VrfCommand>,
+    },
+
+    /// Put the switch into maintenance, peers stop flooding traffic to it
+    Drain,
+
+    /// Bring the switch back from maintenance
+    Activate,
+
+    /// Show the state of the switch
+    Status,
+
+    /// Show the traffic counters of the vrfs and peers of the switch
+    Stats {
+        /// Refresh them every few seconds, 2 by default
+        #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "2")]
+        watch: Option<u64>,
+    },
+
+    /// Peer commands
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommand,
+    },
+
+    /// Mac table commands
+    Mac {
+        #[command(subcommand)]
+        command: MacCommand,
+    },
+
+    /// Acl commands
+    Acl {
+        #[command(subcommand)]
+        command: AclCommand,
+    },
+
+    /// Persist the vrfs and macs of the switch now, before a host maintenance
+    Save,
+
+    /// Print the events of the switch as they happen
+    Events,
+
+    /// Send a probe into each vrf of the switch and report where else it shows up
+    Audit,
+
+    /// Show the path a frame for a mac address takes from this switch through a vrf
+    Trace {
+        #[command(flatten)]
+        vrf_id: VrfIdArg,
+
+        /// Destination mac address of the traced frame
+        #[arg(value_parser = trace::parse_mac)]
+        destination: [u8; 6],
     },
+
+    /// Vm commands, the address being the vm socket of the daemon
+    Vm {
+        #[command(subcommand)]
+        command: VmCommand,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Output {
+    /// Lines for people to read
+    Table,
+    /// One json document per line, for scripts
+    Json,
+}
+
+impl Output {
+    /// Print a value as json, or as the table the command prints it as.
+    pub fn print<T: Serialize>(self, value: T, table: impl FnOnce(T)) -> eyre::Result<()> {
+        match self {
+            Output::Table => table(value),
+            Output::Json => println!("{}", serde_json::to_string(&value)?),
+        }
+
+        Ok(())
+    }
+}
+
+/// Blocking management connection, the commands run one request at a time.
+pub struct Connection {
+    runtime: Runtime,
+    client: Client,
+}
+
+impl Connection {
+    fn connect(target: Target, key: Option<String>) -> eyre::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let client =
+            runtime.block_on(Client::connect(&target, key.as_deref().map(str::as_bytes)))?;
+
+        Ok(Self { runtime, client })
+    }
+
+    pub fn run<T>(
+        &mut self,
+        f: impl AsyncFnOnce(&mut Client) -> dwitch_client::Result<T>,
+    ) -> eyre::Result<T> {
+        Ok(self.runtime.block_on(f(&mut self.client))?)
+    }
+
+    pub fn send<T: Into<Packet>>(&mut self, packet: T) -> eyre::Result<()> {
+        self.run(async |client| client.send(packet).await)
+    }
+
+    pub fn request<T: Into<Packet>>(&mut self, packet: T) -> eyre::Result<()> {
+        self.run(async |client| client.request(packet).await)
+    }
+
+    pub fn recv(&mut self) -> eyre::Result<Packet> {
+        self.run(async |client| client.recv().await)
+    }
+
+    /// Print the events of the daemon until it closes the connection.
+    pub fn watch(self) -> eyre::Result<()> {
+        let mut subscription = self.runtime.block_on(self.client.subscribe())?;
+
+        loop {
+            println!("{:?}", self.runtime.block_on(subscription.next())?);
+        }
+    }
+}
+
+fn connect(
+    address: Target,
+    key: Option<String>,
+    token: Option<String>,
+) -> eyre::Result<Connection> {
+    let mut connection = Connection::connect(address, key)?;
+
+    if let Some(token) = token {
+        connection.run(async |client| client.authenticate(&token).await)?;
+    }
+
+    Ok(connection)
 }
 
 fn main() -> eyre::Result<()> {
     color_eyre::install()?;
 
-    let args = Args::parse();
-    let mut stream = TcpStream::connect(&args.address)?;
-    let mut buffer = [0u8; SwitchId::BITS as usize];
+    let Args {
+        address,
+        key,
+        token,
+        output,
+        command,
+    } = Args::parse();
+    let address = address.unwrap_or_else(|| {
+        Target::Unix(PathBuf::from(match command {
+            Command::Vm { .. } => VM_SOCKET,
+            _ => MANAGEMENT_SOCKET,
+        }))
+    });
 
-    stream.write_all(&CONFIGURATION_SWITCH_ID.serialize())?;
-    stream.read(&mut buffer)?;
+    match command {
+        Command::Vrf { command } => vrf::command(*command, output, connect(address, key, token)?),
+        Command::Drain => connect(address, key, token)?.request(Maintenance::Drain),
+        Command::Activate => connect(address, key, token)?.request(Maintenance::Activate),
+        Command::Status => status::command(output, connect(address, key, token)?),
+        Command::Stats { watch } => stats::command(watch, output, connect(address, key, token)?),
+        Command::Peer { command } => peer::command(command, output, connect(address, key, token)?),
+        Command::Mac { command } => mac::command(command, output, connect(address, key, token)?),
+        Command::Acl { command } => acl::command(command, output, connect(address, key, token)?),
+        Command::Save => {
+            let (vrfs, macs) =
+                connect(address, key, token)?.run(async |client| client.save().await)?;
 
-    let _switch_id = SwitchId::deserialize(&buffer)?;
+            println!("Saved {vrfs} vrfs and {macs} macs");
 
-    match args.command {
-        Command::Vrf { command } => vrf::command(command, stream),
+            Ok(())
+        }
+        Command::Events => connect(address, key, token)?.watch(),
+        Command::Audit => audit::command(connect(address, key, token)?),
+        Command::Trace {
+            vrf_id,
+            destination,
+        } => trace::command(vrf_id, destination, connect(address, key, token)?),
+        // vms are attached without a management connection
+        Command::Vm { command } => vm::command(command, address),
     }?;
 
     Ok(())
@@ -0,0 +1,57 @@
+use clap::Subcommand;
+use protocol::{PeerReport, PeerState};
+
+use crate::{Connection, Output};
+
+#[derive(Subcommand)]
+pub enum PeerCommand {
+    /// List the connected peers and the members of the vrfs of the switch that aren't
+    List,
+}
+
+pub fn command(
+    command: PeerCommand,
+    output: Output,
+    mut connection: Connection,
+) -> eyre::Result<()> {
+    match command {
+        PeerCommand::List => output.print(
+            connection.run(async |client| client.list_peers().await)?,
+            print,
+        ),
+    }
+}
+
+fn print(peers: Vec<PeerReport>) {
+    for PeerReport {
+        switch_id,
+        state,
+        address,
+        ping_rtt,
+        uptime,
+    } in peers
+    {
+        let mut line = format!(
+            "Switch {switch_id} {}",
+            match state {
+                PeerState::Connected => "connected",
+                PeerState::Draining => "draining",
+                PeerState::Disconnected => "disconnected",
+            }
+        );
+
+        if let Some(address) = address {
+            line += &format!(" through {address}");
+        }
+
+        if let Some(uptime) = uptime {
+            line += &format!(", up {}s", uptime.as_secs());
+        }
+
+        if let Some(ping_rtt) = ping_rtt {
+            line += &format!(", ping rtt {:.2}ms", ping_rtt.as_secs_f64() * 1000.0);
+        }
+
+        println!("{line}");
+    }
+}
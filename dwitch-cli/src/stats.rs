@@ -0,0 +1,64 @@
+use std::{thread::sleep, time::Duration};
+
+use protocol::{PeerStats, StatsReport, TrafficStats, VrfStats};
+
+use crate::{Connection, Output};
+
+// moves the cursor home and clears the screen, like watch does between refreshes
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+pub fn command(watch: Option<u64>, output: Output, mut connection: Connection) -> eyre::Result<()> {
+    let Some(interval) = watch else {
+        return output.print(connection.run(async |client| client.stats().await)?, print);
+    };
+
+    loop {
+        let report = connection.run(async |client| client.stats().await)?;
+
+        // json reports follow each other, one per line
+        if let Output::Table = output {
+            print!("{CLEAR_SCREEN}");
+        }
+
+        output.print(report, print)?;
+        sleep(Duration::from_secs(interval.max(1)));
+    }
+}
+
+fn print(StatsReport { vrfs, peers }: StatsReport) {
+    for VrfStats {
+        vrf_id,
+        received,
+        sent,
+        flooded,
+        dropped,
+        shaped,
+        learned_macs,
+    } in vrfs
+    {
+        println!(
+            "Vrf {vrf_id}: {} from the tap, {} to the tap, {flooded} flooded, {dropped} dropped, \
+             {shaped} shaped, {learned_macs} macs",
+            traffic(received),
+            traffic(sent)
+        );
+    }
+
+    for PeerStats {
+        switch_id,
+        received,
+        sent,
+        send_failures,
+    } in peers
+    {
+        println!(
+            "Peer {switch_id}: {} received, {} sent, {send_failures} send failures",
+            traffic(received),
+            traffic(sent)
+        );
+    }
+}
+
+fn traffic(TrafficStats { packets, bytes }: TrafficStats) -> String {
+    format!("{packets} packets ({bytes} bytes)")
+}
@@ -0,0 +1,44 @@
+use protocol::StatusReport;
+
+use crate::{Connection, Output};
+
+pub fn command(output: Output, mut connection: Connection) -> eyre::Result<()> {
+    output.print(connection.run(async |client| client.status().await)?, print)
+}
+
+fn print(
+    StatusReport {
+        switch_id,
+        draining,
+        peers,
+        draining_peers,
+        path_mtus,
+        vrfs,
+        degraded_vrfs,
+        dropped_frames,
+    }: StatusReport,
+) {
+    println!(
+        "Switch id {switch_id} - {}",
+        if draining { "draining" } else { "active" }
+    );
+    println!("\tPeers: {peers:?}");
+
+    if !draining_peers.is_empty() {
+        println!("\tDraining peers: {draining_peers:?}");
+    }
+
+    for (switch_id, mtu) in path_mtus {
+        println!("\tPath mtu toward {switch_id}: {mtu}");
+    }
+
+    println!("\tVrfs: {vrfs:?}");
+
+    for name in degraded_vrfs {
+        println!("\tVrf {name} degraded: tap missing");
+    }
+
+    for (vrf_id, dropped) in dropped_frames {
+        println!("\tVrf {vrf_id} dropped {dropped} frames: queue full");
+    }
+}
@@ -0,0 +1,64 @@
+use protocol::{mac, Decision, DropReason, Packet, Response, Trace};
+
+use crate::{vrf::VrfIdArg, Connection};
+
+pub fn command(
+    vrf_id: VrfIdArg,
+    destination: [u8; 6],
+    mut connection: Connection,
+) -> eyre::Result<()> {
+    let vrf_id = vrf_id.get(&mut connection)?;
+
+    connection.send(Trace::Start {
+        vrf_id,
+        destination,
+    })?;
+
+    let mut hops = loop {
+        match connection.recv()? {
+            Packet::Trace(Trace::Report(hops)) => break hops,
+            Packet::Response(Response::Error(error)) => eyre::bail!(error),
+            _ => {}
+        }
+    };
+
+    // the hop of the switch the trace started on comes first
+    hops.sort_by_key(|hop| (hop.ingress.is_some(), hop.ingress, hop.switch_id));
+
+    if hops.is_empty() {
+        eyre::bail!("No switch reported the frame");
+    }
+
+    for hop in hops {
+        let ingress = match hop.ingress {
+            Some(switch_id) => format!("from switch {switch_id}"),
+            None => "from its tap".to_string(),
+        };
+        let decision = match hop.decision {
+            Decision::Unicast(switch_id) => format!("unicast to switch {switch_id}"),
+            Decision::Flood => "flooded".to_string(),
+            Decision::Delivered => "delivered to its tap".to_string(),
+            Decision::Openflow => "handled by the openflow table".to_string(),
+            Decision::Dropped(reason) => format!(
+                "dropped, {}",
+                match reason {
+                    DropReason::DeniedEthertype => "denied ethertype",
+                    DropReason::Draining => "flooded to a draining switch",
+                    DropReason::Suspended => "the vrf is suspended",
+                    DropReason::DeniedVlan => "vlan not allowed",
+                    DropReason::Acl => "denied by an acl rule",
+                    DropReason::UnknownDestination => "unknown destination",
+                    DropReason::Encryption => "can't be encrypted",
+                }
+            ),
+        };
+
+        println!("Switch {} {ingress}: {decision}", hop.switch_id);
+    }
+
+    Ok(())
+}
+
+pub fn parse_mac(destination: &str) -> Result<[u8; 6], String> {
+    mac::parse(destination).ok_or_else(|| format!("Invalid mac address {destination}"))
+}
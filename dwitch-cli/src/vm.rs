@@ -0,0 +1,110 @@
+use std::{
+    io::{IoSliceMut, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process,
+};
+
+use clap::Subcommand;
+use eyre::OptionExt;
+use nix::{
+    cmsg_space,
+    sys::socket::{recvmsg, ControlMessageOwned, MsgFlags},
+};
+use protocol::{
+    frame::{self, write_frame, HEADER_SIZE, READ_TIMEOUT},
+    EndpointAction, Packet, PacketSerializer, Response, MAX_PACKET_SIZE,
+};
+
+use crate::Target;
+
+#[derive(Subcommand)]
+pub enum VmCommand {
+    /// Attach a vm to a vrf and run its emulator, the vm port goes away when it exits
+    Run {
+        /// Name of the vrf
+        #[arg(long)]
+        vrf: String,
+
+        /// Name of the vm, unique within the vrf
+        #[arg(long)]
+        name: String,
+
+        /// Emulator command, `{fd}` is replaced by the descriptor of the tap
+        #[arg(trailing_var_arg = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+pub fn command(command: VmCommand, target: Target) -> eyre::Result<()> {
+    let Target::Unix(path) = target else {
+        eyre::bail!("Vms are attached through the vm socket of the daemon");
+    };
+
+    match command {
+        VmCommand::Run { vrf, name, command } => {
+            let mut stream = UnixStream::connect(path)?;
+
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            write_frame(
+                &mut stream,
+                &Packet::from(EndpointAction::AttachVm { vrf, name }).serialize()?,
+            )?;
+
+            let (reply, tap) = read_reply(&mut stream)?;
+
+            match reply {
+                Packet::EndpointAction(EndpointAction::VmAttached { ifname }) => {
+                    // the emulator inherits the tap, it's only closed when it exits
+                    let fd = tap
+                        .ok_or_eyre("The daemon didn't send the tap")?
+                        .into_raw_fd()
+                        .to_string();
+
+                    eprintln!("Attached the vm through {ifname}");
+
+                    let mut arguments =
+                        command.iter().map(|argument| argument.replace("{fd}", &fd));
+                    let program = arguments.next().ok_or_eyre("Missing emulator command")?;
+
+                    Err(process::Command::new(program).args(arguments).exec().into())
+                }
+                Packet::Response(Response::Error(error)) => Err(eyre::eyre!(error)),
+                packet => Err(eyre::eyre!("Unexpected packet {packet:?}")),
+            }
+        }
+    }
+}
+
+// the descriptor comes along with the length prefix of the reply
+fn read_reply(stream: &mut UnixStream) -> eyre::Result<(Packet, Option<OwnedFd>)> {
+    let mut header = [0u8; HEADER_SIZE];
+    let mut iov = [IoSliceMut::new(&mut header)];
+    let mut cmsg_buffer = cmsg_space!(RawFd);
+    let message = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        // kept across exec for the emulator
+        MsgFlags::empty(),
+    )?;
+    let received = message.bytes;
+    let fd = message
+        .cmsgs()?
+        .find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+            _ => None,
+        })
+        // received descriptors are new and owned by this process
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+
+    stream.read_exact(&mut header[received..])?;
+
+    let mut payload = vec![0u8; frame::length(header, MAX_PACKET_SIZE)?];
+
+    stream.read_exact(&mut payload)?;
+
+    Ok((Packet::deserialize(&payload)?, fd))
+}
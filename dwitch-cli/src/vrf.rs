@@ -1,12 +1,13 @@
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-};
-
 use clap::{Args, Subcommand};
 use common::{SwitchId, VrfId};
 use eyre::OptionExt;
-use protocol::{Packet, PacketSerializer, Vrf, VrfAction};
+use protocol::{
+    mac, prefix, Bpdu, Compression, Gateway, IpPrefix, IpRoute, Learning, MirrorAction,
+    MirrorTarget, Packet, Response, StaticMac, Vrf, VrfAction, VrfMetadata, VrfMode, VrfNetns,
+    VrfSettings, VrfTest, Vtep, Vxlan,
+};
+
+use crate::{Connection, Output};
 
 #[derive(Subcommand)]
 pub enum VrfCommand {
@@ -15,14 +16,44 @@ pub enum VrfCommand {
 
     /// Create a new vrf
     Create {
-        /// Id of the vrf
-        id: VrfId,
+        /// Id of the vrf, allocated by the switch when left out
+        #[arg(long)]
+        id: Option<VrfId>,
 
         /// Name of the vrf
         name: String,
 
         /// The list of switch ids where the vrf should be present
         members: Vec<SwitchId>,
+
+        /// Template of the daemon config filling the settings left unset
+        #[arg(long)]
+        template: Option<String>,
+
+        #[command(flatten)]
+        settings: Box<SettingsArgs>,
+
+        #[command(flatten)]
+        metadata: MetadataArgs,
+    },
+
+    /// Show everything about a vrf
+    Show {
+        #[command(flatten)]
+        id: VrfIdArg,
+    },
+
+    /// Change the description, owner or labels of a vrf
+    Describe {
+        #[command(flatten)]
+        id: VrfIdArg,
+
+        #[command(flatten)]
+        metadata: MetadataArgs,
+
+        /// Labels to remove
+        #[arg(long = "remove-label")]
+        remove_labels: Vec<String>,
     },
 
     /// Delete a vrf
@@ -31,6 +62,49 @@ pub enum VrfCommand {
         id: VrfIdArg,
     },
 
+    /// Stop forwarding the frames of a vrf on every member, keeping its configuration
+    Suspend {
+        #[command(flatten)]
+        id: VrfIdArg,
+    },
+
+    /// Forward the frames of a suspended vrf again
+    Resume {
+        #[command(flatten)]
+        id: VrfIdArg,
+    },
+
+    /// Echo test frames between two members through the overlay and their taps
+    Test {
+        #[command(flatten)]
+        id: VrfIdArg,
+
+        /// Switch id sending the test frames
+        #[arg(long)]
+        from: SwitchId,
+
+        /// Switch id echoing them back
+        #[arg(long)]
+        to: SwitchId,
+    },
+
+    /// Copy the frames going through the tap of a vrf on this switch to a pcap file or a tap
+    Mirror {
+        #[command(flatten)]
+        id: VrfIdArg,
+
+        #[command(flatten)]
+        target: MirrorArgs,
+
+        /// Bytes a pcap file reaches before it's rotated
+        #[arg(long, default_value_t = 100_000_000)]
+        max_size: u64,
+
+        /// Pcap files kept, the current one included
+        #[arg(long, default_value_t = 10)]
+        max_files: u32,
+    },
+
     /// Action on members
     Member {
         #[command(flatten)]
@@ -41,6 +115,22 @@ pub enum VrfCommand {
     },
 }
 
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+pub struct MirrorArgs {
+    /// Name of the pcap file, written in the mirror directory of the switch
+    #[arg(long)]
+    pcap: Option<String>,
+
+    /// Name of the tap created on the switch for the copies
+    #[arg(long)]
+    tap: Option<String>,
+
+    /// Stop mirroring the vrf
+    #[arg(long)]
+    stop: bool,
+}
+
 #[derive(Subcommand)]
 pub enum MemberCommand {
     /// Add members to the vrf
@@ -55,45 +145,466 @@ pub enum MemberCommand {
     },
 }
 
-pub fn command(command: VrfCommand, mut stream: TcpStream) -> eyre::Result<()> {
+#[derive(Args)]
+pub struct MetadataArgs {
+    /// What the vrf is for
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Who the vrf belongs to
+    #[arg(long)]
+    owner: Option<String>,
+
+    /// Label of the vrf, like team=network
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+}
+
+impl MetadataArgs {
+    fn apply(self, metadata: &mut VrfMetadata) {
+        if let Some(description) = self.description {
+            metadata.description = Some(description);
+        }
+
+        if let Some(owner) = self.owner {
+            metadata.owner = Some(owner);
+        }
+
+        metadata.labels.extend(self.labels);
+    }
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| "Expected a key and a value, like team=network".to_string())
+}
+
+#[derive(Args)]
+pub struct SettingsArgs {
+    /// What the vrf carries: tap for ethernet frames or tun for ip packets
+    #[arg(long, value_parser = parse_mode)]
+    mode: Option<VrfMode>,
+
+    /// Name of the tap instead of the generated one
+    #[arg(long)]
+    ifname: Option<String>,
+
+    /// Mac of the tap instead of a random one, like 02:00:00:00:00:01
+    #[arg(long, value_parser = parse_mac)]
+    mac: Option<[u8; 6]>,
+
+    /// Mtu of the tap, the endpoints and the uplink, jumbo frames included
+    #[arg(long)]
+    mtu: Option<u32>,
+
+    /// Address of the tap with its prefix, like 10.0.0.2/24
+    #[arg(long = "address", value_parser = parse_prefix)]
+    addresses: Vec<IpPrefix>,
+
+    /// Route through the tap, with its gateway if there's one, like 10.1.0.0/16@10.0.0.254
+    #[arg(long = "route", value_parser = parse_route)]
+    routes: Vec<IpRoute>,
+
+    /// Largest frame carried, the mtu and the room for two vlan tags by default
+    #[arg(long)]
+    max_frame_size: Option<u32>,
+
+    /// Maximum number of learned macs
+    #[arg(long)]
+    max_macs: Option<u32>,
+
+    /// How macs are found: dynamic, flood or static
+    #[arg(long, value_parser = parse_learning)]
+    learning: Option<Learning>,
+
+    /// Mac pinned behind a switch, like 02:00:00:00:00:01@1
+    #[arg(long = "static-mac", value_parser = parse_static_mac)]
+    static_macs: Vec<StaticMac>,
+
+    /// Frames per second read from the tap
+    #[arg(long)]
+    frame_rate: Option<u32>,
+
+    /// Bytes per second sent to the peers
+    #[arg(long)]
+    bandwidth: Option<u64>,
+
+    /// Bytes per second sent to each peer
+    #[arg(long)]
+    peer_bandwidth: Option<u64>,
+
+    /// Frames from peers waiting for the tap before new ones are dropped
+    #[arg(long)]
+    queue_depth: Option<u32>,
+
+    /// What to do with spanning tree bpdus: forward, filter or guard
+    #[arg(long, value_parser = parse_bpdu)]
+    bpdu: Option<Bpdu>,
+
+    /// Ethertype to drop, like 0x86dd
+    #[arg(long = "deny-ethertype", value_parser = parse_ethertype)]
+    deny_ethertypes: Vec<u16>,
+
+    /// Vlan id tagged frames are allowed with, any when left out
+    #[arg(long = "vlan", value_parser = clap::value_parser!(u16).range(1..4095))]
+    vlans: Vec<u16>,
+
+    /// Gateway address of the vrf with its prefix, like 10.0.0.1/24
+    #[arg(long, value_parser = parse_prefix)]
+    gateway: Option<IpPrefix>,
+
+    /// Serve the gateway on one elected member instead of every member
+    #[arg(long, requires = "gateway")]
+    elect_gateway: bool,
+
+    /// Masquerade what the gateway routes to the host network, to reach the internet
+    #[arg(long, requires = "gateway")]
+    masquerade: bool,
+
+    /// Compression of the frames sent to the other members: lz4 or zstd
+    #[arg(long, value_parser = parse_compression)]
+    compression: Option<Compression>,
+
+    /// Send the frames as standard vxlan instead of data packets
+    #[arg(long)]
+    vxlan: bool,
+
+    /// Vni of the vxlan frames, the vrf id by default
+    #[arg(long, requires = "vxlan")]
+    vni: Option<u32>,
+
+    /// Vtep outside dwitch with the switch id its macs are learned with, like 100@192.0.2.1:4789
+    #[arg(long = "vtep", requires = "vxlan", value_parser = parse_vtep)]
+    vteps: Vec<Vtep>,
+
+    /// Where the tap is created: create, default or existing:<name>
+    #[arg(long, value_parser = parse_netns)]
+    netns: Option<VrfNetns>,
+}
+
+impl From<SettingsArgs> for VrfSettings {
+    fn from(settings: SettingsArgs) -> Self {
+        VrfSettings {
+            mode: settings.mode,
+            ifname: settings.ifname,
+            mac: settings.mac,
+            mtu: settings.mtu,
+            addresses: settings.addresses,
+            routes: settings.routes,
+            max_frame_size: settings.max_frame_size,
+            max_macs: settings.max_macs,
+            learning: settings.learning,
+            static_macs: settings.static_macs,
+            frame_rate: settings.frame_rate,
+            bandwidth: settings.bandwidth,
+            peer_bandwidth: settings.peer_bandwidth,
+            queue_depth: settings.queue_depth,
+            deny_ethertypes: settings.deny_ethertypes,
+            vlans: settings.vlans,
+            bpdu: settings.bpdu,
+            gateway: settings.gateway.map(|address| Gateway {
+                address,
+                elect: settings.elect_gateway,
+                masquerade: settings.masquerade,
+            }),
+            compression: settings.compression,
+            vxlan: settings.vxlan.then_some(Vxlan {
+                vni: settings.vni,
+                vteps: settings.vteps,
+            }),
+            netns: settings.netns,
+        }
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<VrfMode, String> {
+    match mode {
+        "tap" => Ok(VrfMode::Tap),
+        "tun" => Ok(VrfMode::Tun),
+        _ => Err("Expected tap or tun".to_string()),
+    }
+}
+
+fn parse_learning(learning: &str) -> Result<Learning, String> {
+    match learning {
+        "dynamic" => Ok(Learning::Dynamic),
+        "flood" => Ok(Learning::Flood),
+        "static" => Ok(Learning::Static),
+        _ => Err("Expected dynamic, flood or static".to_string()),
+    }
+}
+
+fn parse_bpdu(bpdu: &str) -> Result<Bpdu, String> {
+    match bpdu {
+        "forward" => Ok(Bpdu::Forward),
+        "filter" => Ok(Bpdu::Filter),
+        "guard" => Ok(Bpdu::Guard),
+        _ => Err("Expected forward, filter or guard".to_string()),
+    }
+}
+
+fn parse_netns(netns: &str) -> Result<VrfNetns, String> {
+    VrfNetns::try_from(netns.to_string())
+}
+
+fn parse_compression(compression: &str) -> Result<Compression, String> {
+    match compression {
+        "lz4" => Ok(Compression::Lz4),
+        "zstd" => Ok(Compression::Zstd),
+        _ => Err("Expected lz4 or zstd".to_string()),
+    }
+}
+
+pub fn parse_mac(mac: &str) -> Result<[u8; 6], String> {
+    mac::parse(mac).ok_or_else(|| format!("Invalid mac address {mac}"))
+}
+
+fn parse_static_mac(static_mac: &str) -> Result<StaticMac, String> {
+    let (mac, switch_id) = static_mac
+        .split_once('@')
+        .ok_or("Expected a mac and a switch id, like 02:00:00:00:00:01@1")?;
+
+    Ok(StaticMac {
+        mac: mac::parse(mac).ok_or_else(|| format!("Invalid mac address {mac}"))?,
+        switch_id: switch_id.parse().map_err(|error| format!("{error}"))?,
+    })
+}
+
+fn parse_vtep(vtep: &str) -> Result<Vtep, String> {
+    let (switch_id, address) = vtep
+        .split_once('@')
+        .ok_or("Expected a switch id and an address, like 100@192.0.2.1:4789")?;
+
+    Ok(Vtep {
+        switch_id: switch_id.parse().map_err(|error| format!("{error}"))?,
+        address: address.parse().map_err(|error| format!("{error}"))?,
+    })
+}
+
+pub fn parse_prefix(ip_prefix: &str) -> Result<IpPrefix, String> {
+    prefix::parse(ip_prefix).ok_or_else(|| format!("Invalid ip prefix {ip_prefix}"))
+}
+
+fn parse_route(route: &str) -> Result<IpRoute, String> {
+    let (destination, gateway) = match route.split_once('@') {
+        Some((destination, gateway)) => (
+            destination,
+            Some(
+                gateway
+                    .parse()
+                    .map_err(|_| format!("Invalid gateway {gateway}"))?,
+            ),
+        ),
+        None => (route, None),
+    };
+
+    Ok(IpRoute {
+        destination: parse_prefix(destination)?,
+        gateway,
+    })
+}
+
+pub fn parse_ethertype(ethertype: &str) -> Result<u16, String> {
+    match ethertype.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => ethertype.parse(),
+    }
+    .map_err(|error| error.to_string())
+}
+
+pub fn command(
+    command: VrfCommand,
+    output: Output,
+    mut connection: Connection,
+) -> eyre::Result<()> {
     match command {
-        VrfCommand::List => {
-            println!("Vrf list:");
+        VrfCommand::List => output.print(list_vrf(&mut connection)?, print_list)?,
+        VrfCommand::Create {
+            id,
+            name,
+            members,
+            template,
+            settings,
+            metadata,
+        } => {
+            let mut vrf = Vrf {
+                id: id.unwrap_or_default(),
+                name,
+                members,
+                template,
+                settings: (*settings).into(),
+                metadata: VrfMetadata::default(),
+                suspended: false,
+                version: 0,
+            };
+
+            metadata.apply(&mut vrf.metadata);
+
+            if id.is_some() {
+                connection.request(VrfAction::Create(Box::new(vrf)))?;
+
+                return Ok(());
+            }
+
+            connection.send(VrfAction::Allocate(Box::new(vrf)))?;
 
-            for Vrf { id, name, members } in list_vrf(&mut stream)? {
-                println!("\t{id} - {name}: {members:?}");
+            match connection.recv()? {
+                Packet::VrfAction(VrfAction::Allocated { id }) => println!("Created vrf id {id}"),
+                Packet::Response(Response::Error(error)) => eyre::bail!(error),
+                packet => eyre::bail!("Unexpected packet {packet:?}"),
             }
         }
-        VrfCommand::Create { id, name, members } => {
-            stream.write_all(
-                &Packet::from(VrfAction::Create(Vrf { id, name, members })).serialize(),
-            )?;
-            stream.flush()?;
+        VrfCommand::Show { id } => output.print(id.find(&mut connection)?, print_vrf)?,
+        VrfCommand::Describe {
+            id,
+            metadata,
+            remove_labels,
+        } => {
+            let vrf = id.find(&mut connection)?;
+            let mut vrf_metadata = vrf.metadata;
+
+            for key in &remove_labels {
+                vrf_metadata.labels.remove(key);
+            }
+
+            metadata.apply(&mut vrf_metadata);
+
+            connection.request(VrfAction::Describe {
+                id: vrf.id,
+                metadata: vrf_metadata,
+            })?;
         }
         VrfCommand::Delete { id } => {
-            let id = id.get(&mut stream)?;
+            let id = id.get(&mut connection)?;
+
+            connection.request(VrfAction::Delete { id })?;
+        }
+        VrfCommand::Suspend { id } => {
+            let id = id.get(&mut connection)?;
+
+            connection.request(VrfAction::Suspend { id })?;
+        }
+        VrfCommand::Resume { id } => {
+            let id = id.get(&mut connection)?;
+
+            connection.request(VrfAction::Resume { id })?;
+        }
+        VrfCommand::Mirror {
+            id,
+            target: MirrorArgs { pcap, tap, stop },
+            max_size,
+            max_files,
+        } => {
+            let vrf_id = id.get(&mut connection)?;
+
+            if stop {
+                connection.request(MirrorAction::Stop { vrf_id })?;
+            } else {
+                let target = match (pcap, tap) {
+                    (Some(name), _) => MirrorTarget::Pcap {
+                        name,
+                        max_size,
+                        max_files,
+                    },
+                    (None, Some(ifname)) => MirrorTarget::Tap { ifname },
+                    (None, None) => eyre::bail!("Expected a pcap file or a tap"),
+                };
 
-            stream.write_all(&Packet::from(VrfAction::Delete { id }).serialize())?;
-            stream.flush()?;
+                connection.request(MirrorAction::Start { vrf_id, target })?;
+            }
+        }
+        VrfCommand::Test { id, from, to } => {
+            let vrf_id = id.get(&mut connection)?;
+
+            connection.send(VrfTest::Start { vrf_id, from, to })?;
+
+            let report = match connection.recv()? {
+                Packet::VrfTest(VrfTest::Report(report)) => report,
+                Packet::Response(Response::Error(error)) => eyre::bail!(error),
+                packet => eyre::bail!("Unexpected packet {packet:?}"),
+            };
+
+            println!(
+                "Vrf id {vrf_id} from switch {from} to switch {to}: {}/{} frames echoed, {:.3} ms round trip",
+                report.received,
+                report.sent,
+                report.round_trip as f64 / 1000.0
+            );
+
+            match report.mtu {
+                Some(mtu) => println!("\tMtu: {mtu}"),
+                None => println!("\tMtu: no mtu frame came back"),
+            }
         }
         VrfCommand::Member { id, command } => {
-            let id = id.get(&mut stream)?;
+            let id = id.get(&mut connection)?;
 
-            stream.write_all(&match command {
-                MemberCommand::Add { members } => {
-                    Packet::from(VrfAction::AddMember { id, members }).serialize()
-                }
-                MemberCommand::Remove { members } => {
-                    Packet::from(VrfAction::RemoveMember { id, members }).serialize()
-                }
+            connection.request(match command {
+                MemberCommand::Add { members } => VrfAction::AddMember { id, members },
+                MemberCommand::Remove { members } => VrfAction::RemoveMember { id, members },
             })?;
-            stream.flush()?;
         }
     }
 
     Ok(())
 }
 
+fn print_list(vrfs: Vec<Vrf>) {
+    println!("Vrf list:");
+
+    for Vrf {
+        id,
+        name,
+        members,
+        template,
+        metadata,
+        suspended,
+        ..
+    } in vrfs
+    {
+        let suspended = if suspended { " (suspended)" } else { "" };
+
+        match template {
+            Some(template) => println!("\t{id} - {name} ({template}): {members:?}{suspended}"),
+            None => println!("\t{id} - {name}: {members:?}{suspended}"),
+        }
+
+        if let Some(description) = metadata.description {
+            println!("\t\t{description}");
+        }
+    }
+}
+
+fn print_vrf(vrf: Vrf) {
+    println!("Vrf {} - {}", vrf.id, vrf.name);
+    println!("\tMembers: {:?}", vrf.members);
+    println!("\tVersion: {}", vrf.version);
+
+    if vrf.suspended {
+        println!("\tSuspended");
+    }
+
+    if let Some(template) = &vrf.template {
+        println!("\tTemplate: {template}");
+    }
+
+    if let Some(description) = &vrf.metadata.description {
+        println!("\tDescription: {description}");
+    }
+
+    if let Some(owner) = &vrf.metadata.owner {
+        println!("\tOwner: {owner}");
+    }
+
+    for (key, value) in &vrf.metadata.labels {
+        println!("\tLabel: {key}={value}");
+    }
+
+    println!("\tSettings: {:?}", vrf.settings);
+}
+
 #[derive(Args)]
 #[group(required = true, multiple = false)]
 pub struct VrfIdArg {
@@ -107,9 +618,9 @@ pub struct VrfIdArg {
 }
 
 impl VrfIdArg {
-    fn get(&self, stream: &mut TcpStream) -> eyre::Result<VrfId> {
+    pub fn get(&self, connection: &mut Connection) -> eyre::Result<VrfId> {
         Ok(if let Some(name) = &self.name {
-            list_vrf(stream)?
+            list_vrf(connection)?
                 .into_iter()
                 .find(|vrf| vrf.name == *name)
                 .ok_or_eyre("Can't find vrf with this name")?
@@ -118,27 +629,34 @@ impl VrfIdArg {
             self.id.unwrap()
         })
     }
+
+    fn find(&self, connection: &mut Connection) -> eyre::Result<Vrf> {
+        list_vrf(connection)?
+            .into_iter()
+            .find(|vrf| match &self.name {
+                Some(name) => vrf.name == *name,
+                None => Some(vrf.id) == self.id,
+            })
+            .ok_or_eyre("Can't find this vrf")
+    }
 }
 
-fn list_vrf(stream: &mut TcpStream) -> eyre::Result<Vec<Vrf>> {
-    stream.write_all(&Packet::from(VrfAction::List(None)).serialize())?;
-    stream.flush()?;
+pub fn list_vrf(connection: &mut Connection) -> eyre::Result<Vec<Vrf>> {
+    connection.send(VrfAction::List(None))?;
 
     let mut vrf_list = Vec::new();
 
     loop {
-        let mut buffer = [0u8; 65535];
-
-        stream.read(&mut buffer)?;
+        match connection.recv()? {
+            Packet::VrfAction(VrfAction::List(Some(vrf_list_chunk))) => {
+                if vrf_list_chunk.is_empty() {
+                    break;
+                }
 
-        if let Packet::VrfAction(VrfAction::List(Some(vrf_list_chunk))) =
-            Packet::deserialize(&buffer)?
-        {
-            if vrf_list_chunk.is_empty() {
-                break;
+                vrf_list.extend(vrf_list_chunk);
             }
-
-            vrf_list.extend(vrf_list_chunk);
+            Packet::Response(Response::Error(error)) => eyre::bail!(error),
+            _ => {}
         }
     }
 
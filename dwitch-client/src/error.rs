@@ -0,0 +1,53 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use protocol::{AuthError, Packet};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// A packet that can't be encoded, or that the daemon sent and can't be decoded.
+    Malformed(bincode::Error),
+    Auth(AuthError),
+    /// The daemon refused the request.
+    Daemon(String),
+    Unexpected(Box<Packet>),
+    TimedOut,
+    Closed,
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(error) => write!(f, "{error}"),
+            ClientError::Malformed(error) => write!(f, "malformed packet: {error}"),
+            ClientError::Auth(error) => write!(f, "{error}"),
+            ClientError::Daemon(error) => f.write_str(error),
+            ClientError::Unexpected(packet) => write!(f, "unexpected packet {packet:?}"),
+            ClientError::TimedOut => f.write_str("the daemon didn't answer in time"),
+            ClientError::Closed => f.write_str("the daemon closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<bincode::Error> for ClientError {
+    fn from(error: bincode::Error) -> Self {
+        Self::Malformed(error)
+    }
+}
+
+impl From<AuthError> for ClientError {
+    fn from(error: AuthError) -> Self {
+        Self::Auth(error)
+    }
+}
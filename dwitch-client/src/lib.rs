@@ -0,0 +1,399 @@
+//! Async client of the management protocol of the dwitch daemon, over its management socket or its
+//! listener for remote configuration clients.
+//!
+//! Each request waits on its answer, so a client runs one request at a time. A subscription takes
+//! over the connection, open another one for requests.
+
+mod error;
+
+use std::{
+    convert::Infallible,
+    io::{self, IoSlice},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use bytes::BytesMut;
+use common::{SwitchId, VrfId};
+use protocol::{
+    frame::{self, READ_TIMEOUT},
+    AclAction, AclRule, Authenticate, Event, Events, Handshake, HandshakeProof, MacAction,
+    MacEntry, Maintenance, MirrorAction, MirrorTarget, Packet, PacketSerializer, PeerAction,
+    PeerReport, Response, Save, Session, StatsAction, StatsReport, Status, StatusReport, Vrf,
+    VrfAction, VrfMetadata, CONFIGURATION_SWITCH_ID, MAX_PACKET_SIZE,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    time::timeout,
+};
+
+pub use error::ClientError;
+pub use protocol;
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Address of a daemon, or the path of its management socket.
+#[derive(Debug, Clone)]
+pub enum Target {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Target {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(address) => Target::Tcp(address),
+            Err(_) => Target::Unix(PathBuf::from(s)),
+        })
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub struct Client {
+    connection: Connection,
+    key: Option<Vec<u8>>,
+    session: Session,
+}
+
+impl Client {
+    /// Connect to a daemon, `key` being the shared key control packets are signed with.
+    pub async fn connect(target: &Target, key: Option<&[u8]>) -> Result<Self> {
+        let stream: Box<dyn Stream> = match target {
+            Target::Tcp(address) => Box::new(TcpStream::connect(address).await?),
+            Target::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        };
+        let mut connection = Connection {
+            stream,
+            buffer: BytesMut::new(),
+        };
+
+        let handshake = Handshake::new(CONFIGURATION_SWITCH_ID);
+
+        connection.write_frame(&handshake.serialize()?).await?;
+
+        let daemon_handshake = Handshake::deserialize(&connection.read_frame_in_time().await?)?;
+
+        connection
+            .write_frame(
+                &daemon_handshake
+                    .prove(key, CONFIGURATION_SWITCH_ID)
+                    .serialize()?,
+            )
+            .await?;
+
+        let proof = HandshakeProof::deserialize(&connection.read_frame_in_time().await?)?;
+
+        handshake.verify(key, daemon_handshake.switch_id, &proof)?;
+
+        Ok(Self {
+            connection,
+            key: key.map(<[u8]>::to_vec),
+            session: Session::new(&handshake, &daemon_handshake),
+        })
+    }
+
+    /// Switch id of the daemon.
+    pub fn switch_id(&self) -> SwitchId {
+        self.session.peer_switch_id()
+    }
+
+    /// Authenticate with a token, needed by remote clients whose address isn't an admin one.
+    pub async fn authenticate(&mut self, token: &str) -> Result<()> {
+        self.request(Authenticate {
+            token: token.to_string(),
+        })
+        .await
+    }
+
+    pub async fn list_vrfs(&mut self) -> Result<Vec<Vrf>> {
+        self.send(VrfAction::List(None)).await?;
+
+        let mut vrf_list = Vec::new();
+
+        // chunks of vrfs, ending with an empty one
+        loop {
+            match self.recv().await? {
+                Packet::VrfAction(VrfAction::List(Some(vrf_list_chunk))) => {
+                    if vrf_list_chunk.is_empty() {
+                        return Ok(vrf_list);
+                    }
+
+                    vrf_list.extend(vrf_list_chunk);
+                }
+                Packet::Response(Response::Error(error)) => return Err(ClientError::Daemon(error)),
+                packet => return Err(ClientError::Unexpected(Box::new(packet))),
+            }
+        }
+    }
+
+    /// Create a vrf with the id it's given.
+    pub async fn create_vrf(&mut self, vrf: Vrf) -> Result<()> {
+        self.request(VrfAction::Create(Box::new(vrf))).await
+    }
+
+    /// Create a vrf with an id picked by the daemon, its own id is ignored.
+    pub async fn allocate_vrf(&mut self, vrf: Vrf) -> Result<VrfId> {
+        self.send(VrfAction::Allocate(Box::new(vrf))).await?;
+
+        match self.recv().await? {
+            Packet::VrfAction(VrfAction::Allocated { id }) => Ok(id),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    pub async fn delete_vrf(&mut self, id: VrfId) -> Result<()> {
+        self.request(VrfAction::Delete { id }).await
+    }
+
+    pub async fn add_members(&mut self, id: VrfId, members: Vec<SwitchId>) -> Result<()> {
+        self.request(VrfAction::AddMember { id, members }).await
+    }
+
+    pub async fn remove_members(&mut self, id: VrfId, members: Vec<SwitchId>) -> Result<()> {
+        self.request(VrfAction::RemoveMember { id, members }).await
+    }
+
+    pub async fn describe_vrf(&mut self, id: VrfId, metadata: VrfMetadata) -> Result<()> {
+        self.request(VrfAction::Describe { id, metadata }).await
+    }
+
+    /// Connected peers of the daemon and the members of its vrfs that aren't.
+    pub async fn list_peers(&mut self) -> Result<Vec<PeerReport>> {
+        self.send(PeerAction::List).await?;
+
+        match self.recv().await? {
+            Packet::PeerAction(PeerAction::Report(peers)) => Ok(peers),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    /// Learned macs of a vrf on the daemon, most recently seen first.
+    pub async fn list_macs(&mut self, vrf_id: VrfId) -> Result<Vec<MacEntry>> {
+        self.send(MacAction::List { vrf_id }).await?;
+
+        let mut entries = Vec::new();
+
+        // chunks of entries, ending with an empty one
+        loop {
+            match self.recv().await? {
+                Packet::MacAction(MacAction::Entries(entries_chunk)) => {
+                    if entries_chunk.is_empty() {
+                        return Ok(entries);
+                    }
+
+                    entries.extend(entries_chunk);
+                }
+                Packet::Response(Response::Error(error)) => return Err(ClientError::Daemon(error)),
+                packet => return Err(ClientError::Unexpected(Box::new(packet))),
+            }
+        }
+    }
+
+    /// Pin a mac of a vrf behind a switch in the table of the daemon, before the learned ones.
+    pub async fn pin_mac(
+        &mut self,
+        vrf_id: VrfId,
+        mac: [u8; 6],
+        switch_id: SwitchId,
+    ) -> Result<()> {
+        self.request(MacAction::AddStatic {
+            vrf_id,
+            mac,
+            switch_id,
+        })
+        .await
+    }
+
+    pub async fn unpin_mac(&mut self, vrf_id: VrfId, mac: [u8; 6]) -> Result<()> {
+        self.request(MacAction::RemoveStatic { vrf_id, mac }).await
+    }
+
+    pub async fn list_acl(&mut self, vrf_id: VrfId) -> Result<Vec<AclRule>> {
+        self.send(AclAction::List { vrf_id }).await?;
+
+        match self.recv().await? {
+            Packet::AclAction(AclAction::Rules(rules)) => Ok(rules),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    /// Insert an acl rule in a vrf before the one at `position`, or after the last one.
+    pub async fn add_acl_rule(
+        &mut self,
+        vrf_id: VrfId,
+        rule: AclRule,
+        position: Option<u32>,
+    ) -> Result<()> {
+        self.request(AclAction::Add {
+            vrf_id,
+            rule,
+            position,
+        })
+        .await
+    }
+
+    pub async fn remove_acl_rule(&mut self, vrf_id: VrfId, index: u32) -> Result<()> {
+        self.request(AclAction::Remove { vrf_id, index }).await
+    }
+
+    /// Copy the frames going through the tap of a vrf on the daemon's switch to a target.
+    pub async fn start_mirror(&mut self, vrf_id: VrfId, target: MirrorTarget) -> Result<()> {
+        self.request(MirrorAction::Start { vrf_id, target }).await
+    }
+
+    pub async fn stop_mirror(&mut self, vrf_id: VrfId) -> Result<()> {
+        self.request(MirrorAction::Stop { vrf_id }).await
+    }
+
+    /// Stop forwarding the frames of a vrf, keeping its configuration and macs.
+    pub async fn suspend_vrf(&mut self, id: VrfId) -> Result<()> {
+        self.request(VrfAction::Suspend { id }).await
+    }
+
+    pub async fn resume_vrf(&mut self, id: VrfId) -> Result<()> {
+        self.request(VrfAction::Resume { id }).await
+    }
+
+    pub async fn set_maintenance(&mut self, maintenance: Maintenance) -> Result<()> {
+        self.request(maintenance).await
+    }
+
+    pub async fn status(&mut self) -> Result<StatusReport> {
+        self.send(Status::Query).await?;
+
+        match self.recv().await? {
+            Packet::Status(Status::Report(report)) => Ok(report),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    /// Traffic counters of the vrfs and peers of the daemon.
+    pub async fn stats(&mut self) -> Result<StatsReport> {
+        self.send(StatsAction::Query).await?;
+
+        match self.recv().await? {
+            Packet::StatsAction(StatsAction::Report(report)) => Ok(report),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    /// Persist the vrf and mac tables of the daemon now, returning how many of each were saved.
+    pub async fn save(&mut self) -> Result<(usize, usize)> {
+        self.send(Save::Request).await?;
+
+        match self.recv().await? {
+            Packet::Save(Save::Saved { vrfs, macs }) => Ok((vrfs, macs)),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    /// Turn the connection into a stream of the events of the daemon.
+    pub async fn subscribe(mut self) -> Result<Subscription> {
+        self.request(Events::Subscribe).await?;
+
+        Ok(Subscription { client: self })
+    }
+
+    /// Send a request answered with an ok or an error response.
+    pub async fn request<T: Into<Packet>>(&mut self, packet: T) -> Result<()> {
+        self.send(packet).await?;
+
+        match self.recv().await? {
+            Packet::Response(Response::Ok) => Ok(()),
+            Packet::Response(Response::Error(error)) => Err(ClientError::Daemon(error)),
+            packet => Err(ClientError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    pub async fn send<T: Into<Packet>>(&mut self, packet: T) -> Result<()> {
+        let packet = packet.into().seal(self.key.as_deref(), &mut self.session)?;
+
+        self.connection.write_frame(&packet.serialize()?).await
+    }
+
+    /// Next packet from the daemon, failing if it doesn't come in time.
+    pub async fn recv(&mut self) -> Result<Packet> {
+        let frame = self.connection.read_frame_in_time().await?;
+
+        self.open(&frame)
+    }
+
+    fn open(&mut self, frame: &[u8]) -> Result<Packet> {
+        Ok(Packet::deserialize(frame)?.open(self.key.as_deref(), &mut self.session, false)?)
+    }
+}
+
+// the stream to the daemon and the bytes read past the last frame
+struct Connection {
+    stream: Box<dyn Stream>,
+    buffer: BytesMut,
+}
+
+impl Connection {
+    async fn write_frame(&mut self, payload: &[u8]) -> Result<()> {
+        let header = frame::header(payload);
+        let mut slices = [IoSlice::new(&header), IoSlice::new(payload)];
+        let mut slices = &mut slices[..];
+
+        while !slices.is_empty() {
+            let length = self.stream.write_vectored(slices).await?;
+
+            if length == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+
+            IoSlice::advance_slices(&mut slices, length);
+        }
+
+        Ok(self.stream.flush().await?)
+    }
+
+    async fn read_frame_in_time(&mut self) -> Result<BytesMut> {
+        timeout(READ_TIMEOUT, self.read_frame())
+            .await
+            .map_err(|_| ClientError::TimedOut)?
+    }
+
+    async fn read_frame(&mut self) -> Result<BytesMut> {
+        loop {
+            if let Some(frame) = frame::decode(&mut self.buffer, MAX_PACKET_SIZE)? {
+                return Ok(frame);
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(ClientError::Closed);
+            }
+        }
+    }
+}
+
+/// Events of a daemon, as they happen.
+pub struct Subscription {
+    client: Client,
+}
+
+impl Subscription {
+    /// Wait for the next event, however long it takes.
+    pub async fn next(&mut self) -> Result<Event> {
+        loop {
+            let frame = self.client.connection.read_frame().await?;
+
+            if let Packet::Events(Events::Event(event)) = self.client.open(&frame)? {
+                return Ok(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::{os::unix::net::UnixStream, path::Path};
+
+use protocol::{
+    frame::{read_frame, write_frame, READ_TIMEOUT},
+    Handshake, HandshakeProof, Packet, PacketSerializer, Session, CONFIGURATION_SWITCH_ID,
+};
+
+use crate::CniError;
+
+/// Configuration client on the management socket of the local daemon.
+pub struct Connection {
+    stream: UnixStream,
+    key: Option<String>,
+    session: Session,
+}
+
+impl Connection {
+    pub fn connect(path: &Path, key: Option<String>) -> Result<Self, CniError> {
+        let mut stream = UnixStream::connect(path).map_err(|error| {
+            CniError::daemon(format!(
+                "Can't connect to the daemon at {}: {error}",
+                path.display()
+            ))
+        })?;
+
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let invalid = |error| CniError::daemon(format!("Invalid handshake: {error}"));
+        let key_bytes = key.as_deref().map(str::as_bytes);
+        let handshake = Handshake::new(CONFIGURATION_SWITCH_ID);
+
+        write_frame(&mut stream, &serialize(&handshake)?)?;
+
+        let daemon_handshake =
+            Handshake::deserialize(&read_frame(&mut stream)?).map_err(&invalid)?;
+
+        write_frame(
+            &mut stream,
+            &serialize(&daemon_handshake.prove(key_bytes, CONFIGURATION_SWITCH_ID))?,
+        )?;
+
+        let proof = HandshakeProof::deserialize(&read_frame(&mut stream)?).map_err(&invalid)?;
+
+        handshake
+            .verify(key_bytes, daemon_handshake.switch_id, &proof)
+            .map_err(|error| CniError::daemon(format!("Invalid handshake: {error}")))?;
+
+        Ok(Self {
+            stream,
+            key,
+            session: Session::new(&handshake, &daemon_handshake),
+        })
+    }
+
+    pub fn request<T: Into<Packet>>(&mut self, packet: T) -> Result<Packet, CniError> {
+        let key = self.key.as_deref().map(str::as_bytes);
+        let packet = packet
+            .into()
+            .seal(key, &mut self.session)
+            .and_then(|packet| packet.serialize())
+            .map_err(|error| CniError::io(format!("Can't serialize packet: {error}")))?;
+
+        write_frame(&mut self.stream, &packet)?;
+
+        Packet::deserialize(&read_frame(&mut self.stream)?)
+            .map_err(|error| CniError::daemon(format!("Invalid packet: {error}")))?
+            .open(key, &mut self.session, false)
+            .map_err(|error| CniError::daemon(format!("Invalid packet: {error}")))
+    }
+}
+
+fn serialize(handshake: &impl PacketSerializer) -> Result<Vec<u8>, CniError> {
+    PacketSerializer::serialize(handshake)
+        .map_err(|error| CniError::io(format!("Can't serialize handshake: {error}")))
+}
@@ -0,0 +1,128 @@
+use std::{
+    env,
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use protocol::{IpPrefix, IpRoute};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::CniError;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct IpamResult {
+    #[serde(default)]
+    pub ips: Vec<IpConfig>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IpConfig {
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Route {
+    pub dst: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gw: Option<String>,
+}
+
+impl IpamResult {
+    pub fn addresses(&self) -> Result<Vec<IpPrefix>, CniError> {
+        self.ips
+            .iter()
+            .map(|ip| parse_prefix(&ip.address))
+            .collect()
+    }
+
+    pub fn routes(&self) -> Result<Vec<IpRoute>, CniError> {
+        self.routes
+            .iter()
+            .map(|route| {
+                Ok(IpRoute {
+                    destination: parse_prefix(&route.dst)?,
+                    gateway: route.gw.as_deref().map(parse_address).transpose()?,
+                })
+            })
+            .collect()
+    }
+}
+
+fn parse_address(address: &str) -> Result<IpAddr, CniError> {
+    address
+        .parse()
+        .map_err(|_| CniError::decode(format!("Invalid ip address {address}")))
+}
+
+fn parse_prefix(prefix: &str) -> Result<IpPrefix, CniError> {
+    let (address, prefix_length) = prefix
+        .split_once('/')
+        .ok_or_else(|| CniError::decode(format!("Invalid ip prefix {prefix}")))?;
+
+    Ok(IpPrefix {
+        address: parse_address(address)?,
+        prefix_length: prefix_length
+            .parse()
+            .map_err(|_| CniError::decode(format!("Invalid ip prefix {prefix}")))?,
+    })
+}
+
+/// Run the ipam plugin of the network with the same configuration, as the runtime ran this one.
+pub fn delegate(ipam_type: &str, command: &str, config: &[u8]) -> Result<Option<Value>, CniError> {
+    let plugin = find_plugin(ipam_type)?;
+    let mut child = Command::new(&plugin)
+        .env("CNI_COMMAND", command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|error| CniError::io(format!("Can't run {}: {error}", plugin.display())))?;
+
+    // dropping stdin closes it so the plugin sees the whole configuration
+    child
+        .stdin
+        .take()
+        .expect("Stdin is piped")
+        .write_all(config)
+        .map_err(|error| CniError::io(format!("Can't configure {ipam_type}: {error}")))?;
+
+    let output = child
+        .wait_with_output()
+        .map_err(|error| CniError::io(format!("Can't run {ipam_type}: {error}")))?;
+
+    if !output.status.success() {
+        // plugins report their errors in the same format, pass them on as they are
+        return Err(serde_json::from_slice(&output.stdout)
+            .unwrap_or_else(|_| CniError::io(format!("{ipam_type} failed: {}", output.status))));
+    }
+
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map(Some)
+        .map_err(|error| CniError::decode(format!("Invalid {ipam_type} result: {error}")))
+}
+
+fn find_plugin(plugin_type: &str) -> Result<PathBuf, CniError> {
+    if plugin_type.contains('/') {
+        return Err(CniError::config(format!("Invalid ipam type {plugin_type}")));
+    }
+
+    let paths = env::var_os("CNI_PATH")
+        .ok_or_else(|| CniError::environment("Missing CNI_PATH".to_string()))?;
+
+    env::split_paths(&paths)
+        .map(|path| path.join(plugin_type))
+        .find(|path| path.is_file())
+        .ok_or_else(|| CniError::config(format!("Can't find the ipam plugin {plugin_type}")))
+}
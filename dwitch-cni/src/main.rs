@@ -0,0 +1,283 @@
+//! CNI plugin plugging pods into a vrf of the local dwitch daemon.
+
+mod connection;
+mod ipam;
+
+use std::{
+    env,
+    io::{self, Read},
+    path::PathBuf,
+    process::exit,
+};
+
+use protocol::{Endpoint, EndpointAction, Packet, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use connection::Connection;
+use ipam::{delegate, IpamResult};
+
+const CNI_VERSION: &str = "1.0.0";
+const SUPPORTED_VERSIONS: &[&str] = &["0.3.0", "0.3.1", "0.4.0", "1.0.0"];
+const MANAGEMENT_SOCKET_PATH: &str = "/run/dwitch.sock";
+
+// error codes from the cni specification, the ones above 100 belong to the plugin
+const INCOMPATIBLE_VERSION: u32 = 1;
+const INVALID_ENVIRONMENT: u32 = 4;
+const IO_FAILURE: u32 = 5;
+const DECODE_FAILURE: u32 = 6;
+const INVALID_CONFIG: u32 = 7;
+const DAEMON_FAILURE: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct CniError {
+    code: u32,
+    msg: String,
+    #[serde(default)]
+    details: String,
+}
+
+impl CniError {
+    fn new(code: u32, msg: String) -> Self {
+        Self {
+            code,
+            msg,
+            details: String::new(),
+        }
+    }
+
+    pub fn environment(msg: String) -> Self {
+        Self::new(INVALID_ENVIRONMENT, msg)
+    }
+
+    pub fn io(msg: String) -> Self {
+        Self::new(IO_FAILURE, msg)
+    }
+
+    pub fn decode(msg: String) -> Self {
+        Self::new(DECODE_FAILURE, msg)
+    }
+
+    pub fn config(msg: String) -> Self {
+        Self::new(INVALID_CONFIG, msg)
+    }
+
+    pub fn daemon(msg: String) -> Self {
+        Self::new(DAEMON_FAILURE, msg)
+    }
+}
+
+impl From<io::Error> for CniError {
+    fn from(error: io::Error) -> Self {
+        Self::io(error.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetConf {
+    cni_version: String,
+    /// Name of the vrf the pods are attached to
+    vrf: String,
+    /// Management socket of the local daemon
+    #[serde(default = "default_socket")]
+    socket: PathBuf,
+    /// Shared key used to sign control packets
+    key: Option<String>,
+    ipam: Option<IpamConf>,
+}
+
+#[derive(Deserialize)]
+struct IpamConf {
+    #[serde(rename = "type")]
+    ipam_type: String,
+}
+
+fn default_socket() -> PathBuf {
+    PathBuf::from(MANAGEMENT_SOCKET_PATH)
+}
+
+impl NetConf {
+    fn parse(config: &[u8]) -> Result<Self, CniError> {
+        let conf = serde_json::from_slice::<Self>(config)
+            .map_err(|error| CniError::decode(format!("Invalid network config: {error}")))?;
+
+        if !SUPPORTED_VERSIONS.contains(&conf.cni_version.as_str()) {
+            return Err(CniError::new(
+                INCOMPATIBLE_VERSION,
+                format!("Unsupported cni version {}", conf.cni_version),
+            ));
+        }
+
+        Ok(conf)
+    }
+
+    fn delegate_ipam(&self, command: &str, config: &[u8]) -> Result<Option<Value>, CniError> {
+        match &self.ipam {
+            Some(ipam) => delegate(&ipam.ipam_type, command, config),
+            None => Ok(None),
+        }
+    }
+}
+
+fn env_var(name: &str) -> Result<String, CniError> {
+    env::var(name).map_err(|_| CniError::environment(format!("Missing {name}")))
+}
+
+fn add(config: &[u8]) -> Result<Option<Value>, CniError> {
+    let conf = NetConf::parse(config)?;
+    let netns = PathBuf::from(env_var("CNI_NETNS")?);
+    let ifname = env_var("CNI_IFNAME")?;
+    let ipam_result = match conf.delegate_ipam("ADD", config)? {
+        Some(result) => serde_json::from_value::<IpamResult>(result)
+            .map_err(|error| CniError::decode(format!("Invalid ipam result: {error}")))?,
+        None => IpamResult::default(),
+    };
+    let endpoint = Endpoint {
+        vrf: conf.vrf.clone(),
+        netns: netns.clone(),
+        ifname: ifname.clone(),
+        addresses: ipam_result.addresses()?,
+        routes: ipam_result.routes()?,
+    };
+    let mac = match attach(&conf, endpoint) {
+        Ok(mac) => mac,
+        Err(error) => {
+            // give the addresses back, the runtime won't call DEL for a failed ADD
+            let _ = conf.delegate_ipam("DEL", config);
+
+            return Err(error);
+        }
+    };
+    // older results name the ip version of each address
+    let versioned = conf.cni_version.starts_with("0.");
+    let ips = ipam_result
+        .ips
+        .iter()
+        .map(|ip| {
+            let mut value = json!({ "address": ip.address, "interface": 0 });
+
+            if let Some(gateway) = &ip.gateway {
+                value["gateway"] = json!(gateway);
+            }
+
+            if versioned {
+                value["version"] = json!(if ip.address.contains(':') { "6" } else { "4" });
+            }
+
+            value
+        })
+        .collect::<Vec<_>>();
+    let mut result = json!({
+        "cniVersion": conf.cni_version,
+        "interfaces": [{
+            "name": ifname,
+            "mac": mac
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(":"),
+            "sandbox": netns,
+        }],
+        "ips": ips,
+        "routes": ipam_result.routes,
+    });
+
+    if let Some(dns) = ipam_result.dns {
+        result["dns"] = dns;
+    }
+
+    Ok(Some(result))
+}
+
+fn attach(conf: &NetConf, endpoint: Endpoint) -> Result<[u8; 6], CniError> {
+    let mut connection = Connection::connect(&conf.socket, conf.key.clone())?;
+
+    match connection.request(EndpointAction::Attach(endpoint))? {
+        Packet::EndpointAction(EndpointAction::Attached { mac }) => Ok(mac),
+        Packet::Response(Response::Error(error)) => Err(CniError::daemon(error)),
+        packet => Err(CniError::daemon(format!("Unexpected packet {packet:?}"))),
+    }
+}
+
+fn del(config: &[u8]) -> Result<Option<Value>, CniError> {
+    let conf = NetConf::parse(config)?;
+    let ifname = env_var("CNI_IFNAME")?;
+
+    // the runtime may have lost the namespace already, there's nothing left to detach then
+    if let Some(netns) = env::var_os("CNI_NETNS").filter(|netns| !netns.is_empty()) {
+        let mut connection = Connection::connect(&conf.socket, conf.key.clone())?;
+
+        match connection.request(EndpointAction::Detach {
+            netns: PathBuf::from(netns),
+            ifname,
+        })? {
+            Packet::Response(Response::Ok) => {}
+            Packet::Response(Response::Error(error)) => return Err(CniError::daemon(error)),
+            packet => return Err(CniError::daemon(format!("Unexpected packet {packet:?}"))),
+        }
+    }
+
+    conf.delegate_ipam("DEL", config)?;
+
+    Ok(None)
+}
+
+fn check(config: &[u8]) -> Result<Option<Value>, CniError> {
+    let conf = NetConf::parse(config)?;
+
+    conf.delegate_ipam("CHECK", config)?;
+
+    Ok(None)
+}
+
+fn run(config: &[u8]) -> Result<Option<Value>, CniError> {
+    let command = env_var("CNI_COMMAND")?;
+
+    match command.as_str() {
+        "ADD" => add(config),
+        "DEL" => del(config),
+        "CHECK" => check(config),
+        "VERSION" => Ok(Some(json!({
+            "cniVersion": CNI_VERSION,
+            "supportedVersions": SUPPORTED_VERSIONS,
+        }))),
+        _ => Err(CniError::environment(format!(
+            "Unknown CNI_COMMAND {command}"
+        ))),
+    }
+}
+
+fn main() {
+    let mut config = Vec::new();
+
+    // VERSION comes without a configuration
+    if env::var("CNI_COMMAND").as_deref() != Ok("VERSION") {
+        if let Err(error) = io::stdin().read_to_end(&mut config) {
+            eprintln!("Can't read the network config: {error}");
+            exit(1);
+        }
+    }
+
+    let cni_version = serde_json::from_slice::<Value>(&config)
+        .ok()
+        .and_then(|conf| conf["cniVersion"].as_str().map(str::to_string))
+        .unwrap_or_else(|| CNI_VERSION.to_string());
+
+    match run(&config) {
+        Ok(Some(result)) => println!("{result}"),
+        Ok(None) => {}
+        Err(error) => {
+            println!(
+                "{}",
+                json!({
+                    "cniVersion": cni_version,
+                    "code": error.code,
+                    "msg": error.msg,
+                    "details": error.details,
+                })
+            );
+            exit(1);
+        }
+    }
+}
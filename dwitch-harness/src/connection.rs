@@ -0,0 +1,57 @@
+use std::{os::unix::net::UnixStream, path::Path};
+
+use protocol::{
+    frame::{read_frame, write_frame, READ_TIMEOUT},
+    Handshake, HandshakeProof, Packet, PacketSerializer, Session, CONFIGURATION_SWITCH_ID,
+};
+
+use crate::HarnessError;
+
+/// Configuration client on the management socket of a daemon of the harness.
+pub struct Connection {
+    stream: UnixStream,
+    session: Session,
+}
+
+impl Connection {
+    pub fn connect(path: &Path) -> Result<Self, HarnessError> {
+        let mut stream = UnixStream::connect(path)?;
+
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let handshake = Handshake::new(CONFIGURATION_SWITCH_ID);
+
+        write_frame(&mut stream, &handshake.serialize()?)?;
+
+        let daemon_handshake = Handshake::deserialize(&read_frame(&mut stream)?)?;
+
+        write_frame(
+            &mut stream,
+            &daemon_handshake
+                .prove(None, CONFIGURATION_SWITCH_ID)
+                .serialize()?,
+        )?;
+        handshake.verify(
+            None,
+            daemon_handshake.switch_id,
+            &HandshakeProof::deserialize(&read_frame(&mut stream)?)?,
+        )?;
+
+        Ok(Self {
+            stream,
+            session: Session::new(&handshake, &daemon_handshake),
+        })
+    }
+
+    pub fn request<T: Into<Packet>>(&mut self, packet: T) -> Result<Packet, HarnessError> {
+        let packet = packet.into().seal(None, &mut self.session)?;
+
+        write_frame(&mut self.stream, &packet.serialize()?)?;
+
+        Ok(Packet::deserialize(&read_frame(&mut self.stream)?)?.open(
+            None,
+            &mut self.session,
+            false,
+        )?)
+    }
+}
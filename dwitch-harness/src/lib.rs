@@ -0,0 +1,721 @@
+//! Integration harness running real daemons, each in its own network namespace, wired to a shared
+//! bridge through veth pairs. The daemons are driven over their management socket, and the vrfs
+//! they carry are checked end to end from the vrf namespaces.
+//!
+//! It needs root, iproute2 and a built daemon, found at `DWITCH_BIN` or next to the test binaries:
+//! `cargo build -p dwitch && cargo test -p dwitch-harness -- --ignored`.
+
+mod connection;
+
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    path::PathBuf,
+    process::{self, Child, Command, Stdio},
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{SwitchId, VrfId};
+use connection::Connection;
+use netns::Netns;
+use nix::{
+    sched::{setns, CloneFlags},
+    sys::{
+        signal::{kill, Signal},
+        socket::{
+            recv, send, setsockopt, socket, sockopt::ReceiveTimeout, AddressFamily, MsgFlags,
+            SockFlag, SockProtocol, SockType,
+        },
+        time::TimeVal,
+    },
+    unistd::Pid,
+};
+use protocol::{
+    AclAction, AclRule, MacAction, Packet, Response, Vrf, VrfAction, VrfMetadata, VrfSettings,
+};
+
+pub type HarnessError = Box<dyn Error + Send + Sync>;
+
+const CONFIG_DIRECTORY: &str = "/etc/dwitch";
+const RUN_DIRECTORY: &str = "/run";
+const CACHE_DIRECTORY: &str = "/var/cache";
+const NETNS_DIRECTORY: &str = "/run/netns";
+const PORT: u16 = 7000;
+const VXLAN_PORT: u16 = 4789;
+const BRIDGE: &str = "br0";
+const UPLINK: &str = "eth0";
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const MAGIC: &[u8] = b"dwitch-harness";
+
+// harnesses of the tests running in parallel get their own names
+static HARNESS_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub struct Harness {
+    name: String,
+    switches: Vec<Switch>,
+}
+
+struct Switch {
+    switch_id: SwitchId,
+    instance: String,
+    daemon: Option<Child>,
+}
+
+// how the switches find the peers they don't dial
+enum Finding {
+    Configured,
+    Discovery,
+    Gossip,
+}
+
+impl Harness {
+    /// Start daemons with the switch ids 1 to `count`, all peering with each other.
+    pub fn start(count: SwitchId) -> Result<Self, HarnessError> {
+        Self::start_with_peering(count, |_, _| true)
+    }
+
+    /// Start daemons with the switch ids 1 to `count`, each one dialing the peers `dials` picks.
+    pub fn start_with_peering(
+        count: SwitchId,
+        dials: impl Fn(SwitchId, SwitchId) -> bool,
+    ) -> Result<Self, HarnessError> {
+        Self::start_with(count, dials, Finding::Configured)
+    }
+
+    /// Start daemons with the switch ids 1 to `count` dialing no one, they find each other over
+    /// mdns.
+    pub fn start_with_discovery(count: SwitchId) -> Result<Self, HarnessError> {
+        Self::start_with(count, |_, _| false, Finding::Discovery)
+    }
+
+    /// Start daemons with the switch ids 1 to `count` only dialing the first one, they learn about
+    /// each other from the peers it advertises.
+    pub fn start_with_gossip(count: SwitchId) -> Result<Self, HarnessError> {
+        Self::start_with(count, |_, peer| peer == 1, Finding::Gossip)
+    }
+
+    fn start_with(
+        count: SwitchId,
+        dials: impl Fn(SwitchId, SwitchId) -> bool,
+        finding: Finding,
+    ) -> Result<Self, HarnessError> {
+        let mut harness = Self {
+            name: format!(
+                "dwh{}-{}",
+                process::id(),
+                HARNESS_COUNT.fetch_add(1, Ordering::Relaxed)
+            ),
+            switches: Vec::new(),
+        };
+        let fabric = harness.fabric();
+
+        ip(&["netns", "add", &fabric])?;
+        ip(&["-n", &fabric, "link", "add", BRIDGE, "type", "bridge"])?;
+        ip(&["-n", &fabric, "link", "set", BRIDGE, "up"])?;
+
+        for switch_id in 1..=count {
+            let instance = format!("{}-{switch_id}", harness.name);
+
+            // registered first so a failure still cleans it up
+            harness.switches.push(Switch {
+                switch_id,
+                instance: instance.clone(),
+                daemon: None,
+            });
+
+            let port = format!("sw{switch_id}");
+            let address = format!("{}/24", underlay_address(switch_id));
+
+            ip(&["netns", "add", &instance])?;
+            ip(&[
+                "-n", &fabric, "link", "add", &port, "type", "veth", "peer", "name", UPLINK,
+                "netns", &instance,
+            ])?;
+            ip(&["-n", &fabric, "link", "set", &port, "master", BRIDGE, "up"])?;
+            ip(&["-n", &instance, "addr", "add", &address, "dev", UPLINK])?;
+            ip(&["-n", &instance, "link", "set", UPLINK, "up"])?;
+            ip(&["-n", &instance, "link", "set", "lo", "up"])?;
+
+            let servers = (1..=count)
+                .filter(|peer| *peer != switch_id && dials(switch_id, *peer))
+                .map(|peer| format!("\"{}\"", SocketAddrV4::new(underlay_address(peer), PORT)))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let mut config = format!(
+                "switch_id = {switch_id}\nlisten = \"{}\"\nservers = [{servers}]\n\n\
+                 [vxlan]\nlisten = \"{}\"\n",
+                SocketAddrV4::new(underlay_address(switch_id), PORT),
+                SocketAddrV4::new(underlay_address(switch_id), VXLAN_PORT)
+            );
+
+            match finding {
+                Finding::Configured => {}
+                // the namespaces have no multicast route, announcements go out the uplink
+                Finding::Discovery => {
+                    config += &format!(
+                        "\n[discovery]\ninterface = \"{}\"\ninterval = 1\n",
+                        underlay_address(switch_id)
+                    );
+                }
+                Finding::Gossip => config += "\n[gossip]\n",
+            }
+
+            fs::create_dir_all(CONFIG_DIRECTORY)?;
+            fs::write(config_path(&instance), config)?;
+
+            let daemon = spawn_daemon(&instance)?;
+
+            harness.switches.last_mut().unwrap().daemon = Some(daemon);
+        }
+
+        for switch in &harness.switches {
+            retry(|| Connection::connect(&management_socket(&switch.instance)).map(|_| ()))?;
+        }
+
+        Ok(harness)
+    }
+
+    /// Create a vrf on its members, each one gets it directly rather than waiting for its peers.
+    pub fn create_vrf(
+        &self,
+        vrf_id: VrfId,
+        name: &str,
+        members: &[SwitchId],
+    ) -> Result<(), HarnessError> {
+        self.create_vrf_with(vrf_id, name, members, VrfSettings::default())
+    }
+
+    /// Like `create_vrf`, with settings other than the default ones.
+    pub fn create_vrf_with(
+        &self,
+        vrf_id: VrfId,
+        name: &str,
+        members: &[SwitchId],
+        settings: VrfSettings,
+    ) -> Result<(), HarnessError> {
+        let vrf = Vrf {
+            id: vrf_id,
+            name: name.to_string(),
+            members: members.to_vec(),
+            template: None,
+            settings,
+            metadata: VrfMetadata::default(),
+            suspended: false,
+            version: 0,
+        };
+
+        for switch_id in members {
+            let mut connection =
+                Connection::connect(&management_socket(self.instance(*switch_id)?))?;
+
+            match connection.request(VrfAction::Create(Box::new(vrf.clone())))? {
+                Packet::Response(Response::Ok) => {}
+                // a peer was faster
+                Packet::Response(Response::Error(error)) if error.contains("already exists") => {}
+                Packet::Response(Response::Error(error)) => return Err(error.into()),
+                packet => return Err(format!("Unexpected packet {packet:?}").into()),
+            }
+        }
+
+        for switch_id in members {
+            self.wait_for_vrf(*switch_id, name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a vrf through one switch only, the others get it from their peers.
+    pub fn create_vrf_through(
+        &self,
+        switch_id: SwitchId,
+        vrf_id: VrfId,
+        name: &str,
+        members: &[SwitchId],
+    ) -> Result<(), HarnessError> {
+        let vrf = Vrf {
+            id: vrf_id,
+            name: name.to_string(),
+            members: members.to_vec(),
+            template: None,
+            settings: VrfSettings::default(),
+            metadata: VrfMetadata::default(),
+            suspended: false,
+            version: 0,
+        };
+        let mut connection = Connection::connect(&management_socket(self.instance(switch_id)?))?;
+
+        match connection.request(VrfAction::Create(Box::new(vrf)))? {
+            Packet::Response(Response::Ok) => Ok(()),
+            Packet::Response(Response::Error(error)) => Err(error.into()),
+            packet => Err(format!("Unexpected packet {packet:?}").into()),
+        }
+    }
+
+    /// Wait for the tap of a vrf to show up on a switch.
+    pub fn wait_for_vrf(&self, switch_id: SwitchId, name: &str) -> Result<(), HarnessError> {
+        let vrf_netns = self.vrf_netns(switch_id, name)?;
+        let tap = self.tap(switch_id, name)?;
+
+        retry(|| ip(&["-n", &vrf_netns, "link", "show", "dev", &tap]))
+    }
+
+    /// Shut a daemon down the way an operator would, its cache is kept for when it starts again.
+    pub fn stop(&mut self, switch_id: SwitchId) -> Result<(), HarnessError> {
+        let switch = self.switch_mut(switch_id)?;
+
+        if let Some(mut daemon) = switch.daemon.take() {
+            kill(Pid::from_raw(daemon.id() as i32), Signal::SIGTERM)?;
+            daemon.wait()?;
+        }
+
+        Ok(())
+    }
+
+    /// Start a stopped daemon again.
+    pub fn restart(&mut self, switch_id: SwitchId) -> Result<(), HarnessError> {
+        let switch = self.switch_mut(switch_id)?;
+
+        if switch.daemon.is_none() {
+            switch.daemon = Some(spawn_daemon(&switch.instance)?);
+        }
+
+        let instance = switch.instance.clone();
+
+        retry(|| Connection::connect(&management_socket(&instance)).map(|_| ()))
+    }
+
+    /// Suspend or resume a vrf through one switch, which hands it over to the other members.
+    pub fn suspend_vrf(
+        &self,
+        switch_id: SwitchId,
+        vrf_id: VrfId,
+        suspended: bool,
+    ) -> Result<(), HarnessError> {
+        let mut connection = Connection::connect(&management_socket(self.instance(switch_id)?))?;
+        let vrf_action = if suspended {
+            VrfAction::Suspend { id: vrf_id }
+        } else {
+            VrfAction::Resume { id: vrf_id }
+        };
+
+        match connection.request(vrf_action)? {
+            Packet::Response(Response::Ok) => Ok(()),
+            Packet::Response(Response::Error(error)) => Err(error.into()),
+            packet => Err(format!("Unexpected packet {packet:?}").into()),
+        }
+    }
+
+    /// Add an acl rule at the end of the rules of a vrf on one switch.
+    pub fn add_acl_rule(
+        &self,
+        switch_id: SwitchId,
+        vrf_id: VrfId,
+        rule: AclRule,
+    ) -> Result<(), HarnessError> {
+        let mut connection = Connection::connect(&management_socket(self.instance(switch_id)?))?;
+
+        match connection.request(AclAction::Add {
+            vrf_id,
+            rule,
+            position: None,
+        })? {
+            Packet::Response(Response::Ok) => Ok(()),
+            Packet::Response(Response::Error(error)) => Err(error.into()),
+            packet => Err(format!("Unexpected packet {packet:?}").into()),
+        }
+    }
+
+    /// Pin a mac behind a switch in a vrf on one switch.
+    pub fn pin_mac(
+        &self,
+        switch_id: SwitchId,
+        vrf_id: VrfId,
+        mac: [u8; 6],
+        behind: SwitchId,
+    ) -> Result<(), HarnessError> {
+        let mut connection = Connection::connect(&management_socket(self.instance(switch_id)?))?;
+
+        match connection.request(MacAction::AddStatic {
+            vrf_id,
+            mac,
+            switch_id: behind,
+        })? {
+            Packet::Response(Response::Ok) => Ok(()),
+            Packet::Response(Response::Error(error)) => Err(error.into()),
+            packet => Err(format!("Unexpected packet {packet:?}").into()),
+        }
+    }
+
+    /// Give the tap of a vrf on a switch an address, in cidr notation.
+    pub fn add_address(
+        &self,
+        switch_id: SwitchId,
+        vrf_name: &str,
+        address: &str,
+    ) -> Result<(), HarnessError> {
+        ip(&[
+            "-n",
+            &self.vrf_netns(switch_id, vrf_name)?,
+            "addr",
+            "add",
+            address,
+            "dev",
+            &self.tap(switch_id, vrf_name)?,
+        ])
+    }
+
+    /// Whether a datagram goes from one vrf tap to another and back before the timeout.
+    pub fn exchange(
+        &self,
+        (from, from_vrf, from_address): (SwitchId, &str, Ipv4Addr),
+        (to, to_vrf, to_address): (SwitchId, &str, Ipv4Addr),
+        timeout: Duration,
+    ) -> Result<bool, HarnessError> {
+        let from_socket = self.bind(from, from_vrf, from_address)?;
+        let to_socket = self.bind(to, to_vrf, to_address)?;
+        let start = Instant::now();
+        let mut buffer = [0u8; 64];
+
+        // the first datagrams wait on arp and on the peers connecting
+        while start.elapsed() < timeout {
+            from_socket.send_to(b"ping", (to_address, PORT))?;
+
+            if let Ok((length, source)) = to_socket.recv_from(&mut buffer) {
+                if &buffer[..length] == b"ping" {
+                    to_socket.send_to(b"pong", source)?;
+
+                    if let Ok(length) = from_socket.recv(&mut buffer) {
+                        return Ok(&buffer[..length] == b"pong");
+                    }
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Whether a vlan tagged frame sent out of one vrf tap comes out of another before the timeout.
+    ///
+    /// Frames are written to the taps directly, so it doesn't need vlan support in the kernel.
+    pub fn exchange_tagged(
+        &self,
+        (from, from_vrf): (SwitchId, &str),
+        (to, to_vrf): (SwitchId, &str),
+        vlan: u16,
+        timeout: Duration,
+    ) -> Result<bool, HarnessError> {
+        self.send_frame(
+            (from, from_vrf),
+            (to, to_vrf),
+            &tagged_frame(from, vlan),
+            timeout,
+        )
+    }
+
+    /// Whether an untagged frame to `destination` sent out of one vrf tap comes out of another
+    /// before the timeout.
+    pub fn exchange_unicast(
+        &self,
+        (from, from_vrf): (SwitchId, &str),
+        (to, to_vrf): (SwitchId, &str),
+        destination: [u8; 6],
+        timeout: Duration,
+    ) -> Result<bool, HarnessError> {
+        self.send_frame(
+            (from, from_vrf),
+            (to, to_vrf),
+            &frame(from, destination),
+            timeout,
+        )
+    }
+
+    fn send_frame(
+        &self,
+        (from, from_vrf): (SwitchId, &str),
+        (to, to_vrf): (SwitchId, &str),
+        frame: &[u8],
+        timeout: Duration,
+    ) -> Result<bool, HarnessError> {
+        let from_socket = self.packet_socket(from, from_vrf)?;
+        let to_socket = self.packet_socket(to, to_vrf)?;
+        let start = Instant::now();
+        let mut buffer = [0u8; 2048];
+
+        // the first frames wait on the peers connecting, the kernel sends frames of its own too
+        while start.elapsed() < timeout {
+            send(from_socket.as_raw_fd(), frame, MsgFlags::empty())?;
+
+            if let Ok(length) = recv(to_socket.as_raw_fd(), &mut buffer, MsgFlags::empty()) {
+                // the kernel hands the tag over out of band, only the rest of the frame is compared
+                if buffer[6..12] == frame[6..12] && buffer[..length].ends_with(MAGIC) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn bind(
+        &self,
+        switch_id: SwitchId,
+        vrf_name: &str,
+        address: Ipv4Addr,
+    ) -> Result<UdpSocket, HarnessError> {
+        let vrf_netns = Netns::named(self.vrf_netns(switch_id, vrf_name)?);
+
+        // a socket stays in the netns it was made in
+        let socket = vrf_netns.run(|| UdpSocket::bind((address, PORT)))??;
+
+        socket.set_read_timeout(Some(RETRY_INTERVAL))?;
+
+        Ok(socket)
+    }
+
+    // a packet socket on the tap sends frames out of it and sees the ones written into it
+    fn packet_socket(&self, switch_id: SwitchId, vrf_name: &str) -> Result<OwnedFd, HarnessError> {
+        let vrf_netns = self.vrf_netns(switch_id, vrf_name)?;
+        let index = link_index(&vrf_netns, &self.tap(switch_id, vrf_name)?)?;
+        let vrf_netns = Netns::named(vrf_netns);
+
+        let socket = vrf_netns.run(|| packet_socket(index))??;
+
+        setsockopt(
+            &socket,
+            ReceiveTimeout,
+            &TimeVal::new(0, RETRY_INTERVAL.as_micros() as _),
+        )?;
+
+        Ok(socket)
+    }
+
+    fn fabric(&self) -> String {
+        format!("{}-fabric", self.name)
+    }
+
+    fn instance(&self, switch_id: SwitchId) -> Result<&str, HarnessError> {
+        self.switches
+            .iter()
+            .find(|switch| switch.switch_id == switch_id)
+            .map(|switch| switch.instance.as_str())
+            .ok_or_else(|| format!("No switch id {switch_id} in the harness").into())
+    }
+
+    fn switch_mut(&mut self, switch_id: SwitchId) -> Result<&mut Switch, HarnessError> {
+        self.switches
+            .iter_mut()
+            .find(|switch| switch.switch_id == switch_id)
+            .ok_or_else(|| format!("No switch id {switch_id} in the harness").into())
+    }
+
+    // named like the daemon names them for an instance
+    fn vrf_netns(&self, switch_id: SwitchId, vrf_name: &str) -> Result<String, HarnessError> {
+        Ok(format!("{}-{vrf_name}", self.instance(switch_id)?))
+    }
+
+    fn tap(&self, switch_id: SwitchId, vrf_name: &str) -> Result<String, HarnessError> {
+        Ok(format!(
+            "dwitch-{}-{vrf_name}-tap",
+            self.instance(switch_id)?
+        ))
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        for switch in &mut self.switches {
+            if let Some(daemon) = &mut switch.daemon {
+                let _ = daemon.kill();
+                let _ = daemon.wait();
+            }
+
+            // the daemon is killed, so the vrf namespaces it made are left behind
+            let prefix = format!("{}-", switch.instance);
+
+            for entry in fs::read_dir(NETNS_DIRECTORY)
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                if name.starts_with(&prefix) {
+                    let _ = Netns::named(name).delete();
+                }
+            }
+
+            let _ = ip(&["netns", "del", &switch.instance]);
+            let _ = fs::remove_file(config_path(&switch.instance));
+            let _ = fs::remove_file(management_socket(&switch.instance));
+            let _ = fs::remove_file(
+                PathBuf::from(CACHE_DIRECTORY).join(format!("dwitch-{}.cache", switch.instance)),
+            );
+        }
+
+        let _ = ip(&["netns", "del", &self.fabric()]);
+    }
+}
+
+fn underlay_address(switch_id: SwitchId) -> Ipv4Addr {
+    Ipv4Addr::new(10, 99, 0, switch_id as u8)
+}
+
+fn config_path(instance: &str) -> PathBuf {
+    PathBuf::from(CONFIG_DIRECTORY).join(format!("{instance}.toml"))
+}
+
+fn management_socket(instance: &str) -> PathBuf {
+    PathBuf::from(RUN_DIRECTORY).join(format!("dwitch-{instance}.sock"))
+}
+
+// not through ip netns exec, its private mount namespace would hide the vrf namespaces
+fn spawn_daemon(instance: &str) -> Result<Child, HarnessError> {
+    let netns = File::open(Netns::named(instance).path())?;
+    let mut command = Command::new(daemon_path()?);
+
+    command
+        .args(["--instance", instance])
+        .stdout(Stdio::null())
+        .stderr(match env::var_os("DWITCH_LOG") {
+            Some(_) => Stdio::inherit(),
+            None => Stdio::null(),
+        });
+
+    // setns is async signal safe
+    unsafe {
+        command.pre_exec(move || Ok(setns(&netns, CloneFlags::CLONE_NEWNET)?));
+    }
+
+    Ok(command.spawn()?)
+}
+
+fn daemon_path() -> Result<PathBuf, HarnessError> {
+    if let Some(path) = env::var_os("DWITCH_BIN") {
+        return Ok(path.into());
+    }
+
+    // test binaries live in target/<profile>/deps
+    let path = env::current_exe()?
+        .parent()
+        .and_then(|deps| deps.parent())
+        .map(|profile| profile.join("dwitch"))
+        .filter(|path| path.exists())
+        .ok_or("Can't find the daemon, build it or set DWITCH_BIN")?;
+
+    Ok(path)
+}
+
+fn packet_socket(index: i32) -> io::Result<OwnedFd> {
+    let socket = socket(
+        AddressFamily::Packet,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::EthAll,
+    )?;
+    // an all zero sockaddr_ll is valid, only the family, protocol and index are set
+    let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+
+    address.sll_family = libc::AF_PACKET as u16;
+    address.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+    address.sll_ifindex = index;
+
+    // the call only reads the address it's given
+    if unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+            size_of::<libc::sockaddr_ll>() as u32,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+// a broadcast, so it floods to every member without any mac being learned
+/// Mac the frames the harness writes into the taps of a switch come from.
+pub fn source_mac(switch_id: SwitchId) -> [u8; 6] {
+    let [high, low] = (switch_id as u16).to_be_bytes();
+
+    // locally administered, never a real host
+    [0x02, 0xd7, 0x00, 0x00, high, low]
+}
+
+fn frame(switch_id: SwitchId, destination: [u8; 6]) -> Vec<u8> {
+    let mut frame = destination.to_vec();
+
+    frame.extend(source_mac(switch_id));
+    // the local experimental ethertype
+    frame.extend(0x88b5u16.to_be_bytes());
+    frame.extend(MAGIC);
+
+    frame
+}
+
+fn tagged_frame(switch_id: SwitchId, vlan: u16) -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+
+    frame.extend(source_mac(switch_id));
+    frame.extend(0x8100u16.to_be_bytes());
+    frame.extend(vlan.to_be_bytes());
+    // the local experimental ethertype
+    frame.extend(0x88b5u16.to_be_bytes());
+    frame.extend(MAGIC);
+
+    frame
+}
+
+// taps are known by their altname, which only netlink resolves
+fn link_index(netns: &str, name: &str) -> Result<i32, HarnessError> {
+    let output = Command::new("ip")
+        .args(["-n", netns, "-o", "link", "show", "dev", name])
+        .output()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    // lines start with "<index>: <name>:"
+    output
+        .split(':')
+        .next()
+        .and_then(|index| index.trim().parse().ok())
+        .ok_or_else(|| format!("Can't find the index of {name} in {netns}").into())
+}
+
+fn ip(args: &[&str]) -> Result<(), HarnessError> {
+    let output = Command::new("ip").args(args).output()?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn retry<T>(mut f: impl FnMut() -> Result<T, HarnessError>) -> Result<T, HarnessError> {
+    let start = Instant::now();
+
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) if start.elapsed() > START_TIMEOUT => return Err(error),
+            Err(_) => thread::sleep(RETRY_INTERVAL),
+        }
+    }
+}
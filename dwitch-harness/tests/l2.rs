@@ -0,0 +1,280 @@
+use std::{net::Ipv4Addr, time::Duration};
+
+use dwitch_harness::{source_mac, Harness};
+use protocol::{
+    AclDirection, AclRule, AclVerdict, Compression, Learning, VrfMode, VrfSettings, Vxlan,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// a frame that should never arrive gets less time
+const ISOLATION_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn frames_cross_two_switches() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 200, 0, 1), Ipv4Addr::new(10, 200, 0, 2));
+
+    harness.create_vrf(1, "l2", &[1, 2]).unwrap();
+    harness.add_address(1, "l2", "10.200.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.200.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn frames_cross_a_connection_made_one_way() {
+    // like a switch behind a nat, only the second one dials
+    let harness = Harness::start_with_peering(2, |from, _| from == 2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 202, 0, 1), Ipv4Addr::new(10, 202, 0, 2));
+
+    harness.create_vrf(1, "l2", &[1, 2]).unwrap();
+    harness.add_address(1, "l2", "10.202.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.202.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn switches_discover_each_other() {
+    let harness = Harness::start_with_discovery(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 209, 0, 1), Ipv4Addr::new(10, 209, 0, 2));
+
+    // the second switch only gets the vrf once they're connected
+    harness.create_vrf_through(1, 1, "l2", &[1, 2]).unwrap();
+    harness.wait_for_vrf(2, "l2").unwrap();
+    harness.add_address(1, "l2", "10.209.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.209.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn switches_join_the_mesh_through_a_seed() {
+    let harness = Harness::start_with_gossip(3).unwrap();
+    let (address_2, address_3) = (Ipv4Addr::new(10, 210, 0, 2), Ipv4Addr::new(10, 210, 0, 3));
+
+    // the seed isn't a member, the frames only cross if the other two dialed each other
+    harness.create_vrf_through(2, 1, "l2", &[2, 3]).unwrap();
+    harness.wait_for_vrf(3, "l2").unwrap();
+    harness.add_address(2, "l2", "10.210.0.2/24").unwrap();
+    harness.add_address(3, "l2", "10.210.0.3/24").unwrap();
+
+    assert!(harness
+        .exchange((2, "l2", address_2), (3, "l2", address_3), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn compressed_frames_cross() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 204, 0, 1), Ipv4Addr::new(10, 204, 0, 2));
+    let settings = VrfSettings {
+        compression: Some(Compression::Zstd),
+        ..Default::default()
+    };
+
+    harness.create_vrf_with(1, "l2", &[1, 2], settings).unwrap();
+    harness.add_address(1, "l2", "10.204.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.204.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn vxlan_frames_cross() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 206, 0, 1), Ipv4Addr::new(10, 206, 0, 2));
+    let settings = VrfSettings {
+        vxlan: Some(Vxlan {
+            vni: Some(5000),
+            vteps: Vec::new(),
+        }),
+        ..Default::default()
+    };
+
+    harness.create_vrf_with(1, "l2", &[1, 2], settings).unwrap();
+    harness.add_address(1, "l2", "10.206.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.206.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn tun_packets_cross() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 207, 0, 1), Ipv4Addr::new(10, 207, 0, 2));
+    let settings = VrfSettings {
+        mode: Some(VrfMode::Tun),
+        ..Default::default()
+    };
+
+    harness.create_vrf_with(1, "l3", &[1, 2], settings).unwrap();
+    harness.add_address(1, "l3", "10.207.0.1/24").unwrap();
+    harness.add_address(2, "l3", "10.207.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l3", address_1), (2, "l3", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn vrfs_reach_a_switch_that_was_away() {
+    let mut harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 208, 0, 1), Ipv4Addr::new(10, 208, 0, 2));
+
+    harness.stop(2).unwrap();
+    harness.create_vrf_through(1, 1, "l2", &[1, 2]).unwrap();
+    harness.restart(2).unwrap();
+    harness.wait_for_vrf(1, "l2").unwrap();
+    harness.wait_for_vrf(2, "l2").unwrap();
+    harness.add_address(1, "l2", "10.208.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.208.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn suspended_vrfs_stop_forwarding() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 203, 0, 1), Ipv4Addr::new(10, 203, 0, 2));
+
+    harness.create_vrf(1, "l2", &[1, 2]).unwrap();
+    harness.add_address(1, "l2", "10.203.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.203.0.2/24").unwrap();
+    harness.suspend_vrf(1, 1, true).unwrap();
+
+    assert!(!harness
+        .exchange(
+            (1, "l2", address_1),
+            (2, "l2", address_2),
+            ISOLATION_TIMEOUT
+        )
+        .unwrap());
+
+    harness.suspend_vrf(1, 1, false).unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn only_allowed_vlans_cross() {
+    let harness = Harness::start(2).unwrap();
+    let settings = VrfSettings {
+        vlans: vec![10],
+        ..Default::default()
+    };
+
+    harness.create_vrf_with(1, "l2", &[1, 2], settings).unwrap();
+
+    assert!(harness
+        .exchange_tagged((1, "l2"), (2, "l2"), 10, TIMEOUT)
+        .unwrap());
+    assert!(!harness
+        .exchange_tagged((1, "l2"), (2, "l2"), 20, ISOLATION_TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn frames_reach_macs_pinned_in_static_learning() {
+    let harness = Harness::start(2).unwrap();
+    let settings = VrfSettings {
+        learning: Some(Learning::Static),
+        ..Default::default()
+    };
+    let destination = [0x02, 0xd7, 0x00, 0x00, 0xff, 0x02];
+
+    harness.create_vrf_with(1, "l2", &[1, 2], settings).unwrap();
+
+    // pinned through the management protocol rather than the vrf settings
+    for switch_id in [1, 2] {
+        harness.pin_mac(switch_id, 1, source_mac(1), 1).unwrap();
+        harness.pin_mac(switch_id, 1, destination, 2).unwrap();
+    }
+
+    assert!(harness
+        .exchange_unicast((1, "l2"), (2, "l2"), destination, TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn acl_rules_drop_denied_frames() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 211, 0, 1), Ipv4Addr::new(10, 211, 0, 2));
+
+    harness.create_vrf(1, "l2", &[1, 2]).unwrap();
+    harness.add_address(1, "l2", "10.211.0.1/24").unwrap();
+    harness.add_address(2, "l2", "10.211.0.2/24").unwrap();
+
+    assert!(harness
+        .exchange((1, "l2", address_1), (2, "l2", address_2), TIMEOUT)
+        .unwrap());
+
+    // the datagrams from switch 1 are dropped as they come out of switch 2
+    harness
+        .add_acl_rule(
+            2,
+            1,
+            AclRule {
+                verdict: AclVerdict::Deny,
+                direction: AclDirection::Egress,
+                source_ip: protocol::prefix::parse("10.211.0.1/32"),
+                protocol: Some(17),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    assert!(!harness
+        .exchange(
+            (1, "l2", address_1),
+            (2, "l2", address_2),
+            ISOLATION_TIMEOUT
+        )
+        .unwrap());
+}
+
+#[test]
+#[ignore = "needs root, iproute2 and a built daemon"]
+fn vrfs_stay_apart() {
+    let harness = Harness::start(2).unwrap();
+    let (address_1, address_2) = (Ipv4Addr::new(10, 201, 0, 1), Ipv4Addr::new(10, 201, 0, 2));
+
+    harness.create_vrf(1, "red", &[1, 2]).unwrap();
+    harness.create_vrf(2, "blue", &[1, 2]).unwrap();
+    harness.add_address(1, "red", "10.201.0.1/24").unwrap();
+    harness.add_address(2, "blue", "10.201.0.2/24").unwrap();
+
+    assert!(!harness
+        .exchange(
+            (1, "red", address_1),
+            (2, "blue", address_2),
+            ISOLATION_TIMEOUT
+        )
+        .unwrap());
+}
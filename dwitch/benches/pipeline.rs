@@ -0,0 +1,230 @@
+//! Two in-process switches wired through a memory transport and virtual taps.
+//!
+//! Run with `cargo bench -p dwitch --bench pipeline`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::{HashMap, HashSet},
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use dwitch::{
+    acl::Acls,
+    config::{Config, SwitchId},
+    gossip::Gossip,
+    mirror::Mirrors,
+    rate_limit::RateLimiter,
+    socket::{
+        client::{client_connection, peer_channel},
+        server::accept_client,
+    },
+    state::State,
+    tap::{virtual_tap, VirtualWire},
+};
+use protocol::{Vrf, VrfMetadata, VrfSettings};
+use tokio::{
+    io::duplex,
+    runtime::Builder,
+    spawn,
+    sync::{mpsc::channel, RwLock},
+    time::{sleep, timeout},
+};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const VRF_ID: u32 = 1;
+const LATENCY_SAMPLES: usize = 1000;
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn instance(switch_id: SwitchId, vrf: &Vrf) -> (Arc<State>, VirtualWire) {
+    let config: Config = toml::from_str(&format!(
+        "switch_id = {switch_id}\nlisten = \"127.0.0.1:0\"\nservers = []\n"
+    ))
+    .expect("Invalid bench config");
+    let state = Arc::new(State {
+        tls: None,
+        quic: None,
+        vxlan: None,
+        vrf_keys: HashMap::new(),
+        cache_key: None,
+        listening: AtomicBool::new(true),
+        draining: AtomicBool::new(false),
+        action_limiter: RateLimiter::new(config.action_rate_limit),
+        tap_table: RwLock::new(HashMap::new()),
+        degraded_taps: Mutex::new(HashMap::new()),
+        vrf_table: RwLock::new(HashMap::from([(vrf.id, vrf.clone())])),
+        deleted_vrfs: Mutex::new(HashMap::new()),
+        declared_vrfs: Mutex::new(HashSet::new()),
+        client_table: Arc::new(RwLock::new(HashMap::new())),
+        switch_table: Arc::new(RwLock::new(Default::default())),
+        acls: Acls::default(),
+        mirrors: Mirrors::default(),
+        ip_table: Mutex::new(Default::default()),
+        draining_peers: Mutex::new(HashSet::new()),
+        gossip: Gossip::new(channel(1).0),
+        peer_connections: Mutex::new(HashMap::new()),
+        path_mtus: Mutex::new(HashMap::new()),
+        peer_compression: Mutex::new(HashMap::new()),
+        suspensions: Mutex::new(HashMap::new()),
+        metrics: Default::default(),
+        handover_fds: Default::default(),
+        audits: Default::default(),
+        traces: Default::default(),
+        vrf_tests: Default::default(),
+        config,
+    });
+    let (tap, wire) = virtual_tap(vrf.clone(), state.clone());
+
+    state.tap_table.try_write().unwrap().insert(vrf.id, tap);
+
+    (state, wire)
+}
+
+fn frame(size: usize, index: u64) -> Bytes {
+    let mut frame = vec![0u8; size.max(22)];
+
+    frame[..6].fill(0xff);
+    frame[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+    frame[12..14].copy_from_slice(&0x88b5u16.to_be_bytes());
+    frame[14..22].copy_from_slice(&index.to_be_bytes());
+
+    Bytes::from(frame)
+}
+
+async fn bench(frame_size: usize, frames: u64) {
+    let vrf = Vrf {
+        id: VRF_ID,
+        name: "bench".to_string(),
+        members: vec![1, 2],
+        template: None,
+        settings: VrfSettings::default(),
+        metadata: VrfMetadata::default(),
+        suspended: false,
+        version: 0,
+    };
+    let (state_a, wire_a) = instance(1, &vrf);
+    let (state_b, mut wire_b) = instance(2, &vrf);
+    let (stream_a, stream_b) = duplex(1 << 20);
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    spawn(accept_client(
+        state_b.clone(),
+        stream_b,
+        address,
+        None,
+        None,
+        None,
+    ));
+    spawn({
+        let state_a = state_a.clone();
+
+        async move {
+            let (sender, mut receiver) = peer_channel();
+
+            client_connection(&state_a, stream_a, None, None, None, &sender, &mut receiver).await
+        }
+    });
+
+    while !state_a.client_table.read().await.contains_key(&2) {
+        sleep(Duration::from_millis(1)).await;
+    }
+
+    let mut latencies = Vec::with_capacity(LATENCY_SAMPLES);
+
+    for index in 0..LATENCY_SAMPLES as u64 {
+        let start = Instant::now();
+
+        wire_a.sender.send(frame(frame_size, index)).await.unwrap();
+
+        if let Ok(Some(_)) = timeout(RECEIVE_TIMEOUT, wire_b.receiver.recv()).await {
+            latencies.push(start.elapsed());
+        }
+    }
+
+    latencies.sort();
+
+    let sender = wire_a.sender.clone();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    spawn(async move {
+        for index in 0..frames {
+            if sender.send(frame(frame_size, index)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut received = 0u64;
+
+    while received < frames {
+        match timeout(RECEIVE_TIMEOUT, wire_b.receiver.recv()).await {
+            Ok(Some(_)) => received += 1,
+            _ => break,
+        }
+    }
+
+    let elapsed = start.elapsed().saturating_sub(if received < frames {
+        RECEIVE_TIMEOUT
+    } else {
+        Duration::ZERO
+    });
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let percentile = |percentile: usize| {
+        latencies
+            .get(latencies.len() * percentile / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+
+    println!("{frame_size} byte frames");
+    println!(
+        "  latency     p50 {:?}, p99 {:?} over {} samples",
+        percentile(50),
+        percentile(99),
+        latencies.len()
+    );
+    println!(
+        "  throughput  {:.0} packets/s, {received}/{frames} delivered",
+        received as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "  allocations {:.1} per packet",
+        allocations as f64 / received.max(1) as f64
+    );
+}
+
+fn main() {
+    let frames = env::var("DWITCH_BENCH_FRAMES")
+        .ok()
+        .and_then(|frames| frames.parse().ok())
+        .unwrap_or(100_000);
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+
+    for frame_size in [64, 1500] {
+        runtime.block_on(bench(frame_size, frames));
+    }
+}
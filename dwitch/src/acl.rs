@@ -0,0 +1,263 @@
+//! Acl rules of the vrfs, checked by their pipelines on the frames read from the taps and on the
+//! frames from the peers. Each switch has its own rules, kept in the cache.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use common::VrfId;
+use protocol::{AclDirection, AclRule, AclVerdict, IpPrefix};
+
+use crate::switch_table::{MacAddress, Vlan};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const PROTOCOL_SCTP: u8 = 132;
+
+/// Rules of each vrf, shared with its pipeline.
+#[derive(Default)]
+pub struct Acls(Mutex<HashMap<VrfId, Acl>>);
+
+impl Acls {
+    pub fn new(rules: HashMap<VrfId, Vec<AclRule>>) -> Self {
+        Self(Mutex::new(
+            rules
+                .into_iter()
+                .map(|(vrf_id, rules)| (vrf_id, Acl(Arc::new(RwLock::new(rules)))))
+                .collect(),
+        ))
+    }
+
+    pub fn vrf(&self, vrf_id: VrfId) -> Acl {
+        self.0.lock().unwrap().entry(vrf_id).or_default().clone()
+    }
+
+    pub fn remove(&self, vrf_id: VrfId) {
+        self.0.lock().unwrap().remove(&vrf_id);
+    }
+
+    /// Rules of each vrf that has some.
+    pub fn rules(&self) -> HashMap<VrfId, Vec<AclRule>> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(vrf_id, acl)| (*vrf_id, acl.rules()))
+            .filter(|(_, rules)| !rules.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Acl(Arc<RwLock<Vec<AclRule>>>);
+
+impl Acl {
+    pub fn rules(&self) -> Vec<AclRule> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Insert a rule before the one at `position`, or after the last one.
+    pub fn insert(&self, rule: AclRule, position: Option<usize>) -> Result<(), String> {
+        let mut rules = self.0.write().unwrap();
+
+        match position {
+            Some(position) if position > rules.len() => Err(format!(
+                "Position {position} is past the {} rules",
+                rules.len()
+            )),
+            Some(position) => {
+                rules.insert(position, rule);
+                Ok(())
+            }
+            None => {
+                rules.push(rule);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove(&self, index: usize) -> Result<AclRule, String> {
+        let mut rules = self.0.write().unwrap();
+
+        if index >= rules.len() {
+            return Err(format!("No rule at index {index}"));
+        }
+
+        Ok(rules.remove(index))
+    }
+
+    /// Whether an ethernet frame going in a direction gets through.
+    pub fn permits_frame(&self, direction: AclDirection, frame: &[u8]) -> bool {
+        self.permits(direction, || Headers::ethernet(frame))
+    }
+
+    /// Whether an ip packet of a tun going in a direction gets through.
+    pub fn permits_packet(&self, direction: AclDirection, packet: &[u8]) -> bool {
+        self.permits(direction, || Headers {
+            ip: Ip::parse(packet),
+            ..Default::default()
+        })
+    }
+
+    // frames are only parsed for vrfs with rules
+    fn permits(&self, direction: AclDirection, headers: impl FnOnce() -> Headers) -> bool {
+        let rules = self.0.read().unwrap();
+
+        if rules.is_empty() {
+            return true;
+        }
+
+        let headers = headers();
+
+        rules
+            .iter()
+            .filter(|rule| rule.direction.includes(direction))
+            .find(|rule| headers.matches(rule))
+            .is_none_or(|rule| rule.verdict == AclVerdict::Permit)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Headers {
+    source_mac: Option<MacAddress>,
+    destination_mac: Option<MacAddress>,
+    ethertype: Option<u16>,
+    vlan: Option<Vlan>,
+    ip: Option<Ip>,
+}
+
+#[derive(Debug)]
+struct Ip {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    ports: Option<(u16, u16)>,
+}
+
+impl Headers {
+    fn ethernet(frame: &[u8]) -> Self {
+        if frame.len() < 14 {
+            return Self::default();
+        }
+
+        let mut offset = 12;
+        let mut vlan = 0;
+
+        // the outer tag is the vlan, the ethertype comes after the last one
+        while let Some(&[0x81, 0x00, high, low] | &[0x88, 0xa8, high, low]) =
+            frame.get(offset..offset + 4)
+        {
+            if offset == 12 {
+                vlan = u16::from_be_bytes([high, low]) & 0x0fff;
+            }
+
+            offset += 4;
+        }
+
+        let ethertype = frame
+            .get(offset..offset + 2)
+            .map(|ethertype| u16::from_be_bytes([ethertype[0], ethertype[1]]));
+        let payload = frame.get(offset + 2..).unwrap_or_default();
+
+        Self {
+            source_mac: frame[6..12].try_into().ok(),
+            destination_mac: frame[..6].try_into().ok(),
+            ethertype,
+            vlan: Some(vlan),
+            ip: match ethertype {
+                Some(ETHERTYPE_IPV4 | ETHERTYPE_IPV6) => Ip::parse(payload),
+                _ => None,
+            },
+        }
+    }
+
+    fn matches(&self, rule: &AclRule) -> bool {
+        fn matches<T: PartialEq>(criterion: Option<T>, value: Option<T>) -> bool {
+            criterion.is_none() || criterion == value
+        }
+
+        let ip = self.ip.as_ref();
+
+        matches(rule.source_mac, self.source_mac)
+            && matches(rule.destination_mac, self.destination_mac)
+            && matches(rule.ethertype, self.ethertype)
+            && matches(rule.vlan, self.vlan)
+            && rule
+                .source_ip
+                .is_none_or(|prefix| ip.is_some_and(|ip| contains(&prefix, ip.source)))
+            && rule
+                .destination_ip
+                .is_none_or(|prefix| ip.is_some_and(|ip| contains(&prefix, ip.destination)))
+            && matches(rule.protocol, ip.map(|ip| ip.protocol))
+            && matches(
+                rule.source_port,
+                ip.and_then(|ip| ip.ports).map(|(source, _)| source),
+            )
+            && matches(
+                rule.destination_port,
+                ip.and_then(|ip| ip.ports)
+                    .map(|(_, destination)| destination),
+            )
+    }
+}
+
+impl Ip {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let header_length = usize::from(packet[0] & 0x0f) * 4;
+                // only the first fragment carries the ports
+                let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+
+                Some(Self {
+                    source: IpAddr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()),
+                    destination: IpAddr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()),
+                    protocol: packet[9],
+                    ports: (fragment_offset == 0)
+                        .then(|| ports(packet[9], packet.get(header_length..)?))
+                        .flatten(),
+                })
+            }
+            6 if packet.len() >= 40 => Some(Self {
+                source: IpAddr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap()),
+                destination: IpAddr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap()),
+                protocol: packet[6],
+                ports: ports(packet[6], &packet[40..]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn ports(protocol: u8, payload: &[u8]) -> Option<(u16, u16)> {
+    match (protocol, payload) {
+        (PROTOCOL_TCP | PROTOCOL_UDP | PROTOCOL_SCTP, [a, b, c, d, ..]) => {
+            Some((u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d])))
+        }
+        _ => None,
+    }
+}
+
+fn contains(prefix: &IpPrefix, address: IpAddr) -> bool {
+    match (prefix.address, address) {
+        (IpAddr::V4(prefix_address), IpAddr::V4(address)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix.prefix_length))
+                .unwrap_or(0);
+
+            u32::from(prefix_address) & mask == u32::from(address) & mask
+        }
+        (IpAddr::V6(prefix_address), IpAddr::V6(address)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix.prefix_length))
+                .unwrap_or(0);
+
+            u128::from(prefix_address) & mask == u128::from(address) & mask
+        }
+        _ => false,
+    }
+}
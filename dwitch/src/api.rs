@@ -0,0 +1,360 @@
+use std::{error::Error, net::SocketAddr, sync::Arc};
+
+use common::VrfId;
+use protocol::{Response, Vrf, VrfAction, VrfMetadata, VrfSettings};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
+
+#[cfg(feature = "fault-injection")]
+use crate::socket::fault::{self, Fault};
+use crate::{
+    config::{ApiConfig, SwitchId},
+    management::{allocate_vrf, configure, flush_macs, list_macs, list_peers, list_vrfs, save},
+    state::{Source, State},
+    token::{authenticate, Access, Permission, Refusal},
+};
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    authorization: Option<String>,
+    pub(crate) body: Vec<u8>,
+}
+
+// without an id one is allocated from the range of this switch
+#[derive(Deserialize)]
+struct NewVrf {
+    id: Option<VrfId>,
+    name: String,
+    members: Vec<SwitchId>,
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    settings: VrfSettings,
+    #[serde(default)]
+    metadata: VrfMetadata,
+}
+
+pub(crate) struct Reply {
+    pub(crate) status: &'static str,
+    pub(crate) body: String,
+}
+
+impl Reply {
+    pub(crate) fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self {
+                status: "200 OK",
+                body,
+            },
+            Err(error) => Self::error(
+                "500 Internal Server Error",
+                format!("Can't serialize reply: {error}"),
+            ),
+        }
+    }
+
+    fn error(status: &'static str, error: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: json!({ "error": error.into() }).to_string(),
+        }
+    }
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Ok => Self::json(&json!({ "status": "ok" })),
+            Response::Error(error) => Self::error("400 Bad Request", error),
+        }
+    }
+}
+
+pub async fn api(config: ApiConfig, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(config.listen).await?;
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                spawn(api_connection(stream, address, state.clone()));
+            }
+            Err(error) => {
+                tracing::error!("Can't accept api client: {error}");
+            }
+        }
+    }
+}
+
+async fn api_connection(mut stream: TcpStream, address: SocketAddr, state: Arc<State>) {
+    let reply = match read_request(&mut stream).await {
+        Some(request) => handle_request(&state, address, request).await,
+        None => Reply::error("400 Bad Request", "Invalid request"),
+    };
+
+    if let Err(error) = write_reply(&mut stream, reply).await {
+        tracing::warn!("Can't answer api client {address}: {error}");
+    }
+}
+
+pub(crate) async fn write_reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    reply: Reply,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reply.status,
+        reply.body.len(),
+        reply.body
+    );
+
+    stream.write_all(response.as_bytes()).await
+}
+
+pub(crate) async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Option<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_length = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+
+        if buffer.len() > MAX_REQUEST_SIZE {
+            return None;
+        }
+
+        let length = stream.read(&mut chunk).await.ok()?;
+
+        if length == 0 {
+            return None;
+        }
+
+        buffer.extend_from_slice(&chunk[..length]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_length]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let mut content_length = 0;
+    let mut authorization = None;
+
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.trim().parse().ok()?,
+            "authorization" => {
+                authorization = value
+                    .trim()
+                    .strip_prefix("Bearer ")
+                    .map(|token| token.trim().to_string())
+            }
+            _ => {}
+        }
+    }
+
+    if header_length + content_length > MAX_REQUEST_SIZE {
+        return None;
+    }
+
+    while buffer.len() < header_length + content_length {
+        let length = stream.read(&mut chunk).await.ok()?;
+
+        if length == 0 {
+            return None;
+        }
+
+        buffer.extend_from_slice(&chunk[..length]);
+    }
+
+    Some(Request {
+        method,
+        path,
+        authorization,
+        body: buffer[header_length..header_length + content_length].to_vec(),
+    })
+}
+
+async fn handle_request(state: &Arc<State>, address: SocketAddr, request: Request) -> Reply {
+    let ip = address.ip().to_canonical();
+    let permission = if state.config.admins.contains(&ip) {
+        Some(Permission::Admin)
+    } else {
+        request
+            .authorization
+            .as_deref()
+            .and_then(|token| authenticate(&state.config.tokens, token))
+    };
+    let Some(permission) = permission else {
+        tracing::warn!("Rejected api request from {address}");
+
+        return Reply::error("401 Unauthorized", "Missing or invalid token");
+    };
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    let access = match request.method.as_str() {
+        "GET" => Access::Read,
+        _ => Access::Change,
+    };
+
+    if let Err(refusal) =
+        state.authorize(Source::Remote(ip), Some(permission), access, "api request")
+    {
+        let status = match refusal {
+            Refusal::Denied => "403 Forbidden",
+            Refusal::RateLimited => "429 Too Many Requests",
+        };
+
+        return Reply::error(status, refusal.to_string());
+    }
+
+    if access == Access::Read {
+        return match segments.as_slice() {
+            ["vrfs"] => Reply::json(&list_vrfs(state).await),
+            ["peers"] => Reply::json(&list_peers(state).await),
+            ["macs"] => Reply::json(&list_macs(state).await),
+            #[cfg(feature = "fault-injection")]
+            ["faults"] => Reply::json(&fault::list()),
+            _ => Reply::error("404 Not Found", "Not found"),
+        };
+    }
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["vrfs"]) => match parse_body::<NewVrf>(&request.body) {
+            Ok(new_vrf) => {
+                let vrf = Vrf {
+                    id: new_vrf.id.unwrap_or_default(),
+                    name: new_vrf.name,
+                    members: new_vrf.members,
+                    template: new_vrf.template,
+                    settings: new_vrf.settings,
+                    metadata: new_vrf.metadata,
+                    suspended: false,
+                    version: 0,
+                };
+
+                if new_vrf.id.is_some() {
+                    configure(state, VrfAction::Create(Box::new(vrf)))
+                        .await
+                        .into()
+                } else {
+                    match allocate_vrf(state, vrf).await {
+                        Ok(id) => Reply::json(&json!({ "id": id })),
+                        Err(error) => Response::Error(error).into(),
+                    }
+                }
+            }
+            Err(reply) => reply,
+        },
+        ("PUT", ["vrfs", id, "metadata"]) => {
+            match (parse_id(id), parse_body::<VrfMetadata>(&request.body)) {
+                (Ok(id), Ok(metadata)) => configure(state, VrfAction::Describe { id, metadata })
+                    .await
+                    .into(),
+                (Err(reply), _) | (_, Err(reply)) => reply,
+            }
+        }
+        ("DELETE", ["vrfs", id]) => match parse_id(id) {
+            Ok(id) => configure(state, VrfAction::Delete { id }).await.into(),
+            Err(reply) => reply,
+        },
+        ("POST", ["vrfs", id, "suspend"]) => match parse_id(id) {
+            Ok(id) => configure(state, VrfAction::Suspend { id }).await.into(),
+            Err(reply) => reply,
+        },
+        ("POST", ["vrfs", id, "resume"]) => match parse_id(id) {
+            Ok(id) => configure(state, VrfAction::Resume { id }).await.into(),
+            Err(reply) => reply,
+        },
+        ("POST", ["vrfs", id, "members"]) => {
+            match (parse_id(id), parse_body::<Vec<SwitchId>>(&request.body)) {
+                (Ok(id), Ok(members)) => configure(state, VrfAction::AddMember { id, members })
+                    .await
+                    .into(),
+                (Err(reply), _) | (_, Err(reply)) => reply,
+            }
+        }
+        ("DELETE", ["vrfs", id, "members"]) => {
+            match (parse_id(id), parse_body::<Vec<SwitchId>>(&request.body)) {
+                (Ok(id), Ok(members)) => configure(state, VrfAction::RemoveMember { id, members })
+                    .await
+                    .into(),
+                (Err(reply), _) | (_, Err(reply)) => reply,
+            }
+        }
+        ("POST", ["save"]) => match save(state).await {
+            Ok((vrfs, macs)) => Reply::json(&json!({ "vrfs": vrfs, "macs": macs })),
+            Err(error) => Response::Error(format!("Can't save cache: {error}")).into(),
+        },
+        ("DELETE", ["macs"]) => {
+            flush_macs(state, None).await;
+
+            Response::Ok.into()
+        }
+        ("DELETE", ["macs", id]) => match parse_id(id) {
+            Ok(id) => {
+                flush_macs(state, Some(id)).await;
+
+                Response::Ok.into()
+            }
+            Err(reply) => reply,
+        },
+        #[cfg(feature = "fault-injection")]
+        ("PUT", ["faults", switch_id]) => {
+            match (
+                parse_switch_id(switch_id),
+                parse_body::<Fault>(&request.body),
+            ) {
+                (Ok(switch_id), Ok(peer_fault)) => {
+                    fault::set(switch_id, peer_fault);
+
+                    Response::Ok.into()
+                }
+                (Err(reply), _) | (_, Err(reply)) => reply,
+            }
+        }
+        #[cfg(feature = "fault-injection")]
+        ("DELETE", ["faults", switch_id]) => match parse_switch_id(switch_id) {
+            Ok(switch_id) => {
+                fault::clear(switch_id);
+
+                Response::Ok.into()
+            }
+            Err(reply) => reply,
+        },
+        _ => Reply::error("404 Not Found", "Not found"),
+    }
+}
+
+fn parse_id(id: &str) -> Result<VrfId, Reply> {
+    id.parse()
+        .map_err(|_| Reply::error("400 Bad Request", format!("Invalid vrf id {id}")))
+}
+
+#[cfg(feature = "fault-injection")]
+fn parse_switch_id(switch_id: &str) -> Result<SwitchId, Reply> {
+    switch_id
+        .parse()
+        .map_err(|_| Reply::error("400 Bad Request", format!("Invalid switch id {switch_id}")))
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, Reply> {
+    serde_json::from_slice(body)
+        .map_err(|error| Reply::error("400 Bad Request", format!("Invalid body: {error}")))
+}
@@ -0,0 +1,153 @@
+//! Isolation audit. A probe frame goes into each local vrf, through the local tap and the overlay,
+//! and every switch reports where it sees it. A probe seen in another vrf, received from a peer or
+//! read out of another tap, is a leak.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use common::VrfId;
+use protocol::{Audit, AuditReport, Packet, Sighting, DATA_TTL};
+use tokio::time::sleep;
+
+use crate::{config::SwitchId, socket::client::broadcast_to_vrf, state::State, tap::data_packet};
+
+// the local experimental ethertype, the magic tells the probes apart
+const PROBE_ETHERTYPE: u16 = 0x88b5;
+const PROBE_MAGIC: &[u8; 12] = b"dwitch-probe";
+const PROBE_LENGTH: usize = 14 + PROBE_MAGIC.len() + 4 + 8;
+// leaves the probes the time to go through the overlay and the kernel
+const AUDIT_WAIT: Duration = Duration::from_secs(2);
+
+/// Sightings of the probes of the running audits, by nonce.
+#[derive(Default)]
+pub struct Audits(Mutex<HashMap<u64, Vec<Sighting>>>);
+
+impl Audits {
+    pub fn record(&self, sighting: Sighting) {
+        if let Some(sightings) = self.0.lock().unwrap().get_mut(&sighting.nonce) {
+            sightings.push(sighting);
+        }
+    }
+}
+
+pub async fn audit(state: &Arc<State>) -> Vec<AuditReport> {
+    let switch_id = state.config.switch_id;
+    let probes = state
+        .vrf_table
+        .read()
+        .await
+        .values()
+        .filter(|vrf| vrf.members.contains(&switch_id))
+        .map(|vrf| (vrf.clone(), OsRng.next_u64()))
+        .collect::<Vec<_>>();
+
+    {
+        let mut audits = state.audits.0.lock().unwrap();
+
+        for (_, nonce) in &probes {
+            audits.insert(*nonce, Vec::new());
+        }
+    }
+
+    for (vrf, nonce) in &probes {
+        let frame = probe(switch_id, *nonce);
+
+        // the local tap gets it like a frame from a peer
+        if let Some(tap) = state.tap_table.read().await.get(&vrf.id) {
+            let _ = tap.try_send((switch_id, None, DATA_TTL, frame.clone().into()));
+        }
+
+        if let Some(packet) =
+            data_packet(state, vrf, state.vrf_keys.get(&vrf.name), &frame, DATA_TTL)
+        {
+            broadcast_to_vrf(state, vrf, packet, |_| true).await;
+        }
+    }
+
+    sleep(AUDIT_WAIT).await;
+
+    let mut audits = state.audits.0.lock().unwrap();
+
+    probes
+        .into_iter()
+        .map(|(vrf, nonce)| {
+            let (sightings, leaks): (Vec<_>, Vec<_>) = audits
+                .remove(&nonce)
+                .unwrap_or_default()
+                .into_iter()
+                .partition(|sighting| sighting.vrf_id == vrf.id);
+            let mut seen_by = sightings
+                .iter()
+                .map(|sighting| sighting.switch_id)
+                .collect::<Vec<_>>();
+
+            seen_by.sort_unstable();
+            seen_by.dedup();
+
+            if !leaks.is_empty() {
+                tracing::error!("The probe of the vrf {} leaked: {leaks:?}", vrf.name);
+            }
+
+            AuditReport {
+                vrf_id: vrf.id,
+                seen_by,
+                leaks,
+            }
+        })
+        .collect()
+}
+
+/// Report a probe seen in a vrf to the switch that sent it, false for any other frame.
+pub async fn observe(state: &State, vrf_id: VrfId, frame: &[u8], from_tap: bool) -> bool {
+    let Some((origin, nonce)) = parse_probe(frame) else {
+        return false;
+    };
+    let sighting = Sighting {
+        nonce,
+        switch_id: state.config.switch_id,
+        vrf_id,
+        from_tap,
+    };
+
+    if origin == state.config.switch_id {
+        state.audits.record(sighting);
+    } else if let Some(client) = state.client_table.read().await.get(&origin) {
+        if let Err(error) = client.send(Packet::from(Audit::Sighting(sighting))).await {
+            tracing::warn!("Can't report a probe to switch id {origin}: {error}");
+        }
+    }
+
+    true
+}
+
+fn probe(switch_id: SwitchId, nonce: u64) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(PROBE_LENGTH);
+
+    frame.extend_from_slice(&[0xff; 6]);
+    // locally administered, never a real host
+    frame.extend_from_slice(&[0x02, 0xd7, 0x00, 0x00, 0x00, 0x00]);
+    frame.extend_from_slice(&PROBE_ETHERTYPE.to_be_bytes());
+    frame.extend_from_slice(PROBE_MAGIC);
+    frame.extend_from_slice(&switch_id.to_be_bytes());
+    frame.extend_from_slice(&nonce.to_be_bytes());
+    frame
+}
+
+fn parse_probe(frame: &[u8]) -> Option<(SwitchId, u64)> {
+    let payload = frame.get(14..PROBE_LENGTH)?;
+
+    if frame[12..14] != PROBE_ETHERTYPE.to_be_bytes() || !payload.starts_with(PROBE_MAGIC) {
+        return None;
+    }
+
+    let payload = &payload[PROBE_MAGIC.len()..];
+
+    Some((
+        SwitchId::from_be_bytes(payload[..4].try_into().ok()?),
+        u64::from_be_bytes(payload[4..].try_into().ok()?),
+    ))
+}
@@ -1,33 +1,443 @@
-use std::{collections::HashMap, error::Error, io};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
 use common::VrfId;
-use protocol::Vrf;
+use protocol::{
+    mac, prefix, AclRule, Bpdu, Compression, Gateway, IpPrefix, IpRoute, Learning, StaticMac, Vrf,
+    VrfMetadata, VrfMode, VrfNetns, VrfSettings, Vxlan,
+};
 use serde::{Deserialize, Serialize};
-use tokio::fs::{read, write};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{read, rename, File},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
 
-use crate::config::SwitchId;
+use crate::{
+    config::SwitchId,
+    instance,
+    state::State,
+    switch_table::{MacAddress, SwitchTable},
+};
 
-const CACHE_PATH: &str = "/var/cache/dwitch.cache";
+const CACHE_DIRECTORY: &str = "/var/cache";
+const NONCE_SIZE: usize = 12;
+const MAGIC: &[u8] = b"dwitch-cache";
+// bumped with each change of the layout, the older ones are migrated when loaded
+const VERSION: u32 = 4;
+
+// digest of the last cache written, the periodic save, a handover and operators don't write the
+// temporary file together
+static SAVED: Mutex<Option<[u8; 32]>> = Mutex::const_new(None);
 
-pub type SwitchTable = HashMap<VrfId, HashMap<[u8; 6], SwitchId>>;
 pub type VrfTable = HashMap<VrfId, Vrf>;
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Whether the tables are kept across restarts at all.
+    pub persist: bool,
+    /// File the tables are kept in, next to a temporary one while they're saved.
+    pub path: PathBuf,
+    /// Seconds between two saves, a save only writes when the tables changed.
+    pub interval: u64,
+}
+
+impl CacheConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            persist: true,
+            path: PathBuf::from(CACHE_DIRECTORY)
+                .join(format!("{}.cache", instance::suffixed("dwitch"))),
+            interval: 1,
+        }
+    }
+}
+
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Cache {
     pub switch_table: SwitchTable,
     pub vrf_table: VrfTable,
+    pub deleted_vrfs: HashMap<VrfId, u64>,
+    pub declared_vrfs: HashSet<VrfId>,
+    /// Macs pinned behind a switch, by vrf.
+    pub static_macs: Vec<(VrfId, MacAddress, SwitchId)>,
+    pub acls: HashMap<VrfId, Vec<AclRule>>,
+}
+
+// the layout of the releases before the header, with only the vrfs and the learned macs
+#[derive(Deserialize)]
+struct CacheV0 {
+    switch_table: HashMap<VrfId, HashMap<MacAddress, SwitchId>>,
+    vrf_table: HashMap<VrfId, VrfV0>,
+}
+
+#[derive(Deserialize)]
+struct VrfV0 {
+    id: VrfId,
+    name: String,
+    members: Vec<SwitchId>,
+}
+
+impl From<CacheV0> for Cache {
+    fn from(cache: CacheV0) -> Self {
+        let mut switch_table = SwitchTable::default();
+
+        // the macs were learned before vlans were told apart
+        for (vrf_id, macs) in cache.switch_table {
+            let shard = switch_table.shard(vrf_id);
+
+            for (mac, switch_id) in macs {
+                shard.learn(0, mac, switch_id);
+            }
+        }
+
+        Cache {
+            switch_table,
+            vrf_table: cache
+                .vrf_table
+                .into_iter()
+                .map(|(vrf_id, vrf)| {
+                    (
+                        vrf_id,
+                        Vrf {
+                            id: vrf.id,
+                            name: vrf.name,
+                            members: vrf.members,
+                            template: None,
+                            settings: VrfSettings::default(),
+                            metadata: VrfMetadata::default(),
+                            suspended: false,
+                            version: 0,
+                        },
+                    )
+                })
+                .collect(),
+            deleted_vrfs: HashMap::new(),
+            declared_vrfs: HashSet::new(),
+            static_macs: Vec::new(),
+            acls: HashMap::new(),
+        }
+    }
+}
+
+// the first layout with the header, before the pinned macs
+#[derive(Deserialize)]
+struct CacheV1 {
+    switch_table: SwitchTable,
+    vrf_table: HashMap<VrfId, VrfV3>,
+    deleted_vrfs: HashMap<VrfId, u64>,
+    declared_vrfs: HashSet<VrfId>,
+}
+
+impl From<CacheV1> for Cache {
+    fn from(cache: CacheV1) -> Self {
+        Cache {
+            switch_table: cache.switch_table,
+            vrf_table: vrf_table(cache.vrf_table),
+            deleted_vrfs: cache.deleted_vrfs,
+            declared_vrfs: cache.declared_vrfs,
+            static_macs: Vec::new(),
+            acls: HashMap::new(),
+        }
+    }
+}
+
+// before the acls
+#[derive(Deserialize)]
+struct CacheV2 {
+    switch_table: SwitchTable,
+    vrf_table: HashMap<VrfId, VrfV3>,
+    deleted_vrfs: HashMap<VrfId, u64>,
+    declared_vrfs: HashSet<VrfId>,
+    static_macs: Vec<(VrfId, MacAddress, SwitchId)>,
+}
+
+impl From<CacheV2> for Cache {
+    fn from(cache: CacheV2) -> Self {
+        Cache {
+            switch_table: cache.switch_table,
+            vrf_table: vrf_table(cache.vrf_table),
+            deleted_vrfs: cache.deleted_vrfs,
+            declared_vrfs: cache.declared_vrfs,
+            static_macs: cache.static_macs,
+            acls: HashMap::new(),
+        }
+    }
+}
+
+// before the bandwidth limits of the vrfs
+#[derive(Deserialize)]
+struct CacheV3 {
+    switch_table: SwitchTable,
+    vrf_table: HashMap<VrfId, VrfV3>,
+    deleted_vrfs: HashMap<VrfId, u64>,
+    declared_vrfs: HashSet<VrfId>,
+    static_macs: Vec<(VrfId, MacAddress, SwitchId)>,
+    acls: HashMap<VrfId, Vec<AclRule>>,
+}
+
+impl From<CacheV3> for Cache {
+    fn from(cache: CacheV3) -> Self {
+        Cache {
+            switch_table: cache.switch_table,
+            vrf_table: vrf_table(cache.vrf_table),
+            deleted_vrfs: cache.deleted_vrfs,
+            declared_vrfs: cache.declared_vrfs,
+            static_macs: cache.static_macs,
+            acls: cache.acls,
+        }
+    }
+}
+
+// vrfs of the versions up to the third, their settings had no bandwidth limits
+#[derive(Deserialize)]
+struct VrfV3 {
+    id: VrfId,
+    name: String,
+    members: Vec<SwitchId>,
+    template: Option<String>,
+    settings: VrfSettingsV3,
+    metadata: VrfMetadata,
+    suspended: bool,
+    version: u64,
+}
+
+#[derive(Deserialize)]
+struct VrfSettingsV3 {
+    mode: Option<VrfMode>,
+    ifname: Option<String>,
+    #[serde(with = "mac::option")]
+    mac: Option<[u8; 6]>,
+    mtu: Option<u32>,
+    #[serde(with = "prefix::vec")]
+    addresses: Vec<IpPrefix>,
+    routes: Vec<IpRoute>,
+    max_frame_size: Option<u32>,
+    max_macs: Option<u32>,
+    learning: Option<Learning>,
+    static_macs: Vec<StaticMac>,
+    frame_rate: Option<u32>,
+    queue_depth: Option<u32>,
+    deny_ethertypes: Vec<u16>,
+    vlans: Vec<u16>,
+    bpdu: Option<Bpdu>,
+    gateway: Option<Gateway>,
+    compression: Option<Compression>,
+    vxlan: Option<Vxlan>,
+    netns: Option<VrfNetns>,
+}
+
+impl From<VrfV3> for Vrf {
+    fn from(vrf: VrfV3) -> Self {
+        let settings = vrf.settings;
+
+        Vrf {
+            id: vrf.id,
+            name: vrf.name,
+            members: vrf.members,
+            template: vrf.template,
+            settings: VrfSettings {
+                mode: settings.mode,
+                ifname: settings.ifname,
+                mac: settings.mac,
+                mtu: settings.mtu,
+                addresses: settings.addresses,
+                routes: settings.routes,
+                max_frame_size: settings.max_frame_size,
+                max_macs: settings.max_macs,
+                learning: settings.learning,
+                static_macs: settings.static_macs,
+                frame_rate: settings.frame_rate,
+                bandwidth: None,
+                peer_bandwidth: None,
+                queue_depth: settings.queue_depth,
+                deny_ethertypes: settings.deny_ethertypes,
+                vlans: settings.vlans,
+                bpdu: settings.bpdu,
+                gateway: settings.gateway,
+                compression: settings.compression,
+                vxlan: settings.vxlan,
+                netns: settings.netns,
+            },
+            metadata: vrf.metadata,
+            suspended: vrf.suspended,
+            version: vrf.version,
+        }
+    }
+}
+
+fn vrf_table(vrf_table: HashMap<VrfId, VrfV3>) -> VrfTable {
+    vrf_table
+        .into_iter()
+        .map(|(vrf_id, vrf)| (vrf_id, vrf.into()))
+        .collect()
 }
 
 impl Cache {
-    pub async fn load() -> Result<Cache, Box<dyn Error>> {
-        Ok(bincode::deserialize(&read(CACHE_PATH).await?)?)
+    pub async fn from_state(state: &State) -> Cache {
+        let switch_table = state.switch_table.read().await.clone();
+
+        Cache {
+            static_macs: switch_table.statics(),
+            switch_table,
+            vrf_table: state.vrf_table.read().await.clone(),
+            deleted_vrfs: state.deleted_vrfs.lock().unwrap().clone(),
+            declared_vrfs: state.declared_vrfs.lock().unwrap().clone(),
+            acls: state.acls.rules(),
+        }
+    }
+
+    /// An empty cache when there's no file yet or the tables aren't persisted.
+    pub async fn load(
+        config: &CacheConfig,
+        key: Option<&CacheKey>,
+    ) -> Result<Cache, Box<dyn Error>> {
+        if !config.persist {
+            return Ok(Cache::default());
+        }
+
+        let bytes = match read(&config.path).await {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Cache::default()),
+            Err(error) => return Err(error.into()),
+        };
+        // caches written before the header are of the releases without it
+        let (version, bytes) = match bytes.strip_prefix(MAGIC) {
+            Some([a, b, c, d, bytes @ ..]) => (u32::from_be_bytes([*a, *b, *c, *d]), bytes),
+            Some(_) => return Err("Cache header is truncated".into()),
+            None => (0, &bytes[..]),
+        };
+        // the releases without the header never encrypted the cache
+        let bytes = match key {
+            Some(key) if version > 0 => key.decrypt(bytes)?,
+            _ => bytes.to_vec(),
+        };
+
+        // an older version gets an arm reading its own layout and converting it
+        match version {
+            0 => Ok(bincode::deserialize::<CacheV0>(&bytes)?.into()),
+            1 => Ok(bincode::deserialize::<CacheV1>(&bytes)?.into()),
+            2 => Ok(bincode::deserialize::<CacheV2>(&bytes)?.into()),
+            3 => Ok(bincode::deserialize::<CacheV3>(&bytes)?.into()),
+            VERSION => Ok(bincode::deserialize(&bytes)?),
+            version => Err(format!("Can't read cache version {version}, it's newer").into()),
+        }
+    }
+
+    /// Move a cache file that can't be loaded aside, so the next save doesn't overwrite it.
+    pub async fn set_aside(config: &CacheConfig) -> io::Result<PathBuf> {
+        let mut path = config.path.clone().into_os_string();
+
+        path.push(".unreadable");
+        rename(&config.path, &path).await?;
+
+        Ok(path.into())
+    }
+
+    /// Replace the cache file at once, a crash in the middle of a save leaves the previous one.
+    pub async fn save(&self, config: &CacheConfig, key: Option<&CacheKey>) -> io::Result<()> {
+        self.write(config, key, true).await
+    }
+
+    /// Save only what changed since the last cache written.
+    pub async fn save_changed(
+        &self,
+        config: &CacheConfig,
+        key: Option<&CacheKey>,
+    ) -> io::Result<()> {
+        self.write(config, key, false).await
+    }
+
+    async fn write(
+        &self,
+        config: &CacheConfig,
+        key: Option<&CacheKey>,
+        always: bool,
+    ) -> io::Result<()> {
+        if !config.persist {
+            return Ok(());
+        }
+
+        let bytes = bincode::serialize(self).map_err(io::Error::other)?;
+        let digest = Sha256::digest(&bytes).into();
+        let mut saved = SAVED.lock().await;
+
+        if !always && *saved == Some(digest) {
+            return Ok(());
+        }
+
+        let bytes = match key {
+            Some(key) => key.encrypt(&bytes)?,
+            None => bytes,
+        };
+        let mut temporary = config.path.clone().into_os_string();
+
+        temporary.push(".tmp");
+
+        let mut file = File::create(&temporary).await?;
+
+        file.write_all(MAGIC).await?;
+        file.write_all(&VERSION.to_be_bytes()).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        rename(&temporary, &config.path).await?;
+
+        *saved = Some(digest);
+
+        Ok(())
     }
+}
+
+#[derive(Clone)]
+pub struct CacheKey(ChaCha20Poly1305);
+
+impl CacheKey {
+    pub async fn load(path: &Path) -> io::Result<Self> {
+        let key_material = read(path).await?;
+        let key = Sha256::digest(&key_material);
+
+        Ok(Self(ChaCha20Poly1305::new(Key::from_slice(&key))))
+    }
+
+    fn encrypt(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, bytes)
+            .map_err(|_| io::Error::other("Can't encrypt cache"))?;
+
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decrypt(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        if bytes.len() < NONCE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Encrypted cache is truncated",
+            ));
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
 
-    pub async fn save(&self) -> io::Result<()> {
-        Ok(write(
-            CACHE_PATH,
-            bincode::serialize(self).expect("Can't serialize cache"),
-        )
-        .await?)
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Can't decrypt cache"))
     }
 }
@@ -1,21 +1,420 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs::read_to_string,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    path::PathBuf,
+};
 
-use serde::Deserialize;
-use tokio::fs::read_to_string;
+use common::VrfId;
+use protocol::{prefix, IpPrefix, VrfSettings};
+#[cfg(not(target_os = "linux"))]
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer};
+use tokio::net::lookup_host;
 
-const CONFIG_PATH: &str = "/etc/dwitch/config.toml";
+use crate::{
+    cache::CacheConfig,
+    discovery::DiscoveryConfig,
+    evpn::EvpnConfig,
+    gossip::GossipConfig,
+    instance,
+    link::{Dataplane, UplinkConfig},
+    mirror::MirrorConfig,
+    networkd::NetworkdConfig,
+    openflow::OpenflowConfig,
+    rate_limit::RateLimitConfig,
+    runtime::RuntimeConfig,
+    sandbox::SandboxConfig,
+    token::TokenConfig,
+    vxlan::VxlanConfig,
+};
+#[cfg(target_os = "linux")]
+use crate::{dns::DnsConfig, privileges::PrivilegesConfig, route_leak::RouteLeakConfig};
+
+const CONFIG_DIRECTORY: &str = "/etc/dwitch";
+const RUN_DIRECTORY: &str = "/run";
+const DBUS_NAME: &str = "org.dwitch.Dwitch";
+const DOCKER_PLUGIN_DIRECTORY: &str = "/run/docker/plugins";
 
 pub type SwitchId = u32;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub switch_id: SwitchId,
+    /// Addresses peers and remote clients connect to, one or a list, the first is the one
+    /// announced to the other switches.
+    #[serde(deserialize_with = "deserialize_listen")]
+    pub listen: Vec<SocketAddr>,
+    pub servers: Vec<ServerAddress>,
+    /// Find the other switches of the lan over mdns and dial them besides the servers.
+    pub discovery: Option<DiscoveryConfig>,
+    /// Dial the switches the peers advertise, so a single seed is enough to join the mesh.
+    pub gossip: Option<GossipConfig>,
+    pub vrf_id_range: Option<VrfIdRange>,
+    pub control_key: Option<String>,
+    #[serde(default)]
+    pub authenticate_data: bool,
+    #[serde(default = "default_management_socket")]
+    pub management_socket: PathBuf,
+    /// Group whose members can use the management socket, besides the user of the daemon.
+    pub management_group: Option<String>,
+    /// Where a new daemon takes the listeners and the taps over from this one. The peer
+    /// connections aren't handed over, their tls and signing state can't be, the peers reconnect
+    /// to the new daemon through the listener.
+    pub handover_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub admins: Vec<IpAddr>,
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    pub cache_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub mirror: MirrorConfig,
+    #[serde(default)]
+    pub vrf_keys: HashMap<String, PathBuf>,
+    #[serde(default)]
+    pub templates: HashMap<String, VrfSettings>,
+    /// Vrfs kept as declared, at startup and on each reload.
+    #[serde(default)]
+    pub vrfs: Vec<VrfConfig>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub dataplane: Dataplane,
+    #[serde(default)]
+    pub uplinks: HashMap<String, UplinkConfig>,
+    #[serde(default = "default_tap_setup_parallelism")]
+    pub tap_setup_parallelism: usize,
+    /// Connection attempts to peers made at once, the others wait their turn.
+    #[serde(default = "default_connect_parallelism")]
+    pub connect_parallelism: usize,
+    #[serde(default = "default_max_macs_per_vrf")]
+    pub max_macs_per_vrf: NonZeroUsize,
+    #[serde(default = "default_action_rate_limit")]
+    pub action_rate_limit: RateLimitConfig,
+    pub health: Option<HealthConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub api: Option<ApiConfig>,
+    pub dbus: Option<DbusConfig>,
+    pub docker: Option<DockerConfig>,
+    #[cfg(target_os = "linux")]
+    pub dns: Option<DnsConfig>,
+    // the linux only sections are still read elsewhere, to refuse them
+    #[cfg(not(target_os = "linux"))]
+    pub dns: Option<IgnoredAny>,
+    pub vm: Option<VmConfig>,
+    pub networkd: Option<NetworkdConfig>,
+    pub evpn: Option<EvpnConfig>,
+    pub vxlan: Option<VxlanConfig>,
+    pub openflow: Option<OpenflowConfig>,
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub route_leaks: Vec<RouteLeakConfig>,
+    #[cfg(not(target_os = "linux"))]
+    #[serde(default)]
+    pub route_leaks: Vec<IgnoredAny>,
+    /// Split in a /30 per vrf netns masquerading its egress, for the links into the host
+    #[serde(default = "default_nat_transit", with = "prefix")]
+    pub nat_transit: IpPrefix,
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    #[cfg(target_os = "linux")]
+    pub privileges: Option<PrivilegesConfig>,
+    #[cfg(not(target_os = "linux"))]
+    pub privileges: Option<IgnoredAny>,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+}
+
+/// A peer to dial, written `<address>:<port>` or `<host>:<port>`. A host name is resolved again
+/// each time the peer is dialed, so a peer whose address changes is still found.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ServerAddress {
+    Ip(SocketAddr),
+    Host { name: String, port: u16 },
+}
+
+impl ServerAddress {
+    pub fn port(&self) -> u16 {
+        match self {
+            ServerAddress::Ip(address) => address.port(),
+            ServerAddress::Host { port, .. } => *port,
+        }
+    }
+
+    /// The first address the host name resolves to.
+    pub async fn resolve(&self) -> io::Result<SocketAddr> {
+        match self {
+            ServerAddress::Ip(address) => Ok(*address),
+            ServerAddress::Host { name, port } => lookup_host((name.as_str(), *port))
+                .await?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found")),
+        }
+    }
+}
+
+impl Display for ServerAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddress::Ip(address) => address.fmt(f),
+            ServerAddress::Host { name, port } => write!(f, "{name}:{port}"),
+        }
+    }
+}
+
+impl From<SocketAddr> for ServerAddress {
+    fn from(address: SocketAddr) -> Self {
+        ServerAddress::Ip(address)
+    }
+}
+
+impl TryFrom<String> for ServerAddress {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        if let Ok(address) = address.parse() {
+            return Ok(ServerAddress::Ip(address));
+        }
+
+        match address.rsplit_once(':') {
+            Some((name, port))
+                if !name.is_empty()
+                    && !name.contains(|c: char| c == ':' || c == '/' || c.is_whitespace()) =>
+            {
+                match port.parse() {
+                    Ok(port) => Ok(ServerAddress::Host {
+                        name: name.to_string(),
+                        port,
+                    }),
+                    Err(error) => Err(format!("Invalid port {port}: {error}")),
+                }
+            }
+            _ => Err("Expected <address>:<port> or <host>:<port>".to_string()),
+        }
+    }
+}
+
+/// Ids handed out by this switch when a vrf is created without one, both ends included.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct VrfIdRange {
+    pub start: VrfId,
+    pub end: VrfId,
+}
+
+/// A vrf of the config file, its settings sit next to its id like in a template.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VrfConfig {
+    pub id: VrfId,
+    pub name: String,
+    pub members: Vec<SwitchId>,
+    pub template: Option<String>,
+    #[serde(flatten)]
+    pub settings: VrfSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthConfig {
+    pub listen: SocketAddr,
+    #[serde(default)]
+    pub min_peers: usize,
+}
+
+/// Prometheus scrape endpoint, served at `/metrics`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
     pub listen: SocketAddr,
-    pub servers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiConfig {
+    pub listen: SocketAddr,
+}
+
+/// Only served when built with the `dbus` feature.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DbusConfig {
+    #[serde(default = "default_dbus_name")]
+    pub name: String,
+    #[serde(default = "default_polkit")]
+    pub polkit: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DockerConfig {
+    #[serde(default = "default_docker_socket")]
+    pub socket: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VmConfig {
+    #[serde(default = "default_vm_socket")]
+    pub socket: PathBuf,
+}
+
+/// How this switch dials its peers, a quic switch still accepts tcp ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// One quic connection per peer, on the udp port of the listen address, where each vrf sends
+    /// its frames on a stream of its own. Needs tls.
+    Quic,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub topics: MqttTopics,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttTopics {
+    pub prefix: String,
+    pub peer: String,
+    pub vrf: String,
+    pub alert: String,
+}
+
+impl Default for MqttTopics {
+    fn default() -> Self {
+        Self {
+            prefix: "dwitch".to_string(),
+            peer: "peer".to_string(),
+            vrf: "vrf".to_string(),
+            alert: "alert".to_string(),
+        }
+    }
+}
+
+fn default_action_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        rate: 5.0,
+        burst: 20,
+    }
+}
+
+// shared address space, kept out of the networks of the vrfs and the host
+fn default_nat_transit() -> IpPrefix {
+    IpPrefix {
+        address: Ipv4Addr::new(100, 64, 0, 0).into(),
+        prefix_length: 10,
+    }
+}
+
+fn default_tap_setup_parallelism() -> usize {
+    8
+}
+
+fn default_connect_parallelism() -> usize {
+    16
+}
+
+fn default_max_macs_per_vrf() -> NonZeroUsize {
+    NonZeroUsize::new(8192).unwrap()
+}
+
+fn default_management_socket() -> PathBuf {
+    PathBuf::from(RUN_DIRECTORY).join(format!("{}.sock", instance::suffixed("dwitch")))
+}
+
+fn default_dbus_name() -> String {
+    DBUS_NAME.to_string()
+}
+
+fn default_polkit() -> bool {
+    true
+}
+
+fn deserialize_listen<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Listen {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+
+    match Listen::deserialize(deserializer)? {
+        Listen::One(address) => Ok(vec![address]),
+        Listen::Many(addresses) if addresses.is_empty() => Err(serde::de::Error::custom(
+            "Expected at least one listen address",
+        )),
+        Listen::Many(addresses) => Ok(addresses),
+    }
+}
+
+fn default_docker_socket() -> PathBuf {
+    // docker names the driver after the socket
+    PathBuf::from(DOCKER_PLUGIN_DIRECTORY).join(format!("{}.sock", instance::suffixed("dwitch")))
+}
+
+fn default_vm_socket() -> PathBuf {
+    PathBuf::from(RUN_DIRECTORY).join(format!("{}-vm.sock", instance::suffixed("dwitch")))
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
 }
 
 impl Config {
-    pub async fn load() -> eyre::Result<Config> {
-        Ok(toml::from_str(&read_to_string(CONFIG_PATH).await?)?)
+    pub fn control_key(&self) -> Option<&[u8]> {
+        self.control_key.as_deref().map(str::as_bytes)
+    }
+
+    pub fn data_key(&self) -> Option<&[u8]> {
+        self.control_key().filter(|_| self.authenticate_data)
+    }
+
+    /// The listen address announced to the other switches.
+    pub fn listen_address(&self) -> SocketAddr {
+        self.listen[0]
+    }
+
+    // switch ids fit in the high half by default, ids below 0x10000 are left to the operators
+    pub fn vrf_id_range(&self) -> Option<RangeInclusive<VrfId>> {
+        match self.vrf_id_range {
+            Some(VrfIdRange { start, end }) => Some(start..=end),
+            None => {
+                let start = self.switch_id.checked_mul(0x10000)?;
+
+                Some(start..=start | 0xffff)
+            }
+        }
+    }
+
+    pub fn load() -> eyre::Result<Config> {
+        let file = match instance::name() {
+            Some(name) => format!("{name}.toml"),
+            None => "config.toml".to_string(),
+        };
+
+        Ok(toml::from_str(&read_to_string(
+            PathBuf::from(CONFIG_DIRECTORY).join(file),
+        )?)?)
     }
 }
@@ -0,0 +1,293 @@
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+use common::VrfId;
+use protocol::{Maintenance, Response, Vrf, VrfAction, VrfMetadata, VrfSettings};
+use zbus::{connection, fdo, interface, message::Header, proxy, zvariant::Value, Connection};
+
+use crate::{
+    config::{DbusConfig, SwitchId},
+    management::{allocate_vrf, configure, list_peers, list_vrfs, set_maintenance},
+    state::{Source, State},
+    token::{Access, Permission, Refusal},
+};
+
+const OBJECT_PATH: &str = "/org/dwitch/Manager";
+const POLKIT_ACTION: &str = "org.dwitch.manage";
+const POLKIT_ALLOW_USER_INTERACTION: u32 = 1;
+
+#[proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait Authority {
+    fn check_authorization(
+        &self,
+        subject: &(&str, HashMap<&str, Value<'_>>),
+        action_id: &str,
+        details: HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zbus::Result<(bool, bool, HashMap<String, String>)>;
+}
+
+struct Manager {
+    config: DbusConfig,
+    state: Arc<State>,
+}
+
+impl Manager {
+    // the bus policy already let the caller in, polkit's approval is what makes it an admin
+    async fn authorize(
+        &self,
+        header: &Header<'_>,
+        connection: &Connection,
+        access: Access,
+    ) -> fdo::Result<()> {
+        let permission = match (self.config.polkit, access) {
+            (true, Access::Change) => self.check_polkit(header, connection).await?,
+            (true, Access::Read) => Some(Permission::ReadOnly),
+            (false, _) => Some(Permission::Admin),
+        };
+
+        self.state
+            .authorize(Source::Dbus, permission, access, "d-bus request")
+            .map_err(|refusal| match refusal {
+                Refusal::Denied => fdo::Error::AccessDenied(refusal.to_string()),
+                Refusal::RateLimited => fdo::Error::LimitsExceeded(refusal.to_string()),
+            })
+    }
+
+    async fn check_polkit(
+        &self,
+        header: &Header<'_>,
+        connection: &Connection,
+    ) -> fdo::Result<Option<Permission>> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::AccessDenied("Unknown sender".to_string()))?;
+        let subject = (
+            "system-bus-name",
+            HashMap::from([("name", Value::from(sender.as_str()))]),
+        );
+        let (authorized, _, _) = AuthorityProxy::new(connection)
+            .await?
+            .check_authorization(
+                &subject,
+                POLKIT_ACTION,
+                HashMap::new(),
+                POLKIT_ALLOW_USER_INTERACTION,
+                "",
+            )
+            .await?;
+
+        Ok(authorized.then_some(Permission::Admin))
+    }
+
+    async fn configure(
+        &self,
+        header: &Header<'_>,
+        connection: &Connection,
+        vrf_action: VrfAction,
+    ) -> fdo::Result<()> {
+        self.authorize(header, connection, Access::Change).await?;
+
+        match configure(&self.state, vrf_action).await {
+            Response::Ok => Ok(()),
+            Response::Error(error) => Err(fdo::Error::Failed(error)),
+        }
+    }
+}
+
+#[interface(name = "org.dwitch.Manager1")]
+impl Manager {
+    async fn list_vrfs(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<Vec<(VrfId, String, Vec<SwitchId>)>> {
+        self.authorize(&header, connection, Access::Read).await?;
+
+        Ok(list_vrfs(&self.state)
+            .await
+            .into_iter()
+            .map(|vrf| (vrf.id, vrf.name, vrf.members))
+            .collect())
+    }
+
+    async fn create_vrf(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+        name: String,
+        members: Vec<SwitchId>,
+    ) -> fdo::Result<()> {
+        self.configure(
+            &header,
+            connection,
+            VrfAction::Create(Box::new(Vrf {
+                id,
+                name,
+                members,
+                template: None,
+                settings: VrfSettings::default(),
+                metadata: VrfMetadata::default(),
+                suspended: false,
+                version: 0,
+            })),
+        )
+        .await
+    }
+
+    async fn create_vrf_from_template(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+        name: String,
+        members: Vec<SwitchId>,
+        template: String,
+    ) -> fdo::Result<()> {
+        self.configure(
+            &header,
+            connection,
+            VrfAction::Create(Box::new(Vrf {
+                id,
+                name,
+                members,
+                template: Some(template),
+                settings: VrfSettings::default(),
+                metadata: VrfMetadata::default(),
+                suspended: false,
+                version: 0,
+            })),
+        )
+        .await
+    }
+
+    async fn allocate_vrf(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        name: String,
+        members: Vec<SwitchId>,
+    ) -> fdo::Result<VrfId> {
+        self.authorize(&header, connection, Access::Change).await?;
+
+        let vrf = Vrf {
+            id: 0,
+            name,
+            members,
+            template: None,
+            settings: VrfSettings::default(),
+            metadata: VrfMetadata::default(),
+            suspended: false,
+            version: 0,
+        };
+
+        allocate_vrf(&self.state, vrf)
+            .await
+            .map_err(fdo::Error::Failed)
+    }
+
+    async fn delete_vrf(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+    ) -> fdo::Result<()> {
+        self.configure(&header, connection, VrfAction::Delete { id })
+            .await
+    }
+
+    async fn suspend_vrf(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+    ) -> fdo::Result<()> {
+        self.configure(&header, connection, VrfAction::Suspend { id })
+            .await
+    }
+
+    async fn resume_vrf(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+    ) -> fdo::Result<()> {
+        self.configure(&header, connection, VrfAction::Resume { id })
+            .await
+    }
+
+    async fn add_members(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+        members: Vec<SwitchId>,
+    ) -> fdo::Result<()> {
+        self.configure(&header, connection, VrfAction::AddMember { id, members })
+            .await
+    }
+
+    async fn remove_members(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: VrfId,
+        members: Vec<SwitchId>,
+    ) -> fdo::Result<()> {
+        self.configure(&header, connection, VrfAction::RemoveMember { id, members })
+            .await
+    }
+
+    async fn list_peers(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<Vec<(SwitchId, bool)>> {
+        self.authorize(&header, connection, Access::Read).await?;
+
+        Ok(list_peers(&self.state)
+            .await
+            .into_iter()
+            .map(|peer| (peer.switch_id, peer.draining))
+            .collect())
+    }
+
+    async fn set_draining(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        draining: bool,
+    ) -> fdo::Result<()> {
+        self.authorize(&header, connection, Access::Change).await?;
+        set_maintenance(
+            &self.state,
+            match draining {
+                true => Maintenance::Drain,
+                false => Maintenance::Activate,
+            },
+        )
+        .await;
+
+        Ok(())
+    }
+}
+
+/// Take the name on the system bus, before the privileges are dropped. The connection serves the
+/// interface as long as it's kept.
+pub async fn dbus(config: DbusConfig, state: Arc<State>) -> Result<Connection, Box<dyn Error>> {
+    let name = config.name.clone();
+    let connection = connection::Builder::system()?
+        .name(name.as_str())?
+        .serve_at(OBJECT_PATH, Manager { config, state })?
+        .build()
+        .await?;
+
+    tracing::info!("Serving {name} on the system bus");
+
+    Ok(connection)
+}
@@ -0,0 +1,455 @@
+//! Discovery of the switches of the lan over mdns. Each switch announces the `_dwitch._tcp`
+//! service with its switch id and listen port, and dials the switches it hears about.
+//!
+//! Only the records dwitch needs are written and read: the ptr of the service, the srv giving the
+//! port, a txt holding the switch id and, when the switch listens on one address, its a or aaaa
+//! record. A switch announced without one is dialed at the address the announcement came from.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket},
+    os::fd::AsRawFd,
+    sync::Arc,
+    time::Duration,
+};
+
+use nix::sys::socket::{
+    bind, setsockopt, socket,
+    sockopt::{ReuseAddr, ReusePort},
+    AddressFamily, SockFlag, SockType, SockaddrIn,
+};
+use serde::Deserialize;
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::mpsc::Sender,
+    time::{interval, Instant},
+};
+
+use crate::{config::SwitchId, state::State};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_dwitch._tcp.local";
+const MAX_MESSAGE_SIZE: usize = 9000;
+const HEADER_SIZE: usize = 12;
+// a switch not heard from for this many intervals is forgotten
+const MISSED_ANNOUNCEMENTS: u32 = 3;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// set on the records only this switch has, they replace what caches held for their name
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+// names point back into the message with the two high bits of their length set
+const LABEL_POINTER: u8 = 0xc0;
+const MAX_POINTERS: usize = 16;
+
+type DiscoveryError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Address of the interface the switches are announced and heard on, the one of the multicast
+    /// route by default
+    pub interface: Option<Ipv4Addr>,
+    /// Seconds between two announcements
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+/// What discovery tells the peering about the switches of the lan, and gossip about the ones its
+/// peers are connected to.
+#[derive(Debug, Clone, Copy)]
+pub enum Discovery {
+    Found(SocketAddr),
+    Lost(SocketAddr),
+    Advertised {
+        switch_id: SwitchId,
+        address: SocketAddr,
+    },
+}
+
+/// Announce this switch and report the switches announcing themselves, until the daemon stops.
+pub async fn discovery(config: DiscoveryConfig, state: Arc<State>, sender: Sender<Discovery>) {
+    let socket = match open_socket(config.interface.unwrap_or(Ipv4Addr::UNSPECIFIED)) {
+        Ok(socket) => socket,
+        Err(error) => {
+            tracing::error!("Can't start discovery: {error}");
+            return;
+        }
+    };
+    let period = Duration::from_secs(config.interval.max(1));
+    let ttl = (period * MISSED_ANNOUNCEMENTS).as_secs() as u32;
+    let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+    let announcement = announcement(&state, ttl);
+    let mut ticker = interval(period);
+    let mut switches = HashMap::<SwitchId, (SocketAddr, Instant)>::new();
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+
+    // the switches already up answer the query instead of waiting for their next announcement
+    if let Err(error) = socket.send_to(&query(), group).await {
+        tracing::warn!("Can't send discovery query: {error}");
+    }
+
+    loop {
+        select! {
+            _ = ticker.tick() => {
+                if let Err(error) = socket.send_to(&announcement, group).await {
+                    tracing::warn!("Can't announce switch: {error}");
+                }
+
+                let expired = switches
+                    .iter()
+                    .filter(|(_, (_, seen))| seen.elapsed() > period * MISSED_ANNOUNCEMENTS)
+                    .map(|(switch_id, (address, _))| (*switch_id, *address))
+                    .collect::<Vec<_>>();
+
+                for (switch_id, address) in expired {
+                    switches.remove(&switch_id);
+                    tracing::info!("Switch id {switch_id} at {address} is no longer announced");
+                    let _ = sender.send(Discovery::Lost(address)).await;
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (length, source) = match received {
+                    Ok(received) => received,
+                    Err(error) => {
+                        tracing::warn!("Can't receive discovery message: {error}");
+                        continue;
+                    }
+                };
+                let message = &buffer[..length];
+
+                if is_query(message) {
+                    if let Err(error) = socket.send_to(&announcement, group).await {
+                        tracing::warn!("Can't announce switch: {error}");
+                    }
+
+                    continue;
+                }
+
+                let Some((switch_id, address, ttl)) = announced(message, source.ip()) else {
+                    continue;
+                };
+
+                if switch_id == state.config.switch_id {
+                    continue;
+                }
+
+                // a ttl of 0 says goodbye
+                let known = match ttl {
+                    0 => switches.remove(&switch_id),
+                    _ => switches.insert(switch_id, (address, Instant::now())),
+                }
+                .map(|(known, _)| known);
+
+                if ttl > 0 && known == Some(address) {
+                    continue;
+                }
+
+                if let Some(known) = known {
+                    tracing::info!("Switch id {switch_id} at {known} is no longer announced");
+                    let _ = sender.send(Discovery::Lost(known)).await;
+                }
+
+                if ttl > 0 {
+                    tracing::info!("Discovered switch id {switch_id} at {address}");
+                    let _ = sender.send(Discovery::Found(address)).await;
+                }
+            }
+        }
+    }
+}
+
+fn open_socket(interface: Ipv4Addr) -> Result<UdpSocket, DiscoveryError> {
+    let socket = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    // other responders like avahi hold the port too
+    setsockopt(&socket, ReuseAddr, &true)?;
+    setsockopt(&socket, ReusePort, &true)?;
+    bind(
+        socket.as_raw_fd(),
+        &SockaddrIn::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT)),
+    )?;
+
+    let socket = StdUdpSocket::from(socket);
+
+    socket.join_multicast_v4(&MDNS_GROUP, &interface)?;
+    socket.set_multicast_ttl_v4(255)?;
+
+    if !interface.is_unspecified() {
+        let address = libc::in_addr {
+            s_addr: u32::from(interface).to_be(),
+        };
+
+        if unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_MULTICAST_IF,
+                (&raw const address).cast(),
+                size_of::<libc::in_addr>() as libc::socklen_t,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+fn announcement(state: &State, ttl: u32) -> Vec<u8> {
+    let switch_id = state.config.switch_id;
+    let listen = state.config.listen_address();
+    let instance = format!("switch-{switch_id}.{SERVICE}");
+    let host = format!("switch-{switch_id}.local");
+    let service = [0u16, 0, listen.port()]
+        .iter()
+        .flat_map(|field| field.to_be_bytes())
+        .chain(name(&host))
+        .collect();
+    let switch_id_entry = format!("switch_id={switch_id}");
+    let mut records = vec![
+        (SERVICE.to_string(), TYPE_PTR, CLASS_IN, name(&instance)),
+        (
+            instance.clone(),
+            TYPE_SRV,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            service,
+        ),
+        (
+            instance,
+            TYPE_TXT,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            [&[switch_id_entry.len() as u8], switch_id_entry.as_bytes()].concat(),
+        ),
+    ];
+
+    match listen.ip() {
+        ip if ip.is_unspecified() => {}
+        IpAddr::V4(ip) => records.push((
+            host,
+            TYPE_A,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            ip.octets().to_vec(),
+        )),
+        IpAddr::V6(ip) => records.push((
+            host,
+            TYPE_AAAA,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            ip.octets().to_vec(),
+        )),
+    }
+
+    let mut message = header(FLAG_RESPONSE | FLAG_AUTHORITATIVE, 0, records.len() as u16);
+
+    for (owner, kind, class, data) in records {
+        message.extend(name(&owner));
+        message.extend(kind.to_be_bytes());
+        message.extend(class.to_be_bytes());
+        message.extend(ttl.to_be_bytes());
+        message.extend((data.len() as u16).to_be_bytes());
+        message.extend(data);
+    }
+
+    message
+}
+
+fn query() -> Vec<u8> {
+    let mut message = header(0, 1, 0);
+
+    message.extend(name(SERVICE));
+    message.extend(TYPE_PTR.to_be_bytes());
+    message.extend(CLASS_IN.to_be_bytes());
+
+    message
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    [0, flags, questions, answers, 0, 0]
+        .iter()
+        .flat_map(|field| field.to_be_bytes())
+        .collect()
+}
+
+fn name(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    for label in name.split('.') {
+        bytes.push(label.len() as u8);
+        bytes.extend(label.as_bytes());
+    }
+
+    bytes.push(0);
+    bytes
+}
+
+fn field(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        message.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+// whether a message asks for the switches of the service
+fn is_query(message: &[u8]) -> bool {
+    let (Some(flags), Some(questions)) = (field(message, 2), field(message, 4)) else {
+        return false;
+    };
+    let mut offset = HEADER_SIZE;
+
+    if flags & FLAG_RESPONSE != 0 {
+        return false;
+    }
+
+    for _ in 0..questions {
+        let Some((question, end)) = read_name(message, offset) else {
+            return false;
+        };
+
+        if question.eq_ignore_ascii_case(SERVICE) && field(message, end) == Some(TYPE_PTR) {
+            return true;
+        }
+
+        offset = end + 4;
+    }
+
+    false
+}
+
+struct Record<'a> {
+    name: String,
+    kind: u16,
+    ttl: u32,
+    data: &'a [u8],
+    // where the data starts, for the names in it that point back into the message
+    offset: usize,
+}
+
+fn records(message: &[u8]) -> Option<Vec<Record<'_>>> {
+    let mut offset = HEADER_SIZE;
+
+    for _ in 0..field(message, 4)? {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+
+    let count = (6..HEADER_SIZE)
+        .step_by(2)
+        .map(|index| field(message, index).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut records = Vec::new();
+
+    for _ in 0..count {
+        let (name, end) = read_name(message, offset)?;
+        let fixed = message.get(end..end + 10)?;
+        let length = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let data = end + 10;
+
+        records.push(Record {
+            name,
+            kind: u16::from_be_bytes([fixed[0], fixed[1]]),
+            ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            data: message.get(data..data + length)?,
+            offset: data,
+        });
+        offset = data + length;
+    }
+
+    Some(records)
+}
+
+// the switch id, address and ttl a response announces, when it's the one of a switch
+fn announced(message: &[u8], source: IpAddr) -> Option<(SwitchId, SocketAddr, u32)> {
+    let records = records(message)?;
+    let suffix = format!(".{SERVICE}");
+    let service = records.iter().find(|record| {
+        record.kind == TYPE_SRV && record.name.to_ascii_lowercase().ends_with(&suffix)
+    })?;
+    let port = u16::from_be_bytes(service.data.get(4..6)?.try_into().ok()?);
+    let (host, _) = read_name(message, service.offset + 6)?;
+    let switch_id = records
+        .iter()
+        .filter(|record| record.kind == TYPE_TXT && record.name.eq_ignore_ascii_case(&service.name))
+        .find_map(|record| txt_value(record.data, "switch_id"))?
+        .parse()
+        .ok()?;
+    let ip = records
+        .iter()
+        .filter(|record| record.name.eq_ignore_ascii_case(&host))
+        .find_map(|record| match record.kind {
+            TYPE_A => Some(IpAddr::from(<[u8; 4]>::try_from(record.data).ok()?)),
+            TYPE_AAAA => Some(IpAddr::from(<[u8; 16]>::try_from(record.data).ok()?)),
+            _ => None,
+        })
+        .unwrap_or(source);
+
+    Some((switch_id, SocketAddr::new(ip, port), service.ttl))
+}
+
+fn txt_value<'a>(mut data: &'a [u8], key: &str) -> Option<&'a str> {
+    while let Some((length, rest)) = data.split_first() {
+        let (entry, rest) = rest.split_at_checked(usize::from(*length))?;
+
+        data = rest;
+
+        if let Some(value) = std::str::from_utf8(entry)
+            .ok()
+            .and_then(|entry| entry.strip_prefix(key)?.strip_prefix('='))
+        {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+// a name and the offset right after where it starts, following the pointers of compressed names
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let length = *message.get(offset)?;
+
+        if length & LABEL_POINTER == LABEL_POINTER {
+            pointers += 1;
+
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+
+            end.get_or_insert(offset + 2);
+            offset = usize::from(u16::from_be_bytes([
+                length & !LABEL_POINTER,
+                *message.get(offset + 1)?,
+            ]));
+            continue;
+        }
+
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+
+        let label = message.get(offset + 1..offset + 1 + usize::from(length))?;
+
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + usize::from(length);
+    }
+}
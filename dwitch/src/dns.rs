@@ -0,0 +1,144 @@
+//! Dns forwarder on the gateway address of the vrfs served by this switch.
+//!
+//! Queries are read in the vrf, in its netns or on its vrf device, and relayed from the host to the
+//! resolvers of the config, one socket per query so the answers don't need to be matched by id.
+
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
+    os::fd::{AsRawFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use common::VrfId;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use netns::Netns;
+use nix::sys::socket::{
+    bind, setsockopt, socket, sockopt::BindToDevice, AddressFamily, SockFlag, SockType, SockaddrIn,
+    SockaddrIn6,
+};
+use serde::Deserialize;
+use tokio::{net::UdpSocket, spawn, sync::Semaphore, task::JoinHandle, time::timeout};
+
+use crate::link::{self, Dataplane};
+
+pub const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// per vrf, the queries past it are dropped and retried by the clients
+const MAX_PENDING_QUERIES: usize = 256;
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsConfig {
+    /// Tried in order until one answers
+    pub resolvers: Vec<SocketAddr>,
+}
+
+/// Start forwarding the queries sent to the gateway address of a vrf, until the task is aborted.
+pub async fn forwarder(
+    config: DnsConfig,
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    address: IpAddr,
+) -> Result<JoinHandle<()>, Box<dyn Error + Send + Sync>> {
+    let listen = SocketAddr::new(address, DNS_PORT);
+    let socket = match dataplane {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        Dataplane::Netns => {
+            let vrf_netns = Netns::named(link::netns_name(vrf_name)).path();
+
+            link::run_in(&vrf_netns, move || open_socket(listen, None)).await??
+        }
+        Dataplane::Bridge | Dataplane::Tap => open_socket(listen, None)?,
+        Dataplane::Vrf => open_socket(listen, Some(link::master_name(dataplane, vrf_id)))?,
+    };
+    let socket = Arc::new(UdpSocket::from_std(socket)?);
+    let vrf_name = vrf_name.to_string();
+
+    Ok(spawn(async move {
+        let pending = Arc::new(Semaphore::new(MAX_PENDING_QUERIES));
+        let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+
+        loop {
+            let (length, client) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(error) => {
+                    tracing::warn!("Can't read dns queries of vrf {vrf_name}: {error}");
+                    break;
+                }
+            };
+            let Ok(permit) = pending.clone().try_acquire_owned() else {
+                continue;
+            };
+            let query = buffer[..length].to_vec();
+            let resolvers = config.resolvers.clone();
+            let socket = socket.clone();
+
+            spawn(async move {
+                if let Some(answer) = resolve(&resolvers, &query).await {
+                    let _ = socket.send_to(&answer, client).await;
+                }
+
+                drop(permit);
+            });
+        }
+    }))
+}
+
+async fn resolve(resolvers: &[SocketAddr], query: &[u8]) -> Option<Vec<u8>> {
+    for resolver in resolvers {
+        match timeout(QUERY_TIMEOUT, relay(*resolver, query)).await {
+            Ok(Ok(answer)) => return Some(answer),
+            Ok(Err(error)) => tracing::debug!("Can't forward a dns query to {resolver}: {error}"),
+            Err(_) => tracing::debug!("Dns resolver {resolver} didn't answer in time"),
+        }
+    }
+
+    None
+}
+
+async fn relay(resolver: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let unspecified: IpAddr = match resolver {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+
+    socket.connect(resolver).await?;
+    socket.send(query).await?;
+
+    let length = socket.recv(&mut buffer).await?;
+
+    buffer.truncate(length);
+
+    Ok(buffer)
+}
+
+// an address of a vrf device is only usable by a socket bound to the device
+fn open_socket(address: SocketAddr, device: Option<String>) -> io::Result<StdUdpSocket> {
+    let family = match address {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let socket: OwnedFd = socket(
+        family,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+
+    if let Some(device) = device {
+        setsockopt(&socket, BindToDevice, &device.into())?;
+    }
+
+    match address {
+        SocketAddr::V4(address) => bind(socket.as_raw_fd(), &SockaddrIn::from(address))?,
+        SocketAddr::V6(address) => bind(socket.as_raw_fd(), &SockaddrIn6::from(address))?,
+    }
+
+    Ok(StdUdpSocket::from(socket))
+}
@@ -0,0 +1,262 @@
+//! Libnetwork remote driver, docker networks map to vrfs and their containers get a veth into it.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{create_dir_all, remove_file, set_permissions, Permissions},
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    spawn,
+};
+
+use crate::{
+    api::{read_request, write_reply, Reply},
+    config::DockerConfig,
+    link,
+    management::local_vrf,
+    state::State,
+};
+
+const GENERIC_OPTIONS: &str = "com.docker.network.generic";
+const INTERFACE_PREFIX: &str = "eth";
+
+#[derive(Deserialize)]
+struct IpamData {
+    #[serde(rename = "Gateway")]
+    gateway: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateNetworkRequest {
+    #[serde(rename = "NetworkID")]
+    network_id: String,
+    #[serde(rename = "Options", default)]
+    options: HashMap<String, Value>,
+    #[serde(rename = "IPv4Data", default)]
+    ipv4_data: Vec<IpamData>,
+}
+
+#[derive(Deserialize)]
+struct NetworkRequest {
+    #[serde(rename = "NetworkID")]
+    network_id: String,
+}
+
+#[derive(Deserialize)]
+struct EndpointRequest {
+    #[serde(rename = "NetworkID")]
+    network_id: String,
+    #[serde(rename = "EndpointID")]
+    endpoint_id: String,
+}
+
+struct Network {
+    vrf: String,
+    gateway: Option<String>,
+}
+
+struct Driver {
+    state: Arc<State>,
+    // docker only tells about a network once, when it's created
+    networks: Mutex<HashMap<String, Network>>,
+}
+
+impl Driver {
+    async fn handle(&self, path: &str, body: &[u8]) -> Result<Value, String> {
+        match path {
+            "/Plugin.Activate" => Ok(json!({ "Implements": ["NetworkDriver"] })),
+            "/NetworkDriver.GetCapabilities" => {
+                Ok(json!({ "Scope": "local", "ConnectivityScope": "local" }))
+            }
+            "/NetworkDriver.CreateNetwork" => self.create_network(parse(body)?).await,
+            "/NetworkDriver.DeleteNetwork" => {
+                let request = parse::<NetworkRequest>(body)?;
+
+                self.networks.lock().unwrap().remove(&request.network_id);
+
+                Ok(json!({}))
+            }
+            // docker assigns the addresses and sets them on the interface itself
+            "/NetworkDriver.CreateEndpoint" => {
+                let request = parse::<EndpointRequest>(body)?;
+
+                self.network_vrf(&request.network_id)?;
+
+                Ok(json!({}))
+            }
+            "/NetworkDriver.EndpointOperInfo" => Ok(json!({ "Value": {} })),
+            "/NetworkDriver.Join" => self.join(parse(body)?).await,
+            "/NetworkDriver.Leave" => self.leave(parse(body)?).await,
+            "/NetworkDriver.DeleteEndpoint"
+            | "/NetworkDriver.DiscoverNew"
+            | "/NetworkDriver.DiscoverDelete"
+            | "/NetworkDriver.ProgramExternalConnectivity"
+            | "/NetworkDriver.RevokeExternalConnectivity"
+            | "/NetworkDriver.AllocateNetwork"
+            | "/NetworkDriver.FreeNetwork" => Ok(json!({})),
+            _ => Err(format!("Unsupported driver call {path}")),
+        }
+    }
+
+    async fn create_network(&self, request: CreateNetworkRequest) -> Result<Value, String> {
+        let vrf = request
+            .options
+            .get(GENERIC_OPTIONS)
+            .and_then(|options| options["vrf"].as_str())
+            .ok_or("Missing the vrf option")?
+            .to_string();
+
+        local_vrf(&self.state, &vrf).await?;
+
+        tracing::info!("Created docker network {} on vrf {vrf}", request.network_id);
+
+        // the container needs the bare gateway address, docker hands it out with its prefix
+        let gateway = request
+            .ipv4_data
+            .into_iter()
+            .find_map(|data| data.gateway)
+            .map(|gateway| match gateway.split_once('/') {
+                Some((address, _)) => address.to_string(),
+                None => gateway,
+            });
+
+        self.networks
+            .lock()
+            .unwrap()
+            .insert(request.network_id, Network { vrf, gateway });
+
+        Ok(json!({}))
+    }
+
+    fn network_vrf(&self, network_id: &str) -> Result<(String, Option<String>), String> {
+        self.networks
+            .lock()
+            .unwrap()
+            .get(network_id)
+            .map(|network| (network.vrf.clone(), network.gateway.clone()))
+            .ok_or_else(|| format!("Unknown network {network_id}, recreate it"))
+    }
+
+    async fn join(&self, request: EndpointRequest) -> Result<Value, String> {
+        let (vrf, gateway) = self.network_vrf(&request.network_id)?;
+        let vrf_info = local_vrf(&self.state, &vrf).await?;
+        let name = link::create_endpoint(
+            self.state.config.dataplane,
+            vrf_info.id,
+            &vrf,
+            vrf_info.settings.mtu,
+            &request.endpoint_id,
+        )
+        .await
+        .map_err(|error| format!("Can't attach the endpoint to vrf {vrf}: {error}"))?;
+
+        tracing::info!(
+            "Attached docker endpoint {} to vrf {vrf}",
+            request.endpoint_id
+        );
+
+        let mut reply = json!({
+            "InterfaceName": { "SrcName": name, "DstPrefix": INTERFACE_PREFIX },
+        });
+
+        if let Some(gateway) = gateway {
+            reply["Gateway"] = json!(gateway);
+        }
+
+        Ok(reply)
+    }
+
+    async fn leave(&self, request: EndpointRequest) -> Result<Value, String> {
+        let (vrf, _) = self.network_vrf(&request.network_id)?;
+
+        link::delete_endpoint(self.state.config.dataplane, &vrf, &request.endpoint_id).await;
+
+        tracing::info!(
+            "Detached docker endpoint {} from vrf {vrf}",
+            request.endpoint_id
+        );
+
+        Ok(json!({}))
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(body).map_err(|error| format!("Invalid request: {error}"))
+}
+
+/// Bind the docker socket, before the privileges are dropped.
+pub fn docker_listener(config: &DockerConfig) -> Result<UnixListener, Box<dyn Error>> {
+    if let Some(parent) = config.socket.parent() {
+        create_dir_all(parent)?;
+    }
+
+    if let Err(error) = remove_file(&config.socket) {
+        if error.kind() != ErrorKind::NotFound {
+            return Err(error.into());
+        }
+    }
+
+    let listener = UnixListener::bind(&config.socket)?;
+
+    set_permissions(&config.socket, Permissions::from_mode(0o600))?;
+
+    tracing::info!(
+        "Serving the docker network driver on {}",
+        config.socket.display()
+    );
+
+    Ok(listener)
+}
+
+pub async fn docker(listener: UnixListener, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let driver = Arc::new(Driver {
+        state,
+        networks: Mutex::new(HashMap::new()),
+    });
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                spawn(docker_connection(stream, driver.clone()));
+            }
+            Err(error) => {
+                tracing::error!("Can't accept docker client: {error}");
+            }
+        }
+    }
+}
+
+async fn docker_connection(mut stream: UnixStream, driver: Arc<Driver>) {
+    let reply = match read_request(&mut stream).await {
+        Some(request) if request.method == "POST" => {
+            let path = request.path.split('?').next().unwrap_or_default();
+
+            match driver.handle(path, &request.body).await {
+                Ok(value) => Reply::json(&value),
+                Err(error) => {
+                    tracing::warn!("Docker driver call {path} failed: {error}");
+
+                    Reply {
+                        status: "500 Internal Server Error",
+                        body: json!({ "Err": error }).to_string(),
+                    }
+                }
+            }
+        }
+        _ => Reply {
+            status: "400 Bad Request",
+            body: json!({ "Err": "Invalid request" }).to_string(),
+        },
+    };
+
+    if let Err(error) = write_reply(&mut stream, reply).await {
+        tracing::warn!("Can't answer docker client: {error}");
+    }
+}
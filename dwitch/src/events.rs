@@ -0,0 +1,23 @@
+use std::sync::OnceLock;
+
+pub use protocol::{Event, EventKind};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+const EVENT_CHANNEL_SIZE: usize = 256;
+
+static EVENTS: OnceLock<Sender<Event>> = OnceLock::new();
+
+fn sender() -> &'static Sender<Event> {
+    EVENTS.get_or_init(|| channel(EVENT_CHANNEL_SIZE).0)
+}
+
+pub fn publish(event: Event) {
+    tracing::debug!("Event {event:?}");
+
+    // having no subscriber isn't an error
+    let _ = sender().send(event);
+}
+
+pub fn subscribe() -> Receiver<Event> {
+    sender().subscribe()
+}
@@ -0,0 +1,703 @@
+//! Bgp evpn session with a router like frr, advertising the local vrfs and macs as route types 3
+//! and 2 and learning the ones of the fabric.
+//!
+//! Each vrf is advertised with its id as vni, in a route distinguisher made of the router id and
+//! the vrf id, and a route target made of the asn and the vni.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use common::VrfId;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    select, spawn,
+    sync::mpsc::{channel, Receiver},
+    task::JoinHandle,
+    time::{interval, sleep, Instant},
+};
+
+use crate::{
+    management::format_mac,
+    state::State,
+    switch_table::{MacAddress, Vlan},
+};
+
+const EVPN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+const BGP_VERSION: u8 = 4;
+const HEADER_SIZE: usize = 19;
+const MAX_MESSAGE_SIZE: usize = 4096;
+const AS_TRANS: u16 = 23456;
+
+const MESSAGE_OPEN: u8 = 1;
+const MESSAGE_UPDATE: u8 = 2;
+const MESSAGE_NOTIFICATION: u8 = 3;
+const MESSAGE_KEEPALIVE: u8 = 4;
+
+const CAPABILITIES_PARAMETER: u8 = 2;
+const CAPABILITY_MULTIPROTOCOL: u8 = 1;
+const CAPABILITY_FOUR_OCTET_AS: u8 = 65;
+
+const AFI_L2VPN: u16 = 25;
+const SAFI_EVPN: u8 = 70;
+
+const ATTRIBUTE_ORIGIN: u8 = 1;
+const ATTRIBUTE_AS_PATH: u8 = 2;
+const ATTRIBUTE_LOCAL_PREF: u8 = 5;
+const ATTRIBUTE_MP_REACH: u8 = 14;
+const ATTRIBUTE_MP_UNREACH: u8 = 15;
+const ATTRIBUTE_EXTENDED_COMMUNITIES: u8 = 16;
+const ATTRIBUTE_PMSI_TUNNEL: u8 = 22;
+
+const FLAG_OPTIONAL: u8 = 0x80;
+const FLAG_TRANSITIVE: u8 = 0x40;
+const FLAG_EXTENDED_LENGTH: u8 = 0x10;
+
+const ROUTE_MAC_IP: u8 = 2;
+const ROUTE_INCLUSIVE_MULTICAST: u8 = 3;
+
+const TUNNEL_INGRESS_REPLICATION: u8 = 6;
+const ENCAPSULATION_VXLAN: u8 = 8;
+const MAX_VNI: VrfId = (1 << 24) - 1;
+const DEFAULT_LOCAL_PREF: u32 = 100;
+
+type EvpnError = Box<dyn Error + Send + Sync>;
+type Message = (u8, Vec<u8>);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvpnConfig {
+    pub neighbor: SocketAddr,
+    pub asn: u32,
+    /// Asn of the neighbor, the same as `asn` for an ibgp session by default
+    pub peer_asn: Option<u32>,
+    pub router_id: Ipv4Addr,
+    /// Vtep address advertised as next hop, the router id by default
+    pub next_hop: Option<Ipv4Addr>,
+    #[serde(default = "default_hold_time")]
+    pub hold_time: u16,
+}
+
+fn default_hold_time() -> u16 {
+    90
+}
+
+impl EvpnConfig {
+    fn peer_asn(&self) -> u32 {
+        self.peer_asn.unwrap_or(self.asn)
+    }
+
+    fn next_hop(&self) -> Ipv4Addr {
+        self.next_hop.unwrap_or(self.router_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Route {
+    Mac {
+        vrf_id: VrfId,
+        vlan: Vlan,
+        mac: MacAddress,
+    },
+    Vtep {
+        vrf_id: VrfId,
+        address: IpAddr,
+    },
+}
+
+/// Keeps the evpn session up, reconnecting to the neighbor when it drops.
+pub async fn evpn(config: EvpnConfig, state: Arc<State>) {
+    loop {
+        match TcpStream::connect(config.neighbor).await {
+            Ok(stream) => {
+                if let Err(error) = session(&config, &state, stream).await {
+                    tracing::warn!("Evpn session with {} ended: {error}", config.neighbor);
+                }
+            }
+            Err(error) => {
+                tracing::warn!(
+                    "Can't connect to the evpn neighbor {}: {error}",
+                    config.neighbor
+                );
+            }
+        }
+
+        sleep(EVPN_RETRY_INTERVAL).await;
+    }
+}
+
+async fn session(config: &EvpnConfig, state: &State, stream: TcpStream) -> Result<(), EvpnError> {
+    let (mut reader, mut writer) = stream.into_split();
+
+    writer.write_all(&open_message(config)).await?;
+
+    let hold_time = match read_message(&mut reader).await? {
+        (MESSAGE_OPEN, body) => match check_open(config, &body) {
+            Ok(hold_time) => hold_time,
+            Err((subcode, error)) => {
+                writer.write_all(&notification_message(2, subcode)).await?;
+                return Err(error.into());
+            }
+        },
+        (MESSAGE_NOTIFICATION, body) => return Err(notification_error(&body).into()),
+        (kind, _) => return Err(format!("Expected an open message, got {kind}").into()),
+    };
+
+    writer.write_all(&message(MESSAGE_KEEPALIVE, &[])).await?;
+
+    // reads aren't cancel safe, they get a task of their own
+    let (mut messages, reader_task) = spawn_reader(reader);
+    let result = established_session(config, state, &mut writer, &mut messages, hold_time).await;
+
+    reader_task.abort();
+
+    result
+}
+
+async fn established_session(
+    config: &EvpnConfig,
+    state: &State,
+    writer: &mut (impl AsyncWrite + Unpin),
+    messages: &mut Receiver<Result<Message, EvpnError>>,
+    hold_time: u16,
+) -> Result<(), EvpnError> {
+    let hold_time = Duration::from_secs(hold_time.into());
+    let mut hold_deadline = Instant::now() + hold_time;
+    let mut keepalive = interval(match hold_time.is_zero() {
+        true => Duration::from_secs(30),
+        false => hold_time / 3,
+    });
+    let mut sync = interval(SYNC_INTERVAL);
+    let mut established = false;
+    let mut advertised = HashSet::new();
+    let mut learned = HashMap::new();
+
+    loop {
+        select! {
+            message = messages.recv() => {
+                let (kind, body) = message.ok_or("Connection closed")??;
+
+                hold_deadline = Instant::now() + hold_time;
+
+                match kind {
+                    MESSAGE_KEEPALIVE if !established => {
+                        established = true;
+                        tracing::info!("Evpn session established with {}", config.neighbor);
+                    }
+                    MESSAGE_KEEPALIVE => {}
+                    MESSAGE_UPDATE if established => {
+                        learn_routes(&body, &mut learned)?;
+                    }
+                    MESSAGE_NOTIFICATION => return Err(notification_error(&body).into()),
+                    kind => return Err(format!("Unexpected message {kind}").into()),
+                }
+            }
+            _ = sleep_until_deadline(hold_deadline, hold_time) => {
+                writer.write_all(&notification_message(4, 0)).await?;
+                return Err("Hold timer expired".into());
+            }
+            _ = keepalive.tick() => {
+                writer.write_all(&message(MESSAGE_KEEPALIVE, &[])).await?;
+            }
+            _ = sync.tick(), if established => {
+                let routes = local_routes(config, state).await;
+
+                for route in advertised.difference(&routes) {
+                    writer.write_all(&update_message(config, route, false)).await?;
+                }
+
+                for route in routes.difference(&advertised) {
+                    writer.write_all(&update_message(config, route, true)).await?;
+                }
+
+                advertised = routes;
+            }
+        }
+    }
+}
+
+// a hold time of zero disables the hold timer
+async fn sleep_until_deadline(deadline: Instant, hold_time: Duration) {
+    if hold_time.is_zero() {
+        std::future::pending::<()>().await;
+    }
+
+    tokio::time::sleep_until(deadline).await;
+}
+
+fn spawn_reader(
+    mut reader: impl AsyncRead + Unpin + Send + 'static,
+) -> (Receiver<Result<Message, EvpnError>>, JoinHandle<()>) {
+    let (sender, receiver) = channel(16);
+    let reader_task = spawn(async move {
+        loop {
+            let message = read_message(&mut reader).await;
+            let failed = message.is_err();
+
+            if sender.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    (receiver, reader_task)
+}
+
+async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> Result<Message, EvpnError> {
+    let mut header = [0u8; HEADER_SIZE];
+
+    reader.read_exact(&mut header).await?;
+
+    if header[..16].iter().any(|byte| *byte != 0xff) {
+        return Err("Invalid message marker".into());
+    }
+
+    let length = u16::from_be_bytes([header[16], header[17]]) as usize;
+
+    if !(HEADER_SIZE..=MAX_MESSAGE_SIZE).contains(&length) {
+        return Err(format!("Invalid message length {length}").into());
+    }
+
+    let mut body = vec![0u8; length - HEADER_SIZE];
+
+    reader.read_exact(&mut body).await?;
+
+    Ok((header[18], body))
+}
+
+fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![0xff; 16];
+
+    message.extend_from_slice(&((HEADER_SIZE + body.len()) as u16).to_be_bytes());
+    message.push(kind);
+    message.extend_from_slice(body);
+    message
+}
+
+fn notification_message(code: u8, subcode: u8) -> Vec<u8> {
+    message(MESSAGE_NOTIFICATION, &[code, subcode])
+}
+
+fn notification_error(body: &[u8]) -> String {
+    match body {
+        [code, subcode, ..] => format!("Notification from the neighbor, code {code}.{subcode}"),
+        _ => "Notification from the neighbor".to_string(),
+    }
+}
+
+fn open_message(config: &EvpnConfig) -> Vec<u8> {
+    let mut capabilities = vec![CAPABILITY_MULTIPROTOCOL, 4];
+
+    capabilities.extend_from_slice(&AFI_L2VPN.to_be_bytes());
+    capabilities.extend_from_slice(&[0, SAFI_EVPN, CAPABILITY_FOUR_OCTET_AS, 4]);
+    capabilities.extend_from_slice(&config.asn.to_be_bytes());
+
+    let mut body = vec![BGP_VERSION];
+
+    body.extend_from_slice(&u16::try_from(config.asn).unwrap_or(AS_TRANS).to_be_bytes());
+    body.extend_from_slice(&config.hold_time.to_be_bytes());
+    body.extend_from_slice(&config.router_id.octets());
+    body.push(capabilities.len() as u8 + 2);
+    body.extend_from_slice(&[CAPABILITIES_PARAMETER, capabilities.len() as u8]);
+    body.extend_from_slice(&capabilities);
+
+    message(MESSAGE_OPEN, &body)
+}
+
+/// Negotiated hold time, or the open message error subcode.
+fn check_open(config: &EvpnConfig, body: &[u8]) -> Result<u16, (u8, String)> {
+    if body.len() < 10 {
+        return Err((0, "Truncated open message".to_string()));
+    }
+
+    if body[0] != BGP_VERSION {
+        return Err((1, format!("Unsupported bgp version {}", body[0])));
+    }
+
+    let mut peer_asn = u16::from_be_bytes([body[1], body[2]]) as u32;
+    let hold_time = u16::from_be_bytes([body[3], body[4]]);
+    let parameters = body
+        .get(10..10 + body[9] as usize)
+        .ok_or((0, "Truncated open parameters".to_string()))?;
+    let mut evpn = false;
+
+    for (kind, parameter) in tlvs(parameters) {
+        if kind != CAPABILITIES_PARAMETER {
+            continue;
+        }
+
+        for (code, capability) in tlvs(parameter) {
+            match (code, capability) {
+                (CAPABILITY_MULTIPROTOCOL, [afi_high, afi_low, _, safi]) => {
+                    evpn |= u16::from_be_bytes([*afi_high, *afi_low]) == AFI_L2VPN
+                        && *safi == SAFI_EVPN;
+                }
+                (CAPABILITY_FOUR_OCTET_AS, [a, b, c, d]) => {
+                    peer_asn = u32::from_be_bytes([*a, *b, *c, *d]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if peer_asn != config.peer_asn() {
+        return Err((2, format!("Unexpected neighbor asn {peer_asn}")));
+    }
+
+    if !evpn {
+        return Err((7, "The neighbor doesn't support l2vpn evpn".to_string()));
+    }
+
+    if hold_time == 1 || hold_time == 2 {
+        return Err((6, format!("Unacceptable hold time {hold_time}")));
+    }
+
+    Ok(hold_time.min(config.hold_time))
+}
+
+// type, length and value sequences of open parameters and capabilities
+fn tlvs(mut bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let [kind, length, rest @ ..] = bytes else {
+            return None;
+        };
+        let value = rest.get(..*length as usize)?;
+
+        bytes = &rest[*length as usize..];
+
+        Some((*kind, value))
+    })
+}
+
+async fn local_routes(config: &EvpnConfig, state: &State) -> HashSet<Route> {
+    let vrf_ids = state
+        .tap_table
+        .read()
+        .await
+        .keys()
+        .copied()
+        .filter(|vrf_id| *vrf_id <= MAX_VNI)
+        .collect::<HashSet<_>>();
+    let degraded_taps = state.degraded_taps.lock().unwrap().clone();
+    let mut routes = vrf_ids
+        .iter()
+        .filter(|vrf_id| !degraded_taps.contains_key(vrf_id))
+        .map(|vrf_id| Route::Vtep {
+            vrf_id: *vrf_id,
+            address: IpAddr::V4(config.next_hop()),
+        })
+        .collect::<HashSet<_>>();
+
+    // macs learned behind the local taps are the ones of this switch
+    routes.extend(
+        state
+            .switch_table
+            .read()
+            .await
+            .entries()
+            .into_iter()
+            .filter(|(vrf_id, _, _, learned)| {
+                learned.switch_id == state.config.switch_id && vrf_ids.contains(vrf_id)
+            })
+            .map(|(vrf_id, vlan, mac, _)| Route::Mac { vrf_id, vlan, mac }),
+    );
+
+    routes
+}
+
+fn update_message(config: &EvpnConfig, route: &Route, reachable: bool) -> Vec<u8> {
+    let vrf_id = match route {
+        Route::Mac { vrf_id, .. } | Route::Vtep { vrf_id, .. } => *vrf_id,
+    };
+    let mut route_distinguisher = vec![0, 1];
+
+    route_distinguisher.extend_from_slice(&config.router_id.octets());
+    route_distinguisher.extend_from_slice(&(vrf_id as u16).to_be_bytes());
+
+    let vni = vrf_id.to_be_bytes();
+    let mut nlri = match route {
+        Route::Mac { vlan, mac, .. } => {
+            let mut value = route_distinguisher;
+
+            // no ethernet segment, the vlan as ethernet tag, and no ip with the mac
+            value.extend_from_slice(&[0; 10]);
+            value.extend_from_slice(&u32::from(*vlan).to_be_bytes());
+            value.push(48);
+            value.extend_from_slice(mac);
+            value.push(0);
+            value.extend_from_slice(&vni[1..]);
+
+            let mut nlri = vec![ROUTE_MAC_IP, value.len() as u8];
+
+            nlri.extend_from_slice(&value);
+            nlri
+        }
+        Route::Vtep { .. } => {
+            let mut value = route_distinguisher;
+
+            value.extend_from_slice(&[0; 4]);
+            value.push(32);
+            value.extend_from_slice(&config.next_hop().octets());
+
+            let mut nlri = vec![ROUTE_INCLUSIVE_MULTICAST, value.len() as u8];
+
+            nlri.extend_from_slice(&value);
+            nlri
+        }
+    };
+    let mut attributes = Vec::new();
+    let mut multiprotocol = AFI_L2VPN.to_be_bytes().to_vec();
+
+    multiprotocol.push(SAFI_EVPN);
+
+    if !reachable {
+        multiprotocol.append(&mut nlri);
+        push_attribute(
+            &mut attributes,
+            FLAG_OPTIONAL,
+            ATTRIBUTE_MP_UNREACH,
+            &multiprotocol,
+        );
+
+        return update(&attributes);
+    }
+
+    multiprotocol.push(4);
+    multiprotocol.extend_from_slice(&config.next_hop().octets());
+    multiprotocol.push(0);
+    multiprotocol.append(&mut nlri);
+
+    push_attribute(&mut attributes, FLAG_TRANSITIVE, ATTRIBUTE_ORIGIN, &[0]);
+
+    if config.peer_asn() == config.asn {
+        push_attribute(&mut attributes, FLAG_TRANSITIVE, ATTRIBUTE_AS_PATH, &[]);
+        push_attribute(
+            &mut attributes,
+            FLAG_TRANSITIVE,
+            ATTRIBUTE_LOCAL_PREF,
+            &DEFAULT_LOCAL_PREF.to_be_bytes(),
+        );
+    } else {
+        let mut as_path = vec![2, 1];
+
+        as_path.extend_from_slice(&config.asn.to_be_bytes());
+        push_attribute(
+            &mut attributes,
+            FLAG_TRANSITIVE,
+            ATTRIBUTE_AS_PATH,
+            &as_path,
+        );
+    }
+
+    let mut communities = match u16::try_from(config.asn) {
+        Ok(asn) => {
+            let mut community = vec![0x00, 0x02];
+
+            community.extend_from_slice(&asn.to_be_bytes());
+            community.extend_from_slice(&vrf_id.to_be_bytes());
+            community
+        }
+        Err(_) => {
+            let mut community = vec![0x02, 0x02];
+
+            community.extend_from_slice(&config.asn.to_be_bytes());
+            community.extend_from_slice(&(vrf_id as u16).to_be_bytes());
+            community
+        }
+    };
+
+    communities.extend_from_slice(&[0x03, 0x0c, 0, 0, 0, 0, 0, ENCAPSULATION_VXLAN]);
+    push_attribute(
+        &mut attributes,
+        FLAG_OPTIONAL | FLAG_TRANSITIVE,
+        ATTRIBUTE_EXTENDED_COMMUNITIES,
+        &communities,
+    );
+
+    if let Route::Vtep { .. } = route {
+        let mut tunnel = vec![0, TUNNEL_INGRESS_REPLICATION];
+
+        tunnel.extend_from_slice(&vni[1..]);
+        tunnel.extend_from_slice(&config.next_hop().octets());
+        push_attribute(
+            &mut attributes,
+            FLAG_OPTIONAL | FLAG_TRANSITIVE,
+            ATTRIBUTE_PMSI_TUNNEL,
+            &tunnel,
+        );
+    }
+
+    push_attribute(
+        &mut attributes,
+        FLAG_OPTIONAL,
+        ATTRIBUTE_MP_REACH,
+        &multiprotocol,
+    );
+
+    update(&attributes)
+}
+
+fn push_attribute(attributes: &mut Vec<u8>, flags: u8, kind: u8, value: &[u8]) {
+    attributes.extend_from_slice(&[flags | FLAG_EXTENDED_LENGTH, kind]);
+    attributes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    attributes.extend_from_slice(value);
+}
+
+fn update(attributes: &[u8]) -> Vec<u8> {
+    // no withdrawn ipv4 routes, evpn withdraws through its own attribute
+    let mut body = vec![0, 0];
+
+    body.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+    body.extend_from_slice(attributes);
+
+    message(MESSAGE_UPDATE, &body)
+}
+
+fn learn_routes(body: &[u8], learned: &mut HashMap<Route, IpAddr>) -> Result<(), EvpnError> {
+    let truncated = || -> EvpnError { "Truncated update message".into() };
+    let withdrawn_length = u16::from_be_bytes([
+        *body.first().ok_or_else(truncated)?,
+        *body.get(1).ok_or_else(truncated)?,
+    ]) as usize;
+    let attributes_start = 2 + withdrawn_length + 2;
+    let attributes_length = u16::from_be_bytes([
+        *body.get(attributes_start - 2).ok_or_else(truncated)?,
+        *body.get(attributes_start - 1).ok_or_else(truncated)?,
+    ]) as usize;
+    let mut attributes = body
+        .get(attributes_start..attributes_start + attributes_length)
+        .ok_or_else(truncated)?;
+    let mut values = HashMap::new();
+
+    while let [flags, kind, rest @ ..] = attributes {
+        let (length, rest) = if flags & FLAG_EXTENDED_LENGTH != 0 {
+            match rest {
+                [high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+                _ => return Err(truncated()),
+            }
+        } else {
+            match rest {
+                [length, rest @ ..] => (*length as usize, rest),
+                _ => return Err(truncated()),
+            }
+        };
+
+        values.insert(*kind, rest.get(..length).ok_or_else(truncated)?);
+        attributes = &rest[length..];
+    }
+
+    // vtep routes usually leave the vni to the label of their tunnel
+    let tunnel_vni = match values.get(&ATTRIBUTE_PMSI_TUNNEL) {
+        Some([_, _, a, b, c, ..]) => Some(VrfId::from_be_bytes([0, *a, *b, *c])),
+        _ => None,
+    };
+
+    if let Some([afi_high, afi_low, safi, next_hop_length, rest @ ..]) =
+        values.get(&ATTRIBUTE_MP_REACH)
+    {
+        if u16::from_be_bytes([*afi_high, *afi_low]) == AFI_L2VPN && *safi == SAFI_EVPN {
+            let next_hop_length = *next_hop_length as usize;
+            let next_hop = rest.get(..next_hop_length).ok_or_else(truncated)?;
+            let next_hop = match next_hop.len() {
+                4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(next_hop)?)),
+                // a global address, maybe followed by a link local one
+                16 | 32 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&next_hop[..16])?)),
+                length => return Err(format!("Invalid next hop length {length}").into()),
+            };
+            let nlri = rest.get(next_hop_length + 1..).ok_or_else(truncated)?;
+
+            for route in parse_nlri(nlri, tunnel_vni)? {
+                if learned.insert(route, next_hop).is_none() {
+                    log_route("Learned", &route, next_hop);
+                }
+            }
+        }
+    }
+
+    if let Some([afi_high, afi_low, safi, nlri @ ..]) = values.get(&ATTRIBUTE_MP_UNREACH) {
+        if u16::from_be_bytes([*afi_high, *afi_low]) == AFI_L2VPN && *safi == SAFI_EVPN {
+            for route in parse_nlri(nlri, tunnel_vni)? {
+                if let Some(next_hop) = learned.remove(&route) {
+                    log_route("Withdrew", &route, next_hop);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn log_route(action: &str, route: &Route, next_hop: IpAddr) {
+    match route {
+        Route::Mac { vrf_id, vlan, mac } => tracing::debug!(
+            "{action} evpn mac {} of vrf id {vrf_id} and vlan {vlan} behind {next_hop}",
+            format_mac(mac)
+        ),
+        Route::Vtep { vrf_id, address } => {
+            tracing::info!("{action} evpn vtep {address} of vrf id {vrf_id}")
+        }
+    }
+}
+
+// only the routes this switch can map to a vrf, the other evpn route types are skipped
+fn parse_nlri(mut nlri: &[u8], tunnel_vni: Option<VrfId>) -> Result<Vec<Route>, EvpnError> {
+    let mut routes = Vec::new();
+
+    while let [kind, length, rest @ ..] = nlri {
+        let value = rest.get(..*length as usize).ok_or("Truncated evpn route")?;
+
+        nlri = &rest[*length as usize..];
+
+        match *kind {
+            // route distinguisher, ethernet segment and tag, mac, ip and label
+            ROUTE_MAC_IP => {
+                let Some(&[48, ref mac @ ..]) = value.get(22..29) else {
+                    continue;
+                };
+                let tag = value.get(18..22).ok_or("Truncated evpn mac route")?;
+                let ip_length = *value.get(29).ok_or("Truncated evpn mac route")? as usize / 8;
+                let label = value
+                    .get(30 + ip_length..33 + ip_length)
+                    .ok_or("Truncated evpn mac route")?;
+
+                routes.push(Route::Mac {
+                    vrf_id: VrfId::from_be_bytes([0, label[0], label[1], label[2]]),
+                    vlan: u32::from_be_bytes(tag.try_into()?) as Vlan,
+                    mac: mac.try_into()?,
+                });
+            }
+            // route distinguisher, ethernet tag and originating router
+            ROUTE_INCLUSIVE_MULTICAST => {
+                let vrf_id = match value.get(8..12) {
+                    Some(&[0, 0, 0, 0]) => match tunnel_vni {
+                        Some(vni) => vni,
+                        None => continue,
+                    },
+                    Some(tag) => VrfId::from_be_bytes(tag.try_into()?),
+                    None => continue,
+                };
+                let address = match value.get(12) {
+                    Some(32) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(
+                        value.get(13..17).ok_or("Truncated evpn vtep route")?,
+                    )?)),
+                    Some(128) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(
+                        value.get(13..29).ok_or("Truncated evpn vtep route")?,
+                    )?)),
+                    _ => continue,
+                };
+
+                routes.push(Route::Vtep { vrf_id, address });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(routes)
+}
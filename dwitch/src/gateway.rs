@@ -0,0 +1,368 @@
+//! Default gateway of the vrfs with a gateway address, served by their local tap.
+//!
+//! Without election every member serves it. With it, each switch serves the gateway of a vrf only
+//! if it's the lowest active member it can see, so the gateway moves to the next member when a
+//! switch goes down or drains.
+//!
+//! A masquerading gateway hides the vrf behind the switch. With the netns dataplane the vrf netns
+//! masquerades what leaves it through a veth pair into the host, so vrfs can reuse the same
+//! networks, and the host masquerades what comes in from the pairs like it does for the vrf devices
+//! and bridges of the other dataplanes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+};
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use std::{
+    error::Error,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use common::VrfId;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use netns::Netns;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use protocol::IpPrefix;
+use protocol::{Gateway, Vrf};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    task::{spawn_blocking, JoinHandle},
+};
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use crate::link::Dataplane;
+use crate::{
+    config::SwitchId,
+    dns,
+    events::{subscribe, Event},
+    link,
+    nftables::{set_masquerade, Masquerade},
+    state::State,
+};
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+const TRANSIT_PREFIX_LENGTH: u8 = 30;
+
+struct Served {
+    vrf_name: String,
+    gateway: Gateway,
+    // index of the /30 of the egress pair of a vrf netns
+    transit: Option<u32>,
+    dns: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Gateways {
+    serving: HashMap<VrfId, Served>,
+    host_rules: Vec<Masquerade>,
+}
+
+pub async fn gateways(state: Arc<State>) {
+    let mut gateways = Gateways::default();
+    // subscribed before the first pass so no change in between is missed
+    let mut events = subscribe();
+
+    gateways.apply(&state, false).await;
+
+    loop {
+        match events.recv().await {
+            Ok(Event::TapRecovered { .. }) | Err(RecvError::Lagged(_)) => {
+                gateways.apply(&state, true).await
+            }
+            Ok(
+                Event::PeerUp { .. }
+                | Event::PeerDown { .. }
+                | Event::SwitchDraining { .. }
+                | Event::SwitchActivated { .. }
+                | Event::VrfCreated { .. }
+                | Event::VrfDeleted { .. }
+                | Event::VrfMembersAdded { .. }
+                | Event::VrfMembersRemoved { .. },
+            ) => gateways.apply(&state, false).await,
+            Ok(_) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+impl Gateways {
+    async fn apply(&mut self, state: &State, refresh: bool) {
+        let active_peers = active_peers(state).await;
+        let wanted = state
+            .vrf_table
+            .read()
+            .await
+            .values()
+            .filter_map(|vrf| {
+                let gateway = vrf.settings.gateway?;
+
+                is_serving(state, &active_peers, vrf, &gateway)
+                    .then(|| (vrf.id, (vrf.name.clone(), gateway)))
+            })
+            .collect::<HashMap<_, _>>();
+        let stopped = self
+            .serving
+            .iter()
+            .filter(|(vrf_id, served)| {
+                wanted.get(vrf_id) != Some(&(served.vrf_name.clone(), served.gateway))
+            })
+            .map(|(vrf_id, _)| *vrf_id)
+            .collect::<Vec<_>>();
+
+        for vrf_id in stopped {
+            if let Some(served) = self.serving.remove(&vrf_id) {
+                stop(state, vrf_id, served).await;
+            }
+        }
+
+        for (vrf_id, (vrf_name, gateway)) in wanted {
+            // a recreated tap lost its address
+            if !refresh && self.serving.contains_key(&vrf_id) {
+                continue;
+            }
+
+            if let Some(served) = self.serving.remove(&vrf_id) {
+                stop(state, vrf_id, served).await;
+            }
+
+            #[cfg(all(target_os = "linux", feature = "netns"))]
+            let transit = (gateway.masquerade && state.config.dataplane == Dataplane::Netns)
+                .then(|| self.free_transit());
+            // only a vrf netns needs a pair into the host
+            #[cfg(not(all(target_os = "linux", feature = "netns")))]
+            let transit = None;
+
+            match start(state, vrf_id, &vrf_name, &gateway, transit).await {
+                Ok(dns) => {
+                    tracing::info!(
+                        "Serving the gateway {}/{} of vrf {vrf_name}",
+                        gateway.address.address,
+                        gateway.address.prefix_length
+                    );
+                    self.serving.insert(
+                        vrf_id,
+                        Served {
+                            vrf_name,
+                            gateway,
+                            transit,
+                            dns,
+                        },
+                    );
+                }
+                Err(error) => {
+                    tracing::warn!("Can't serve the gateway of vrf {vrf_name}: {error}");
+                }
+            }
+        }
+
+        self.masquerade_host(state).await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    fn free_transit(&self) -> u32 {
+        let used = self
+            .serving
+            .values()
+            .filter_map(|served| served.transit)
+            .collect::<HashSet<_>>();
+
+        (0..)
+            .find(|index| !used.contains(index))
+            .unwrap_or_default()
+    }
+
+    async fn masquerade_host(&mut self, state: &State) {
+        let dataplane = state.config.dataplane;
+        let mut host_rules = self
+            .serving
+            .iter()
+            .filter(|(_, served)| served.gateway.masquerade)
+            .map(|(vrf_id, _)| {
+                Masquerade::Incoming(match dataplane {
+                    #[cfg(all(target_os = "linux", feature = "netns"))]
+                    Dataplane::Netns => link::egress_name(*vrf_id),
+                    // the vrf device is the input of what's routed out of a vrf
+                    dataplane => link::master_name(dataplane, *vrf_id),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        host_rules.sort();
+
+        // the table is left alone until a gateway masquerades
+        if host_rules == self.host_rules {
+            return;
+        }
+
+        if !host_rules.is_empty() && !link::host_forwarding() {
+            tracing::warn!("Ip forwarding is disabled on the host, masqueraded egress is dropped");
+        }
+
+        let rules = host_rules.clone();
+
+        match spawn_blocking(move || set_masquerade(&rules)).await {
+            Ok(Ok(())) => self.host_rules = host_rules,
+            Ok(Err(error)) => tracing::error!("Can't masquerade the vrf egress: {error}"),
+            Err(error) => tracing::error!("Can't masquerade the vrf egress: {error}"),
+        }
+    }
+}
+
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "netns")),
+    allow(unused_variables)
+)]
+async fn start(
+    state: &State,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    gateway: &Gateway,
+    transit: Option<u32>,
+) -> Result<Option<JoinHandle<()>>, String> {
+    let dataplane = state.config.dataplane;
+
+    link::add_gateway(dataplane, vrf_id, vrf_name, &gateway.address)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    if let Some(transit) = transit {
+        let result = async {
+            let (host_address, peer_address) =
+                transit_addresses(&state.config.nat_transit, transit)
+                    .ok_or("The nat transit prefix is full or isn't an ipv4 prefix")?;
+            let peer_name =
+                link::add_egress(vrf_id, vrf_name, &host_address, &peer_address).await?;
+            let vrf_netns = Netns::named(link::netns_name(vrf_name)).path();
+
+            link::run_in(&vrf_netns, move || {
+                set_masquerade(&[Masquerade::Outgoing(peer_name)])
+            })
+            .await??;
+
+            Ok::<_, Box<dyn Error + Send + Sync>>(())
+        }
+        .await;
+
+        if let Err(error) = result {
+            let _ = link::remove_gateway(dataplane, vrf_id, vrf_name, &gateway.address).await;
+            let _ = link::delete(&link::egress_name(vrf_id)).await;
+
+            return Err(format!("Can't route the vrf netns into the host: {error}"));
+        }
+    }
+
+    let Some(dns_config) = state.config.dns.clone() else {
+        return Ok(None);
+    };
+
+    // the gateway works without it
+    match dns::forwarder(
+        dns_config,
+        dataplane,
+        vrf_id,
+        vrf_name,
+        gateway.address.address,
+    )
+    .await
+    {
+        Ok(forwarder) => Ok(Some(forwarder)),
+        Err(error) => {
+            tracing::warn!("Can't forward the dns queries of vrf {vrf_name}: {error}");
+            Ok(None)
+        }
+    }
+}
+
+async fn stop(state: &State, vrf_id: VrfId, served: Served) {
+    let vrf_name = &served.vrf_name;
+
+    if let Some(dns) = &served.dns {
+        dns.abort();
+    }
+
+    if let Err(error) = link::remove_gateway(
+        state.config.dataplane,
+        vrf_id,
+        vrf_name,
+        &served.gateway.address,
+    )
+    .await
+    {
+        tracing::warn!("Can't stop serving the gateway of vrf {vrf_name}: {error}");
+    }
+
+    // the masquerading table of the vrf netns goes away with it or stays unused
+    if served.transit.is_some() {
+        let _ = link::delete(&link::egress_name(vrf_id)).await;
+    }
+
+    tracing::info!("Stopped serving the gateway of vrf {vrf_name}");
+}
+
+// the host end of a pair takes the first address of its /30 and the vrf netns end the second
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn transit_addresses(transit: &IpPrefix, index: u32) -> Option<(IpPrefix, IpPrefix)> {
+    let IpAddr::V4(address) = transit.address else {
+        return None;
+    };
+    let count = 1u32.checked_shl(
+        TRANSIT_PREFIX_LENGTH
+            .checked_sub(transit.prefix_length)?
+            .into(),
+    )?;
+
+    if index >= count {
+        return None;
+    }
+
+    let mask = u32::MAX
+        .checked_shl(32 - transit.prefix_length as u32)
+        .unwrap_or(0);
+    let base = (address.to_bits() & mask) + index * 4;
+    let prefix = |offset| IpPrefix {
+        address: Ipv4Addr::from(base + offset).into(),
+        prefix_length: TRANSIT_PREFIX_LENGTH,
+    };
+
+    Some((prefix(1), prefix(2)))
+}
+
+async fn active_peers(state: &State) -> HashSet<SwitchId> {
+    let connected = state
+        .client_table
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<HashSet<_>>();
+    let draining_peers = state.draining_peers.lock().unwrap();
+
+    connected.difference(&draining_peers).copied().collect()
+}
+
+fn is_serving(
+    state: &State,
+    active_peers: &HashSet<SwitchId>,
+    vrf: &Vrf,
+    gateway: &Gateway,
+) -> bool {
+    let switch_id = state.config.switch_id;
+
+    // its routes would go in a netns the vrf tap was only plugged into
+    if !vrf.members.contains(&switch_id) || !link::owns_netns(state.config.dataplane, vrf) {
+        return false;
+    }
+
+    if !gateway.elect {
+        return true;
+    }
+
+    // an active member below this switch serves it instead
+    !state.draining.load(Ordering::Relaxed)
+        && !vrf
+            .members
+            .iter()
+            .any(|member| *member < switch_id && active_peers.contains(member))
+}
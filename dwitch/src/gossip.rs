@@ -0,0 +1,143 @@
+//! Gossip of the peer list. Each switch tells its peers where it and the switches it's connected to
+//! listen, so a switch dialing a single seed learns about the others and dials them too, until the
+//! switches form a full mesh.
+//!
+//! An advert is sent to each peer when it connects, and to all of them each time a switch learns
+//! a listen address it didn't know, which stops once every switch knows them all.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use protocol::{Packet, PeerAdvert};
+use serde::Deserialize;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::{config::SwitchId, discovery::Discovery, socket::client::PeerSender, state::State};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GossipConfig {
+    /// Switches dialed because a peer advertised them, at most
+    #[serde(default = "default_max_peers")]
+    pub max_peers: usize,
+}
+
+fn default_max_peers() -> usize {
+    64
+}
+
+/// Listen addresses the peers advertised, the new ones are handed to the peering to be dialed.
+pub struct Gossip {
+    listen_addresses: Mutex<HashMap<SwitchId, SocketAddr>>,
+    sender: Sender<Discovery>,
+}
+
+impl Gossip {
+    pub fn new(sender: Sender<Discovery>) -> Self {
+        Self {
+            listen_addresses: Mutex::new(HashMap::new()),
+            sender,
+        }
+    }
+}
+
+/// Tell a peer that just connected where this switch and its other peers listen.
+pub async fn advertise(state: &State, switch_id: SwitchId, sender: &PeerSender) {
+    if state.config.gossip.is_none() {
+        return;
+    }
+
+    let advert = advert(state).await;
+
+    if let Err(error) = sender.send(Packet::from(advert)).await {
+        tracing::error!("Can't advertise peers to switch id {switch_id}: {error}");
+    }
+}
+
+/// Record the listen addresses a peer advertised, dial the switches they belong to and pass the
+/// new ones on to the other peers.
+pub async fn learn(state: &State, from: SwitchId, PeerAdvert { peers }: PeerAdvert) {
+    if state.config.gossip.is_none() {
+        return;
+    }
+
+    // the address the advertising switch is reached at, for the ip it couldn't tell itself
+    let reached_at = state
+        .peer_connections
+        .lock()
+        .unwrap()
+        .get(&from)
+        .and_then(|connection| connection.address)
+        .map(|address| address.ip());
+    let mut learned = Vec::new();
+
+    {
+        let mut listen_addresses = state.gossip.listen_addresses.lock().unwrap();
+
+        for (switch_id, mut address) in peers {
+            if switch_id == state.config.switch_id {
+                continue;
+            }
+
+            if address.ip().is_unspecified() {
+                match reached_at {
+                    Some(ip) if switch_id == from => address.set_ip(ip),
+                    _ => continue,
+                }
+            }
+
+            if listen_addresses.insert(switch_id, address) != Some(address) {
+                learned.push((switch_id, address));
+            }
+        }
+    }
+
+    if learned.is_empty() {
+        return;
+    }
+
+    for (switch_id, address) in learned {
+        tracing::debug!("Switch id {from} advertised switch id {switch_id} at {address}");
+
+        match state
+            .gossip
+            .sender
+            .try_send(Discovery::Advertised { switch_id, address })
+        {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("Not dialing switch id {switch_id} at {address}, queue full");
+            }
+        }
+    }
+
+    let packet = Packet::from(advert(state).await);
+    let client_table = state.client_table.read().await;
+
+    for (switch_id, client) in client_table.iter() {
+        if let Err(error) = client.send(packet.clone()).await {
+            tracing::error!("Can't advertise peers to switch id {switch_id}: {error}");
+        }
+    }
+}
+
+// this switch and the connected peers whose listen address is known
+async fn advert(state: &State) -> PeerAdvert {
+    let connected = state
+        .client_table
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let listen_addresses = state.gossip.listen_addresses.lock().unwrap();
+    let peers = connected
+        .into_iter()
+        .filter_map(|switch_id| {
+            listen_addresses
+                .get(&switch_id)
+                .map(|address| (switch_id, *address))
+        })
+        .chain([(state.config.switch_id, state.config.listen_address())])
+        .collect();
+
+    PeerAdvert { peers }
+}
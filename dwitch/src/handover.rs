@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs::{remove_file, set_permissions, Permissions},
+    io::{self, ErrorKind, IoSlice, IoSliceMut},
+    net::{SocketAddr, TcpListener},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
+    path::Path,
+    process,
+    sync::{Arc, Mutex},
+};
+
+use common::VrfId;
+use nix::{
+    cmsg_space,
+    sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+};
+use tokio::{net::UnixListener, task::spawn_blocking};
+
+use crate::{cache::Cache, config::Config, state::State};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// every record is a kind and a vrf id, with at most one descriptor attached
+const RECORD_SIZE: usize = 5;
+const RECORD_DONE: u8 = 0;
+const RECORD_LISTENER: u8 = 1;
+const RECORD_TAP: u8 = 2;
+
+/// Duplicates of the descriptors a new daemon takes over.
+#[derive(Default)]
+pub struct HandoverFds {
+    listeners: Mutex<HashMap<SocketAddr, OwnedFd>>,
+    taps: Mutex<HashMap<VrfId, OwnedFd>>,
+}
+
+impl HandoverFds {
+    pub fn add_listener(&self, address: SocketAddr, fd: BorrowedFd) {
+        match fd.try_clone_to_owned() {
+            Ok(fd) => {
+                self.listeners.lock().unwrap().insert(address, fd);
+            }
+            Err(error) => {
+                tracing::warn!("Can't keep the listener on {address} for a handover: {error}")
+            }
+        }
+    }
+
+    // the duplicate would keep the socket listening
+    pub fn remove_listener(&self, address: SocketAddr) {
+        self.listeners.lock().unwrap().remove(&address);
+    }
+
+    pub fn add_tap(&self, vrf_id: VrfId, fd: BorrowedFd) {
+        match fd.try_clone_to_owned() {
+            Ok(fd) => {
+                self.taps.lock().unwrap().insert(vrf_id, fd);
+            }
+            Err(error) => {
+                tracing::warn!("Can't keep the tap of the vrf {vrf_id} for a handover: {error}")
+            }
+        }
+    }
+
+    pub fn remove_tap(&self, vrf_id: VrfId) {
+        self.taps.lock().unwrap().remove(&vrf_id);
+    }
+}
+
+/// Descriptors inherited from systemd or from the previous daemon.
+#[derive(Default)]
+pub struct Inherited {
+    pub listeners: Vec<TcpListener>,
+    pub taps: HashMap<VrfId, OwnedFd>,
+}
+
+impl Inherited {
+    pub fn load(config: &Config) -> Self {
+        let mut inherited = match &config.handover_socket {
+            Some(path) => match receive(path) {
+                Ok(inherited) => inherited,
+                Err(error) => {
+                    tracing::error!("Can't take over from the previous daemon: {error}");
+                    Self::default()
+                }
+            },
+            None => Self::default(),
+        };
+
+        if inherited.listeners.is_empty() {
+            inherited.listeners = systemd_listeners();
+        }
+
+        inherited
+    }
+}
+
+fn systemd_listeners() -> Vec<TcpListener> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok());
+
+    // only meant for this process, not its children
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Vec::new();
+    };
+
+    if pid != process::id() || fds <= 0 {
+        return Vec::new();
+    }
+
+    tracing::info!("Using the {fds} listeners passed by systemd");
+
+    // the passed sockets start at this descriptor and belong to this process
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+fn receive(path: &Path) -> io::Result<Inherited> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        // no daemon to take over from
+        Err(error)
+            if matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(Inherited::default())
+        }
+        Err(error) => return Err(error),
+    };
+    let mut inherited = Inherited::default();
+
+    loop {
+        let mut record = [0u8; RECORD_SIZE];
+        let mut iov = [IoSliceMut::new(&mut record)];
+        let mut cmsg_buffer = cmsg_space!(RawFd);
+        let message = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        let length = message.bytes;
+        let fd = message
+            .cmsgs()?
+            .find_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+                _ => None,
+            })
+            // received descriptors are new and owned by this process
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+
+        if length != RECORD_SIZE {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        let vrf_id = VrfId::from_be_bytes([record[1], record[2], record[3], record[4]]);
+
+        match (record[0], fd) {
+            (RECORD_DONE, _) => break,
+            (RECORD_LISTENER, Some(fd)) => inherited.listeners.push(TcpListener::from(fd)),
+            (RECORD_TAP, Some(fd)) => {
+                inherited.taps.insert(vrf_id, fd);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid handover record",
+                ))
+            }
+        }
+    }
+
+    tracing::info!(
+        "Took over {} listeners and {} taps from the previous daemon",
+        inherited.listeners.len(),
+        inherited.taps.len()
+    );
+
+    Ok(inherited)
+}
+
+fn send(stream: &UnixStream, fds: &HandoverFds) -> io::Result<()> {
+    for fd in fds.listeners.lock().unwrap().values() {
+        send_record(stream, RECORD_LISTENER, 0, Some(fd.as_fd()))?;
+    }
+
+    for (vrf_id, fd) in fds.taps.lock().unwrap().iter() {
+        send_record(stream, RECORD_TAP, *vrf_id, Some(fd.as_fd()))?;
+    }
+
+    send_record(stream, RECORD_DONE, 0, None)
+}
+
+fn send_record(
+    stream: &UnixStream,
+    kind: u8,
+    vrf_id: VrfId,
+    fd: Option<BorrowedFd>,
+) -> io::Result<()> {
+    let mut record = [0u8; RECORD_SIZE];
+
+    record[0] = kind;
+    record[1..].copy_from_slice(&vrf_id.to_be_bytes());
+
+    let fds = fd.map(|fd| [fd.as_raw_fd()]);
+    let cmsgs = fds
+        .iter()
+        .map(|fds| ControlMessage::ScmRights(fds))
+        .collect::<Vec<_>>();
+
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&record)],
+        &cmsgs,
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    Ok(())
+}
+
+/// Bind the handover socket if there's one, before the privileges are dropped.
+pub fn handover_listener(config: &Config) -> Result<Option<UnixListener>, Box<dyn Error>> {
+    let Some(path) = &config.handover_socket else {
+        return Ok(None);
+    };
+
+    if let Err(error) = remove_file(path) {
+        if error.kind() != ErrorKind::NotFound {
+            return Err(error.into());
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    set_permissions(path, Permissions::from_mode(0o600))?;
+
+    Ok(Some(listener))
+}
+
+/// Waits for a new daemon, hands it the listener and the taps, then exits without tearing them down.
+///
+/// The peer connections close with this daemon, the peers reconnect and queue in the backlog of the
+/// listener until the new daemon accepts them.
+pub async fn handover(listener: UnixListener, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream.into_std()?,
+            Err(error) => {
+                tracing::error!("Can't accept handover client: {error}");
+                continue;
+            }
+        };
+
+        tracing::info!("Handing over to a new daemon");
+
+        // the new daemon loads the cache once it has the descriptors
+        if let Err(error) = Cache::from_state(&state)
+            .await
+            .save(&state.config.cache, state.cache_key.as_ref())
+            .await
+        {
+            tracing::error!("Can't save cache: {error}");
+        }
+
+        let result = spawn_blocking({
+            let state = state.clone();
+
+            move || {
+                stream.set_nonblocking(false)?;
+                send(&stream, &state.handover_fds)
+            }
+        })
+        .await?;
+
+        match result {
+            Ok(()) => {
+                tracing::info!("Handed over to the new daemon, exiting, the peers reconnect to it");
+                process::exit(0);
+            }
+            Err(error) => tracing::error!("Can't hand over to the new daemon: {error}"),
+        }
+    }
+}
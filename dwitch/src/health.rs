@@ -0,0 +1,134 @@
+use std::{
+    error::Error,
+    sync::{atomic::Ordering, Arc},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::broadcast::error::RecvError,
+};
+
+use crate::{
+    config::HealthConfig,
+    events::{publish, subscribe, Event},
+    state::State,
+};
+
+pub async fn health(config: HealthConfig, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(config.listen).await?;
+
+    if config.min_peers > 0 {
+        spawn(peer_threshold_alert(config.min_peers, state.clone()));
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                spawn(health_connection(stream, config.min_peers, state.clone()));
+            }
+            Err(error) => {
+                tracing::error!("Can't accept health probe: {error}");
+            }
+        }
+    }
+}
+
+async fn peer_threshold_alert(min_peers: usize, state: Arc<State>) {
+    let mut events = subscribe();
+
+    loop {
+        match events.recv().await {
+            Ok(Event::PeerDown { .. }) => {
+                let connected = state.client_table.read().await.len();
+
+                if connected < min_peers {
+                    publish(Event::PeersBelowThreshold {
+                        connected,
+                        min_peers,
+                    });
+                }
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn health_connection(mut stream: TcpStream, min_peers: usize, state: Arc<State>) {
+    let mut buffer = [0u8; 1024];
+    let length = match stream.read(&mut buffer).await {
+        Ok(length) => length,
+        Err(error) => {
+            tracing::warn!("Can't read health probe: {error}");
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&buffer[..length]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, body) = match path {
+        "/live" | "/livez" => ("200 OK", "alive".to_string()),
+        "/ready" | "/readyz" => {
+            let peers = state.client_table.read().await.len();
+            let unhealthy_taps = state
+                .tap_table
+                .read()
+                .await
+                .iter()
+                .filter(|(_, tap)| tap.is_closed())
+                .map(|(id, _)| id.to_string())
+                .collect::<Vec<_>>();
+            let degraded_taps = state
+                .degraded_taps
+                .lock()
+                .unwrap()
+                .values()
+                .map(|name| format!("vrf {name} degraded: tap missing"))
+                .collect::<Vec<_>>();
+
+            if !state.listening.load(Ordering::Relaxed) {
+                ("503 Service Unavailable", "listener down".to_string())
+            } else if state.draining.load(Ordering::Relaxed) {
+                ("503 Service Unavailable", "draining".to_string())
+            } else if peers < min_peers {
+                (
+                    "503 Service Unavailable",
+                    format!("{peers}/{min_peers} peers connected"),
+                )
+            } else if !degraded_taps.is_empty() {
+                ("503 Service Unavailable", degraded_taps.join("\n"))
+            } else if !unhealthy_taps.is_empty() {
+                (
+                    "503 Service Unavailable",
+                    format!("unhealthy taps for vrfs {}", unhealthy_taps.join(", ")),
+                )
+            } else {
+                ("200 OK", "ready".to_string())
+            }
+        }
+        "/mac_table" => (
+            "200 OK",
+            state
+                .switch_table
+                .read()
+                .await
+                .usage()
+                .map(|(vrf_id, entries, evictions)| {
+                    format!("vrf {vrf_id} entries {entries} evictions {evictions}\n")
+                })
+                .collect::<String>(),
+        ),
+        _ => ("404 Not Found", "not found".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    if let Err(error) = stream.write_all(response.as_bytes()).await {
+        tracing::warn!("Can't answer health probe: {error}");
+    }
+}
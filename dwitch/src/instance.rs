@@ -0,0 +1,40 @@
+//! Named daemon instances, so a host can take part in several independent overlays.
+//!
+//! The default instance keeps the plain paths and link names. A named one gets its own config
+//! file, cache, sockets, netns and networkd file names, and hashed link names since the vrf id
+//! doesn't leave room for the name in the 15 bytes of an interface name.
+
+use std::sync::OnceLock;
+
+static INSTANCE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Set once at startup, before anything names a path or a link.
+pub fn set(name: Option<String>) -> Result<(), String> {
+    if let Some(name) = &name {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+        {
+            return Err(format!(
+                "Invalid instance name {name:?}, only letters, digits, - and _ are allowed"
+            ));
+        }
+    }
+
+    INSTANCE
+        .set(name)
+        .map_err(|_| "The instance is already set".to_string())
+}
+
+pub fn name() -> Option<&'static str> {
+    INSTANCE.get().and_then(Option::as_deref)
+}
+
+/// `base` for the default instance, `base-name` for a named one.
+pub fn suffixed(base: &str) -> String {
+    match name() {
+        Some(name) => format!("{base}-{name}"),
+        None => base.to_string(),
+    }
+}
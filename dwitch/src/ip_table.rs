@@ -0,0 +1,73 @@
+//! Ip addresses learned behind the switches for the vrfs in tun mode, what the mac table is to the
+//! others. It isn't cached, the addresses are learned again from the first packets.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{Arc, RwLock},
+};
+
+use common::VrfId;
+use lru::LruCache;
+
+use crate::config::SwitchId;
+
+#[derive(Debug, Default)]
+pub struct IpTable {
+    vrfs: HashMap<VrfId, IpShard>,
+}
+
+impl IpTable {
+    pub fn shard(&mut self, vrf_id: VrfId, capacity: NonZeroUsize) -> IpShard {
+        self.vrfs
+            .entry(vrf_id)
+            .or_insert_with(|| IpShard(Arc::new(RwLock::new(LruCache::new(capacity)))))
+            .clone()
+    }
+
+    pub fn remove(&mut self, vrf_id: &VrfId) {
+        self.vrfs.remove(vrf_id);
+    }
+
+    /// Forget the ip addresses learned behind a switch in every vrf. Returns how many were
+    /// forgotten.
+    pub fn forget(&self, switch_id: SwitchId) -> usize {
+        self.vrfs
+            .values()
+            .map(|shard| shard.forget(switch_id))
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IpShard(Arc<RwLock<LruCache<IpAddr, SwitchId>>>);
+
+impl IpShard {
+    pub fn get(&self, address: &IpAddr) -> Option<SwitchId> {
+        self.0.read().unwrap().peek(address).copied()
+    }
+
+    pub fn learn(&self, address: IpAddr, switch_id: SwitchId) {
+        if let Some((evicted, _)) = self.0.write().unwrap().push(address, switch_id) {
+            if evicted != address {
+                tracing::debug!("Evicted ip address {evicted}");
+            }
+        }
+    }
+
+    pub fn forget(&self, switch_id: SwitchId) -> usize {
+        let mut entries = self.0.write().unwrap();
+        let addresses = entries
+            .iter()
+            .filter(|(_, learned)| **learned == switch_id)
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+
+        for address in &addresses {
+            entries.pop(address);
+        }
+
+        addresses.len()
+    }
+}
@@ -0,0 +1,65 @@
+pub mod acl;
+pub mod api;
+pub mod audit;
+pub mod cache;
+pub mod config;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod discovery;
+#[cfg(target_os = "linux")]
+pub mod dns;
+#[cfg(target_os = "linux")]
+pub mod docker;
+pub mod events;
+pub mod evpn;
+#[cfg(target_os = "linux")]
+pub mod gateway;
+pub mod gossip;
+pub mod handover;
+pub mod health;
+pub mod instance;
+pub mod ip_table;
+pub mod link;
+pub mod management;
+pub mod metrics;
+pub mod mirror;
+pub mod mqtt;
+pub mod networkd;
+#[cfg(target_os = "linux")]
+pub mod nftables;
+pub mod openflow;
+#[cfg(target_os = "linux")]
+pub mod privileges;
+pub mod rate_limit;
+pub mod reload;
+#[cfg(target_os = "linux")]
+pub mod route_leak;
+pub mod runtime;
+pub mod sandbox;
+pub mod self_test;
+pub mod shaper;
+pub mod shutdown;
+pub mod socket;
+pub mod state;
+pub mod switch_table;
+pub mod tap;
+pub mod token;
+pub mod trace;
+#[cfg(target_os = "linux")]
+pub mod vm;
+pub mod vrf_key;
+pub mod vrf_test;
+pub mod vxlan;
+
+/// Largest frame a vrf can be configured to carry, and what it carries without a mtu.
+pub const MAX_BUFFER_SIZE: usize = 65535;
+
+pub trait BufferExt {
+    fn clear(&mut self);
+}
+
+impl BufferExt for [u8] {
+    fn clear(&mut self) {
+        self.iter_mut().for_each(|byte| *byte = 0)
+    }
+}
@@ -0,0 +1,192 @@
+//! Linux vrf devices and bridges in the default namespace, for the dataplanes that don't isolate
+//! vrfs in their own netns, and the veth pairs and taps plugging containers and vms into vrfs.
+//!
+//! The netns dataplane needs the `netns` feature, without it a vrf is at best a plain tap. Off
+//! linux there's no netlink, a vrf is always a plain tap.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    error::Error,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use common::VrfId;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use netns::Netns;
+use protocol::Vrf;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use protocol::VrfNetns;
+use serde::Deserialize;
+
+use crate::instance;
+
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(not(target_os = "linux"))]
+mod plain;
+
+#[cfg(target_os = "linux")]
+pub use netlink::*;
+#[cfg(not(target_os = "linux"))]
+pub use plain::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dataplane {
+    /// Each vrf tap lives in a network namespace named after the vrf.
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    #[default]
+    Netns,
+    /// Each vrf tap is enslaved to a linux vrf device, routing in the vrf id's table.
+    Vrf,
+    /// Each vrf tap is enslaved to a bridge.
+    Bridge,
+    /// Each vrf is only a tap in the host namespace, for leaf members that don't route or bridge
+    /// it any further.
+    #[cfg_attr(not(all(target_os = "linux", feature = "netns")), default)]
+    Tap,
+}
+
+/// Sub-interface of a physical nic plugging a vrf into its local segment, the kernel switches the
+/// local traffic while the other members are still reached through the tap.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UplinkConfig {
+    pub parent: String,
+    #[serde(default)]
+    pub kind: UplinkKind,
+    /// Outer 802.1ad tag of a vlan uplink, selecting a customer on a provider trunk.
+    pub s_tag: Option<u16>,
+    /// 802.1q tag of a vlan uplink, inside the s-tag if there's one. Without it the customer's
+    /// c-tags are carried as they are.
+    pub c_tag: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UplinkKind {
+    /// Bridged with the tap in passthru mode, or enslaved to the vrf device in bridge mode.
+    #[default]
+    Macvlan,
+    /// Shares the mac of its parent so it can't be bridged, only for the vrf dataplane.
+    Ipvlan,
+    /// The frames of a vlan of a trunk, or of a customer with q-in-q.
+    Vlan,
+}
+
+pub type LinkError = Box<dyn Error + Send + Sync>;
+
+const MAX_ALTNAME_LENGTH: usize = 127;
+
+// names the vrf settings give their taps, set before each tap is created
+static TAP_NAMES: Mutex<BTreeMap<VrfId, String>> = Mutex::new(BTreeMap::new());
+
+/// Use the name of the vrf settings for its tap, or the generated one without.
+pub fn set_tap_name(vrf: &Vrf) {
+    let mut tap_names = TAP_NAMES.lock().unwrap();
+
+    match &vrf.settings.ifname {
+        Some(ifname) => tap_names.insert(vrf.id, ifname.clone()),
+        None => tap_names.remove(&vrf.id),
+    };
+}
+
+pub fn tap_name(vrf_id: VrfId) -> String {
+    if let Some(ifname) = TAP_NAMES.lock().unwrap().get(&vrf_id) {
+        return ifname.clone();
+    }
+
+    match instance::name() {
+        Some(_) => format!("dwt{:08x}", name_hash(vrf_id)),
+        None => format!("dwtap{vrf_id}"),
+    }
+}
+
+pub fn master_name(dataplane: Dataplane, vrf_id: VrfId) -> String {
+    match (dataplane, instance::name()) {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        (Dataplane::Netns, Some(_)) => format!("dwr{:08x}", name_hash(vrf_id)),
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        (Dataplane::Netns, None) => format!("dwvrf{vrf_id}"),
+        (Dataplane::Vrf, Some(_)) => format!("dwr{:08x}", name_hash(vrf_id)),
+        (Dataplane::Vrf, None) => format!("dwvrf{vrf_id}"),
+        (Dataplane::Bridge, Some(_)) => format!("dwb{:08x}", name_hash(vrf_id)),
+        (Dataplane::Bridge, None) => format!("dwbr{vrf_id}"),
+        // the tap is the only device of its vrf
+        (Dataplane::Tap, _) => tap_name(vrf_id),
+    }
+}
+
+pub fn uplink_name(vrf_id: VrfId) -> String {
+    match instance::name() {
+        Some(_) => format!("dwu{:08x}", name_hash(vrf_id)),
+        None => format!("dwup{vrf_id}"),
+    }
+}
+
+pub fn egress_name(vrf_id: VrfId) -> String {
+    match instance::name() {
+        Some(_) => format!("dwe{:08x}", name_hash(("egress", vrf_id))),
+        None => format!("dweg{vrf_id}"),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+pub fn netns_name(vrf_name: &str) -> String {
+    match instance::name() {
+        Some(name) => format!("{name}-{vrf_name}"),
+        None => vrf_name.to_string(),
+    }
+}
+
+/// Netns the tap of a vrf is created in with the netns dataplane, `None` for the host one.
+#[cfg(all(target_os = "linux", feature = "netns"))]
+pub fn vrf_netns(vrf: &Vrf) -> Option<Netns> {
+    match vrf.settings.netns.clone().unwrap_or_default() {
+        VrfNetns::Create => Some(Netns::named(netns_name(&vrf.name))),
+        VrfNetns::Default => None,
+        VrfNetns::Existing(name) => Some(Netns::named(name)),
+    }
+}
+
+/// Whether the links of a vrf besides its tap can be added, dwitch leaves alone a netns it only
+/// plugged the tap into.
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "netns")),
+    allow(unused_variables)
+)]
+pub fn owns_netns(dataplane: Dataplane, vrf: &Vrf) -> bool {
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    if dataplane == Dataplane::Netns {
+        return matches!(vrf.settings.netns, None | Some(VrfNetns::Create));
+    }
+
+    true
+}
+
+/// Stable alternative name of a dwitch link, for udev rules and networkd matches by vrf name.
+pub fn altname(vrf_name: &str, role: &str) -> String {
+    format!("{}-{vrf_name}-{role}", instance::suffixed("dwitch"))
+        .chars()
+        // same rules as interface names
+        .map(|char| match char {
+            '/' | ':' => '-',
+            char if char.is_ascii_graphic() => char,
+            _ => '-',
+        })
+        .take(MAX_ALTNAME_LENGTH)
+        .collect()
+}
+
+// the instance is part of the hash so the instances never share a link
+fn name_hash(key: impl Hash) -> u32 {
+    let mut hasher = DefaultHasher::new();
+
+    instance::name().hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+pub fn vm_tap_name(vrf_name: &str, name: &str) -> String {
+    format!("dwvm{:08x}", name_hash((vrf_name, name)))
+}
@@ -0,0 +1,1005 @@
+//! The links of the vrfs and their endpoints, through rtnetlink.
+
+use std::{
+    fs::{read_to_string, File},
+    hash::Hash,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use std::fs::write;
+
+use common::VrfId;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use netns::Netns;
+use nix::sched::{setns, CloneFlags};
+use protocol::{Endpoint, IpPrefix, IpRoute};
+use rtnetlink::{
+    new_connection,
+    packet_route::{
+        link::{IpVlanMode, LinkAttribute, LinkMessage, MacVlanMode, VlanProtocol},
+        route::RouteType,
+        rule::RuleAction,
+        AddressFamily,
+    },
+    Handle, LinkBridge, LinkIpVlan, LinkMacVlan, LinkUnspec, LinkVeth, LinkVlan, LinkVrf,
+    RouteMessageBuilder,
+};
+use tokio::{spawn, task::spawn_blocking};
+use tokio_stream::StreamExt;
+
+use super::{
+    altname, master_name, name_hash, tap_name, uplink_name, Dataplane, LinkError, UplinkConfig,
+    UplinkKind,
+};
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use super::{egress_name, netns_name};
+use crate::switch_table::MacAddress;
+
+// keeps the vrf routing tables clear of the main, local and default tables
+const VRF_TABLE_BASE: u32 = 1000;
+const MAIN_TABLE: u32 = 254;
+// right after the l3mdev rule, what the table of a vrf doesn't route goes on to the main table
+const GATEWAY_RULE_PRIORITY: u32 = 1001;
+
+const THREAD_NETNS_PATH: &str = "/proc/thread-self/ns/net";
+// end of the egress pair in a vrf netns
+#[cfg(all(target_os = "linux", feature = "netns"))]
+const EGRESS_PEER_NAME: &str = "egress";
+const IP_FORWARD_PATH: &str = "/proc/sys/net/ipv4/ip_forward";
+
+fn connect() -> Result<Handle, LinkError> {
+    let (connection, handle, _) = new_connection()?;
+
+    // the connection ends with its last handle
+    spawn(connection);
+
+    Ok(handle)
+}
+
+// a netlink socket stays in the namespace it was opened in
+async fn connect_in(netns: &Path) -> Result<Handle, LinkError> {
+    let (connection, handle, _) = run_in(netns, new_connection).await??;
+
+    spawn(connection);
+
+    Ok(handle)
+}
+
+/// Run `f` on a blocking thread moved into `netns` for the time of the call.
+pub async fn run_in<T: Send + 'static>(
+    netns: &Path,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, LinkError> {
+    let netns = netns.to_path_buf();
+
+    spawn_blocking(move || -> Result<_, LinkError> {
+        let initial_netns = File::open(THREAD_NETNS_PATH)?;
+
+        setns(File::open(netns)?, CloneFlags::CLONE_NEWNET)?;
+
+        let result = f();
+
+        // always leave the namespace, the thread may be reused for other work
+        setns(initial_netns, CloneFlags::CLONE_NEWNET)?;
+
+        Ok(result)
+    })
+    .await?
+}
+
+async fn index(handle: &Handle, name: &str) -> Result<u32, LinkError> {
+    match handle
+        .link()
+        .get()
+        .match_name(name.to_string())
+        .execute()
+        .next()
+        .await
+    {
+        Some(link) => Ok(link?.header.index),
+        None => Err(format!("Can't find the link {name}").into()),
+    }
+}
+
+/// Create the vrf device or bridge of a vrf if it's missing and enslave its tap to it.
+pub async fn attach(dataplane: Dataplane, vrf_id: VrfId, tap: &str) -> Result<String, LinkError> {
+    let handle = connect()?;
+    let master = master_name(dataplane, vrf_id);
+    let message = match dataplane {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        Dataplane::Netns => return Err("The netns dataplane has no master device".into()),
+        Dataplane::Tap => return Err("The tap dataplane has no master device".into()),
+        Dataplane::Vrf => LinkVrf::new(&master, vrf_table(vrf_id)?).up().build(),
+        Dataplane::Bridge => LinkBridge::new(&master).up().build(),
+    };
+
+    match handle.link().add(message).execute().await {
+        Ok(()) => {}
+        // left by a previous run or a handover
+        Err(rtnetlink::Error::NetlinkError(error))
+            if error.to_io().kind() == ErrorKind::AlreadyExists => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let master_index = index(&handle, &master).await?;
+    let tap_index = index(&handle, tap).await?;
+
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(tap_index)
+                .controller(master_index)
+                .up()
+                .build(),
+        )
+        .execute()
+        .await?;
+
+    Ok(master)
+}
+
+fn vrf_table(vrf_id: VrfId) -> Result<u32, LinkError> {
+    Ok(VRF_TABLE_BASE
+        .checked_add(vrf_id)
+        .ok_or("Vrf id out of the routing table range")?)
+}
+
+/// Route `allowed` from the table of a vrf into the vrf device of `target_id`, and make `denied`
+/// unreachable there so only the rest of the allowed prefixes leak.
+pub async fn leak_routes(
+    vrf_id: VrfId,
+    target_id: VrfId,
+    allowed: &[IpPrefix],
+    denied: &[IpPrefix],
+) -> Result<(), LinkError> {
+    let handle = connect()?;
+    let table = vrf_table(vrf_id)?;
+    let target_index = index(&handle, &master_name(Dataplane::Vrf, target_id)).await?;
+
+    for prefix in allowed {
+        let message = RouteMessageBuilder::<IpAddr>::new()
+            .destination_prefix(prefix.address, prefix.prefix_length)?
+            .output_interface(target_index)
+            .table_id(table)
+            .build();
+
+        handle.route().add(message).replace().execute().await?;
+    }
+
+    for prefix in denied {
+        let message = RouteMessageBuilder::<IpAddr>::new()
+            .destination_prefix(prefix.address, prefix.prefix_length)?
+            .kind(RouteType::Prohibit)
+            .table_id(table)
+            .build();
+
+        handle.route().add(message).replace().execute().await?;
+    }
+
+    Ok(())
+}
+
+/// Serve the gateway address of a vrf, the kernel answers arp and neighbor solicitations for it.
+/// The vrf and bridge dataplanes route what it gets into the host network, the netns one keeps it
+/// in the vrf netns.
+pub async fn add_gateway(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    address: &IpPrefix,
+) -> Result<(), LinkError> {
+    let (handle, link) = gateway_link(dataplane, vrf_id, vrf_name).await?;
+
+    // the tap can't keep the address once a bridge joins it with the endpoints
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    if dataplane == Dataplane::Netns {
+        let bridge_index = ensure_bridge(&handle, &link).await?;
+
+        enslave(&handle, &tap_name(vrf_id), bridge_index).await?;
+    }
+
+    let link_index = index(&handle, &link).await?;
+
+    handle
+        .address()
+        .add(link_index, address.address, address.prefix_length)
+        .replace()
+        .execute()
+        .await?;
+
+    if dataplane == Dataplane::Vrf {
+        let master = master_name(dataplane, vrf_id);
+        let master_index = index(&handle, &master).await?;
+
+        // the replies come back through the vrf device
+        handle
+            .route()
+            .add(gateway_route(address, master_index)?)
+            .replace()
+            .execute()
+            .await?;
+
+        match gateway_rule(&handle, &master, address).execute().await {
+            Ok(()) => {}
+            Err(rtnetlink::Error::NetlinkError(error))
+                if error.to_io().kind() == ErrorKind::AlreadyExists => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Stop serving the gateway address of a vrf, the links may already be gone with the vrf.
+pub async fn remove_gateway(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    address: &IpPrefix,
+) -> Result<(), LinkError> {
+    if dataplane == Dataplane::Vrf {
+        let handle = connect()?;
+        let mut rule = gateway_rule(&handle, &master_name(dataplane, vrf_id), address);
+
+        // the route went away with the vrf device if it's gone
+        if let Ok(master_index) = index(&handle, &master_name(dataplane, vrf_id)).await {
+            let _ = handle
+                .route()
+                .del(gateway_route(address, master_index)?)
+                .execute()
+                .await;
+        }
+
+        match handle
+            .rule()
+            .del(rule.message_mut().clone())
+            .execute()
+            .await
+        {
+            Ok(()) => {}
+            Err(rtnetlink::Error::NetlinkError(error))
+                if error.to_io().kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let Ok((handle, link)) = gateway_link(dataplane, vrf_id, vrf_name).await else {
+        return Ok(());
+    };
+    let Ok(link_index) = index(&handle, &link).await else {
+        return Ok(());
+    };
+    let mut addresses = handle
+        .address()
+        .get()
+        .set_link_index_filter(link_index)
+        .set_address_filter(address.address)
+        .set_prefix_length_filter(address.prefix_length)
+        .execute();
+
+    while let Some(message) = addresses.try_next().await? {
+        handle.address().del(message).execute().await?;
+    }
+
+    Ok(())
+}
+
+// the bridge of the vrf netns or the bridge dataplane, or the tap enslaved to the vrf device or
+// left alone
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "netns")),
+    allow(unused_variables)
+)]
+async fn gateway_link(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+) -> Result<(Handle, String), LinkError> {
+    Ok(match dataplane {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        Dataplane::Netns => (
+            connect_in(&Netns::named(netns_name(vrf_name)).path()).await?,
+            master_name(Dataplane::Bridge, vrf_id),
+        ),
+        Dataplane::Bridge => (connect()?, master_name(dataplane, vrf_id)),
+        Dataplane::Vrf | Dataplane::Tap => (connect()?, tap_name(vrf_id)),
+    })
+}
+
+fn gateway_route(
+    address: &IpPrefix,
+    master_index: u32,
+) -> Result<rtnetlink::packet_route::route::RouteMessage, LinkError> {
+    Ok(RouteMessageBuilder::<IpAddr>::new()
+        .destination_prefix(network(address), address.prefix_length)?
+        .output_interface(master_index)
+        .table_id(MAIN_TABLE)
+        .build())
+}
+
+fn gateway_rule(handle: &Handle, master: &str, address: &IpPrefix) -> rtnetlink::RuleAddRequest {
+    let mut rule = handle
+        .rule()
+        .add()
+        .input_interface(master)
+        .table_id(MAIN_TABLE)
+        .priority(GATEWAY_RULE_PRIORITY)
+        .action(RuleAction::ToTable);
+
+    rule.message_mut().header.family = match address.address {
+        IpAddr::V4(_) => AddressFamily::Inet,
+        IpAddr::V6(_) => AddressFamily::Inet6,
+    };
+
+    rule
+}
+
+// routes can't have host bits set
+fn network(prefix: &IpPrefix) -> IpAddr {
+    match prefix.address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.prefix_length as u32)
+                .unwrap_or(0);
+
+            Ipv4Addr::from(address.to_bits() & mask).into()
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.prefix_length as u32)
+                .unwrap_or(0);
+
+            Ipv6Addr::from(address.to_bits() & mask).into()
+        }
+    }
+}
+
+/// Veth pair routing a vrf netns into the host through `host_address`, the vrf netns end gets
+/// `peer_address` and the default route of the netns.
+#[cfg(all(target_os = "linux", feature = "netns"))]
+pub async fn add_egress(
+    vrf_id: VrfId,
+    vrf_name: &str,
+    host_address: &IpPrefix,
+    peer_address: &IpPrefix,
+) -> Result<String, LinkError> {
+    let handle = connect()?;
+    let host_name = egress_name(vrf_id);
+    let peer_name = format!("dwo{:08x}", name_hash(("egress", vrf_id)));
+    let vrf_netns = Netns::named(netns_name(vrf_name)).path();
+
+    // left by a previous run, its peer goes with it
+    if let Ok(host_index) = index(&handle, &host_name).await {
+        handle.link().del(host_index).execute().await?;
+    }
+
+    handle
+        .link()
+        .add(LinkVeth::new(&host_name, &peer_name).up().build())
+        .execute()
+        .await?;
+
+    let result = async {
+        let host_index = index(&handle, &host_name).await?;
+        let peer_index = index(&handle, &peer_name).await?;
+        let vrf_netns_file = File::open(&vrf_netns)?;
+
+        handle
+            .address()
+            .add(host_index, host_address.address, host_address.prefix_length)
+            .execute()
+            .await?;
+        handle
+            .link()
+            .set(
+                LinkUnspec::new_with_index(peer_index)
+                    .setns_by_fd(vrf_netns_file.as_raw_fd())
+                    .build(),
+            )
+            .execute()
+            .await?;
+
+        let vrf_handle = connect_in(&vrf_netns).await?;
+        let peer_index = index(&vrf_handle, &peer_name).await?;
+
+        vrf_handle
+            .link()
+            .set(
+                LinkUnspec::new_with_index(peer_index)
+                    .name(EGRESS_PEER_NAME.to_string())
+                    .up()
+                    .build(),
+            )
+            .execute()
+            .await?;
+        vrf_handle
+            .address()
+            .add(peer_index, peer_address.address, peer_address.prefix_length)
+            .execute()
+            .await?;
+        vrf_handle
+            .route()
+            .add(
+                RouteMessageBuilder::<IpAddr>::new()
+                    .destination_prefix(unspecified(host_address.address), 0)?
+                    .gateway(host_address.address)?
+                    .output_interface(peer_index)
+                    .build(),
+            )
+            .replace()
+            .execute()
+            .await?;
+
+        run_in(&vrf_netns, || write(IP_FORWARD_PATH, "1")).await??;
+
+        Ok(EGRESS_PEER_NAME.to_string())
+    }
+    .await;
+
+    if result.is_err() {
+        if let Ok(host_index) = index(&handle, &host_name).await {
+            let _ = handle.link().del(host_index).execute().await;
+        }
+    }
+
+    result
+}
+
+/// Whether the host routes between its links, masqueraded egress goes nowhere without it.
+pub fn host_forwarding() -> bool {
+    read_to_string(IP_FORWARD_PATH).is_ok_and(|forwarding| forwarding.trim() == "1")
+}
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn unspecified(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Give a link its altname, in the default namespace or in `netns`.
+pub async fn add_altname(netns: Option<&Path>, name: &str, altname: &str) -> Result<(), LinkError> {
+    let handle = match netns {
+        Some(netns) => connect_in(netns).await?,
+        None => connect()?,
+    };
+
+    add_altname_with(&handle, name, altname).await
+}
+
+/// Set the mtu of a link, in the default namespace or in `netns`.
+pub async fn set_mtu(netns: Option<&Path>, name: &str, mtu: u32) -> Result<(), LinkError> {
+    let handle = match netns {
+        Some(netns) => connect_in(netns).await?,
+        None => connect()?,
+    };
+
+    set_mtu_with(&handle, name, mtu).await
+}
+
+/// Set the mac of a link, in the default namespace or in `netns`.
+pub async fn set_mac(netns: Option<&Path>, name: &str, mac: MacAddress) -> Result<(), LinkError> {
+    let handle = match netns {
+        Some(netns) => connect_in(netns).await?,
+        None => connect()?,
+    };
+    let link_index = index(&handle, name).await?;
+
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(link_index)
+                .address(mac.to_vec())
+                .build(),
+        )
+        .execute()
+        .await?;
+
+    Ok(())
+}
+
+async fn set_mtu_with(handle: &Handle, name: &str, mtu: u32) -> Result<(), LinkError> {
+    let link_index = index(handle, name).await?;
+
+    handle
+        .link()
+        .set(LinkUnspec::new_with_index(link_index).mtu(mtu).build())
+        .execute()
+        .await?;
+
+    Ok(())
+}
+
+// a bridge takes the smallest mtu of its ports, both ends carry the frames of the vrf
+async fn set_pair_mtu(
+    handle: &Handle,
+    host_name: &str,
+    peer_name: &str,
+    mtu: Option<u32>,
+) -> Result<(), LinkError> {
+    if let Some(mtu) = mtu {
+        set_mtu_with(handle, host_name, mtu).await?;
+        set_mtu_with(handle, peer_name, mtu).await?;
+    }
+
+    Ok(())
+}
+
+async fn add_altname_with(handle: &Handle, name: &str, altname: &str) -> Result<(), LinkError> {
+    let link_index = index(handle, name).await?;
+
+    match handle
+        .link()
+        .property_add(link_index)
+        .alt_ifname(&[altname])
+        .execute()
+        .await
+    {
+        Ok(()) => Ok(()),
+        // kept from a previous run or a handover
+        Err(rtnetlink::Error::NetlinkError(error))
+            if error.to_io().kind() == ErrorKind::AlreadyExists =>
+        {
+            Ok(())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn ensure_bridge(handle: &Handle, name: &str) -> Result<u32, LinkError> {
+    match handle
+        .link()
+        .add(LinkBridge::new(name).up().build())
+        .execute()
+        .await
+    {
+        Ok(()) => {}
+        Err(rtnetlink::Error::NetlinkError(error))
+            if error.to_io().kind() == ErrorKind::AlreadyExists => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    index(handle, name).await
+}
+
+async fn enslave(handle: &Handle, name: &str, master_index: u32) -> Result<(), LinkError> {
+    let link_index = index(handle, name).await?;
+
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(link_index)
+                .controller(master_index)
+                .up()
+                .build(),
+        )
+        .execute()
+        .await?;
+
+    Ok(())
+}
+
+// stable names for both ends until the container end is moved and renamed
+fn veth_names(key: impl Hash) -> (String, String) {
+    let hash = name_hash(key);
+
+    (format!("dwv{hash:08x}"), format!("dwp{hash:08x}"))
+}
+
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "netns")),
+    allow(unused_variables)
+)]
+fn endpoint_netns(dataplane: Dataplane, vrf_name: &str) -> Result<Option<PathBuf>, LinkError> {
+    match dataplane {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        Dataplane::Netns => Ok(Some(Netns::named(netns_name(vrf_name)).path())),
+        Dataplane::Bridge => Ok(None),
+        Dataplane::Vrf | Dataplane::Tap => {
+            Err("Endpoints need the netns or bridge dataplane".into())
+        }
+    }
+}
+
+/// Create a veth pair with one end in the container namespace, configured with the endpoint
+/// addresses and routes, and the other end bridged with the tap of the vrf.
+pub async fn attach_endpoint(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    mtu: Option<u32>,
+    endpoint: &Endpoint,
+) -> Result<MacAddress, LinkError> {
+    let vrf_netns = endpoint_netns(dataplane, &endpoint.vrf)?;
+    let (host_name, peer_name) = veth_names((&endpoint.netns, &endpoint.ifname));
+    let handle = connect()?;
+
+    handle
+        .link()
+        .add(LinkVeth::new(&host_name, &peer_name).build())
+        .execute()
+        .await?;
+
+    let result = async {
+        set_pair_mtu(&handle, &host_name, &peer_name, mtu).await?;
+
+        let mac = configure_container_end(&handle, endpoint, &peer_name).await?;
+
+        bridge_host_end(
+            &handle,
+            vrf_id,
+            &endpoint.vrf,
+            vrf_netns.as_deref(),
+            &host_name,
+        )
+        .await?;
+
+        Ok(mac)
+    }
+    .await;
+
+    if result.is_err() {
+        delete_host_end(&handle, vrf_netns.as_deref(), &host_name).await;
+    }
+
+    result
+}
+
+/// Create a veth pair bridged with the tap of the vrf, leaving the container end in the default
+/// namespace for the container runtime to move, and return its name.
+pub async fn create_endpoint(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    mtu: Option<u32>,
+    endpoint_id: &str,
+) -> Result<String, LinkError> {
+    let vrf_netns = endpoint_netns(dataplane, vrf_name)?;
+    let (host_name, peer_name) = veth_names(endpoint_id);
+    let handle = connect()?;
+
+    handle
+        .link()
+        .add(LinkVeth::new(&host_name, &peer_name).build())
+        .execute()
+        .await?;
+
+    let result = async {
+        set_pair_mtu(&handle, &host_name, &peer_name, mtu).await?;
+
+        bridge_host_end(&handle, vrf_id, vrf_name, vrf_netns.as_deref(), &host_name).await
+    }
+    .await;
+
+    if let Err(error) = result {
+        delete_host_end(&handle, vrf_netns.as_deref(), &host_name).await;
+
+        return Err(error);
+    }
+
+    Ok(peer_name)
+}
+
+/// Bridge the tap of a vm with the tap of the vrf, like the host end of an endpoint.
+pub async fn attach_vm_tap(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    mtu: Option<u32>,
+    tap: &str,
+) -> Result<(), LinkError> {
+    let vrf_netns = endpoint_netns(dataplane, vrf_name)?;
+    let handle = connect()?;
+
+    if let Some(mtu) = mtu {
+        set_mtu_with(&handle, tap, mtu).await?;
+    }
+
+    bridge_host_end(&handle, vrf_id, vrf_name, vrf_netns.as_deref(), tap).await
+}
+
+/// Create the uplink of a vrf on its parent nic and plug it in next to the tap.
+pub async fn attach_uplink(
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    mtu: Option<u32>,
+    uplink: &UplinkConfig,
+) -> Result<String, LinkError> {
+    if dataplane == Dataplane::Tap {
+        return Err("Uplinks need a bridge or a vrf device, the tap dataplane has neither".into());
+    }
+
+    let handle = connect()?;
+    let name = uplink_name(vrf_id);
+    let parent_index = index(&handle, &uplink.parent).await?;
+    // passthru hands every frame of the parent to the bridge, it takes the parent for itself
+    let message = match (uplink.kind, dataplane) {
+        (UplinkKind::Macvlan, Dataplane::Vrf) => {
+            LinkMacVlan::new(&name, parent_index, MacVlanMode::Bridge).build()
+        }
+        (UplinkKind::Macvlan, _) => {
+            LinkMacVlan::new(&name, parent_index, MacVlanMode::Passthrough).build()
+        }
+        (UplinkKind::Ipvlan, Dataplane::Vrf) => {
+            LinkIpVlan::new(&name, parent_index, IpVlanMode::L3).build()
+        }
+        (UplinkKind::Ipvlan, _) => return Err("Ipvlan uplinks need the vrf dataplane".into()),
+        (UplinkKind::Vlan, _) => vlan_uplink(&handle, &name, parent_index, uplink).await?,
+    };
+
+    handle.link().add(message).execute().await?;
+
+    let result = async {
+        // no larger than the mtu of the parent
+        if let Some(mtu) = mtu {
+            set_mtu_with(&handle, &name, mtu).await?;
+        }
+
+        match dataplane {
+            Dataplane::Vrf => {
+                let master_index = index(&handle, &master_name(dataplane, vrf_id)).await?;
+
+                enslave(&handle, &name, master_index).await
+            }
+            _ => {
+                let vrf_netns = endpoint_netns(dataplane, vrf_name)?;
+
+                bridge_host_end(&handle, vrf_id, vrf_name, vrf_netns.as_deref(), &name).await
+            }
+        }
+    }
+    .await;
+
+    if let Err(error) = result {
+        let _ = delete(&name).await;
+
+        return Err(error);
+    }
+
+    Ok(name)
+}
+
+// the s-tag link is shared by the vrfs of a customer, it's left on the trunk
+async fn vlan_uplink(
+    handle: &Handle,
+    name: &str,
+    parent_index: u32,
+    uplink: &UplinkConfig,
+) -> Result<LinkMessage, LinkError> {
+    match (uplink.s_tag, uplink.c_tag) {
+        (Some(s_tag), Some(c_tag)) => {
+            let outer = format!("dws{:08x}", name_hash((&uplink.parent, s_tag)));
+
+            match handle
+                .link()
+                .add(
+                    LinkVlan::new(&outer, parent_index, s_tag)
+                        .protocol(VlanProtocol::Ieee8021Ad)
+                        .up()
+                        .build(),
+                )
+                .execute()
+                .await
+            {
+                Ok(()) => {}
+                Err(rtnetlink::Error::NetlinkError(error))
+                    if error.to_io().kind() == ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(error.into()),
+            }
+
+            Ok(LinkVlan::new(name, index(handle, &outer).await?, c_tag).build())
+        }
+        (Some(s_tag), None) => Ok(LinkVlan::new(name, parent_index, s_tag)
+            .protocol(VlanProtocol::Ieee8021Ad)
+            .build()),
+        (None, Some(c_tag)) => Ok(LinkVlan::new(name, parent_index, c_tag).build()),
+        (None, None) => Err("Vlan uplinks need an s_tag or a c_tag".into()),
+    }
+}
+
+/// Remove the veth pair made by `create_endpoint`, wherever its ends are by now.
+pub async fn delete_endpoint(dataplane: Dataplane, vrf_name: &str, endpoint_id: &str) {
+    let Ok(vrf_netns) = endpoint_netns(dataplane, vrf_name) else {
+        return;
+    };
+    let (host_name, _) = veth_names(endpoint_id);
+
+    if let Ok(handle) = connect() {
+        delete_host_end(&handle, vrf_netns.as_deref(), &host_name).await;
+    }
+}
+
+// removing either end removes the pair
+async fn delete_host_end(handle: &Handle, vrf_netns: Option<&Path>, host_name: &str) {
+    let handle = match vrf_netns {
+        Some(vrf_netns) if index(handle, host_name).await.is_err() => {
+            match connect_in(vrf_netns).await {
+                Ok(handle) => handle,
+                Err(_) => return,
+            }
+        }
+        _ => handle.clone(),
+    };
+
+    if let Ok(host_index) = index(&handle, host_name).await {
+        let _ = handle.link().del(host_index).execute().await;
+    }
+}
+
+async fn configure_container_end(
+    handle: &Handle,
+    endpoint: &Endpoint,
+    peer_name: &str,
+) -> Result<MacAddress, LinkError> {
+    let container_netns = File::open(&endpoint.netns)?;
+    let peer_index = index(handle, peer_name).await?;
+
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(peer_index)
+                .setns_by_fd(container_netns.as_raw_fd())
+                .build(),
+        )
+        .execute()
+        .await?;
+
+    let container_handle = connect_in(&endpoint.netns).await?;
+    let peer_index = index(&container_handle, peer_name).await?;
+
+    container_handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(peer_index)
+                .name(endpoint.ifname.clone())
+                .up()
+                .build(),
+        )
+        .execute()
+        .await?;
+
+    add_addresses_with(
+        &container_handle,
+        peer_index,
+        &endpoint.addresses,
+        &endpoint.routes,
+    )
+    .await?;
+
+    mac_address(&container_handle, peer_index).await
+}
+
+/// Give a link its addresses and the routes through it, in the default namespace or in `netns`.
+pub async fn add_addresses(
+    netns: Option<&Path>,
+    name: &str,
+    addresses: &[IpPrefix],
+    routes: &[IpRoute],
+) -> Result<(), LinkError> {
+    let handle = match netns {
+        Some(netns) => connect_in(netns).await?,
+        None => connect()?,
+    };
+    let link_index = index(&handle, name).await?;
+
+    add_addresses_with(&handle, link_index, addresses, routes).await
+}
+
+// the addresses first, a gateway has to be on a connected network
+async fn add_addresses_with(
+    handle: &Handle,
+    link_index: u32,
+    addresses: &[IpPrefix],
+    routes: &[IpRoute],
+) -> Result<(), LinkError> {
+    for prefix in addresses {
+        handle
+            .address()
+            .add(link_index, prefix.address, prefix.prefix_length)
+            .execute()
+            .await?;
+    }
+
+    for route in routes {
+        let mut message = RouteMessageBuilder::<IpAddr>::new()
+            .destination_prefix(route.destination.address, route.destination.prefix_length)?
+            .output_interface(link_index);
+
+        if let Some(gateway) = route.gateway {
+            message = message.gateway(gateway)?;
+        }
+
+        handle.route().add(message.build()).execute().await?;
+    }
+
+    Ok(())
+}
+
+async fn bridge_host_end(
+    handle: &Handle,
+    vrf_id: VrfId,
+    vrf_name: &str,
+    vrf_netns: Option<&Path>,
+    host_name: &str,
+) -> Result<(), LinkError> {
+    match vrf_netns {
+        // the tap sits alone in the vrf netns until a bridge joins it with the endpoints
+        Some(vrf_netns) => {
+            let vrf_netns_file = File::open(vrf_netns)?;
+            let host_index = index(handle, host_name).await?;
+
+            handle
+                .link()
+                .set(
+                    LinkUnspec::new_with_index(host_index)
+                        .setns_by_fd(vrf_netns_file.as_raw_fd())
+                        .build(),
+                )
+                .execute()
+                .await?;
+
+            let vrf_handle = connect_in(vrf_netns).await?;
+            let bridge_index =
+                ensure_bridge(&vrf_handle, &master_name(Dataplane::Bridge, vrf_id)).await?;
+
+            enslave(&vrf_handle, &tap_name(vrf_id), bridge_index).await?;
+            enslave(&vrf_handle, host_name, bridge_index).await?;
+            add_altname_with(&vrf_handle, host_name, &altname(vrf_name, host_name)).await
+        }
+        None => {
+            let bridge_index = index(handle, &master_name(Dataplane::Bridge, vrf_id)).await?;
+
+            enslave(handle, host_name, bridge_index).await?;
+            add_altname_with(handle, host_name, &altname(vrf_name, host_name)).await
+        }
+    }
+}
+
+async fn mac_address(handle: &Handle, link_index: u32) -> Result<MacAddress, LinkError> {
+    let link = handle
+        .link()
+        .get()
+        .match_index(link_index)
+        .execute()
+        .next()
+        .await
+        .ok_or("Can't find the endpoint link")??;
+
+    link.attributes
+        .into_iter()
+        .find_map(|attribute| match attribute {
+            LinkAttribute::Address(address) => address.try_into().ok(),
+            _ => None,
+        })
+        .ok_or_else(|| "The endpoint link has no mac address".into())
+}
+
+/// Remove the veth pair of an endpoint, a namespace that's already gone took it along.
+pub async fn detach_endpoint(netns: &Path, ifname: &str) -> Result<(), LinkError> {
+    if !netns.exists() {
+        return Ok(());
+    }
+
+    let handle = connect_in(netns).await?;
+    let Ok(link_index) = index(&handle, ifname).await else {
+        return Ok(());
+    };
+
+    handle.link().del(link_index).execute().await?;
+
+    Ok(())
+}
+
+pub async fn delete(name: &str) -> Result<(), LinkError> {
+    let handle = connect()?;
+    let link_index = index(&handle, name).await?;
+
+    handle.link().del(link_index).execute().await?;
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Without netlink a vrf is only its tap, whose link can't be configured any further.
+
+use std::path::Path;
+
+use common::VrfId;
+use protocol::{Endpoint, IpPrefix, IpRoute};
+
+use super::{Dataplane, LinkError, UplinkConfig};
+use crate::switch_table::MacAddress;
+
+const UNSUPPORTED: &str = "only supported on linux";
+
+pub async fn attach(dataplane: Dataplane, _vrf_id: VrfId, _tap: &str) -> Result<String, LinkError> {
+    Err(format!("The {dataplane:?} dataplane is {UNSUPPORTED}").into())
+}
+
+// only a convenience for udev rules and networkd, neither exists here
+pub async fn add_altname(
+    _netns: Option<&Path>,
+    _name: &str,
+    _altname: &str,
+) -> Result<(), LinkError> {
+    Ok(())
+}
+
+pub async fn set_mtu(_netns: Option<&Path>, name: &str, _mtu: u32) -> Result<(), LinkError> {
+    Err(format!("Setting the mtu of {name} is {UNSUPPORTED}").into())
+}
+
+pub async fn set_mac(_netns: Option<&Path>, name: &str, _mac: MacAddress) -> Result<(), LinkError> {
+    Err(format!("Setting the mac of {name} is {UNSUPPORTED}").into())
+}
+
+/// Nothing to do for a vrf without addresses and routes, the tap is up already.
+pub async fn add_addresses(
+    _netns: Option<&Path>,
+    name: &str,
+    addresses: &[IpPrefix],
+    routes: &[IpRoute],
+) -> Result<(), LinkError> {
+    match addresses.is_empty() && routes.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Adding addresses and routes to {name} is {UNSUPPORTED}").into()),
+    }
+}
+
+pub async fn attach_uplink(
+    _dataplane: Dataplane,
+    _vrf_id: VrfId,
+    _vrf_name: &str,
+    _mtu: Option<u32>,
+    _uplink: &UplinkConfig,
+) -> Result<String, LinkError> {
+    Err(format!("Uplinks are {UNSUPPORTED}").into())
+}
+
+pub async fn attach_endpoint(
+    _dataplane: Dataplane,
+    _vrf_id: VrfId,
+    _mtu: Option<u32>,
+    _endpoint: &Endpoint,
+) -> Result<MacAddress, LinkError> {
+    Err(format!("Endpoints are {UNSUPPORTED}").into())
+}
+
+pub async fn detach_endpoint(_netns: &Path, _ifname: &str) -> Result<(), LinkError> {
+    Err(format!("Endpoints are {UNSUPPORTED}").into())
+}
+
+pub async fn delete(name: &str) -> Result<(), LinkError> {
+    Err(format!("Deleting {name} is {UNSUPPORTED}").into())
+}
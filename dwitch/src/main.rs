@@ -1,24 +1,79 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
 
-use cache::Cache;
-use config::Config;
+use clap::Parser;
+#[cfg(feature = "dbus")]
+use dwitch::dbus::dbus;
+#[cfg(not(target_os = "linux"))]
+use dwitch::link::Dataplane;
+use dwitch::{
+    acl::Acls,
+    api::api,
+    cache::{Cache, CacheKey},
+    config::{Config, Transport},
+    discovery::discovery,
+    evpn::evpn,
+    gossip::Gossip,
+    handover::{handover, handover_listener, Inherited},
+    health::health,
+    instance,
+    management::declare_vrfs,
+    metrics::metrics,
+    mirror::Mirrors,
+    mqtt::mqtt,
+    rate_limit::RateLimiter,
+    reload::{reload, Peering},
+    runtime,
+    self_test::{self_test, SELF_TEST_INSTANCE},
+    shutdown::shutdown,
+    socket::{
+        quic::{self, quic_server},
+        server::{management, management_listener},
+        tls::Tls,
+    },
+    state::State,
+    tap::initiate_tap_table,
+    vrf_key::VrfKey,
+    vxlan::{self, vxlan},
+};
+#[cfg(target_os = "linux")]
+use dwitch::{
+    docker::{docker, docker_listener},
+    gateway::gateways,
+    privileges,
+    route_leak::route_leaks,
+    sandbox,
+    vm::{vm, vm_listener},
+};
 use protocol::CONFIGURATION_SWITCH_ID;
-use socket::{client::client, server::server};
-use tap::initiate_tap_table;
-use tokio::{sync::RwLock, task::spawn, time::sleep};
+use tokio::{
+    pin, select,
+    sync::{mpsc::channel, RwLock},
+    task::spawn,
+    time::sleep,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-mod cache;
-mod config;
-mod socket;
-mod tap;
+const DISCOVERY_QUEUE_SIZE: usize = 16;
 
-const MAX_BUFFER_SIZE: usize = 65535;
+#[derive(Parser)]
+struct Args {
+    /// Name of the instance, to run several independent daemons on one host
+    #[arg(long, env = "DWITCH_INSTANCE")]
+    instance: Option<String>,
 
-#[tokio::main]
-async fn main() -> eyre::Result<()> {
+    /// Check the build and the host with two in-process switches and a real tap, then exit
+    #[arg(long, conflicts_with = "instance")]
+    self_test: bool,
+}
+
+fn main() -> eyre::Result<()> {
     color_eyre::install()?;
 
+    let args = Args::parse();
+
     #[cfg(feature = "tokio-console")]
     console_subscriber::init();
     #[cfg(not(feature = "tokio-console"))]
@@ -27,72 +82,347 @@ async fn main() -> eyre::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let config = Config::load().await?;
+    if args.self_test {
+        instance::set(Some(SELF_TEST_INSTANCE.to_string())).map_err(|error| eyre::eyre!(error))?;
+
+        return runtime::build(&Default::default())?
+            .block_on(self_test())
+            .map_err(|error| eyre::eyre!(error));
+    }
+
+    instance::set(args.instance).map_err(|error| eyre::eyre!(error))?;
+
+    let config = Config::load()?;
+
+    if let Some(name) = instance::name() {
+        tracing::info!("Running the instance {name}");
+    }
 
     tracing::info!("{config:#?}");
 
+    #[cfg(target_os = "linux")]
+    if config.sandbox.landlock {
+        sandbox::restrict_network(&config)?;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if config.sandbox.landlock || config.sandbox.seccomp {
+        eyre::bail!("Can't sandbox the daemon: only supported on linux");
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if config.privileges.is_some() {
+        eyre::bail!("Can't drop privileges: only supported on linux");
+    }
+
+    let inherited = Inherited::load(&config);
+
+    runtime::build(&config.runtime)?.block_on(run(config, inherited))
+}
+
+async fn run(config: Config, inherited: Inherited) -> eyre::Result<()> {
     if config.switch_id == CONFIGURATION_SWITCH_ID {
         tracing::error!("Switch id can't be {CONFIGURATION_SWITCH_ID}");
         return Ok(());
     }
 
-    let cache = Cache::load().await.unwrap_or_default();
+    if config.authenticate_data && config.control_key.is_none() {
+        tracing::error!("Data authentication needs a control key");
+        return Ok(());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if config.dataplane != Dataplane::Tap {
+        tracing::error!(
+            "The {:?} dataplane is only supported on linux",
+            config.dataplane
+        );
+        return Ok(());
+    }
+
+    if config.transport == Transport::Quic && config.tls.is_none() {
+        tracing::error!("Quic transport needs tls");
+        return Ok(());
+    }
+
+    let cache_key = match &config.cache_key_file {
+        Some(path) => Some(CacheKey::load(path).await?),
+        None => None,
+    };
+    let vrf_keys = VrfKey::load_all(&config.vrf_keys).await?;
+    let cache = match Cache::load(&config.cache, cache_key.as_ref()).await {
+        Ok(cache) => cache,
+        Err(error) => {
+            tracing::error!("Can't load cache, starting without it: {error}");
+
+            let path = Cache::set_aside(&config.cache)
+                .await
+                .map_err(|error| eyre::eyre!("Can't move the unreadable cache aside: {error}"))?;
+
+            tracing::warn!("Moved the unreadable cache to {}", path.display());
+            Cache::default()
+        }
+    };
     let client_table = Arc::new(RwLock::new(HashMap::new()));
-    let switch_table = Arc::new(RwLock::new(cache.switch_table));
-    let tap_table = Arc::new(RwLock::new(initiate_tap_table(
-        config.switch_id,
-        &cache.vrf_table,
-        client_table.clone(),
-        switch_table.clone(),
-    )));
-    let vrf_table = Arc::new(RwLock::new(cache.vrf_table));
-
-    spawn({
-        let config = config.clone();
-        let tap_table = tap_table.clone();
-        let vrf_table = vrf_table.clone();
-        let client_table = client_table.clone();
-        let switch_table = switch_table.clone();
-
-        async {
-            if let Err(error) =
-                server(config, tap_table, vrf_table, client_table, switch_table).await
-            {
-                tracing::error!("Can't start server: {error}");
+    let mut switch_table = cache.switch_table;
+
+    switch_table.set_capacity(config.max_macs_per_vrf);
+
+    for (vrf_id, mac, switch_id) in cache.static_macs {
+        switch_table.shard(vrf_id).pin(mac, switch_id);
+    }
+
+    let switch_table = Arc::new(RwLock::new(switch_table));
+    let suspensions = cache
+        .vrf_table
+        .values()
+        .map(|vrf| (vrf.id, Arc::new(AtomicBool::new(vrf.suspended))))
+        .collect();
+    let tls = match &config.tls {
+        Some(tls_config) => Some(Tls::load(tls_config)?),
+        None => None,
+    };
+    let quic = match (&tls, config.transport) {
+        (Some(tls), Transport::Quic) => Some(quic::endpoint(tls, config.listen_address())?),
+        _ => None,
+    };
+    let vxlan_socket = match &config.vxlan {
+        Some(vxlan_config) => Some(vxlan::bind(vxlan_config).await?),
+        None => None,
+    };
+    let (discovered, discoveries) = channel(DISCOVERY_QUEUE_SIZE);
+    let state = Arc::new(State {
+        tls,
+        quic,
+        vxlan: vxlan_socket,
+        vrf_keys,
+        cache_key,
+        listening: AtomicBool::new(false),
+        draining: AtomicBool::new(false),
+        action_limiter: RateLimiter::new(config.action_rate_limit),
+        tap_table: RwLock::new(HashMap::new()),
+        degraded_taps: Mutex::new(HashMap::new()),
+        vrf_table: RwLock::new(cache.vrf_table),
+        deleted_vrfs: Mutex::new(cache.deleted_vrfs),
+        declared_vrfs: Mutex::new(cache.declared_vrfs),
+        client_table,
+        switch_table,
+        acls: Acls::new(cache.acls),
+        mirrors: Mirrors::default(),
+        ip_table: Mutex::new(Default::default()),
+        draining_peers: Mutex::new(HashSet::new()),
+        gossip: Gossip::new(discovered.clone()),
+        peer_connections: Mutex::new(HashMap::new()),
+        path_mtus: Mutex::new(HashMap::new()),
+        peer_compression: Mutex::new(HashMap::new()),
+        suspensions: Mutex::new(suspensions),
+        metrics: Default::default(),
+        handover_fds: Default::default(),
+        audits: Default::default(),
+        traces: Default::default(),
+        vrf_tests: Default::default(),
+        config,
+    });
+
+    initiate_tap_table(&state, inherited.taps).await;
+    declare_vrfs(&state, &state.config.vrfs).await;
+
+    if let Some(mqtt_config) = state.config.mqtt.clone() {
+        spawn(mqtt(mqtt_config, state.config.switch_id));
+    }
+
+    if let Some(health_config) = state.config.health.clone() {
+        spawn({
+            let state = state.clone();
+
+            async {
+                if let Err(error) = health(health_config, state).await {
+                    tracing::error!("Can't start health endpoint: {error}");
+                }
+            }
+        });
+    }
+
+    if let Some(metrics_config) = state.config.metrics.clone() {
+        spawn({
+            let state = state.clone();
+
+            async {
+                if let Err(error) = metrics(metrics_config, state).await {
+                    tracing::error!("Can't start metrics endpoint: {error}");
+                }
             }
+        });
+    }
+
+    if let Some(api_config) = state.config.api.clone() {
+        spawn({
+            let state = state.clone();
+
+            async {
+                if let Err(error) = api(api_config, state).await {
+                    tracing::error!("Can't start http api: {error}");
+                }
+            }
+        });
+    }
+
+    if let Some(evpn_config) = state.config.evpn.clone() {
+        spawn(evpn(evpn_config, state.clone()));
+    }
+
+    if state.vxlan.is_some() {
+        runtime::spawn_data_plane(vxlan(state.clone()));
+    }
+
+    #[cfg(target_os = "linux")]
+    spawn(gateways(state.clone()));
+
+    #[cfg(target_os = "linux")]
+    if !state.config.route_leaks.is_empty() {
+        spawn(route_leaks(state.config.route_leaks.clone(), state.clone()));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(docker_config) = &state.config.docker {
+        match docker_listener(docker_config) {
+            Ok(listener) => {
+                spawn({
+                    let state = state.clone();
+
+                    async {
+                        if let Err(error) = docker(listener, state).await {
+                            tracing::error!("Can't start docker network driver: {error}");
+                        }
+                    }
+                });
+            }
+            Err(error) => tracing::error!("Can't start docker network driver: {error}"),
         }
-    });
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(vm_config) = &state.config.vm {
+        match vm_listener(vm_config) {
+            Ok(listener) => {
+                spawn({
+                    let state = state.clone();
 
-    for address in config.servers.clone() {
-        spawn(client(config.switch_id, address, client_table.clone()));
+                    async {
+                        if let Err(error) = vm(listener, state).await {
+                            tracing::error!("Can't start vm socket: {error}");
+                        }
+                    }
+                });
+            }
+            Err(error) => tracing::error!("Can't start vm socket: {error}"),
+        }
     }
 
-    loop {
-        sleep(Duration::from_secs(1)).await;
+    #[cfg(not(target_os = "linux"))]
+    for (name, configured) in [
+        ("dns forwarder", state.config.dns.is_some()),
+        ("route leaks", !state.config.route_leaks.is_empty()),
+        ("docker network driver", state.config.docker.is_some()),
+        ("vm socket", state.config.vm.is_some()),
+    ] {
+        if configured {
+            tracing::error!("Can't start {name}: only supported on linux");
+        }
+    }
 
-        {
-            let switch_table = switch_table.read().await;
-            let vrf_table = vrf_table.read().await;
-
-            if let Err(error) = (Cache {
-                switch_table: switch_table.clone(),
-                vrf_table: vrf_table.clone(),
-            })
-            .save()
-            .await
-            {
-                tracing::error!("Can't save cache: {error}");
+    // the interface is served as long as the connection is kept
+    #[cfg(feature = "dbus")]
+    let _dbus = match state.config.dbus.clone() {
+        Some(dbus_config) => match dbus(dbus_config, state.clone()).await {
+            Ok(connection) => Some(connection),
+            Err(error) => {
+                tracing::error!("Can't start d-bus interface: {error}");
+                None
             }
+        },
+        None => None,
+    };
+
+    #[cfg(not(feature = "dbus"))]
+    if state.config.dbus.is_some() {
+        tracing::error!("Can't start d-bus interface: built without the dbus feature");
+    }
+
+    match management_listener(&state.config) {
+        Ok(listener) => {
+            spawn({
+                let state = state.clone();
+
+                async {
+                    if let Err(error) = management(listener, state).await {
+                        tracing::error!("Can't start management socket: {error}");
+                    }
+                }
+            });
         }
+        Err(error) => tracing::error!("Can't start management socket: {error}"),
     }
-}
 
-trait BufferExt {
-    fn clear(&mut self);
-}
+    match handover_listener(&state.config) {
+        Ok(Some(listener)) => {
+            spawn({
+                let state = state.clone();
 
-impl BufferExt for [u8] {
-    fn clear(&mut self) {
-        self.iter_mut().for_each(|byte| *byte = 0)
+                async {
+                    if let Err(error) = handover(listener, state).await {
+                        tracing::error!("Can't start handover socket: {error}");
+                    }
+                }
+            });
+        }
+        Ok(None) => {}
+        Err(error) => tracing::error!("Can't start handover socket: {error}"),
+    }
+
+    if let Some(endpoint) = state.quic.clone() {
+        spawn(quic_server(state.clone(), endpoint));
+    }
+
+    let peering = Peering::start(state.clone(), inherited.listeners).await;
+
+    if let Some(discovery_config) = state.config.discovery.clone() {
+        spawn(discovery(discovery_config, state.clone(), discovered));
+    }
+
+    spawn(async {
+        if let Err(error) = reload(peering, discoveries).await {
+            tracing::error!("Can't watch for config reloads: {error}");
+        }
+    });
+
+    // the listeners and the initial taps exist by now
+    #[cfg(target_os = "linux")]
+    if let Some(privileges_config) = &state.config.privileges {
+        privileges::drop_privileges(privileges_config, &state.config)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    if state.config.sandbox.seccomp {
+        sandbox::install_seccomp()?;
+    }
+
+    let shutdown = shutdown(state.clone());
+
+    pin!(shutdown);
+
+    loop {
+        select! {
+            result = &mut shutdown => return Ok(result?),
+            _ = sleep(state.config.cache.interval()) => {}
+        }
+
+        if let Err(error) = Cache::from_state(&state)
+            .await
+            .save_changed(&state.config.cache, state.cache_key.as_ref())
+            .await
+        {
+            tracing::error!("Can't save cache: {error}");
+        }
     }
 }
@@ -0,0 +1,999 @@
+//! Configuration actions shared by the management socket, the peers and the http api.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io, mem,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
+
+use common::VrfId;
+use protocol::{
+    AclRule, Endpoint, Maintenance, MirrorTarget, Packet, PeerReport, PeerState, PeerStats,
+    Response, StatsReport, StatusReport, Vrf, VrfAction, VrfMode, VrfStats, VrfSync,
+};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::{
+    cache::Cache,
+    config::{SwitchId, VrfConfig},
+    events::{publish, Event},
+    link, mirror,
+    networkd::remove_vrf,
+    socket::client::ClientTable,
+    state::State,
+    switch_table::{MacAddress, Vlan},
+    tap::{tap, MAX_TAP_QUEUE_DEPTH},
+    MAX_BUFFER_SIZE,
+};
+
+// ids and versions only, far smaller than vrfs
+const DELETED_CHUNK_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    pub switch_id: SwitchId,
+    pub draining: bool,
+    pub path_mtu: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MacEntry {
+    pub vrf_id: VrfId,
+    pub vlan: Vlan,
+    pub mac: String,
+    pub switch_id: SwitchId,
+    pub learned: u64,
+}
+
+pub async fn list_vrfs(state: &State) -> Vec<Vrf> {
+    state.vrf_table.read().await.values().cloned().collect()
+}
+
+pub async fn list_peers(state: &State) -> Vec<Peer> {
+    let draining_peers = state.draining_peers.lock().unwrap().clone();
+    let path_mtus = state.path_mtus.lock().unwrap().clone();
+
+    state
+        .client_table
+        .read()
+        .await
+        .keys()
+        .map(|switch_id| Peer {
+            switch_id: *switch_id,
+            draining: draining_peers.contains(switch_id),
+            path_mtu: path_mtus.get(switch_id).copied(),
+        })
+        .collect()
+}
+
+/// Connected peers and the members of the vrfs of this switch, by switch id.
+pub async fn peer_reports(state: &State) -> Vec<PeerReport> {
+    let connected = state
+        .client_table
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<BTreeSet<_>>();
+    let mut switch_ids = connected.clone();
+
+    switch_ids.extend(
+        state
+            .vrf_table
+            .read()
+            .await
+            .values()
+            .flat_map(|vrf| vrf.members.iter().copied()),
+    );
+    switch_ids.remove(&state.config.switch_id);
+
+    let draining_peers = state.draining_peers.lock().unwrap().clone();
+    let peer_connections = state.peer_connections.lock().unwrap().clone();
+
+    switch_ids
+        .into_iter()
+        .map(|switch_id| {
+            let connection = peer_connections.get(&switch_id);
+            let connected = connected.contains(&switch_id);
+
+            PeerReport {
+                switch_id,
+                state: match (connected, draining_peers.contains(&switch_id)) {
+                    (false, _) => PeerState::Disconnected,
+                    (true, true) => PeerState::Draining,
+                    (true, false) => PeerState::Connected,
+                },
+                address: connection.and_then(|connection| connection.address),
+                ping_rtt: connected
+                    .then(|| state.metrics.ping_rtt(switch_id))
+                    .flatten(),
+                uptime: connection.map(|connection| connection.since.elapsed()),
+            }
+        })
+        .collect()
+}
+
+pub async fn list_macs(state: &State) -> Vec<MacEntry> {
+    state
+        .switch_table
+        .read()
+        .await
+        .entries()
+        .into_iter()
+        .map(|(vrf_id, vlan, mac, learned)| MacEntry {
+            vrf_id,
+            vlan,
+            mac: format_mac(&mac),
+            switch_id: learned.switch_id,
+            learned: learned.since,
+        })
+        .collect()
+}
+
+pub async fn list_vrf_macs(
+    state: &State,
+    vrf_id: VrfId,
+) -> Result<Vec<protocol::MacEntry>, String> {
+    if !state.vrf_table.read().await.contains_key(&vrf_id) {
+        return Err(format!("Vrf id {vrf_id} doesn't exist"));
+    }
+
+    let switch_table = state.switch_table.read().await;
+    let pinned = switch_table
+        .vrf_statics(vrf_id)
+        .into_iter()
+        .map(|(mac, switch_id)| protocol::MacEntry {
+            vlan: 0,
+            mac,
+            switch_id,
+            learned: 0,
+            pinned: true,
+        });
+    let learned = switch_table
+        .vrf_entries(vrf_id)
+        .into_iter()
+        .map(|(vlan, mac, learned)| protocol::MacEntry {
+            vlan,
+            mac,
+            switch_id: learned.switch_id,
+            learned: learned.since,
+            pinned: false,
+        });
+
+    Ok(pinned.chain(learned).collect())
+}
+
+/// Pin a mac of a vrf behind one of its members in the table of this switch.
+pub async fn pin_mac(
+    state: &State,
+    vrf_id: VrfId,
+    mac: MacAddress,
+    switch_id: SwitchId,
+) -> Result<(), String> {
+    match state.vrf_table.read().await.get(&vrf_id) {
+        None => return Err(format!("Vrf id {vrf_id} doesn't exist")),
+        Some(vrf) if !vrf.members.contains(&switch_id) => {
+            return Err(format!(
+                "Switch id {switch_id} isn't a member of vrf id {vrf_id}"
+            ))
+        }
+        Some(_) => {}
+    }
+
+    state
+        .switch_table
+        .write()
+        .await
+        .shard(vrf_id)
+        .pin(mac, switch_id);
+
+    Ok(())
+}
+
+pub async fn unpin_mac(state: &State, vrf_id: VrfId, mac: MacAddress) -> Result<(), String> {
+    if !state.vrf_table.read().await.contains_key(&vrf_id) {
+        return Err(format!("Vrf id {vrf_id} doesn't exist"));
+    }
+
+    if !state.switch_table.write().await.shard(vrf_id).unpin(&mac) {
+        return Err(format!(
+            "Mac {} isn't pinned in vrf id {vrf_id}",
+            protocol::mac::format(&mac)
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn list_acl(state: &State, vrf_id: VrfId) -> Result<Vec<AclRule>, String> {
+    if !state.vrf_table.read().await.contains_key(&vrf_id) {
+        return Err(format!("Vrf id {vrf_id} doesn't exist"));
+    }
+
+    Ok(state.acls.vrf(vrf_id).rules())
+}
+
+/// Insert an acl rule of a vrf on this switch, at the end when there's no position.
+pub async fn add_acl_rule(
+    state: &State,
+    vrf_id: VrfId,
+    rule: AclRule,
+    position: Option<u32>,
+) -> Result<(), String> {
+    if !state.vrf_table.read().await.contains_key(&vrf_id) {
+        return Err(format!("Vrf id {vrf_id} doesn't exist"));
+    }
+
+    state
+        .acls
+        .vrf(vrf_id)
+        .insert(rule, position.map(|position| position as usize))
+}
+
+pub async fn remove_acl_rule(state: &State, vrf_id: VrfId, index: u32) -> Result<(), String> {
+    if !state.vrf_table.read().await.contains_key(&vrf_id) {
+        return Err(format!("Vrf id {vrf_id} doesn't exist"));
+    }
+
+    state.acls.vrf(vrf_id).remove(index as usize).map(|_| ())
+}
+
+/// Mirror the frames of a vrf with a tap on this switch to a pcap file or a tap.
+pub async fn start_mirror(
+    state: &State,
+    vrf_id: VrfId,
+    target: MirrorTarget,
+) -> Result<(), String> {
+    let Some(vrf) = state.vrf_table.read().await.get(&vrf_id).cloned() else {
+        return Err(format!("Vrf id {vrf_id} doesn't exist"));
+    };
+
+    if !vrf.members.contains(&state.config.switch_id) {
+        return Err(format!("Vrf id {vrf_id} has no tap on this switch"));
+    }
+
+    mirror::start(state, &vrf, target).await
+}
+
+pub fn stop_mirror(state: &State, vrf_id: VrfId) -> Result<(), String> {
+    if !state.mirrors.vrf(vrf_id).stop() {
+        return Err(format!("Vrf id {vrf_id} isn't mirrored"));
+    }
+
+    Ok(())
+}
+
+/// Persist the vrf and mac tables now, returning how many of each were saved.
+pub async fn save(state: &State) -> io::Result<(usize, usize)> {
+    if !state.config.cache.persist {
+        return Err(io::Error::other(
+            "The tables aren't persisted, see cache.persist",
+        ));
+    }
+
+    let cache = Cache::from_state(state).await;
+
+    cache
+        .save(&state.config.cache, state.cache_key.as_ref())
+        .await?;
+
+    Ok((cache.vrf_table.len(), cache.switch_table.entries().len()))
+}
+
+pub async fn stats(state: &State) -> StatsReport {
+    let learned_macs = state
+        .switch_table
+        .read()
+        .await
+        .usage()
+        .map(|(vrf_id, entries, _)| (vrf_id, entries))
+        .collect::<HashMap<_, _>>();
+
+    StatsReport {
+        vrfs: state
+            .metrics
+            .vrfs()
+            .into_iter()
+            .map(|(vrf_id, metrics)| VrfStats {
+                vrf_id,
+                received: metrics.received.stats(),
+                sent: metrics.sent.stats(),
+                flooded: metrics.flooded(),
+                dropped: metrics.dropped(),
+                shaped: metrics.shaped(),
+                learned_macs: learned_macs.get(&vrf_id).copied().unwrap_or_default(),
+            })
+            .collect(),
+        peers: state
+            .metrics
+            .peers()
+            .into_iter()
+            .map(|(switch_id, metrics)| PeerStats {
+                switch_id,
+                received: metrics.received.stats(),
+                sent: metrics.sent.stats(),
+                send_failures: metrics.send_failures(),
+            })
+            .collect(),
+    }
+}
+
+pub async fn status(state: &State) -> StatusReport {
+    let mut peers = state
+        .client_table
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let mut draining_peers = state
+        .draining_peers
+        .lock()
+        .unwrap()
+        .iter()
+        .copied()
+        .collect::<Vec<_>>();
+    let mut path_mtus = state
+        .path_mtus
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(switch_id, mtu)| (*switch_id, *mtu))
+        .collect::<Vec<_>>();
+    let mut vrfs = state
+        .tap_table
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+
+    peers.sort_unstable();
+    draining_peers.sort_unstable();
+    path_mtus.sort_unstable();
+    vrfs.sort_unstable();
+
+    let dropped_frames = vrfs
+        .iter()
+        .map(|vrf_id| (*vrf_id, state.metrics.vrf(*vrf_id).dropped()))
+        .filter(|(_, dropped)| *dropped > 0)
+        .collect();
+
+    StatusReport {
+        switch_id: state.config.switch_id,
+        draining: state.draining.load(Ordering::Relaxed),
+        peers,
+        draining_peers,
+        path_mtus,
+        vrfs,
+        degraded_vrfs: state
+            .degraded_taps
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect(),
+        dropped_frames,
+    }
+}
+
+pub async fn flush_macs(state: &State, vrf_id: Option<VrfId>) {
+    state.switch_table.read().await.flush(vrf_id);
+}
+
+pub fn format_mac(mac: &MacAddress) -> String {
+    mac.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Apply a vrf action asked by a configuration client, then propagate it to the peers.
+pub async fn configure(state: &Arc<State>, vrf_action: VrfAction) -> Response {
+    // peers get the settings of the template, they may not know it
+    let vrf_action = match vrf_action {
+        VrfAction::Create(vrf) => match apply_template(state, *vrf) {
+            Ok(mut vrf) => {
+                // a vrf created again wins over the deletion peers may still hold
+                vrf.version = state
+                    .deleted_vrfs
+                    .lock()
+                    .unwrap()
+                    .get(&vrf.id)
+                    .map_or(0, |version| version + 1);
+
+                VrfAction::Create(Box::new(vrf))
+            }
+            Err(error) => return Response::Error(error),
+        },
+        vrf_action => vrf_action,
+    };
+    let response = apply_vrf_action(state, vrf_action.clone()).await;
+
+    // only propagate configuration changes that were valid locally
+    if matches!(response, Response::Ok) && !matches!(vrf_action, VrfAction::List(_)) {
+        broadcast_packet(&state.client_table, Packet::from(vrf_action)).await;
+    }
+
+    response
+}
+
+fn apply_template(state: &State, mut vrf: Vrf) -> Result<Vrf, String> {
+    if let Some(template) = &vrf.template {
+        let settings = state
+            .config
+            .templates
+            .get(template)
+            .ok_or_else(|| format!("Vrf template {template} doesn't exist"))?;
+
+        vrf.settings = vrf.settings.or(settings);
+    }
+
+    if let Some(ifname) = &vrf.settings.ifname {
+        if ifname.is_empty()
+            || ifname.len() >= libc::IFNAMSIZ
+            || ifname == "."
+            || ifname == ".."
+            || ifname.contains(['/', ':'])
+            || ifname.contains(char::is_whitespace)
+        {
+            return Err(format!("Invalid tap name {ifname}"));
+        }
+    }
+
+    // the tap sends frames with it
+    if vrf.settings.mac.is_some_and(|mac| mac[0] & 1 == 1) {
+        return Err(format!("Vrf {} can't have a multicast tap mac", vrf.name));
+    }
+
+    if vrf.settings.mode == Some(VrfMode::Tun)
+        && (vrf.settings.mac.is_some() || vrf.settings.vxlan.is_some())
+    {
+        return Err(format!(
+            "Vrf {} is in tun mode, it has no mac and can't use vxlan",
+            vrf.name
+        ));
+    }
+
+    if vrf
+        .settings
+        .queue_depth
+        .is_some_and(|queue_depth| !(1..=MAX_TAP_QUEUE_DEPTH).contains(&queue_depth))
+    {
+        return Err(format!(
+            "Vrf {} can only queue between 1 and {MAX_TAP_QUEUE_DEPTH} frames",
+            vrf.name
+        ));
+    }
+
+    // a second of bandwidth has to hold the largest frame, or none would ever get through
+    let max_frame_size = vrf
+        .settings
+        .max_frame_size()
+        .map_or(MAX_BUFFER_SIZE as u64, u64::from);
+
+    if [vrf.settings.bandwidth, vrf.settings.peer_bandwidth]
+        .into_iter()
+        .flatten()
+        .any(|bandwidth| bandwidth < max_frame_size)
+    {
+        return Err(format!(
+            "Vrf {} needs a bandwidth of at least its largest frame, {max_frame_size} bytes",
+            vrf.name
+        ));
+    }
+
+    // overlay packets never carry more than a buffer
+    if vrf
+        .settings
+        .max_frame_size()
+        .is_some_and(|max_frame_size| max_frame_size as usize > MAX_BUFFER_SIZE)
+    {
+        return Err(format!(
+            "Vrf {} can't carry frames over {MAX_BUFFER_SIZE} bytes",
+            vrf.name
+        ));
+    }
+
+    Ok(vrf)
+}
+
+/// Create a vrf with the first free id of this switch's range, ranges don't overlap between switches.
+pub async fn allocate_vrf(state: &Arc<State>, mut vrf: Vrf) -> Result<VrfId, String> {
+    let range = state.config.vrf_id_range().ok_or_else(|| {
+        format!(
+            "Switch id {} has no default vrf id range, set vrf_id_range",
+            state.config.switch_id
+        )
+    })?;
+    vrf.id = {
+        let vrf_table = state.vrf_table.read().await;
+
+        range
+            .clone()
+            .find(|id| !vrf_table.contains_key(id))
+            .ok_or_else(|| {
+                format!(
+                    "No vrf id left between {} and {}",
+                    range.start(),
+                    range.end()
+                )
+            })?
+    };
+
+    let id = vrf.id;
+
+    into_result(configure(state, VrfAction::Create(Box::new(vrf))).await)?;
+
+    Ok(id)
+}
+
+pub async fn apply_vrf_action(state: &Arc<State>, vrf_action: VrfAction) -> Response {
+    let server_switch_id = state.config.switch_id;
+
+    match vrf_action {
+        // listing doesn't change anything, each transport sends the list its own way
+        VrfAction::List(_) => Response::Ok,
+        // allocations are turned into a create by the switch the client is connected to
+        VrfAction::Allocate { .. } | VrfAction::Allocated { .. } => {
+            Response::Error("Unexpected vrf action".to_string())
+        }
+        VrfAction::Create(vrf) => {
+            let mut vrf_table = state.vrf_table.write().await;
+
+            if vrf_table.contains_key(&vrf.id) {
+                return Response::Error(format!("Vrf id {} already exists", vrf.id));
+            }
+
+            if vrf_table.values().any(|vrf_| vrf_.name == vrf.name) {
+                return Response::Error(format!("Vrf name {} already exists", vrf.name));
+            }
+
+            if let Some(ifname) = &vrf.settings.ifname {
+                if let Some(vrf_) = vrf_table
+                    .values()
+                    .find(|vrf_| vrf_.settings.ifname.as_ref() == Some(ifname))
+                {
+                    return Response::Error(format!(
+                        "Vrf {} already uses the tap name {ifname}",
+                        vrf_.name
+                    ));
+                }
+            }
+
+            state
+                .suspension(vrf.id)
+                .store(vrf.suspended, Ordering::Relaxed);
+
+            if vrf.members.contains(&server_switch_id) {
+                let mut tap_table = state.tap_table.write().await;
+
+                tap_table.insert(vrf.id, tap(*vrf.clone(), state.clone()).await);
+            }
+
+            publish(Event::VrfCreated {
+                id: vrf.id,
+                name: vrf.name.clone(),
+            });
+
+            state.deleted_vrfs.lock().unwrap().remove(&vrf.id);
+            vrf_table.insert(vrf.id, *vrf);
+
+            Response::Ok
+        }
+        VrfAction::Delete { id } => {
+            let mut vrf_table = state.vrf_table.write().await;
+            let mut tap_table = state.tap_table.write().await;
+            let mut switch_table = state.switch_table.write().await;
+
+            tap_table.remove(&id);
+            switch_table.remove(&id);
+            state.ip_table.lock().unwrap().remove(&id);
+            state.degraded_taps.lock().unwrap().remove(&id);
+            state.suspensions.lock().unwrap().remove(&id);
+            state.acls.remove(id);
+            state.mirrors.remove(id);
+            state.metrics.remove_vrf(id);
+            state.handover_fds.remove_tap(id);
+            remove_networkd_files(state, id).await;
+
+            for client in state.client_table.read().await.values() {
+                client.remove_vrf(id);
+            }
+
+            let Some(vrf) = vrf_table.remove(&id) else {
+                return Response::Error(format!("Vrf id {id} doesn't exist"));
+            };
+            let mut deleted_vrfs = state.deleted_vrfs.lock().unwrap();
+            let deleted = deleted_vrfs.entry(id).or_default();
+
+            *deleted = (*deleted).max(vrf.version + 1);
+
+            publish(Event::VrfDeleted { id });
+
+            Response::Ok
+        }
+        VrfAction::AddMember { id, members } => {
+            let mut vrf_table = state.vrf_table.write().await;
+            let Some(vrf) = vrf_table.get_mut(&id) else {
+                return Response::Error(format!("Vrf id {id} doesn't exist"));
+            };
+
+            publish(Event::VrfMembersAdded {
+                id,
+                members: members.clone(),
+            });
+
+            for new_member in members {
+                if new_member == server_switch_id {
+                    let mut tap_table = state.tap_table.write().await;
+
+                    tap_table.insert(vrf.id, tap(vrf.clone(), state.clone()).await);
+                }
+
+                if !vrf.members.contains(&new_member) {
+                    vrf.members.push(new_member);
+                }
+            }
+
+            vrf.version += 1;
+
+            Response::Ok
+        }
+        VrfAction::Describe { id, metadata } => {
+            let mut vrf_table = state.vrf_table.write().await;
+            let Some(vrf) = vrf_table.get_mut(&id) else {
+                return Response::Error(format!("Vrf id {id} doesn't exist"));
+            };
+
+            vrf.metadata = metadata;
+            vrf.version += 1;
+
+            Response::Ok
+        }
+        VrfAction::Suspend { id } => suspend_vrf(state, id, true).await,
+        VrfAction::Resume { id } => suspend_vrf(state, id, false).await,
+        VrfAction::RemoveMember { id, members } => {
+            let mut vrf_table = state.vrf_table.write().await;
+            let Some(vrf) = vrf_table.get_mut(&id) else {
+                return Response::Error(format!("Vrf id {id} doesn't exist"));
+            };
+
+            publish(Event::VrfMembersRemoved {
+                id,
+                members: members.clone(),
+            });
+
+            for old_member in members {
+                if old_member == server_switch_id {
+                    let mut tap_table = state.tap_table.write().await;
+
+                    tap_table.remove(&vrf.id);
+                    state.degraded_taps.lock().unwrap().remove(&vrf.id);
+                    state.handover_fds.remove_tap(vrf.id);
+                    remove_networkd_files(state, vrf.id).await;
+                }
+
+                vrf.members.retain(|member| *member != old_member);
+            }
+
+            vrf.version += 1;
+
+            Response::Ok
+        }
+    }
+}
+
+async fn suspend_vrf(state: &State, id: VrfId, suspended: bool) -> Response {
+    let mut vrf_table = state.vrf_table.write().await;
+    let Some(vrf) = vrf_table.get_mut(&id) else {
+        return Response::Error(format!("Vrf id {id} doesn't exist"));
+    };
+
+    vrf.suspended = suspended;
+    vrf.version += 1;
+
+    // the taps and macs are kept, only the pipeline stops forwarding
+    if state.suspension(id).swap(suspended, Ordering::Relaxed) != suspended {
+        if suspended {
+            tracing::info!("Vrf {id} suspended");
+            publish(Event::VrfSuspended { id });
+        } else {
+            tracing::info!("Vrf {id} resumed");
+            publish(Event::VrfResumed { id });
+        }
+    }
+
+    Response::Ok
+}
+
+/// What this switch knows of the vrfs, for a peer to reconcile its own with.
+pub async fn vrf_syncs(state: &State) -> Vec<VrfSync> {
+    let vrfs = list_vrfs(state).await;
+    let deleted = state
+        .deleted_vrfs
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, version)| (*id, *version))
+        .collect::<Vec<_>>();
+
+    vrfs.chunks(10)
+        .map(|vrfs| VrfSync {
+            vrfs: vrfs.to_vec(),
+            deleted: Vec::new(),
+        })
+        .chain(deleted.chunks(DELETED_CHUNK_SIZE).map(|deleted| VrfSync {
+            vrfs: Vec::new(),
+            deleted: deleted.to_vec(),
+        }))
+        .collect()
+}
+
+/// Reconcile the vrf table with what a peer knows, the highest version of a vrf wins, and the
+/// switch with the lowest id when both changed it as many times.
+pub async fn sync_vrfs(state: &Arc<State>, peer_switch_id: SwitchId, vrf_sync: VrfSync) {
+    for (id, version) in vrf_sync.deleted {
+        let local = state.vrf_table.read().await.get(&id).map(|vrf| vrf.version);
+
+        if local.is_some_and(|local| local >= version) {
+            continue;
+        }
+
+        if local.is_some() {
+            tracing::info!("Vrf id {id} was deleted while away from switch id {peer_switch_id}");
+            apply_vrf_action(state, VrfAction::Delete { id }).await;
+        }
+
+        // passed on to the peers that missed the deletion too
+        let mut deleted_vrfs = state.deleted_vrfs.lock().unwrap();
+        let deleted = deleted_vrfs.entry(id).or_default();
+
+        *deleted = (*deleted).max(version);
+    }
+
+    for vrf in vrf_sync.vrfs {
+        let local = state.vrf_table.read().await.get(&vrf.id).cloned();
+        let newer = match &local {
+            Some(local) => {
+                vrf.version > local.version
+                    || (vrf.version == local.version
+                        && peer_switch_id < state.config.switch_id
+                        && vrf != *local)
+            }
+            None => state
+                .deleted_vrfs
+                .lock()
+                .unwrap()
+                .get(&vrf.id)
+                .is_none_or(|deleted| *deleted < vrf.version),
+        };
+
+        if !newer {
+            continue;
+        }
+
+        let name = vrf.name.clone();
+
+        match replace_vrf(state, local, vrf).await {
+            Ok(()) => tracing::info!("Synced vrf {name} from switch id {peer_switch_id}"),
+            Err(error) => {
+                tracing::warn!("Can't sync vrf {name} from switch id {peer_switch_id}: {error}")
+            }
+        }
+    }
+}
+
+async fn replace_vrf(state: &Arc<State>, local: Option<Vrf>, vrf: Vrf) -> Result<(), String> {
+    // the tap is only made again when something it's made from changed
+    if local.as_ref().is_some_and(|local| {
+        local.name == vrf.name
+            && local.template == vrf.template
+            && local.members == vrf.members
+            && local.settings == vrf.settings
+    }) {
+        suspend_vrf(state, vrf.id, vrf.suspended).await;
+
+        if let Some(local) = state.vrf_table.write().await.get_mut(&vrf.id) {
+            local.metadata = vrf.metadata;
+            local.version = vrf.version;
+        }
+
+        return Ok(());
+    }
+
+    if local.is_some() {
+        apply_vrf_action(state, VrfAction::Delete { id: vrf.id }).await;
+    }
+
+    into_result(apply_vrf_action(state, VrfAction::Create(Box::new(vrf))).await)
+}
+
+/// Bring the vrfs the config file declares to their declaration, and delete the ones it declared
+/// before and no longer does. The changes reach the peers like those of a configuration client.
+pub async fn declare_vrfs(state: &Arc<State>, declared: &[VrfConfig]) {
+    let previous = mem::replace(
+        &mut *state.declared_vrfs.lock().unwrap(),
+        declared.iter().map(|vrf_config| vrf_config.id).collect(),
+    );
+
+    for id in previous {
+        if declared.iter().any(|vrf_config| vrf_config.id == id)
+            || !state.vrf_table.read().await.contains_key(&id)
+        {
+            continue;
+        }
+
+        match configure(state, VrfAction::Delete { id }).await {
+            Response::Ok => tracing::info!("Deleted vrf id {id}, it's no longer declared"),
+            Response::Error(error) => tracing::error!("Can't delete vrf id {id}: {error}"),
+        }
+    }
+
+    for vrf_config in declared {
+        if let Err(error) = declare_vrf(state, vrf_config.clone()).await {
+            tracing::error!("Can't declare vrf {}: {error}", vrf_config.name);
+        }
+    }
+}
+
+async fn declare_vrf(state: &Arc<State>, vrf_config: VrfConfig) -> Result<(), String> {
+    let mut vrf = apply_template(
+        state,
+        Vrf {
+            id: vrf_config.id,
+            name: vrf_config.name,
+            members: vrf_config.members,
+            template: vrf_config.template,
+            settings: vrf_config.settings,
+            metadata: Default::default(),
+            suspended: false,
+            version: 0,
+        },
+    )?;
+    let id = vrf.id;
+    let Some(local) = state.vrf_table.read().await.get(&id).cloned() else {
+        tracing::info!("Creating declared vrf {}", vrf.name);
+
+        return into_result(configure(state, VrfAction::Create(Box::new(vrf))).await);
+    };
+
+    // what operators changed through the cli is kept
+    vrf.metadata = local.metadata.clone();
+    vrf.suspended = local.suspended;
+
+    if local.name != vrf.name || local.template != vrf.template || local.settings != vrf.settings {
+        tracing::info!("Creating declared vrf {} again, it changed", vrf.name);
+        into_result(configure(state, VrfAction::Delete { id }).await)?;
+
+        return into_result(configure(state, VrfAction::Create(Box::new(vrf))).await);
+    }
+
+    let added = vrf
+        .members
+        .iter()
+        .filter(|member| !local.members.contains(member))
+        .copied()
+        .collect::<Vec<_>>();
+    let removed = local
+        .members
+        .iter()
+        .filter(|member| !vrf.members.contains(member))
+        .copied()
+        .collect::<Vec<_>>();
+
+    if !added.is_empty() {
+        into_result(configure(state, VrfAction::AddMember { id, members: added }).await)?;
+    }
+
+    if !removed.is_empty() {
+        into_result(
+            configure(
+                state,
+                VrfAction::RemoveMember {
+                    id,
+                    members: removed,
+                },
+            )
+            .await,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn into_result(response: Response) -> Result<(), String> {
+    match response {
+        Response::Ok => Ok(()),
+        Response::Error(error) => Err(error),
+    }
+}
+
+async fn remove_networkd_files(state: &State, vrf_id: VrfId) {
+    if let Some(networkd) = &state.config.networkd {
+        if let Err(error) = remove_vrf(networkd, vrf_id).await {
+            tracing::warn!("Can't remove the networkd files of the vrf {vrf_id}: {error}");
+        }
+    }
+}
+
+pub async fn set_maintenance(state: &State, maintenance: Maintenance) {
+    let draining = maintenance == Maintenance::Drain;
+    let switch_id = state.config.switch_id;
+
+    if state.draining.swap(draining, Ordering::Relaxed) != draining {
+        if draining {
+            tracing::info!("Draining the switch");
+            publish(Event::SwitchDraining { switch_id });
+        } else {
+            tracing::info!("Activated the switch");
+            publish(Event::SwitchActivated { switch_id });
+        }
+    }
+
+    // peers stop flooding to a draining switch and let its macs age out
+    broadcast_packet(&state.client_table, Packet::from(maintenance)).await;
+}
+
+/// Id of a vrf with a working tap on this switch, the only ones endpoints can be plugged into.
+pub async fn local_vrf(state: &State, name: &str) -> Result<Vrf, String> {
+    let vrf = state
+        .vrf_table
+        .read()
+        .await
+        .values()
+        .find(|vrf| vrf.name == name)
+        .cloned()
+        .ok_or_else(|| format!("Vrf name {name} doesn't exist"))?;
+
+    if !state.tap_table.read().await.contains_key(&vrf.id)
+        || state.degraded_taps.lock().unwrap().contains_key(&vrf.id)
+    {
+        return Err(format!("Vrf {name} has no tap on this switch"));
+    }
+
+    if !link::owns_netns(state.config.dataplane, &vrf) {
+        return Err(format!("Vrf {name} isn't in a netns of its own"));
+    }
+
+    Ok(vrf)
+}
+
+/// Plug a container namespace into a local vrf, endpoints stay local.
+pub async fn attach_endpoint(state: &State, endpoint: Endpoint) -> Result<MacAddress, String> {
+    let vrf = local_vrf(state, &endpoint.vrf).await?;
+    let mac = link::attach_endpoint(state.config.dataplane, vrf.id, vrf.settings.mtu, &endpoint)
+        .await
+        .map_err(|error| format!("Can't attach the endpoint to vrf {}: {error}", endpoint.vrf))?;
+
+    tracing::info!(
+        "Attached {} in {} to vrf {}",
+        endpoint.ifname,
+        endpoint.netns.display(),
+        endpoint.vrf
+    );
+
+    Ok(mac)
+}
+
+pub async fn detach_endpoint(netns: &Path, ifname: &str) -> Result<(), String> {
+    link::detach_endpoint(netns, ifname)
+        .await
+        .map_err(|error| format!("Can't detach the endpoint: {error}"))?;
+
+    tracing::info!("Detached {ifname} in {}", netns.display());
+
+    Ok(())
+}
+
+async fn broadcast_packet(client_table: &RwLock<ClientTable>, packet: Packet) {
+    let client_table = client_table.read().await;
+
+    for (switch_id, client) in client_table.iter() {
+        if let Err(error) = client.send(packet.clone()).await {
+            tracing::error!("Can't broadcast packet to switch id {}: {error}", switch_id);
+        }
+    }
+}
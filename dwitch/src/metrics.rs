@@ -0,0 +1,398 @@
+//! Counters of the daemon, served in the prometheus text format.
+//!
+//! Vrfs count the frames read from and written to their tap, the ones they flooded and the ones
+//! their queue dropped, peers the data packets sent to and received from them. Counters only grow, across reconnects, and are forgotten with their vrf.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Display, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use common::VrfId;
+use protocol::{Packet, TrafficStats};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
+
+use crate::{
+    config::{MetricsConfig, SwitchId},
+    state::State,
+};
+
+// how a counter is read from the metrics of a vrf or a peer
+type Field<T> = fn(&T) -> &Counter;
+type Unit = (&'static str, fn(&Counter) -> u64);
+
+// each counter is served as its packets and its bytes
+const UNITS: [Unit; 2] = [
+    ("packets", |counter| counter.packets.load(Ordering::Relaxed)),
+    ("bytes", |counter| counter.bytes.load(Ordering::Relaxed)),
+];
+
+#[derive(Default)]
+pub struct Counter {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counter {
+    pub fn count(&self, length: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(length as u64, Ordering::Relaxed);
+    }
+
+    /// Count a packet if it carries a frame, control packets aren't traffic.
+    pub fn count_data(&self, packet: &Packet) {
+        if let Packet::Data(data) = packet {
+            self.count(data.data.len());
+        }
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VrfMetrics {
+    /// Frames read from the tap.
+    pub received: Counter,
+    /// Frames written to the tap.
+    pub sent: Counter,
+    /// Frames from the tap sent to every member.
+    flooded: AtomicU64,
+    /// Frames for the tap dropped because its queue was full.
+    dropped: AtomicU64,
+    /// Frames toward the peers dropped by the bandwidth limits.
+    shaped: AtomicU64,
+}
+
+impl VrfMetrics {
+    pub fn flood(&self) {
+        self.flooded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn flooded(&self) -> u64 {
+        self.flooded.load(Ordering::Relaxed)
+    }
+
+    pub fn drop_frame(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn shape(&self) {
+        self.shaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn shaped(&self) -> u64 {
+        self.shaped.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct PeerMetrics {
+    pub received: Counter,
+    pub sent: Counter,
+    /// Packets that couldn't be queued or written toward the peer.
+    send_failures: AtomicU64,
+    // in microseconds, zero until the first ping came back
+    ping_rtt: AtomicU64,
+}
+
+impl PeerMetrics {
+    pub fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_failures(&self) -> u64 {
+        self.send_failures.load(Ordering::Relaxed)
+    }
+
+    fn ping_rtt(&self) -> Option<Duration> {
+        match self.ping_rtt.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+
+    pub fn set_ping_rtt(&self, rtt: Duration) {
+        self.ping_rtt
+            .store(rtt.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of the vrfs and peers, each pipeline and connection keeps its own handle.
+#[derive(Default)]
+pub struct Metrics {
+    vrfs: Mutex<HashMap<VrfId, Arc<VrfMetrics>>>,
+    peers: Mutex<HashMap<SwitchId, Arc<PeerMetrics>>>,
+    switch_id_conflicts: AtomicU64,
+}
+
+impl Metrics {
+    pub fn vrf(&self, vrf_id: VrfId) -> Arc<VrfMetrics> {
+        self.vrfs.lock().unwrap().entry(vrf_id).or_default().clone()
+    }
+
+    pub fn remove_vrf(&self, vrf_id: VrfId) {
+        self.vrfs.lock().unwrap().remove(&vrf_id);
+    }
+
+    /// Metrics of each vrf, by id.
+    pub fn vrfs(&self) -> Vec<(VrfId, Arc<VrfMetrics>)> {
+        let mut vrfs = self
+            .vrfs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(vrf_id, metrics)| (*vrf_id, metrics.clone()))
+            .collect::<Vec<_>>();
+
+        vrfs.sort_by_key(|(vrf_id, _)| *vrf_id);
+        vrfs
+    }
+
+    /// Metrics of each peer, by switch id.
+    pub fn peers(&self) -> Vec<(SwitchId, Arc<PeerMetrics>)> {
+        let mut peers = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(switch_id, metrics)| (*switch_id, metrics.clone()))
+            .collect::<Vec<_>>();
+
+        peers.sort_by_key(|(switch_id, _)| *switch_id);
+        peers
+    }
+
+    /// Round trip time of the last ping answered by a peer, if one was.
+    pub fn ping_rtt(&self, switch_id: SwitchId) -> Option<Duration> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&switch_id)
+            .and_then(|metrics| metrics.ping_rtt())
+    }
+
+    pub fn switch_id_conflict(&self) {
+        self.switch_id_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn peer(&self, switch_id: SwitchId) -> Arc<PeerMetrics> {
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(switch_id)
+            .or_default()
+            .clone()
+    }
+}
+
+pub async fn metrics(config: MetricsConfig, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(config.listen).await?;
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                spawn(metrics_connection(stream, state.clone()));
+            }
+            Err(error) => {
+                tracing::error!("Can't accept metrics scrape: {error}");
+            }
+        }
+    }
+}
+
+async fn metrics_connection(mut stream: TcpStream, state: Arc<State>) {
+    let mut buffer = [0u8; 1024];
+    let length = match stream.read(&mut buffer).await {
+        Ok(length) => length,
+        Err(error) => {
+            tracing::warn!("Can't read metrics scrape: {error}");
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&buffer[..length]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", render(&state).await),
+        _ => ("404 Not Found", "not found".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    if let Err(error) = stream.write_all(response.as_bytes()).await {
+        tracing::warn!("Can't answer metrics scrape: {error}");
+    }
+}
+
+async fn render(state: &State) -> String {
+    let mut body = String::new();
+    let connected_peers = state.client_table.read().await.len();
+    let learned_macs = state
+        .switch_table
+        .read()
+        .await
+        .usage()
+        .map(|(vrf_id, entries, _)| (vrf_id, entries))
+        .collect::<Vec<_>>();
+    let vrfs = state.metrics.vrfs();
+    let peers = state.metrics.peers();
+
+    let vrf_counters: [(_, _, Field<VrfMetrics>); 2] = [
+        ("received", "Frames read from the tap of a vrf", |metrics| {
+            &metrics.received
+        }),
+        ("sent", "Frames written to the tap of a vrf", |metrics| {
+            &metrics.sent
+        }),
+    ];
+    let peer_counters: [(_, _, Field<PeerMetrics>); 2] = [
+        ("received", "Data packets received from a peer", |metrics| {
+            &metrics.received
+        }),
+        ("sent", "Data packets sent to a peer", |metrics| {
+            &metrics.sent
+        }),
+    ];
+
+    for (direction, help, counter) in vrf_counters {
+        for (unit, load) in UNITS {
+            family(
+                &mut body,
+                &format!("dwitch_vrf_{direction}_{unit}_total"),
+                "counter",
+                help,
+                vrfs.iter()
+                    .map(|(vrf_id, metrics)| (format!("vrf=\"{vrf_id}\""), load(counter(metrics)))),
+            );
+        }
+    }
+
+    for (direction, help, counter) in peer_counters {
+        for (unit, load) in UNITS {
+            family(
+                &mut body,
+                &format!("dwitch_peer_{direction}_{unit}_total"),
+                "counter",
+                help,
+                peers.iter().map(|(switch_id, metrics)| {
+                    (format!("switch_id=\"{switch_id}\""), load(counter(metrics)))
+                }),
+            );
+        }
+    }
+
+    family(
+        &mut body,
+        "dwitch_vrf_flooded_packets_total",
+        "counter",
+        "Frames from the tap of a vrf sent to every member",
+        vrfs.iter()
+            .map(|(vrf_id, metrics)| (format!("vrf=\"{vrf_id}\""), metrics.flooded())),
+    );
+    family(
+        &mut body,
+        "dwitch_vrf_dropped_packets_total",
+        "counter",
+        "Frames for the tap of a vrf dropped because its queue was full",
+        vrfs.iter()
+            .map(|(vrf_id, metrics)| (format!("vrf=\"{vrf_id}\""), metrics.dropped())),
+    );
+    family(
+        &mut body,
+        "dwitch_vrf_shaped_packets_total",
+        "counter",
+        "Frames of a vrf toward the peers dropped by its bandwidth limits",
+        vrfs.iter()
+            .map(|(vrf_id, metrics)| (format!("vrf=\"{vrf_id}\""), metrics.shaped())),
+    );
+    family(
+        &mut body,
+        "dwitch_peer_send_failures_total",
+        "counter",
+        "Packets that couldn't be queued or written toward a peer",
+        peers.iter().map(|(switch_id, metrics)| {
+            (
+                format!("switch_id=\"{switch_id}\""),
+                metrics.send_failures(),
+            )
+        }),
+    );
+    family(
+        &mut body,
+        "dwitch_peer_ping_rtt_seconds",
+        "gauge",
+        "Round trip time of the last ping answered by a peer this switch dials",
+        peers.iter().filter_map(|(switch_id, metrics)| {
+            metrics
+                .ping_rtt()
+                .map(|rtt| (format!("switch_id=\"{switch_id}\""), rtt.as_secs_f64()))
+        }),
+    );
+    family(
+        &mut body,
+        "dwitch_learned_macs",
+        "gauge",
+        "Mac addresses learned in a vrf",
+        learned_macs
+            .into_iter()
+            .map(|(vrf_id, entries)| (format!("vrf=\"{vrf_id}\""), entries)),
+    );
+    family(
+        &mut body,
+        "dwitch_switch_id_conflicts_total",
+        "counter",
+        "Connections refused for claiming a switch id that's taken",
+        [(
+            String::new(),
+            state.metrics.switch_id_conflicts.load(Ordering::Relaxed),
+        )],
+    );
+    family(
+        &mut body,
+        "dwitch_connected_peers",
+        "gauge",
+        "Peers with a connection up",
+        [(String::new(), connected_peers)],
+    );
+
+    body
+}
+
+fn family<T: Display>(
+    body: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, T)>,
+) {
+    let _ = writeln!(body, "# HELP {name} {help}.\n# TYPE {name} {kind}");
+
+    for (labels, value) in samples {
+        let _ = match labels.is_empty() {
+            true => writeln!(body, "{name} {value}"),
+            false => writeln!(body, "{name}{{{labels}}} {value}"),
+        };
+    }
+}
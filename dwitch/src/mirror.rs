@@ -0,0 +1,285 @@
+//! Mirrors of the vrfs, copies of the frames going through the tap of a vrf written to a rotating
+//! pcap file or to a tap of their own, so they can be looked at without touching the workloads.
+//!
+//! The pipeline only queues the copies, a mirror that can't keep up loses some of them instead of
+//! slowing the vrf down.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use common::VrfId;
+use protocol::{MirrorTarget, Vrf, VrfMode};
+use serde::Deserialize;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{unix::AsyncFd, AsyncWriteExt, BufWriter, Interest},
+    spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use crate::{instance, state::State, tap, MAX_BUFFER_SIZE};
+
+const MIRROR_DIRECTORY: &str = "/var/log";
+const MIRROR_QUEUE_DEPTH: usize = 1024;
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_HEADER_SIZE: u64 = 24;
+const PCAP_RECORD_HEADER_SIZE: u64 = 16;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+
+type FrameCopy = (SystemTime, Bytes);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    /// Directory the pcap files are written in, clients only name the files.
+    pub directory: PathBuf,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from(MIRROR_DIRECTORY).join(instance::suffixed("dwitch")),
+        }
+    }
+}
+
+/// Mirror of each vrf, shared with its pipeline.
+#[derive(Default)]
+pub struct Mirrors(Mutex<HashMap<VrfId, Mirror>>);
+
+impl Mirrors {
+    pub fn vrf(&self, vrf_id: VrfId) -> Mirror {
+        self.0.lock().unwrap().entry(vrf_id).or_default().clone()
+    }
+
+    pub fn remove(&self, vrf_id: VrfId) {
+        if let Some(mirror) = self.0.lock().unwrap().remove(&vrf_id) {
+            mirror.stop();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Mirror(Arc<RwLock<Option<Sender<FrameCopy>>>>);
+
+impl Mirror {
+    pub fn copy(&self, frame: &[u8]) {
+        if let Some(sender) = &*self.0.read().unwrap() {
+            let _ = sender.try_send((SystemTime::now(), Bytes::copy_from_slice(frame)));
+        }
+    }
+
+    /// Whether there was a mirror to stop, its writer ends once it wrote the queued copies.
+    pub fn stop(&self) -> bool {
+        self.0.write().unwrap().take().is_some()
+    }
+}
+
+/// Mirror the frames of a vrf to a target, replacing its previous mirror.
+pub async fn start(state: &State, vrf: &Vrf, target: MirrorTarget) -> Result<(), String> {
+    let mode = vrf.settings.mode.unwrap_or_default();
+    let (sender, receiver) = channel(MIRROR_QUEUE_DEPTH);
+
+    match target {
+        MirrorTarget::Pcap {
+            name,
+            max_size,
+            max_files,
+        } => {
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                return Err(format!("Invalid pcap file name {name}"));
+            }
+
+            if max_size < PCAP_HEADER_SIZE + PCAP_RECORD_HEADER_SIZE + MAX_BUFFER_SIZE as u64 {
+                return Err(format!(
+                    "Pcap files need a size of at least {} bytes to hold a frame",
+                    PCAP_HEADER_SIZE + PCAP_RECORD_HEADER_SIZE + MAX_BUFFER_SIZE as u64
+                ));
+            }
+
+            let directory = &state.config.mirror.directory;
+
+            if let Err(error) = fs::create_dir_all(directory).await {
+                return Err(format!("Can't create {}: {error}", directory.display()));
+            }
+
+            let pcap = Pcap::create(
+                directory.join(&name),
+                mode,
+                max_size,
+                max_files.max(1) as usize,
+            )
+            .await
+            .map_err(|error| format!("Can't create the pcap file {name}: {error}"))?;
+
+            spawn(write_pcap(vrf.name.clone(), pcap, receiver));
+        }
+        MirrorTarget::Tap { ifname } => {
+            if ifname.is_empty()
+                || ifname.len() >= libc::IFNAMSIZ
+                || ifname == "."
+                || ifname == ".."
+                || ifname.contains(['/', ':'])
+                || ifname.contains(char::is_whitespace)
+            {
+                return Err(format!("Invalid tap name {ifname}"));
+            }
+
+            let tap = tap::open_tap(&ifname, mode)
+                .and_then(|fd| AsyncFd::new(File::from(fd)))
+                .map_err(|error| format!("Can't create the tap {ifname}: {error}"))?;
+
+            spawn(write_tap(vrf.name.clone(), tap, receiver));
+        }
+    }
+
+    state.mirrors.vrf(vrf.id).0.write().unwrap().replace(sender);
+
+    Ok(())
+}
+
+async fn write_pcap(vrf_name: String, mut pcap: Pcap, mut receiver: Receiver<FrameCopy>) {
+    let mut copies = Vec::new();
+
+    while receiver.recv_many(&mut copies, MIRROR_QUEUE_DEPTH).await > 0 {
+        for (time, frame) in copies.drain(..) {
+            if let Err(error) = pcap.write(time, &frame).await {
+                tracing::error!("Stopped mirroring the vrf {vrf_name}, can't write: {error}");
+                return;
+            }
+        }
+
+        // a reader following the file gets the frames as they come
+        if let Err(error) = pcap.file.flush().await {
+            tracing::error!("Stopped mirroring the vrf {vrf_name}, can't write: {error}");
+            return;
+        }
+    }
+}
+
+async fn write_tap(vrf_name: String, tap: AsyncFd<File>, mut receiver: Receiver<FrameCopy>) {
+    while let Some((_, frame)) = receiver.recv().await {
+        if let Err(error) = tap
+            .async_io(Interest::WRITABLE, |mut file| file.write(&frame))
+            .await
+        {
+            tracing::debug!("Can't write a mirrored frame of the vrf {vrf_name}: {error}");
+        }
+    }
+}
+
+// the current file at `path`, the older ones with the number of rotations since appended
+struct Pcap {
+    path: PathBuf,
+    file: BufWriter<fs::File>,
+    link_type: u32,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl Pcap {
+    async fn create(
+        path: PathBuf,
+        mode: VrfMode,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let link_type = match mode {
+            VrfMode::Tap => LINKTYPE_ETHERNET,
+            VrfMode::Tun => LINKTYPE_RAW,
+        };
+        let file = open(&path, link_type).await?;
+
+        Ok(Self {
+            path,
+            file,
+            link_type,
+            size: PCAP_HEADER_SIZE,
+            max_size,
+            max_files,
+        })
+    }
+
+    async fn write(&mut self, time: SystemTime, frame: &[u8]) -> io::Result<()> {
+        let size = PCAP_RECORD_HEADER_SIZE + frame.len() as u64;
+
+        if self.size + size > self.max_size {
+            self.rotate().await?;
+        }
+
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(size as usize);
+
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        self.file.write_all(&record).await?;
+        self.size += size;
+
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+
+        for index in (1..self.max_files).rev() {
+            let from = match index {
+                1 => self.path.clone(),
+                index => rotated(&self.path, index - 1),
+            };
+
+            match fs::rename(&from, rotated(&self.path, index)).await {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+
+        self.file = open(&self.path, self.link_type).await?;
+        self.size = PCAP_HEADER_SIZE;
+
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+
+    path.push(format!(".{index}"));
+    path.into()
+}
+
+// truncated, with the global header of the pcap format
+async fn open(path: &Path, link_type: u32) -> io::Result<BufWriter<fs::File>> {
+    let mut file = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .await?,
+    );
+    let mut header = Vec::with_capacity(PCAP_HEADER_SIZE as usize);
+
+    header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // no time zone offset nor timestamp accuracy
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&(MAX_BUFFER_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&link_type.to_le_bytes());
+    file.write_all(&header).await?;
+    file.flush().await?;
+
+    Ok(file)
+}
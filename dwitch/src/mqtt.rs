@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde_json::{Map, Value};
+use tokio::{spawn, sync::broadcast::error::RecvError, time::sleep};
+
+use crate::{
+    config::{MqttConfig, SwitchId},
+    events::{subscribe, Event, EventKind},
+    instance,
+};
+
+const MQTT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+pub async fn mqtt(config: MqttConfig, switch_id: SwitchId) {
+    let client_id = config
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("{}-{switch_id}", instance::suffixed("dwitch")));
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+
+    options.set_keep_alive(Duration::from_secs(30));
+
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+
+    spawn(async move {
+        loop {
+            if let Err(error) = event_loop.poll().await {
+                tracing::warn!("Mqtt connection error: {error}");
+                sleep(MQTT_RETRY_INTERVAL).await;
+            }
+        }
+    });
+
+    // the instances of a host publish apart from each other
+    let prefix = match instance::name() {
+        Some(name) => format!("{}/{name}", config.topics.prefix),
+        None => config.topics.prefix.clone(),
+    };
+    let mut events = subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!("Mqtt publisher lagged, {count} events dropped");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let topic = format!(
+            "{prefix}/{switch_id}/{}",
+            match event.kind() {
+                EventKind::Peer => &config.topics.peer,
+                EventKind::Vrf => &config.topics.vrf,
+                EventKind::Alert => &config.topics.alert,
+            }
+        );
+        let payload = match payload(&event) {
+            Ok(payload) => payload,
+            Err(error) => {
+                tracing::error!("Can't serialize event {event:?}: {error}");
+                continue;
+            }
+        };
+
+        if let Err(error) = client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+        {
+            tracing::error!("Can't publish event to mqtt: {error}");
+        }
+    }
+}
+
+// the event is named in an `event` field next to its own fields, like `{"event": "peer_up", ...}`
+fn payload(event: &Event) -> serde_json::Result<Vec<u8>> {
+    let mut payload = Map::new();
+
+    if let Value::Object(event) = serde_json::to_value(event)? {
+        for (name, fields) in event {
+            if let Value::Object(fields) = fields {
+                payload.extend(fields);
+            }
+
+            payload.insert("event".to_string(), Value::String(name));
+        }
+    }
+
+    serde_json::to_vec(&payload)
+}
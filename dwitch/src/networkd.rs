@@ -0,0 +1,98 @@
+//! `.network` files for the vrf devices in the default namespace, so addressing and policy can be
+//! managed by systemd-networkd through drop-ins next to them.
+
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use common::VrfId;
+use serde::Deserialize;
+use tokio::fs::{create_dir_all, remove_file, write};
+
+use crate::{
+    instance,
+    link::{master_name, tap_name, Dataplane},
+};
+
+const NETWORKD_PATH: &str = "/run/systemd/network";
+const ENDPOINTS_FILE: &str = "50-dwitch-endpoints.network";
+
+/// Networkd picks new files up on its next reload.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NetworkdConfig {
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from(NETWORKD_PATH)
+}
+
+fn vrf_file(vrf_id: VrfId) -> String {
+    format!("50-{}-vrf{vrf_id}.network", instance::suffixed("dwitch"))
+}
+
+fn tap_file(vrf_id: VrfId) -> String {
+    format!(
+        "50-{}-vrf{vrf_id}-tap.network",
+        instance::suffixed("dwitch")
+    )
+}
+
+pub async fn write_vrf(
+    config: &NetworkdConfig,
+    dataplane: Dataplane,
+    vrf_id: VrfId,
+    vrf_name: &str,
+) -> io::Result<()> {
+    let master = master_name(dataplane, vrf_id);
+    let controller = match dataplane {
+        Dataplane::Bridge => "Bridge",
+        Dataplane::Vrf => "VRF",
+        // the netns dataplane keeps its devices out of networkd's reach
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        Dataplane::Netns => return Ok(()),
+        // a lone tap has no master to match on
+        Dataplane::Tap => return Ok(()),
+    };
+
+    create_dir_all(&config.directory).await?;
+    write(
+        config.directory.join(vrf_file(vrf_id)),
+        format!(
+            "# Generated by dwitch for the vrf {vrf_name:?}, addresses and policy go in {}.d/\n\
+             [Match]\nName={master}\n\n[Network]\nConfigureWithoutCarrier=yes\n",
+            vrf_file(vrf_id)
+        ),
+    )
+    .await?;
+    write(
+        config.directory.join(tap_file(vrf_id)),
+        format!(
+            "# Generated by dwitch for the tap of the vrf {vrf_name:?}\n\
+             [Match]\nName={}\n\n[Network]\n{controller}={master}\nLinkLocalAddressing=no\n\
+             ConfigureWithoutCarrier=yes\n",
+            tap_name(vrf_id)
+        ),
+    )
+    .await?;
+    // the daemon enslaves the endpoint veths itself
+    write(
+        config.directory.join(ENDPOINTS_FILE),
+        "# Generated by dwitch for the endpoint veths\n\
+         [Match]\nName=dwv[0-9a-f]*\n\n[Link]\nUnmanaged=yes\n",
+    )
+    .await
+}
+
+pub async fn remove_vrf(config: &NetworkdConfig, vrf_id: VrfId) -> io::Result<()> {
+    for file in [vrf_file(vrf_id), tap_file(vrf_id)] {
+        match remove_file(config.directory.join(file)).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,275 @@
+//! Masquerading table of the daemon, programmed over nf_tables netlink since the sandbox doesn't
+//! let it run nft.
+//!
+//! The table is replaced as a whole in one batch, in the namespace of the calling thread, with a
+//! nat postrouting chain masquerading what comes in or goes out through some links.
+
+use std::{
+    io::{self, ErrorKind},
+    os::fd::AsRawFd,
+};
+
+use nix::sys::socket::{
+    bind, recv, send, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol,
+    SockType,
+};
+
+use crate::instance;
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLMSG_ERROR: u16 = 2;
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NFPROTO_UNSPEC: u8 = 0;
+const NFPROTO_INET: u8 = 1;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_DATA_VALUE: u16 = 1;
+
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_IP_PRI_NAT_SRC: u32 = 100;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_REG_1: u32 = 1;
+const NFT_CMP_EQ: u32 = 0;
+
+const CHAIN: &str = "postrouting";
+const RECEIVE_BUFFER_SIZE: usize = 8192;
+
+/// Link whose traffic is masqueraded.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Masquerade {
+    /// What's routed in from it.
+    Incoming(String),
+    /// What's routed out through it.
+    Outgoing(String),
+}
+
+/// Replace the table of the daemon, an empty list only removes it.
+pub fn set_masquerade(rules: &[Masquerade]) -> io::Result<()> {
+    let table = instance::suffixed("dwitch");
+    let mut batch = Batch::default();
+
+    batch.begin();
+    // created first so deleting it never fails
+    batch.message(NFT_MSG_NEWTABLE, NLM_F_CREATE, |buffer| {
+        put_str(buffer, NFTA_TABLE_NAME, &table)
+    });
+    batch.message(NFT_MSG_DELTABLE, 0, |buffer| {
+        put_str(buffer, NFTA_TABLE_NAME, &table)
+    });
+
+    if !rules.is_empty() {
+        batch.message(NFT_MSG_NEWTABLE, NLM_F_CREATE, |buffer| {
+            put_str(buffer, NFTA_TABLE_NAME, &table)
+        });
+        batch.message(NFT_MSG_NEWCHAIN, NLM_F_CREATE, |buffer| {
+            put_str(buffer, NFTA_CHAIN_TABLE, &table);
+            put_str(buffer, NFTA_CHAIN_NAME, CHAIN);
+            nest(buffer, NFTA_CHAIN_HOOK, |buffer| {
+                put_u32(buffer, NFTA_HOOK_HOOKNUM, NF_INET_POST_ROUTING);
+                put_u32(buffer, NFTA_HOOK_PRIORITY, NF_IP_PRI_NAT_SRC);
+            });
+            put_str(buffer, NFTA_CHAIN_TYPE, "nat");
+        });
+
+        for rule in rules {
+            let (key, name) = match rule {
+                Masquerade::Incoming(name) => (NFT_META_IIFNAME, name),
+                Masquerade::Outgoing(name) => (NFT_META_OIFNAME, name),
+            };
+
+            batch.message(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, |buffer| {
+                put_str(buffer, NFTA_RULE_TABLE, &table);
+                put_str(buffer, NFTA_RULE_CHAIN, CHAIN);
+                nest(buffer, NFTA_RULE_EXPRESSIONS, |buffer| {
+                    expression(buffer, "meta", |buffer| {
+                        put_u32(buffer, NFTA_META_DREG, NFT_REG_1);
+                        put_u32(buffer, NFTA_META_KEY, key);
+                    });
+                    expression(buffer, "cmp", |buffer| {
+                        put_u32(buffer, NFTA_CMP_SREG, NFT_REG_1);
+                        put_u32(buffer, NFTA_CMP_OP, NFT_CMP_EQ);
+                        nest(buffer, NFTA_CMP_DATA, |buffer| {
+                            put(buffer, NFTA_DATA_VALUE, &interface_name(name))
+                        });
+                    });
+                    expression(buffer, "masq", |_| {});
+                });
+            });
+        }
+    }
+
+    batch.end();
+    batch.send()
+}
+
+#[derive(Default)]
+struct Batch {
+    buffer: Vec<u8>,
+    sequence: u32,
+    acks: u32,
+}
+
+impl Batch {
+    fn begin(&mut self) {
+        self.header(NFNL_MSG_BATCH_BEGIN, 0, NFPROTO_UNSPEC, |_| {});
+    }
+
+    fn end(&mut self) {
+        self.header(NFNL_MSG_BATCH_END, 0, NFPROTO_UNSPEC, |_| {});
+    }
+
+    fn message(&mut self, kind: u16, flags: u16, attributes: impl FnOnce(&mut Vec<u8>)) {
+        self.acks += 1;
+        self.header(
+            (NFNL_SUBSYS_NFTABLES << 8) | kind,
+            NLM_F_ACK | flags,
+            NFPROTO_INET,
+            attributes,
+        );
+    }
+
+    fn header(&mut self, kind: u16, flags: u16, family: u8, attributes: impl FnOnce(&mut Vec<u8>)) {
+        let start = self.buffer.len();
+
+        self.sequence += 1;
+        self.buffer.extend([0; 4]);
+        self.buffer.extend(kind.to_ne_bytes());
+        self.buffer.extend((NLM_F_REQUEST | flags).to_ne_bytes());
+        self.buffer.extend(self.sequence.to_ne_bytes());
+        self.buffer.extend(0u32.to_ne_bytes());
+        // the batch messages name the subsystem they're for in the resource id
+        self.buffer.extend([family, 0]);
+        self.buffer.extend(NFNL_SUBSYS_NFTABLES.to_be_bytes());
+        attributes(&mut self.buffer);
+
+        let length = (self.buffer.len() - start) as u32;
+
+        self.buffer[start..start + 4].copy_from_slice(&length.to_ne_bytes());
+    }
+
+    fn send(self) -> io::Result<()> {
+        let socket = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkNetFilter,
+        )?;
+
+        bind(socket.as_raw_fd(), &NetlinkAddr::new(0, 0))?;
+        send(socket.as_raw_fd(), &self.buffer, MsgFlags::empty())?;
+
+        let mut buffer = [0u8; RECEIVE_BUFFER_SIZE];
+        let mut acks = 0;
+
+        while acks < self.acks {
+            let length = recv(socket.as_raw_fd(), &mut buffer, MsgFlags::empty())?;
+            let mut messages = &buffer[..length];
+
+            while messages.len() >= 16 {
+                let message_length =
+                    u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+
+                if message_length < 16 || message_length > messages.len() {
+                    return Err(ErrorKind::InvalidData.into());
+                }
+
+                if kind == NLMSG_ERROR && message_length >= 20 {
+                    let error = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+
+                    // the batch is aborted on the first error
+                    if error != 0 {
+                        return Err(io::Error::from_raw_os_error(-error));
+                    }
+
+                    acks += 1;
+                }
+
+                messages = &messages[align(message_length).min(messages.len())..];
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn expression(buffer: &mut Vec<u8>, name: &str, data: impl FnOnce(&mut Vec<u8>)) {
+    nest(buffer, NFTA_LIST_ELEM, |buffer| {
+        put_str(buffer, NFTA_EXPR_NAME, name);
+        nest(buffer, NFTA_EXPR_DATA, data);
+    });
+}
+
+// compared with the whole name register, zero padded like the kernel keeps it
+fn interface_name(name: &str) -> [u8; libc::IFNAMSIZ] {
+    let mut bytes = [0u8; libc::IFNAMSIZ];
+
+    for (byte, char) in bytes.iter_mut().zip(name.bytes()) {
+        *byte = char;
+    }
+
+    bytes
+}
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+fn put(buffer: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    buffer.extend(((4 + payload.len()) as u16).to_ne_bytes());
+    buffer.extend(kind.to_ne_bytes());
+    buffer.extend(payload);
+    buffer.resize(align(buffer.len()), 0);
+}
+
+fn put_str(buffer: &mut Vec<u8>, kind: u16, value: &str) {
+    put(buffer, kind, &[value.as_bytes(), &[0]].concat());
+}
+
+// nf_tables takes its integers in network order
+fn put_u32(buffer: &mut Vec<u8>, kind: u16, value: u32) {
+    put(buffer, kind, &value.to_be_bytes());
+}
+
+fn nest(buffer: &mut Vec<u8>, kind: u16, attributes: impl FnOnce(&mut Vec<u8>)) {
+    let start = buffer.len();
+
+    buffer.extend([0; 4]);
+    attributes(buffer);
+
+    let length = (buffer.len() - start) as u16;
+
+    buffer[start..start + 2].copy_from_slice(&length.to_ne_bytes());
+    buffer[start + 2..start + 4].copy_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+}
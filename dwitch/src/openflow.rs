@@ -0,0 +1,744 @@
+//! Openflow 1.3 datapath of each vrf, letting a controller steer frames before the built-in mac
+//! learning.
+//!
+//! Every vrf connects to the controller as its own datapath, with the switch id and the vrf id as
+//! datapath id. The local tap is the `LOCAL` port and each peer is the port numbered by its switch
+//! id. There's a single table matching on the in port, the ethernet addresses and the ethernet
+//! type, with output actions only. Flows stay until deleted or their hard timeout, idle timeouts
+//! aren't enforced.
+
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use protocol::Vrf;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    select, spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{link, state::State, switch_table::MacAddress};
+
+const OPENFLOW_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const PACKET_QUEUE_SIZE: usize = 64;
+
+const VERSION: u8 = 4;
+const HEADER_SIZE: usize = 8;
+
+const MESSAGE_HELLO: u8 = 0;
+const MESSAGE_ERROR: u8 = 1;
+const MESSAGE_ECHO_REQUEST: u8 = 2;
+const MESSAGE_ECHO_REPLY: u8 = 3;
+const MESSAGE_FEATURES_REQUEST: u8 = 5;
+const MESSAGE_FEATURES_REPLY: u8 = 6;
+const MESSAGE_GET_CONFIG_REQUEST: u8 = 7;
+const MESSAGE_GET_CONFIG_REPLY: u8 = 8;
+const MESSAGE_SET_CONFIG: u8 = 9;
+const MESSAGE_PACKET_IN: u8 = 10;
+const MESSAGE_PACKET_OUT: u8 = 13;
+const MESSAGE_FLOW_MOD: u8 = 14;
+const MESSAGE_MULTIPART_REQUEST: u8 = 18;
+const MESSAGE_MULTIPART_REPLY: u8 = 19;
+const MESSAGE_BARRIER_REQUEST: u8 = 20;
+const MESSAGE_BARRIER_REPLY: u8 = 21;
+
+// ports above this one are reserved, below it they are peer switch ids
+const MAX_PORT: u32 = 0xffff_ff00;
+pub const PORT_IN_PORT: u32 = 0xffff_fff8;
+pub const PORT_NORMAL: u32 = 0xffff_fffa;
+pub const PORT_FLOOD: u32 = 0xffff_fffb;
+pub const PORT_ALL: u32 = 0xffff_fffc;
+pub const PORT_CONTROLLER: u32 = 0xffff_fffd;
+pub const PORT_LOCAL: u32 = 0xffff_fffe;
+const PORT_ANY: u32 = 0xffff_ffff;
+
+const NO_BUFFER: u32 = 0xffff_ffff;
+const TABLE_ALL: u8 = 0xff;
+const PACKET_IN_ACTION: u8 = 1;
+
+const FLOW_ADD: u8 = 0;
+const FLOW_MODIFY: u8 = 1;
+const FLOW_MODIFY_STRICT: u8 = 2;
+const FLOW_DELETE: u8 = 3;
+const FLOW_DELETE_STRICT: u8 = 4;
+
+const MATCH_OXM: u16 = 1;
+const OXM_CLASS_BASIC: u16 = 0x8000;
+const FIELD_IN_PORT: u8 = 0;
+const FIELD_ETH_DST: u8 = 3;
+const FIELD_ETH_SRC: u8 = 4;
+const FIELD_ETH_TYPE: u8 = 5;
+
+const INSTRUCTION_WRITE_ACTIONS: u16 = 3;
+const INSTRUCTION_APPLY_ACTIONS: u16 = 4;
+const INSTRUCTION_CLEAR_ACTIONS: u16 = 5;
+const ACTION_OUTPUT: u16 = 0;
+
+const MULTIPART_DESC: u16 = 0;
+const MULTIPART_PORT_DESC: u16 = 13;
+
+const PORT_STATE_LINK_DOWN: u32 = 1;
+const PORT_STATE_LIVE: u32 = 4;
+
+const BAD_REQUEST_VERSION: Refused = Refused(1, 0);
+const BAD_REQUEST_TYPE: Refused = Refused(1, 1);
+const BAD_REQUEST_MULTIPART: Refused = Refused(1, 2);
+const BAD_REQUEST_LEN: Refused = Refused(1, 6);
+const BAD_REQUEST_BUFFER_UNKNOWN: Refused = Refused(1, 8);
+const BAD_ACTION_TYPE: Refused = Refused(2, 0);
+const BAD_ACTION_LEN: Refused = Refused(2, 1);
+const BAD_ACTION_OUT_PORT: Refused = Refused(2, 4);
+const BAD_INSTRUCTION_UNSUPPORTED: Refused = Refused(3, 1);
+const BAD_INSTRUCTION_LEN: Refused = Refused(3, 7);
+const BAD_MATCH_TYPE: Refused = Refused(4, 0);
+const BAD_MATCH_LEN: Refused = Refused(4, 1);
+const BAD_MATCH_FIELD: Refused = Refused(4, 6);
+const FLOW_MOD_BAD_TABLE_ID: Refused = Refused(5, 2);
+const FLOW_MOD_BAD_COMMAND: Refused = Refused(5, 6);
+
+type OpenflowError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpenflowConfig {
+    pub controller: SocketAddr,
+}
+
+/// Error type and code sent back for a message the datapath can't handle.
+#[derive(Debug, Clone, Copy)]
+struct Refused(u16, u16);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Match {
+    in_port: Option<u32>,
+    // masked value and mask
+    eth_dst: Option<(MacAddress, MacAddress)>,
+    eth_src: Option<(MacAddress, MacAddress)>,
+    eth_type: Option<u16>,
+}
+
+impl Match {
+    fn matches(&self, in_port: u32, frame: &[u8]) -> bool {
+        self.in_port.is_none_or(|port| port == in_port)
+            && self
+                .eth_dst
+                .is_none_or(|(mac, mask)| mac_matches(&frame[0..6], mac, mask))
+            && self
+                .eth_src
+                .is_none_or(|(mac, mask)| mac_matches(&frame[6..12], mac, mask))
+            && self
+                .eth_type
+                .is_none_or(|eth_type| frame[12..14] == eth_type.to_be_bytes())
+    }
+
+    // whether every frame matched by `other` is matched by this one, to select flows loosely
+    fn covers(&self, other: &Match) -> bool {
+        let covers_mac = |mac: Option<(MacAddress, MacAddress)>,
+                          other: Option<(MacAddress, MacAddress)>| {
+            match (mac, other) {
+                (None, _) => true,
+                (Some((mac, mask)), Some((other_mac, other_mask))) => (0..6).all(|index| {
+                    other_mask[index] & mask[index] == mask[index]
+                        && other_mac[index] & mask[index] == mac[index]
+                }),
+                (Some(_), None) => false,
+            }
+        };
+
+        self.in_port.is_none_or(|port| other.in_port == Some(port))
+            && covers_mac(self.eth_dst, other.eth_dst)
+            && covers_mac(self.eth_src, other.eth_src)
+            && self
+                .eth_type
+                .is_none_or(|eth_type| other.eth_type == Some(eth_type))
+    }
+}
+
+fn mac_matches(address: &[u8], mac: MacAddress, mask: MacAddress) -> bool {
+    (0..6).all(|index| address[index] & mask[index] == mac[index])
+}
+
+#[derive(Debug, Clone)]
+struct Flow {
+    priority: u16,
+    cookie: u64,
+    matching: Match,
+    // no port drops the frame
+    ports: Vec<u32>,
+    expires: Option<Instant>,
+}
+
+/// Flows of a vrf datapath, highest priority first.
+#[derive(Debug, Clone, Default)]
+pub struct FlowTable(Arc<RwLock<Vec<Flow>>>);
+
+impl FlowTable {
+    /// Output ports of the flow matching the frame, `None` leaves it to the mac table.
+    pub fn lookup(&self, in_port: u32, frame: &[u8]) -> Option<Vec<u32>> {
+        let now = Instant::now();
+
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .find(|flow| {
+                flow.expires.is_none_or(|expires| expires > now)
+                    && flow.matching.matches(in_port, frame)
+            })
+            .map(|flow| flow.ports.clone())
+    }
+}
+
+/// Frame the controller sends out of some ports of the datapath.
+pub struct PacketOut {
+    pub in_port: u32,
+    pub ports: Vec<u32>,
+    pub frame: Bytes,
+}
+
+/// What the data plane of a vrf uses of its openflow datapath.
+#[derive(Clone)]
+pub struct Datapath {
+    pub flow_table: FlowTable,
+    packet_ins: Sender<(u32, Bytes)>,
+}
+
+impl Datapath {
+    // dropped when the controller doesn't keep up, like a switch running out of buffers
+    pub fn packet_in(&self, in_port: u32, frame: &[u8]) {
+        let _ = self
+            .packet_ins
+            .try_send((in_port, Bytes::copy_from_slice(frame)));
+    }
+}
+
+pub fn is_peer_port(port: u32) -> bool {
+    port < MAX_PORT
+}
+
+/// Start the datapath of a vrf, kept connected to the controller until the task is aborted.
+pub fn datapath(
+    config: OpenflowConfig,
+    vrf: Vrf,
+    state: Arc<State>,
+) -> (Datapath, Receiver<PacketOut>, JoinHandle<()>) {
+    let (packet_in_sender, packet_ins) = channel(PACKET_QUEUE_SIZE);
+    let (packet_outs, packet_out_receiver) = channel(PACKET_QUEUE_SIZE);
+    let datapath = Datapath {
+        flow_table: FlowTable::default(),
+        packet_ins: packet_in_sender,
+    };
+    let task = spawn(controller(
+        config,
+        vrf,
+        state,
+        datapath.flow_table.clone(),
+        packet_ins,
+        packet_outs,
+    ));
+
+    (datapath, packet_out_receiver, task)
+}
+
+async fn controller(
+    config: OpenflowConfig,
+    vrf: Vrf,
+    state: Arc<State>,
+    flow_table: FlowTable,
+    mut packet_ins: Receiver<(u32, Bytes)>,
+    packet_outs: Sender<PacketOut>,
+) {
+    loop {
+        match TcpStream::connect(config.controller).await {
+            Ok(stream) => {
+                tracing::info!(
+                    "Connected the vrf {} to the openflow controller {}",
+                    vrf.name,
+                    config.controller
+                );
+
+                if let Err(error) = session(
+                    &vrf,
+                    &state,
+                    stream,
+                    &flow_table,
+                    &mut packet_ins,
+                    &packet_outs,
+                )
+                .await
+                {
+                    tracing::warn!("Openflow session of the vrf {} ended: {error}", vrf.name);
+                }
+            }
+            Err(error) => {
+                tracing::warn!(
+                    "Can't connect the vrf {} to the openflow controller {}: {error}",
+                    vrf.name,
+                    config.controller
+                );
+            }
+        }
+
+        sleep(OPENFLOW_RETRY_INTERVAL).await;
+    }
+}
+
+// flows are kept across sessions, the datapath goes on forwarding while the controller is away
+async fn session(
+    vrf: &Vrf,
+    state: &State,
+    mut stream: TcpStream,
+    flow_table: &FlowTable,
+    packet_ins: &mut Receiver<(u32, Bytes)>,
+    packet_outs: &Sender<PacketOut>,
+) -> Result<(), OpenflowError> {
+    let mut buffer = BytesMut::new();
+
+    // frames queued while no controller was there are stale
+    while packet_ins.try_recv().is_ok() {}
+
+    stream.write_all(&message(MESSAGE_HELLO, 0, &[])).await?;
+
+    loop {
+        select! {
+            received = recv_message(&mut stream, &mut buffer) => {
+                let (version, kind, xid, body) = received?;
+                let reply = match handle_message(vrf, state, flow_table, packet_outs, version, kind, &body).await {
+                    Ok(Some((kind, body))) => message(kind, xid, &body),
+                    Ok(None) => continue,
+                    Err(Refused(error_type, code)) => {
+                        let mut error = Vec::new();
+
+                        error.extend_from_slice(&error_type.to_be_bytes());
+                        error.extend_from_slice(&code.to_be_bytes());
+                        error.extend_from_slice(&message(kind, xid, &body)[..(HEADER_SIZE + body.len()).min(64)]);
+
+                        message(MESSAGE_ERROR, xid, &error)
+                    }
+                };
+
+                stream.write_all(&reply).await?;
+            }
+            Some((in_port, frame)) = packet_ins.recv() => {
+                stream.write_all(&packet_in_message(in_port, &frame)).await?;
+            }
+        }
+    }
+}
+
+/// Kind and body of the reply, if any.
+async fn handle_message(
+    vrf: &Vrf,
+    state: &State,
+    flow_table: &FlowTable,
+    packet_outs: &Sender<PacketOut>,
+    version: u8,
+    kind: u8,
+    body: &[u8],
+) -> Result<Option<(u8, Vec<u8>)>, Refused> {
+    if kind != MESSAGE_HELLO && version != VERSION {
+        return Err(BAD_REQUEST_VERSION);
+    }
+
+    match kind {
+        MESSAGE_HELLO | MESSAGE_ECHO_REPLY | MESSAGE_SET_CONFIG => Ok(None),
+        MESSAGE_ERROR => {
+            tracing::warn!(
+                "Openflow controller of the vrf {} sent an error {:?}",
+                vrf.name,
+                body.get(..4)
+            );
+            Ok(None)
+        }
+        MESSAGE_ECHO_REQUEST => Ok(Some((MESSAGE_ECHO_REPLY, body.to_vec()))),
+        MESSAGE_FEATURES_REQUEST => {
+            let mut features = datapath_id(state, vrf).to_be_bytes().to_vec();
+
+            // no buffers, one table, no auxiliary connection nor capabilities
+            features.extend_from_slice(&0u32.to_be_bytes());
+            features.extend_from_slice(&[1, 0, 0, 0]);
+            features.extend_from_slice(&[0; 8]);
+
+            Ok(Some((MESSAGE_FEATURES_REPLY, features)))
+        }
+        MESSAGE_GET_CONFIG_REQUEST => Ok(Some((MESSAGE_GET_CONFIG_REPLY, vec![0, 0, 0xff, 0xff]))),
+        MESSAGE_FLOW_MOD => {
+            flow_mod(flow_table, body)?;
+            Ok(None)
+        }
+        MESSAGE_PACKET_OUT => {
+            let packet_out = packet_out(body)?;
+
+            if packet_outs.try_send(packet_out).is_err() {
+                tracing::warn!("Dropped a packet out of the vrf {}", vrf.name);
+            }
+
+            Ok(None)
+        }
+        MESSAGE_MULTIPART_REQUEST => {
+            let multipart_type =
+                u16::from_be_bytes(body.get(..2).ok_or(BAD_REQUEST_LEN)?.try_into().unwrap());
+            let mut reply = multipart_type.to_be_bytes().to_vec();
+
+            reply.extend_from_slice(&[0; 6]);
+
+            match multipart_type {
+                MULTIPART_DESC => {
+                    for (text, length) in [
+                        ("dwitch", 256),
+                        ("dwitch vrf", 256),
+                        (env!("CARGO_PKG_VERSION"), 256),
+                        ("", 32),
+                        (vrf.name.as_str(), 256),
+                    ] {
+                        reply.extend_from_slice(&fixed_string(text, length));
+                    }
+                }
+                MULTIPART_PORT_DESC => {
+                    reply.extend_from_slice(&port_descriptions(vrf, state).await)
+                }
+                _ => return Err(BAD_REQUEST_MULTIPART),
+            }
+
+            Ok(Some((MESSAGE_MULTIPART_REPLY, reply)))
+        }
+        MESSAGE_BARRIER_REQUEST => Ok(Some((MESSAGE_BARRIER_REPLY, Vec::new()))),
+        _ => Err(BAD_REQUEST_TYPE),
+    }
+}
+
+fn datapath_id(state: &State, vrf: &Vrf) -> u64 {
+    ((state.config.switch_id as u64) << 32) | vrf.id as u64
+}
+
+// cancel safe, bytes past the message are kept in `buffer` for the next call
+async fn recv_message(
+    stream: &mut TcpStream,
+    buffer: &mut BytesMut,
+) -> Result<(u8, u8, u32, BytesMut), OpenflowError> {
+    loop {
+        if buffer.len() >= HEADER_SIZE {
+            let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+
+            if length < HEADER_SIZE {
+                return Err(format!("Invalid message length {length}").into());
+            }
+
+            if buffer.len() >= length {
+                let mut body = buffer.split_to(length);
+                let header = body.split_to(HEADER_SIZE);
+
+                return Ok((
+                    header[0],
+                    header[1],
+                    u32::from_be_bytes(header[4..8].try_into().unwrap()),
+                    body,
+                ));
+            }
+        }
+
+        if stream.read_buf(buffer).await? == 0 {
+            return Err("Connection closed".into());
+        }
+    }
+}
+
+fn message(kind: u8, xid: u32, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![VERSION, kind];
+
+    message.extend_from_slice(&((HEADER_SIZE + body.len()) as u16).to_be_bytes());
+    message.extend_from_slice(&xid.to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+fn packet_in_message(in_port: u32, frame: &[u8]) -> Vec<u8> {
+    let mut body = NO_BUFFER.to_be_bytes().to_vec();
+
+    body.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+    body.extend_from_slice(&[PACKET_IN_ACTION, 0]);
+    body.extend_from_slice(&[0; 8]);
+    // a match of the in port alone, padded to 8 bytes
+    body.extend_from_slice(&MATCH_OXM.to_be_bytes());
+    body.extend_from_slice(&12u16.to_be_bytes());
+    body.extend_from_slice(&OXM_CLASS_BASIC.to_be_bytes());
+    body.extend_from_slice(&[FIELD_IN_PORT << 1, 4]);
+    body.extend_from_slice(&in_port.to_be_bytes());
+    body.extend_from_slice(&[0; 4]);
+    body.extend_from_slice(&[0; 2]);
+
+    // jumbo frames are cut to what a message can hold
+    let length = frame
+        .len()
+        .min(u16::MAX as usize - HEADER_SIZE - body.len());
+
+    body.extend_from_slice(&frame[..length]);
+
+    message(MESSAGE_PACKET_IN, 0, &body)
+}
+
+fn fixed_string(text: &str, length: usize) -> Vec<u8> {
+    let mut bytes = text.as_bytes().to_vec();
+
+    // always terminated by a null byte
+    bytes.truncate(length - 1);
+    bytes.resize(length, 0);
+    bytes
+}
+
+async fn port_descriptions(vrf: &Vrf, state: &State) -> Vec<u8> {
+    let members = state
+        .vrf_table
+        .read()
+        .await
+        .get(&vrf.id)
+        .map(|vrf| vrf.members.clone())
+        .unwrap_or_default();
+    let client_table = state.client_table.read().await;
+    let mut ports = vec![(PORT_LOCAL, link::tap_name(vrf.id), PORT_STATE_LIVE)];
+
+    for member in members {
+        if member != state.config.switch_id && is_peer_port(member) {
+            let port_state = match client_table.contains_key(&member) {
+                true => PORT_STATE_LIVE,
+                false => PORT_STATE_LINK_DOWN,
+            };
+
+            ports.push((member, format!("peer{member}"), port_state));
+        }
+    }
+
+    let mut descriptions = Vec::new();
+
+    for (port, name, port_state) in ports {
+        descriptions.extend_from_slice(&port.to_be_bytes());
+        descriptions.extend_from_slice(&[0; 4]);
+        // no hardware address
+        descriptions.extend_from_slice(&[0; 8]);
+        descriptions.extend_from_slice(&fixed_string(&name, 16));
+        descriptions.extend_from_slice(&0u32.to_be_bytes());
+        descriptions.extend_from_slice(&port_state.to_be_bytes());
+        // no features nor speeds
+        descriptions.extend_from_slice(&[0; 24]);
+    }
+
+    descriptions
+}
+
+fn flow_mod(flow_table: &FlowTable, body: &[u8]) -> Result<(), Refused> {
+    let header = body.get(..40).ok_or(BAD_REQUEST_LEN)?;
+    let cookie = u64::from_be_bytes(header[0..8].try_into().unwrap());
+    let cookie_mask = u64::from_be_bytes(header[8..16].try_into().unwrap());
+    let table_id = header[16];
+    let command = header[17];
+    let hard_timeout = u16::from_be_bytes([header[20], header[21]]);
+    let priority = u16::from_be_bytes([header[22], header[23]]);
+    let out_port = u32::from_be_bytes(header[28..32].try_into().unwrap());
+    let (matching, instructions) = parse_match(&body[40..])?;
+    let ports = parse_instructions(instructions)?;
+    let selected = |flow: &Flow, strict: bool| {
+        flow.cookie & cookie_mask == cookie & cookie_mask
+            && match strict {
+                true => flow.priority == priority && flow.matching == matching,
+                false => matching.covers(&flow.matching),
+            }
+    };
+    let mut flows = flow_table.0.write().unwrap();
+    let now = Instant::now();
+
+    flows.retain(|flow| flow.expires.is_none_or(|expires| expires > now));
+
+    match command {
+        FLOW_ADD | FLOW_MODIFY | FLOW_MODIFY_STRICT if table_id != 0 => {
+            return Err(FLOW_MOD_BAD_TABLE_ID)
+        }
+        FLOW_DELETE | FLOW_DELETE_STRICT if table_id != 0 && table_id != TABLE_ALL => {
+            return Err(FLOW_MOD_BAD_TABLE_ID)
+        }
+        FLOW_ADD => {
+            flows.retain(|flow| flow.priority != priority || flow.matching != matching);
+
+            let index = flows.partition_point(|flow| flow.priority >= priority);
+
+            flows.insert(
+                index,
+                Flow {
+                    priority,
+                    cookie,
+                    matching,
+                    ports,
+                    expires: (hard_timeout != 0)
+                        .then(|| now + Duration::from_secs(hard_timeout.into())),
+                },
+            );
+        }
+        FLOW_MODIFY | FLOW_MODIFY_STRICT => {
+            for flow in flows
+                .iter_mut()
+                .filter(|flow| selected(flow, command == FLOW_MODIFY_STRICT))
+            {
+                flow.ports = ports.clone();
+            }
+        }
+        FLOW_DELETE | FLOW_DELETE_STRICT => {
+            flows.retain(|flow| {
+                !selected(flow, command == FLOW_DELETE_STRICT)
+                    || (out_port != PORT_ANY && !flow.ports.contains(&out_port))
+            });
+        }
+        _ => return Err(FLOW_MOD_BAD_COMMAND),
+    }
+
+    tracing::debug!("Openflow table now has {} flows", flows.len());
+
+    Ok(())
+}
+
+/// The match and the bytes following it.
+fn parse_match(bytes: &[u8]) -> Result<(Match, &[u8]), Refused> {
+    let [type_high, type_low, length_high, length_low, ..] = *bytes else {
+        return Err(BAD_MATCH_LEN);
+    };
+
+    if u16::from_be_bytes([type_high, type_low]) != MATCH_OXM {
+        return Err(BAD_MATCH_TYPE);
+    }
+
+    let length = u16::from_be_bytes([length_high, length_low]) as usize;
+    let mut fields = bytes.get(4..length).ok_or(BAD_MATCH_LEN)?;
+    let rest = bytes.get(length.div_ceil(8) * 8..).ok_or(BAD_MATCH_LEN)?;
+    let mut matching = Match::default();
+
+    while let [class_high, class_low, field, length, rest @ ..] = fields {
+        let value = rest.get(..*length as usize).ok_or(BAD_MATCH_LEN)?;
+        let masked = field & 1 == 1;
+
+        fields = &rest[*length as usize..];
+
+        if u16::from_be_bytes([*class_high, *class_low]) != OXM_CLASS_BASIC {
+            return Err(BAD_MATCH_FIELD);
+        }
+
+        match (field >> 1, masked, value) {
+            (FIELD_IN_PORT, false, &[a, b, c, d]) => {
+                matching.in_port = Some(u32::from_be_bytes([a, b, c, d]))
+            }
+            (FIELD_ETH_DST, _, _) => matching.eth_dst = Some(mac_match(value, masked)?),
+            (FIELD_ETH_SRC, _, _) => matching.eth_src = Some(mac_match(value, masked)?),
+            (FIELD_ETH_TYPE, false, &[high, low]) => {
+                matching.eth_type = Some(u16::from_be_bytes([high, low]))
+            }
+            _ => return Err(BAD_MATCH_FIELD),
+        }
+    }
+
+    if !fields.is_empty() {
+        return Err(BAD_MATCH_LEN);
+    }
+
+    Ok((matching, rest))
+}
+
+fn mac_match(value: &[u8], masked: bool) -> Result<(MacAddress, MacAddress), Refused> {
+    let (mac, mask): (MacAddress, MacAddress) = match (masked, value.len()) {
+        (false, 6) => (value.try_into().unwrap(), [0xff; 6]),
+        (true, 12) => (
+            value[..6].try_into().unwrap(),
+            value[6..].try_into().unwrap(),
+        ),
+        _ => return Err(BAD_MATCH_LEN),
+    };
+
+    Ok((std::array::from_fn(|index| mac[index] & mask[index]), mask))
+}
+
+// there is a single table, so written actions apply right away like the applied ones
+fn parse_instructions(mut instructions: &[u8]) -> Result<Vec<u32>, Refused> {
+    let mut ports = Vec::new();
+
+    while !instructions.is_empty() {
+        let [type_high, type_low, length_high, length_low, ..] = *instructions else {
+            return Err(BAD_INSTRUCTION_LEN);
+        };
+        let length = u16::from_be_bytes([length_high, length_low]) as usize;
+        let instruction = instructions
+            .get(..length)
+            .filter(|instruction| instruction.len() >= 8)
+            .ok_or(BAD_INSTRUCTION_LEN)?;
+
+        instructions = &instructions[length..];
+
+        match u16::from_be_bytes([type_high, type_low]) {
+            INSTRUCTION_WRITE_ACTIONS | INSTRUCTION_APPLY_ACTIONS => {
+                ports.extend(parse_actions(&instruction[8..])?)
+            }
+            INSTRUCTION_CLEAR_ACTIONS => {}
+            _ => return Err(BAD_INSTRUCTION_UNSUPPORTED),
+        }
+    }
+
+    Ok(ports)
+}
+
+fn parse_actions(mut actions: &[u8]) -> Result<Vec<u32>, Refused> {
+    let mut ports = Vec::new();
+
+    while !actions.is_empty() {
+        let [type_high, type_low, length_high, length_low, ..] = *actions else {
+            return Err(BAD_ACTION_LEN);
+        };
+        let length = u16::from_be_bytes([length_high, length_low]) as usize;
+        let action = actions
+            .get(..length)
+            .filter(|_| length >= 4)
+            .ok_or(BAD_ACTION_LEN)?;
+
+        actions = &actions[length..];
+
+        if u16::from_be_bytes([type_high, type_low]) != ACTION_OUTPUT {
+            return Err(BAD_ACTION_TYPE);
+        }
+
+        let port = u32::from_be_bytes(action.get(4..8).ok_or(BAD_ACTION_LEN)?.try_into().unwrap());
+
+        if port == 0 || port == PORT_ANY {
+            return Err(BAD_ACTION_OUT_PORT);
+        }
+
+        ports.push(port);
+    }
+
+    Ok(ports)
+}
+
+fn packet_out(body: &[u8]) -> Result<PacketOut, Refused> {
+    let header = body.get(..16).ok_or(BAD_REQUEST_LEN)?;
+    let buffer_id = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let in_port = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let actions_length = u16::from_be_bytes([header[8], header[9]]) as usize;
+
+    if buffer_id != NO_BUFFER {
+        return Err(BAD_REQUEST_BUFFER_UNKNOWN);
+    }
+
+    let actions = body.get(16..16 + actions_length).ok_or(BAD_REQUEST_LEN)?;
+    let frame = &body[16 + actions_length..];
+
+    if frame.len() < 14 {
+        return Err(BAD_REQUEST_LEN);
+    }
+
+    Ok(PacketOut {
+        in_port,
+        ports: parse_actions(actions)?,
+        frame: Bytes::copy_from_slice(frame),
+    })
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashSet,
+    fs, io, process,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use caps::{Capability, CapsHashSet};
+use nix::unistd::{gettid, Group, User};
+use serde::Deserialize;
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use crate::link::Dataplane;
+use crate::{config::Config, dns::DNS_PORT};
+
+const THREAD_TIMEOUT: Duration = Duration::from_secs(5);
+const THREAD_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// _LINUX_CAPABILITY_VERSION_3, with 64 bits capability sets
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+// read by the signal handlers, which can't be handed anything
+static UID: AtomicU32 = AtomicU32::new(0);
+static GID: AtomicU32 = AtomicU32::new(0);
+static RETAINED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct CapabilityHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapabilityData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrivilegesConfig {
+    pub user: String,
+    pub group: Option<String>,
+    /// Only those the config needs by default, the user then needs write access to the cache and
+    /// /run/netns.
+    pub capabilities: Option<Vec<Capability>>,
+}
+
+impl PrivilegesConfig {
+    fn capabilities(&self, config: &Config) -> CapsHashSet {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.iter().copied().collect(),
+            None => default_capabilities(config),
+        }
+    }
+}
+
+// creating taps and links needs net admin, binding the ports under 1024 net bind service and the
+// namespace mounts of the netns dataplane sys admin
+fn default_capabilities(config: &Config) -> CapsHashSet {
+    let low_port = config
+        .listen
+        .iter()
+        .chain(config.health.as_ref().map(|health| &health.listen))
+        .chain(config.metrics.as_ref().map(|metrics| &metrics.listen))
+        .chain(config.api.as_ref().map(|api| &api.listen))
+        .chain(config.vxlan.as_ref().map(|vxlan| &vxlan.listen))
+        .map(|address| address.port())
+        // the gateways of the vrfs forward dns on its port
+        .chain(config.dns.as_ref().map(|_| DNS_PORT))
+        .any(|port| port < 1024);
+    let mut capabilities = CapsHashSet::from([Capability::CAP_NET_ADMIN]);
+
+    if low_port {
+        capabilities.insert(Capability::CAP_NET_BIND_SERVICE);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    if config.dataplane == Dataplane::Netns {
+        capabilities.insert(Capability::CAP_SYS_ADMIN);
+    }
+
+    capabilities
+}
+
+/// Switch every thread to the configured user and group, keeping only the retained capabilities.
+///
+/// Ids and capabilities are per thread for the kernel, so each other thread of the process switches
+/// itself from a signal handler, threads they spawn meanwhile are found by listing the threads again.
+/// This thread switches last.
+pub fn drop_privileges(privileges_config: &PrivilegesConfig, config: &Config) -> eyre::Result<()> {
+    let user = User::from_name(&privileges_config.user)?
+        .ok_or_else(|| eyre::eyre!("User {} doesn't exist", privileges_config.user))?;
+    let gid = match &privileges_config.group {
+        Some(group) => {
+            Group::from_name(group)?
+                .ok_or_else(|| eyre::eyre!("Group {group} doesn't exist"))?
+                .gid
+        }
+        None => user.gid,
+    };
+    let retained = privileges_config.capabilities(config);
+    let retained_mask = mask(&retained);
+
+    UID.store(user.uid.as_raw(), Ordering::SeqCst);
+    GID.store(gid.as_raw(), Ordering::SeqCst);
+    RETAINED.store(retained_mask, Ordering::SeqCst);
+    DROPPED.store(
+        mask(&caps::runtime::thread_all_supported()) & !retained_mask,
+        Ordering::SeqCst,
+    );
+
+    let signal = libc::SIGRTMIN();
+    // an all zero sigaction is valid, only the handler and flags are set
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+
+    action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+
+    if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let pid = process::id() as libc::pid_t;
+    let mut switched = HashSet::from([gettid().as_raw()]);
+
+    loop {
+        let threads = fs::read_dir("/proc/self/task")?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|tid| switched.insert(*tid))
+            .collect::<Vec<libc::pid_t>>();
+
+        if threads.is_empty() {
+            break;
+        }
+
+        for tid in threads {
+            PENDING.fetch_add(1, Ordering::SeqCst);
+
+            // the thread is already gone
+            if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) } != 0 {
+                PENDING.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let deadline = Instant::now() + THREAD_TIMEOUT;
+
+        while PENDING.load(Ordering::SeqCst) > 0 {
+            if Instant::now() > deadline {
+                eyre::bail!("Can't drop privileges, a thread didn't switch in time");
+            }
+
+            thread::sleep(THREAD_POLL_INTERVAL);
+        }
+    }
+
+    if !switch_thread() {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    if FAILED.load(Ordering::SeqCst) {
+        eyre::bail!("Can't drop privileges, a thread failed to switch");
+    }
+
+    tracing::info!(
+        "Dropped privileges to {}:{gid}, keeping {retained:?}",
+        privileges_config.user,
+    );
+
+    Ok(())
+}
+
+fn mask(capabilities: &CapsHashSet) -> u64 {
+    capabilities
+        .iter()
+        .fold(0, |mask, capability| mask | capability.bitmask())
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // the interrupted code may still look at errno
+    let errno = unsafe { *libc::__errno_location() };
+
+    if !switch_thread() {
+        FAILED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe { *libc::__errno_location() = errno };
+    PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
+// raw system calls only, the c library wrappers would act on every thread and aren't safe to call
+// from a signal handler
+fn switch_thread() -> bool {
+    let uid = UID.load(Ordering::SeqCst);
+    let gid = GID.load(Ordering::SeqCst);
+    let retained = RETAINED.load(Ordering::SeqCst);
+    let dropped = DROPPED.load(Ordering::SeqCst);
+    let header = CapabilityHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [retained as u32, (retained >> 32) as u32].map(|retained| CapabilityData {
+        effective: retained,
+        permitted: retained,
+        inheritable: 0,
+    });
+
+    unsafe {
+        (0..u64::BITS)
+            .filter(|index| dropped & (1 << index) != 0)
+            .all(|index| libc::prctl(libc::PR_CAPBSET_DROP, index as libc::c_ulong, 0, 0, 0) == 0)
+            // the permitted capabilities survive the switch away from root
+            && libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) == 0
+            && libc::syscall(libc::SYS_setgroups, 1, &gid) == 0
+            && libc::syscall(libc::SYS_setresgid, gid, gid, gid) == 0
+            && libc::syscall(libc::SYS_setresuid, uid, uid, uid) == 0
+            && libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) == 0
+            && libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) == 0
+            && libc::syscall(libc::SYS_capset, &header, data.as_ptr()) == 0
+    }
+}
@@ -0,0 +1,53 @@
+use std::{collections::HashMap, hash::Hash, sync::Mutex, time::Instant};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateLimitConfig {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+pub struct RateLimiter<K> {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, key: K) -> bool {
+        self.check_n(key, 1.0)
+    }
+
+    pub fn check_n(&self, key: K, cost: f64) -> bool {
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock poisoned");
+        let now = Instant::now();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.config.burst as f64,
+            last_refill: now,
+        });
+
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last_refill).as_secs_f64() * self.config.rate)
+            .min(self.config.burst as f64);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+}
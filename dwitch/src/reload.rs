@@ -0,0 +1,254 @@
+//! Reloading the config on SIGHUP. Only the peers to dial, the listen addresses and the declared
+//! vrfs are applied, the other settings still need a restart. The peers discovery finds and the
+//! ones gossip advertises are dialed along with the configured ones.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    sync::Arc,
+};
+
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    spawn,
+    sync::{mpsc::Receiver, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::{Config, ServerAddress},
+    discovery::Discovery,
+    management::declare_vrfs,
+    runtime::spawn_data_plane,
+    socket::{
+        client::{client, stop_client},
+        server::{bind, server},
+    },
+    state::State,
+};
+
+/// The tasks a reload can replace: a listener for each listen address and a client for each peer
+/// dialed.
+pub struct Peering {
+    state: Arc<State>,
+    // the address the quic endpoint is bound to
+    quic_listen: SocketAddr,
+    listeners: HashMap<SocketAddr, JoinHandle<()>>,
+    clients: HashMap<ServerAddress, JoinHandle<()>>,
+    servers: HashSet<ServerAddress>,
+    discovered: HashSet<ServerAddress>,
+    advertised: HashSet<ServerAddress>,
+    attempts: Arc<Semaphore>,
+}
+
+impl Peering {
+    /// Listen and dial the peers of the config the daemon started with.
+    pub async fn start(state: Arc<State>, mut inherited: Vec<TcpListener>) -> Self {
+        let mut peering = Self {
+            quic_listen: state.config.listen_address(),
+            listeners: HashMap::new(),
+            clients: HashMap::new(),
+            servers: state.config.servers.iter().cloned().collect(),
+            discovered: HashSet::new(),
+            advertised: HashSet::new(),
+            attempts: Arc::new(Semaphore::new(state.config.connect_parallelism.max(1))),
+            state,
+        };
+
+        for address in peering.state.config.listen.clone() {
+            peering.listen(address, &mut inherited).await;
+        }
+
+        for listener in inherited {
+            tracing::warn!(
+                "Ignored the inherited listener on {:?}, it isn't bound to a listen address",
+                listener.local_addr()
+            );
+        }
+
+        for address in peering.state.config.servers.clone() {
+            peering.dial(address);
+        }
+
+        peering
+    }
+
+    async fn listen(&mut self, address: SocketAddr, inherited: &mut Vec<TcpListener>) {
+        match bind(address, inherited).await {
+            Ok(listener) => {
+                let task = spawn(server(self.state.clone(), address, listener));
+
+                self.listeners.insert(address, task);
+                tracing::info!("Listening on {address}");
+            }
+            Err(error) => tracing::error!("Can't listen on {address}: {error}"),
+        }
+    }
+
+    fn dial(&mut self, address: ServerAddress) {
+        self.clients.entry(address.clone()).or_insert_with(|| {
+            spawn_data_plane(client(self.state.clone(), address, self.attempts.clone()))
+        });
+    }
+
+    async fn discover(&mut self, discovery: Discovery) {
+        match discovery {
+            Discovery::Found(address) => {
+                let address = ServerAddress::from(address);
+
+                if self.discovered.insert(address.clone()) && !self.clients.contains_key(&address) {
+                    tracing::info!("Dialing {address}");
+                    self.dial(address);
+                }
+            }
+            Discovery::Lost(address) => {
+                let address = ServerAddress::from(address);
+
+                if !self.discovered.remove(&address)
+                    || self.servers.contains(&address)
+                    || self.advertised.contains(&address)
+                {
+                    return;
+                }
+
+                if let Some(task) = self.clients.remove(&address) {
+                    stop_client(&self.state, task).await;
+                    tracing::info!("Stopped dialing {address}");
+                }
+            }
+            Discovery::Advertised { switch_id, address } => {
+                let address = ServerAddress::from(address);
+                let Some(gossip) = &self.state.config.gossip else {
+                    return;
+                };
+
+                // a switch already connected, whichever side dialed, isn't dialed again
+                if self.clients.contains_key(&address)
+                    || self
+                        .state
+                        .client_table
+                        .read()
+                        .await
+                        .contains_key(&switch_id)
+                {
+                    return;
+                }
+
+                if self.advertised.len() >= gossip.max_peers {
+                    tracing::debug!(
+                        "Not dialing switch id {switch_id} at {address}, gossip.max_peers reached"
+                    );
+                    return;
+                }
+
+                tracing::info!("Dialing switch id {switch_id} at {address}");
+                self.advertised.insert(address.clone());
+                self.dial(address);
+            }
+        }
+    }
+
+    async fn apply(&mut self, config: Config) {
+        self.servers = config.servers.iter().cloned().collect();
+
+        let removed = self
+            .clients
+            .keys()
+            .filter(|address| {
+                !self.servers.contains(address)
+                    && !self.discovered.contains(address)
+                    && !self.advertised.contains(address)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for address in removed {
+            if let Some(task) = self.clients.remove(&address) {
+                stop_client(&self.state, task).await;
+                tracing::info!("Stopped dialing {address}");
+            }
+        }
+
+        for address in self.servers.clone() {
+            if !self.clients.contains_key(&address) {
+                tracing::info!("Dialing {address}");
+                self.dial(address);
+            }
+        }
+
+        for address in config.listen.clone() {
+            if !self.listeners.contains_key(&address) {
+                self.listen(address, &mut Vec::new()).await;
+            }
+        }
+
+        // quic connections migrate to the new socket
+        if config.listen_address() != self.quic_listen {
+            if let Some(endpoint) = &self.state.quic {
+                match UdpSocket::bind(config.listen_address())
+                    .and_then(|socket| endpoint.rebind(socket))
+                {
+                    Ok(()) => self.quic_listen = config.listen_address(),
+                    Err(error) => tracing::error!(
+                        "Can't listen on {} over quic: {error}",
+                        config.listen_address()
+                    ),
+                }
+            }
+        }
+
+        // the old listeners are kept until a new one is bound, landlock only allows the ports of
+        // the config the daemon started with and its extra ports
+        if !config
+            .listen
+            .iter()
+            .any(|address| self.listeners.contains_key(address))
+        {
+            return;
+        }
+
+        let stopped = self
+            .listeners
+            .keys()
+            .filter(|address| !config.listen.contains(address))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for address in stopped {
+            if let Some(task) = self.listeners.remove(&address) {
+                task.abort();
+                self.state.handover_fds.remove_listener(address);
+                tracing::info!("Stopped listening on {address}");
+            }
+        }
+    }
+}
+
+/// Apply the config again each time the daemon gets a SIGHUP, and follow what discovery finds.
+pub async fn reload(mut peering: Peering, mut discovered: Receiver<Discovery>) -> io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    loop {
+        select! {
+            signal = hangup.recv() => {
+                if signal.is_none() {
+                    break;
+                }
+
+                match Config::load() {
+                    Ok(config) => {
+                        tracing::info!("Reloading the config");
+                        declare_vrfs(&peering.state, &config.vrfs).await;
+                        peering.apply(config).await;
+                    }
+                    Err(error) => tracing::error!("Can't reload config: {error}"),
+                }
+            }
+            Some(discovery) = discovered.recv() => peering.discover(discovery).await,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! Routes leaked between the vrfs of a switch, for the shared services pattern without an external
+//! router.
+//!
+//! A leak routes some prefixes from the routing table of a vrf into the vrf device of another one,
+//! carving out the denied prefixes with prohibit routes. It's one way, the replies need a leak the
+//! other way round. Leaks need the vrf dataplane and are only set up on the switch they name, once
+//! both vrfs have a local tap, and again whenever one of them comes back.
+
+use std::sync::Arc;
+
+use protocol::{prefix, IpPrefix};
+use serde::{Deserialize, Deserializer};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    config::SwitchId,
+    events::{subscribe, Event},
+    link::{self, Dataplane},
+    state::State,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteLeakConfig {
+    /// Switch routing between the two vrfs, the leak is ignored by the others
+    pub switch_id: SwitchId,
+    /// Vrf whose routing table gets the routes
+    pub from: String,
+    /// Vrf the prefixes are reached in
+    pub to: String,
+    #[serde(deserialize_with = "deserialize_prefixes")]
+    pub prefixes: Vec<IpPrefix>,
+    /// Parts of the prefixes kept out of the leak
+    #[serde(default, deserialize_with = "deserialize_prefixes")]
+    pub deny: Vec<IpPrefix>,
+}
+
+pub async fn route_leaks(leaks: Vec<RouteLeakConfig>, state: Arc<State>) {
+    let leaks = leaks
+        .into_iter()
+        .filter(|leak| leak.switch_id == state.config.switch_id)
+        .collect::<Vec<_>>();
+
+    if leaks.is_empty() {
+        return;
+    }
+
+    if state.config.dataplane != Dataplane::Vrf {
+        tracing::error!("Can't leak routes between vrfs without the vrf dataplane");
+        return;
+    }
+
+    // subscribed before the first pass so no tap coming up in between is missed
+    let mut events = subscribe();
+
+    apply(&leaks, &state).await;
+
+    loop {
+        match events.recv().await {
+            Ok(
+                Event::VrfCreated { .. }
+                | Event::VrfMembersAdded { .. }
+                | Event::TapRecovered { .. },
+            )
+            | Err(RecvError::Lagged(_)) => apply(&leaks, &state).await,
+            Ok(_) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn apply(leaks: &[RouteLeakConfig], state: &State) {
+    let local_vrfs = state
+        .vrf_table
+        .read()
+        .await
+        .values()
+        .filter(|vrf| vrf.members.contains(&state.config.switch_id))
+        .map(|vrf| (vrf.name.clone(), vrf.id))
+        .collect::<Vec<_>>();
+    let local_vrf_id = |name: &str| {
+        local_vrfs
+            .iter()
+            .find(|(vrf_name, _)| vrf_name == name)
+            .map(|(_, id)| *id)
+    };
+
+    for leak in leaks {
+        let (Some(from_id), Some(to_id)) = (local_vrf_id(&leak.from), local_vrf_id(&leak.to))
+        else {
+            continue;
+        };
+
+        // the routes are replaced, applying a leak again is harmless
+        if let Err(error) = link::leak_routes(from_id, to_id, &leak.prefixes, &leak.deny).await {
+            tracing::warn!(
+                "Can't leak routes from vrf {} to vrf {}: {error}",
+                leak.from,
+                leak.to
+            );
+        }
+    }
+}
+
+fn deserialize_prefixes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<IpPrefix>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|prefix| {
+            prefix::parse(prefix)
+                .ok_or_else(|| serde::de::Error::custom(format!("Invalid ip prefix {prefix}")))
+        })
+        .collect()
+}
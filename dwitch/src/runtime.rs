@@ -0,0 +1,80 @@
+use std::{future::Future, io, sync::OnceLock};
+
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+use serde::Deserialize;
+use tokio::{
+    runtime::{Builder, EnterGuard, Runtime},
+    task::JoinHandle,
+};
+
+static DATA_PLANE: OnceLock<Runtime> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub worker_threads: Option<usize>,
+    pub cpus: Vec<usize>,
+    pub data_plane_threads: Option<usize>,
+    pub data_plane_cpus: Vec<usize>,
+}
+
+/// Build the main runtime, and a separate one for taps and peer writers if it's configured.
+pub fn build(config: &RuntimeConfig) -> io::Result<Runtime> {
+    if config.data_plane_threads.is_some() || !config.data_plane_cpus.is_empty() {
+        let runtime = builder(
+            "dwitch-data",
+            config.data_plane_threads,
+            &config.data_plane_cpus,
+        )?
+        .build()?;
+
+        let _ = DATA_PLANE.set(runtime);
+    }
+
+    builder("dwitch-worker", config.worker_threads, &config.cpus)?.build()
+}
+
+fn builder(name: &str, worker_threads: Option<usize>, cpus: &[usize]) -> io::Result<Builder> {
+    let mut builder = Builder::new_multi_thread();
+
+    builder.enable_all().thread_name(name);
+
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+
+    if !cpus.is_empty() {
+        let mut cpu_set = CpuSet::new();
+
+        for cpu in cpus {
+            cpu_set.set(*cpu).map_err(io::Error::from)?;
+        }
+
+        builder.on_thread_start(move || {
+            if let Err(error) = sched_setaffinity(Pid::from_raw(0), &cpu_set) {
+                tracing::error!("Can't set cpu affinity: {error}");
+            }
+        });
+    }
+
+    Ok(builder)
+}
+
+// io resources are driven by the runtime they're created in
+pub fn enter_data_plane() -> Option<EnterGuard<'static>> {
+    DATA_PLANE.get().map(Runtime::enter)
+}
+
+pub fn spawn_data_plane<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match DATA_PLANE.get() {
+        Some(runtime) => runtime.spawn(future),
+        None => tokio::spawn(future),
+    }
+}
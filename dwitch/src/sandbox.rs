@@ -0,0 +1,150 @@
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+
+#[cfg(target_os = "linux")]
+use landlock::{
+    Access, AccessNet, NetPort, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI,
+};
+#[cfg(target_os = "linux")]
+use seccompiler::{apply_filter_all_threads, BpfProgram, SeccompAction, SeccompFilter};
+use serde::Deserialize;
+
+#[cfg(target_os = "linux")]
+use crate::config::Config;
+
+// syscalls the daemon never needs once it's running, denied to limit what an exploit could do
+#[cfg(target_os = "linux")]
+const DENIED_SYSCALLS: &[i64] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_acct,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_personality,
+];
+
+/// Both modes are opt in, the denylist forbids running hooks and Landlock only allows tcp.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    pub seccomp: bool,
+    pub landlock: bool,
+    /// Tcp ports allowed to bind and connect to besides those of the config, for the listen address
+    /// and peers a reload brings in.
+    pub extra_ports: Vec<u16>,
+}
+
+/// Restrict tcp binds and connections to the ports found in the config and its extra ports.
+///
+/// Landlock only applies to the calling thread and the threads it spawns afterwards, so this must
+/// run before the runtime is built. Filesystem rules aren't used because they forbid the mounts
+/// needed to create vrf namespaces.
+#[cfg(target_os = "linux")]
+pub fn restrict_network(config: &Config) -> eyre::Result<()> {
+    let abi = ABI::V4;
+    let mut bind_ports = config
+        .listen
+        .iter()
+        .map(|address| address.port())
+        .collect::<Vec<_>>();
+    let mut connect_ports = config
+        .servers
+        .iter()
+        .map(|address| address.port())
+        .collect::<Vec<_>>();
+
+    // discovered and advertised switches are expected on the port of this one, others need an
+    // extra port
+    if config.discovery.is_some() || config.gossip.is_some() {
+        connect_ports.push(config.listen_address().port());
+    }
+
+    bind_ports.extend(&config.sandbox.extra_ports);
+    connect_ports.extend(&config.sandbox.extra_ports);
+
+    if let Some(health) = &config.health {
+        bind_ports.push(health.listen.port());
+    }
+
+    if let Some(metrics) = &config.metrics {
+        bind_ports.push(metrics.listen.port());
+    }
+
+    if let Some(api) = &config.api {
+        bind_ports.push(api.listen.port());
+    }
+
+    if let Some(mqtt) = &config.mqtt {
+        connect_ports.push(mqtt.port);
+    }
+
+    if let Some(evpn) = &config.evpn {
+        connect_ports.push(evpn.neighbor.port());
+    }
+
+    if let Some(openflow) = &config.openflow {
+        connect_ports.push(openflow.controller.port());
+    }
+
+    let status =
+        Ruleset::default()
+            .handle_access(AccessNet::from_all(abi))?
+            .create()?
+            .add_rules(bind_ports.into_iter().map(|port| {
+                Ok::<_, landlock::RulesetError>(NetPort::new(port, AccessNet::BindTcp))
+            }))?
+            .add_rules(connect_ports.into_iter().map(|port| {
+                Ok::<_, landlock::RulesetError>(NetPort::new(port, AccessNet::ConnectTcp))
+            }))?
+            .restrict_self()?;
+
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => tracing::info!("Landlock network rules enforced"),
+        RulesetStatus::PartiallyEnforced => {
+            tracing::warn!("Landlock network rules are only partially enforced by this kernel")
+        }
+        RulesetStatus::NotEnforced => {
+            tracing::warn!("Landlock isn't supported by this kernel, network rules not enforced")
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn install_seccomp() -> eyre::Result<()> {
+    let filter: BpfProgram = SeccompFilter::new(
+        DENIED_SYSCALLS
+            .iter()
+            .map(|syscall| (*syscall, Vec::new()))
+            .collect::<BTreeMap<_, _>>(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        std::env::consts::ARCH.try_into()?,
+    )?
+    .try_into()?;
+
+    apply_filter_all_threads(&filter)?;
+
+    tracing::info!("Seccomp filter installed");
+
+    Ok(())
+}
@@ -0,0 +1,419 @@
+//! Loopback self-test, for smoke testing a build or a host before it joins an overlay.
+//!
+//! Two switches run in the process, wired through memory. The first one gets a real tap in a test
+//! vrf, the second a virtual one. Frames are sent both ways through the tap and the forwarding
+//! pipelines, then the learned macs and the mac table counters are checked.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    future::Future,
+    io,
+    net::SocketAddr,
+    os::fd::{AsRawFd, OwnedFd},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use netns::Netns;
+use nix::sys::socket::{recv, send, MsgFlags};
+#[cfg(target_os = "linux")]
+use nix::{
+    net::if_::if_nametoindex,
+    sys::{
+        socket::{
+            setsockopt, socket, sockopt::ReceiveTimeout, AddressFamily, SockFlag, SockProtocol,
+            SockType,
+        },
+        time::TimeVal,
+    },
+};
+use protocol::{Vrf, VrfMetadata, VrfSettings};
+use tokio::{
+    io::duplex,
+    spawn,
+    sync::{mpsc::channel, RwLock},
+    task::spawn_blocking,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    acl::Acls,
+    config::{Config, SwitchId},
+    gossip::Gossip,
+    link,
+    mirror::Mirrors,
+    rate_limit::RateLimiter,
+    socket::{
+        client::{client_connection, peer_channel},
+        server::accept_client,
+    },
+    state::State,
+    switch_table::MacAddress,
+    tap::{try_tap, virtual_tap, VirtualWire},
+};
+
+/// Instance the self-test runs as, so its links don't clash with a running daemon.
+pub const SELF_TEST_INSTANCE: &str = "self-test";
+
+const VRF_ID: u32 = 1;
+const TAP_SWITCH_ID: SwitchId = 1;
+const VIRTUAL_SWITCH_ID: SwitchId = 2;
+// locally administered, never a real host
+const TAP_MAC: MacAddress = [0x02, 0xd7, 0x00, 0x00, 0x01, 0x01];
+const VIRTUAL_MAC: MacAddress = [0x02, 0xd7, 0x00, 0x00, 0x01, 0x02];
+const ETHERTYPE: u16 = 0x88b5;
+const MAGIC: &[u8; 16] = b"dwitch-self-test";
+const STEP_TIMEOUT: Duration = Duration::from_secs(2);
+// leaves the tap connection the time to tear the tap down
+const TEARDOWN_WAIT: Duration = Duration::from_secs(1);
+
+type StepError = Box<dyn Error + Send + Sync>;
+
+pub async fn self_test() -> Result<(), StepError> {
+    let vrf = Vrf {
+        id: VRF_ID,
+        name: SELF_TEST_INSTANCE.to_string(),
+        members: vec![TAP_SWITCH_ID, VIRTUAL_SWITCH_ID],
+        template: None,
+        settings: VrfSettings::default(),
+        metadata: VrfMetadata::default(),
+        suspended: false,
+        version: 0,
+    };
+    let tap_state = instance(TAP_SWITCH_ID, &vrf)?;
+    let virtual_state = instance(VIRTUAL_SWITCH_ID, &vrf)?;
+    let (tap, wire) = virtual_tap(vrf.clone(), virtual_state.clone());
+
+    virtual_state.tap_table.write().await.insert(vrf.id, tap);
+
+    let tap = step("tap", try_tap(vrf.clone(), tap_state.clone())).await?;
+
+    tap_state.tap_table.write().await.insert(vrf.id, tap);
+
+    let result = run(&tap_state, &virtual_state, &vrf, wire).await;
+
+    // dropping the sender ends the tap connection, which removes the tap
+    tap_state.tap_table.write().await.clear();
+    sleep(TEARDOWN_WAIT).await;
+
+    if result.is_ok() {
+        println!("Self-test passed");
+    }
+
+    result
+}
+
+async fn run(
+    tap_state: &Arc<State>,
+    virtual_state: &Arc<State>,
+    vrf: &Vrf,
+    mut wire: VirtualWire,
+) -> Result<(), StepError> {
+    // peers send their frames over the connection they opened to each other
+    for (client, server) in [(tap_state, virtual_state), (virtual_state, tap_state)] {
+        let (client_stream, server_stream) = duplex(1 << 20);
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        spawn(accept_client(
+            server.clone(),
+            server_stream,
+            address,
+            None,
+            None,
+            None,
+        ));
+        spawn({
+            let client = client.clone();
+
+            async move {
+                let (sender, mut receiver) = peer_channel();
+
+                client_connection(
+                    &client,
+                    client_stream,
+                    None,
+                    None,
+                    None,
+                    &sender,
+                    &mut receiver,
+                )
+                .await
+            }
+        });
+    }
+
+    step("peering", async {
+        let start = Instant::now();
+
+        while !tap_state
+            .client_table
+            .read()
+            .await
+            .contains_key(&VIRTUAL_SWITCH_ID)
+            || !virtual_state
+                .client_table
+                .read()
+                .await
+                .contains_key(&TAP_SWITCH_ID)
+        {
+            if start.elapsed() > STEP_TIMEOUT {
+                return Err("The switches didn't connect".into());
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    let socket = Arc::new(step("packet socket", tap_socket(tap_state, vrf)).await?);
+
+    step("flood to the tap", async {
+        wire.sender
+            .send(frame([0xff; 6], VIRTUAL_MAC))
+            .await
+            .map_err(|_| "The virtual tap is gone")?;
+
+        let socket = socket.clone();
+
+        spawn_blocking(move || receive(&socket, VIRTUAL_MAC)).await??;
+
+        Ok(())
+    })
+    .await?;
+
+    step("learning from a peer", async {
+        learned(tap_state, VIRTUAL_MAC, VIRTUAL_SWITCH_ID).await
+    })
+    .await?;
+
+    step("unicast from the tap", async {
+        let socket = socket.clone();
+
+        spawn_blocking(move || {
+            send(
+                socket.as_raw_fd(),
+                &frame(VIRTUAL_MAC, TAP_MAC),
+                MsgFlags::empty(),
+            )
+        })
+        .await??;
+
+        timeout(STEP_TIMEOUT, async {
+            while let Some(frame) = wire.receiver.recv().await {
+                if is_test_frame(&frame, TAP_MAC) {
+                    return Ok(());
+                }
+            }
+
+            Err("The virtual tap is gone")
+        })
+        .await
+        .map_err(|_| "The frame didn't reach the peer")??;
+
+        Ok(())
+    })
+    .await?;
+
+    step("learning from the tap", async {
+        learned(virtual_state, TAP_MAC, TAP_SWITCH_ID).await
+    })
+    .await?;
+
+    // the kernel sends frames of its own on the tap, their macs are learned too
+    step("mac table counters", async {
+        for state in [tap_state, virtual_state] {
+            let usage = state
+                .switch_table
+                .read()
+                .await
+                .usage()
+                .find(|(vrf_id, _, _)| *vrf_id == VRF_ID);
+
+            match usage {
+                Some((_, entries, 0)) if entries > 0 => {}
+                usage => {
+                    return Err(format!(
+                        "Switch id {} counts {usage:?} macs and evictions",
+                        state.config.switch_id
+                    )
+                    .into())
+                }
+            }
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+async fn step<T>(
+    name: &str,
+    future: impl Future<Output = Result<T, StepError>>,
+) -> Result<T, StepError> {
+    match future.await {
+        Ok(value) => {
+            println!("{name}: ok");
+            Ok(value)
+        }
+        Err(error) => {
+            println!("{name}: failed, {error}");
+            Err(format!("Self-test failed at {name}").into())
+        }
+    }
+}
+
+fn instance(switch_id: SwitchId, vrf: &Vrf) -> Result<Arc<State>, StepError> {
+    let config: Config = toml::from_str(&format!(
+        "switch_id = {switch_id}\nlisten = \"127.0.0.1:0\"\nservers = []\n"
+    ))?;
+
+    Ok(Arc::new(State {
+        tls: None,
+        quic: None,
+        vxlan: None,
+        vrf_keys: HashMap::new(),
+        cache_key: None,
+        listening: AtomicBool::new(true),
+        draining: AtomicBool::new(false),
+        action_limiter: RateLimiter::new(config.action_rate_limit),
+        tap_table: RwLock::new(HashMap::new()),
+        degraded_taps: Mutex::new(HashMap::new()),
+        vrf_table: RwLock::new(HashMap::from([(vrf.id, vrf.clone())])),
+        deleted_vrfs: Mutex::new(HashMap::new()),
+        declared_vrfs: Mutex::new(HashSet::new()),
+        client_table: Arc::new(RwLock::new(HashMap::new())),
+        switch_table: Arc::new(RwLock::new(Default::default())),
+        acls: Acls::default(),
+        mirrors: Mirrors::default(),
+        ip_table: Mutex::new(Default::default()),
+        draining_peers: Mutex::new(HashSet::new()),
+        gossip: Gossip::new(channel(1).0),
+        peer_connections: Mutex::new(HashMap::new()),
+        path_mtus: Mutex::new(HashMap::new()),
+        peer_compression: Mutex::new(HashMap::new()),
+        suspensions: Mutex::new(HashMap::new()),
+        metrics: Default::default(),
+        handover_fds: Default::default(),
+        audits: Default::default(),
+        traces: Default::default(),
+        vrf_tests: Default::default(),
+        config,
+    }))
+}
+
+// a packet socket on the tap sends frames out of it and sees the ones written into it
+async fn tap_socket(state: &State, vrf: &Vrf) -> Result<OwnedFd, StepError> {
+    let name = link::tap_name(vrf.id);
+
+    Ok(match state.config.dataplane {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        link::Dataplane::Netns => {
+            let vrf_netns = Netns::named(link::netns_name(&vrf.name)).path();
+
+            link::run_in(&vrf_netns, move || packet_socket(&name)).await??
+        }
+        _ => packet_socket(&name)?,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn packet_socket(name: &str) -> io::Result<OwnedFd> {
+    let index = if_nametoindex(name)?;
+    let socket = socket(
+        AddressFamily::Packet,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::EthAll,
+    )?;
+    // an all zero sockaddr_ll is valid, only the family, protocol and index are set
+    let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+
+    address.sll_family = libc::AF_PACKET as u16;
+    address.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+    address.sll_ifindex = index as i32;
+
+    // the call only reads the address it's given
+    if unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+            size_of::<libc::sockaddr_ll>() as u32,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    setsockopt(
+        &socket,
+        ReceiveTimeout,
+        &TimeVal::new(STEP_TIMEOUT.as_secs() as _, 0),
+    )?;
+
+    Ok(socket)
+}
+
+// reading the frames written into a tap needs bpf elsewhere
+#[cfg(not(target_os = "linux"))]
+fn packet_socket(_name: &str) -> io::Result<OwnedFd> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "packet sockets only exist on linux",
+    ))
+}
+
+// the kernel sends frames of its own on the tap, like ipv6 neighbor discovery
+fn receive(socket: &OwnedFd, source: MacAddress) -> Result<(), StepError> {
+    let mut buffer = [0u8; 2048];
+    let start = Instant::now();
+
+    while start.elapsed() < STEP_TIMEOUT {
+        match recv(socket.as_raw_fd(), &mut buffer, MsgFlags::empty()) {
+            Ok(length) if is_test_frame(&buffer[..length], source) => return Ok(()),
+            Ok(_) => {}
+            Err(nix::errno::Errno::EAGAIN) => break,
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Err("The frame didn't come out of the tap".into())
+}
+
+async fn learned(state: &State, mac: MacAddress, switch_id: SwitchId) -> Result<(), StepError> {
+    let learned = state.switch_table.write().await.shard(VRF_ID).get(0, &mac);
+
+    if learned != Some(switch_id) {
+        return Err(format!(
+            "Switch id {} learned {learned:?} instead of switch id {switch_id}",
+            state.config.switch_id
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn frame(destination: MacAddress, source: MacAddress) -> Bytes {
+    let mut frame = Vec::with_capacity(64);
+
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&ETHERTYPE.to_be_bytes());
+    frame.extend_from_slice(MAGIC);
+    // padded to the minimum ethernet frame
+    frame.resize(60, 0);
+
+    Bytes::from(frame)
+}
+
+fn is_test_frame(frame: &[u8], source: MacAddress) -> bool {
+    frame.len() >= 14 + MAGIC.len()
+        && frame[6..12] == source
+        && frame[12..14] == ETHERTYPE.to_be_bytes()
+        && frame[14..].starts_with(MAGIC)
+}
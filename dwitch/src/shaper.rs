@@ -0,0 +1,52 @@
+//! Bandwidth limits of a vrf toward the underlay. Frames are checked before they're encapsulated,
+//! the ones over the limits are dropped and counted.
+
+use std::{hash::Hash, sync::Arc};
+
+use protocol::VrfSettings;
+
+use crate::{
+    config::SwitchId,
+    metrics::VrfMetrics,
+    rate_limit::{RateLimitConfig, RateLimiter},
+};
+
+pub struct Shaper {
+    vrf: Option<RateLimiter<()>>,
+    peers: Option<RateLimiter<SwitchId>>,
+    metrics: Arc<VrfMetrics>,
+}
+
+impl Shaper {
+    pub fn new(settings: &VrfSettings, metrics: Arc<VrfMetrics>) -> Self {
+        Self {
+            vrf: settings.bandwidth.map(limiter),
+            peers: settings.peer_bandwidth.map(limiter),
+            metrics,
+        }
+    }
+
+    /// Whether a frame of `length` bytes can be sent to a peer, or to the underlay when it's
+    /// `None` and the peers it reaches aren't known.
+    pub fn admits(&self, switch_id: Option<SwitchId>, length: usize) -> bool {
+        let length = length as f64;
+        let admitted = match (&self.peers, switch_id) {
+            (Some(peers), Some(switch_id)) => peers.check_n(switch_id, length),
+            _ => true,
+        } && self.vrf.as_ref().is_none_or(|vrf| vrf.check_n((), length));
+
+        if !admitted {
+            self.metrics.shape();
+        }
+
+        admitted
+    }
+}
+
+// a second of traffic fits in the bucket
+fn limiter<K: Hash + Eq>(bandwidth: u64) -> RateLimiter<K> {
+    RateLimiter::new(RateLimitConfig {
+        rate: bandwidth as f64,
+        burst: u32::try_from(bandwidth).unwrap_or(u32::MAX),
+    })
+}
@@ -0,0 +1,65 @@
+//! Bringing the daemon down on SIGTERM or SIGINT: the cache is saved, peers are told so they don't
+//! wait for the connection to time out, and the taps are torn down with their netns and links.
+
+use std::{io, sync::Arc, time::Duration};
+
+use protocol::{Goodbye, Packet};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    time::timeout,
+};
+
+use crate::{cache::Cache, state::State, tap::close_taps};
+
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Wait for SIGTERM or SIGINT, then clean up, returning once the daemon can exit.
+pub async fn shutdown(state: Arc<State>) -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+
+    tracing::info!("Shutting down");
+
+    if let Err(error) = Cache::from_state(&state)
+        .await
+        .save(&state.config.cache, state.cache_key.as_ref())
+        .await
+    {
+        tracing::error!("Can't save cache: {error}");
+    }
+
+    let peers = state
+        .client_table
+        .read()
+        .await
+        .iter()
+        .map(|(switch_id, sender)| (*switch_id, sender.clone()))
+        .collect::<Vec<_>>();
+
+    // bounded, a stuck connection can't hold the shutdown back
+    let goodbyes = async {
+        for (switch_id, sender) in &peers {
+            if let Err(error) = sender.send(Packet::from(Goodbye)).await {
+                tracing::warn!("Can't say goodbye to switch id {switch_id}: {error}");
+            }
+        }
+
+        for (_, sender) in &peers {
+            sender.flushed().await;
+        }
+    };
+
+    if timeout(GOODBYE_TIMEOUT, goodbyes).await.is_err() {
+        tracing::warn!("Can't say goodbye to every peer in time");
+    }
+
+    close_taps(&state).await;
+
+    Ok(())
+}
@@ -1,100 +1,592 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
 
-use protocol::{Packet, Ping, Vrf};
+use bytes::BytesMut;
+use common::VrfId;
+use protocol::{Goodbye, Maintenance, Packet, Ping, Session, Vrf};
+use quinn::Connection;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::TcpStream,
     select, spawn,
     sync::{
-        mpsc::{channel, Sender},
-        RwLock,
+        mpsc::{
+            channel, error::SendError, unbounded_channel, Receiver, Sender, UnboundedReceiver,
+            UnboundedSender,
+        },
+        Semaphore,
     },
+    task::JoinHandle,
     time::{sleep, sleep_until, Instant},
 };
+use tokio_stream::{wrappers::ReceiverStream, StreamExt, StreamMap};
 
+#[cfg(feature = "fault-injection")]
+use crate::socket::fault;
 use crate::{
-    config::SwitchId,
+    config::{ServerAddress, SwitchId},
+    events::{publish, Event},
+    gossip::advertise,
+    management::vrf_syncs,
     socket::{
-        exchange_switch_id, TransmitPacket, CONNECTION_RETRY_INTERVAL, PING_INTERVAL, PING_TIMEOUT,
+        exchange_switch_id, peer_address, probe_path_mtu,
+        quic::{self, receive_data, DataStreams},
+        server::handle_peer_packet,
+        switch_id_conflict,
+        tls::{verify_switch_id, PeerCertificates},
+        TransmitPacket, CONNECTION_RETRY_INTERVAL, MAX_CONNECTION_RETRY_INTERVAL, PING_INTERVAL,
+        PING_TIMEOUT,
     },
-    MAX_BUFFER_SIZE,
+    state::State,
 };
 
-pub type ClientTable = HashMap<SwitchId, Sender<Packet>>;
+pub type ClientTable = HashMap<SwitchId, PeerSender>;
 
-pub async fn client(
-    switch_id: SwitchId,
-    address: SocketAddr,
-    client_table: Arc<RwLock<ClientTable>>,
-) {
-    let (sender, mut receiver) = channel::<Packet>(32);
-    let mut buffer = [0u8; MAX_BUFFER_SIZE];
+/// The connection a peer is reached through.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerConnection {
+    pub address: Option<SocketAddr>,
+    pub since: Instant,
+}
+
+const QUEUE_SIZE: usize = 32;
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Queues toward a peer, each vrf gets its own data queue so a busy vrf can't starve the others.
+#[derive(Clone)]
+pub struct PeerSender {
+    control: Sender<Packet>,
+    data: Arc<Mutex<HashMap<VrfId, Sender<Packet>>>>,
+    new_queues: UnboundedSender<(VrfId, Receiver<Packet>)>,
+}
+
+pub struct PeerReceiver {
+    control: Receiver<Packet>,
+    data: StreamMap<VrfId, ReceiverStream<Packet>>,
+    new_queues: UnboundedReceiver<(VrfId, Receiver<Packet>)>,
+}
+
+pub fn peer_channel() -> (PeerSender, PeerReceiver) {
+    let (control_sender, control_receiver) = channel(QUEUE_SIZE);
+    let (new_queues_sender, new_queues_receiver) = unbounded_channel();
+
+    (
+        PeerSender {
+            control: control_sender,
+            data: Arc::new(Mutex::new(HashMap::new())),
+            new_queues: new_queues_sender,
+        },
+        PeerReceiver {
+            control: control_receiver,
+            data: StreamMap::new(),
+            new_queues: new_queues_receiver,
+        },
+    )
+}
+
+impl PeerSender {
+    pub async fn send(&self, packet: Packet) -> Result<(), SendError<Packet>> {
+        self.control.send(packet).await
+    }
+
+    /// Queue a frame of the vrf, waiting only on the vrf's own queue toward this peer.
+    pub async fn send_data(&self, vrf_id: VrfId, packet: Packet) -> Result<(), SendError<Packet>> {
+        let sender = self
+            .data
+            .lock()
+            .unwrap()
+            .entry(vrf_id)
+            .or_insert_with(|| {
+                let (sender, receiver) = channel(QUEUE_SIZE);
+
+                let _ = self.new_queues.send((vrf_id, receiver));
+
+                sender
+            })
+            .clone();
+
+        sender.send(packet).await
+    }
+
+    /// Wait until the connection took every control packet queued so far, or is gone.
+    pub async fn flushed(&self) {
+        while self.control.capacity() < self.control.max_capacity() && !self.control.is_closed() {
+            sleep(FLUSH_POLL_INTERVAL).await;
+        }
+    }
+
+    pub fn remove_vrf(&self, vrf_id: VrfId) {
+        self.data.lock().unwrap().remove(&vrf_id);
+    }
+
+    fn same_peer(&self, other: &PeerSender) -> bool {
+        self.control.same_channel(&other.control)
+    }
+}
+
+impl PeerReceiver {
+    // cancel safe, every branch is
+    pub(super) async fn recv(&mut self) -> Option<Packet> {
+        loop {
+            select! {
+                packet = self.control.recv() => return packet,
+                Some((vrf_id, receiver)) = self.new_queues.recv() => {
+                    self.data.insert(vrf_id, ReceiverStream::new(receiver));
+                }
+                Some((_, packet)) = self.data.next(), if !self.data.is_empty() => {
+                    return Some(packet)
+                }
+            }
+        }
+    }
+}
+
+/// Keep a connection to a peer, `attempts` bounding the connection attempts made at once by all
+/// the peers.
+pub async fn client(state: Arc<State>, address: ServerAddress, attempts: Arc<Semaphore>) {
+    let (sender, mut receiver) = peer_channel();
+    let mut interval = CONNECTION_RETRY_INTERVAL;
 
     loop {
-        let mut stream = match TcpStream::connect(address).await {
-            Ok(stream) => stream,
-            Err(error) => {
-                tracing::warn!("Can't connect to {address}: {error}");
-                sleep(CONNECTION_RETRY_INTERVAL).await;
-                continue;
+        let connected = 'attempt: {
+            // only held until the session is up, connected peers don't keep others waiting
+            let Ok(permit) = attempts.acquire().await else {
+                return;
+            };
+            // a host name is resolved again on each attempt, its address may have changed
+            let resolved = match address.resolve().await {
+                Ok(resolved) => resolved,
+                Err(error) => {
+                    tracing::warn!("Can't resolve {address}: {error}");
+                    break 'attempt false;
+                }
+            };
+
+            if let Some(endpoint) = &state.quic {
+                match quic::connect(endpoint, resolved).await {
+                    Ok((connection, stream, certificates)) => {
+                        drop(permit);
+
+                        let connected = client_connection(
+                            &state,
+                            stream,
+                            None,
+                            Some(certificates),
+                            Some(&connection),
+                            &sender,
+                            &mut receiver,
+                        )
+                        .await;
+
+                        connection.close(0u32.into(), b"");
+                        break 'attempt connected;
+                    }
+                    Err(error) => {
+                        tracing::warn!("Can't connect to {address} over quic: {error}");
+                        break 'attempt false;
+                    }
+                }
+            }
+
+            let stream = match TcpStream::connect(resolved).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    tracing::warn!("Can't connect to {address}: {error}");
+                    break 'attempt false;
+                }
+            };
+
+            tracing::debug!("Client connected to {}", address);
+
+            let socket = stream.as_raw_fd();
+
+            match &state.tls {
+                Some(tls) => match tls.connect(stream).await {
+                    Ok((stream, certificates)) => {
+                        drop(permit);
+
+                        client_connection(
+                            &state,
+                            stream,
+                            Some(socket),
+                            Some(certificates),
+                            None,
+                            &sender,
+                            &mut receiver,
+                        )
+                        .await
+                    }
+                    Err(error) => {
+                        tracing::warn!("Can't establish tls session with {address}: {error}");
+                        false
+                    }
+                },
+                None => {
+                    drop(permit);
+
+                    client_connection(
+                        &state,
+                        stream,
+                        Some(socket),
+                        None,
+                        None,
+                        &sender,
+                        &mut receiver,
+                    )
+                    .await
+                }
             }
         };
 
-        tracing::debug!("Client connected to {}", address);
+        // a session that was up starts over from the shortest interval
+        if connected {
+            interval = CONNECTION_RETRY_INTERVAL;
+        }
 
-        {
-            let Some(switch_id) = exchange_switch_id(&mut stream, switch_id).await else {
-                continue;
-            };
+        sleep(jitter(interval)).await;
+
+        if !connected {
+            interval = (interval * 2).min(MAX_CONNECTION_RETRY_INTERVAL);
+        }
+    }
+}
+
+/// Stop a `client` task, dropping its connection.
+pub async fn stop_client(state: &State, task: JoinHandle<()>) {
+    task.abort();
+    let _ = task.await;
+
+    // aborted midway, the connection didn't forget its peer
+    let closed = state
+        .client_table
+        .read()
+        .await
+        .iter()
+        .filter(|(_, sender)| sender.control.is_closed())
+        .map(|(switch_id, sender)| (*switch_id, sender.clone()))
+        .collect::<Vec<_>>();
+
+    for (switch_id, sender) in closed {
+        unregister(state, switch_id, &sender).await;
+    }
+}
+
+// anywhere in the second half of the interval, so peers cut off together don't retry together
+fn jitter(interval: Duration) -> Duration {
+    let half = interval / 2;
+    let spread = RandomState::new().hash_one(Instant::now()) % (half.as_millis() as u64 + 1);
+
+    half + Duration::from_millis(spread)
+}
 
-            tracing::debug!("Server switch id {switch_id}");
+/// Run a connection to a peer, `socket` being the tcp socket under `stream` when there's one and
+/// `quic` the quic connection `stream` is the first stream of.
+pub async fn client_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &Arc<State>,
+    mut stream: S,
+    socket: Option<RawFd>,
+    certificates: Option<PeerCertificates>,
+    quic: Option<&Connection>,
+    sender: &PeerSender,
+    receiver: &mut PeerReceiver,
+) -> bool {
+    let switch_id = state.config.switch_id;
+    let key = state.control_key();
+    let mut buffer = BytesMut::new();
+    let Some((server_handshake, mut session)) =
+        exchange_switch_id(&mut stream, &mut buffer, switch_id, key).await
+    else {
+        return false;
+    };
+    let server_switch_id = server_handshake.switch_id;
 
-            let mut client_table = client_table.write().await;
+    tracing::debug!("Server switch id {server_switch_id}");
 
-            client_table.insert(switch_id, sender.clone());
+    if let Some(certificates) = certificates {
+        if let Err(error) = verify_switch_id(&certificates, server_switch_id) {
+            tracing::error!(
+                "Server certificate doesn't match switch id {server_switch_id}: {error}"
+            );
+            return false;
         }
+    }
+
+    let address = socket
+        .and_then(peer_address)
+        .or_else(|| quic.map(Connection::remote_address));
+
+    if switch_id_conflict(state, server_switch_id, address) {
+        return false;
+    }
+
+    state
+        .peer_compression
+        .lock()
+        .unwrap()
+        .insert(server_switch_id, server_handshake.compression);
 
-        spawn({
-            let sender = sender.clone();
+    // takes over from a connection the peer made to this switch
+    register(state, server_switch_id, sender, true, socket).await;
+    probe_path_mtu(state, server_switch_id, socket);
 
-            async move {
-                while let Ok(()) = sender.send(Packet::Ping(Ping)).await {
-                    sleep(PING_INTERVAL).await;
+    let metrics = state.metrics.peer(server_switch_id);
+    // the peer answers each ping, the rtt includes the time spent in the control queue
+    let ping_sent = Arc::new(Mutex::new(None));
+    let ping_task = spawn({
+        let sender = sender.clone();
+        let ping_sent = ping_sent.clone();
+
+        async move {
+            loop {
+                *ping_sent.lock().unwrap() = Some(Instant::now());
+
+                if sender.send(Packet::Ping(Ping)).await.is_err() {
+                    break;
                 }
+
+                sleep(PING_INTERVAL).await;
             }
-        });
+        }
+    });
 
-        let mut ping_timeout = Instant::now() + PING_TIMEOUT;
+    let mut ping_timeout = Instant::now() + PING_TIMEOUT;
+    #[cfg(feature = "fault-injection")]
+    let mut injector = fault::Injector::default();
+    let mut data_streams = quic.cloned().map(DataStreams::new);
+    let data_task = quic.map(|connection| {
+        spawn(receive_data(
+            state.clone(),
+            session.without_replay_window(),
+            connection.clone(),
+        ))
+    });
 
-        loop {
-            select! {
-                Some(packet) = receiver.recv() => {
-                    stream.send_packet(packet).await;
+    loop {
+        select! {
+            Some(packet) = receiver.recv() => {
+                metrics.sent.count_data(&packet);
+
+                let vrf_id = match &packet {
+                    Packet::Data(data) => Some(data.vrf_id),
+                    _ => None,
+                };
+
+                // one packet that can't be signed doesn't take the connection down
+                let packet = match seal_for(state, &mut session, packet) {
+                    Ok(packet) => packet,
+                    Err(error) => {
+                        tracing::error!("Can't seal packet for switch id {server_switch_id}: {error}");
+                        continue;
+                    }
+                };
+                #[cfg(feature = "fault-injection")]
+                let packets = injector.apply(server_switch_id, packet).await;
+                #[cfg(not(feature = "fault-injection"))]
+                let packets = [packet];
+
+                for packet in packets {
+                    let sent = match (&mut data_streams, vrf_id) {
+                        (Some(data_streams), Some(vrf_id)) => data_streams.send(vrf_id, packet),
+                        _ => stream.send_packet(packet).await,
+                    };
+
+                    if !sent {
+                        metrics.send_failed();
+                    }
                 }
-                Some(Packet::Ping(Ping)) = stream.recv_packet(&mut buffer) => {
-                    ping_timeout = Instant::now() + PING_TIMEOUT;
+            }
+            Some(packet) = stream.recv_packet(&mut buffer) => {
+                ping_timeout = Instant::now() + PING_TIMEOUT;
+
+                // the peer answers pings and sends its own traffic back on this connection
+                let packet = packet.open(key, &mut session, state.data_key().is_some());
+
+                match packet {
+                    Ok(Packet::Ping(Ping)) => {
+                        if let Some(sent) = ping_sent.lock().unwrap().take() {
+                            metrics.set_ping_rtt(sent.elapsed());
+                        }
+
+                        probe_path_mtu(state, server_switch_id, socket);
+                    }
+                    Ok(Packet::Goodbye(Goodbye)) => {
+                        tracing::info!("Switch id {server_switch_id} is shutting down");
+                        forget_peer(state, server_switch_id).await;
+                        break
+                    }
+                    Ok(packet) => {
+                        metrics.received.count_data(&packet);
+                        handle_peer_packet(state, server_switch_id, packet).await
+                    }
+                    Err(error) => {
+                        tracing::warn!("Rejected packet from switch id {server_switch_id}: {error}");
+                    }
                 }
-                _ = sleep_until(ping_timeout) => {
-                    tracing::warn!("Client connection closed, ping timed out");
-                    break
-                },
-                else => {
-                    tracing::warn!("Client connection closed");
-                    break
-                },
+            }
+            _ = sleep_until(ping_timeout) => {
+                tracing::warn!("Client connection closed, ping timed out");
+                break
+            },
+            else => {
+                tracing::warn!("Client connection closed");
+                break
+            },
+        }
+    }
+
+    ping_task.abort();
+
+    if let Some(data_task) = data_task {
+        data_task.abort();
+    }
+
+    unregister(state, server_switch_id, sender).await;
+
+    true
+}
+
+/// Make `sender` the way to reach a peer, unless another connection already is and `replace` is
+/// false. Returns whether it was registered.
+pub(super) async fn register(
+    state: &State,
+    switch_id: SwitchId,
+    sender: &PeerSender,
+    replace: bool,
+    socket: Option<RawFd>,
+) -> bool {
+    // a connection taking over from another one doesn't bring the peer up again
+    let replaced = {
+        let mut client_table = state.client_table.write().await;
+
+        if !replace && client_table.contains_key(&switch_id) {
+            return false;
+        }
+
+        client_table.insert(switch_id, sender.clone()).is_some()
+    };
+
+    state.peer_connections.lock().unwrap().insert(
+        switch_id,
+        PeerConnection {
+            address: socket.and_then(peer_address),
+            since: Instant::now(),
+        },
+    );
+
+    if state.draining.load(Ordering::Relaxed) {
+        if let Err(error) = sender.send(Packet::from(Maintenance::Drain)).await {
+            tracing::error!("Can't announce draining to switch id {switch_id}: {error}");
+        }
+    }
+
+    // queued aside, the connection only takes its queue once registered
+    let vrf_syncs = vrf_syncs(state).await;
+    let vrf_sync_sender = sender.clone();
+
+    spawn(async move {
+        for vrf_sync in vrf_syncs {
+            if let Err(error) = vrf_sync_sender.send(Packet::from(vrf_sync)).await {
+                tracing::error!("Can't send vrfs to switch id {switch_id}: {error}");
+                break;
             }
         }
+    });
+
+    advertise(state, switch_id, sender).await;
+
+    if !replaced {
+        publish(Event::PeerUp { switch_id });
     }
+
+    true
 }
 
-pub async fn broadcast_to_vrf(vrf: &Vrf, packet: Packet, client_table: Arc<RwLock<ClientTable>>) {
-    let client_table = client_table.read().await;
+/// Forget a peer and what was kept about it if `sender` is still the way to reach it, the other
+/// connections of the peer are left alone.
+pub(super) async fn unregister(state: &State, switch_id: SwitchId, sender: &PeerSender) {
+    {
+        let mut client_table = state.client_table.write().await;
+
+        if !client_table
+            .get(&switch_id)
+            .is_some_and(|registered| registered.same_peer(sender))
+        {
+            return;
+        }
+
+        client_table.remove(&switch_id);
+    }
+
+    state.path_mtus.lock().unwrap().remove(&switch_id);
+    state.peer_connections.lock().unwrap().remove(&switch_id);
+    state.peer_compression.lock().unwrap().remove(&switch_id);
+    // announced again by the peer when it reconnects
+    state.draining_peers.lock().unwrap().remove(&switch_id);
+
+    let forgotten = state.switch_table.read().await.forget(switch_id);
+
+    if forgotten > 0 {
+        tracing::info!("Forgot {forgotten} mac addresses of switch id {switch_id}");
+    }
+
+    let forgotten = state.ip_table.lock().unwrap().forget(switch_id);
+
+    if forgotten > 0 {
+        tracing::info!("Forgot {forgotten} ip addresses of switch id {switch_id}");
+    }
+
+    publish(Event::PeerDown { switch_id });
+}
+
+/// Forget a peer shutting down whichever of its connections is registered, the other one would
+/// only notice on its ping timeout and keep a restarted peer from registering until then.
+pub(super) async fn forget_peer(state: &State, switch_id: SwitchId) {
+    let sender = state.client_table.read().await.get(&switch_id).cloned();
+
+    if let Some(sender) = sender {
+        unregister(state, switch_id, &sender).await;
+    }
+}
+
+/// Sign a packet queued for a peer, data too when data packets are authenticated.
+pub(super) fn seal_for(
+    state: &State,
+    session: &mut Session,
+    packet: Packet,
+) -> bincode::Result<Packet> {
+    match (packet, state.data_key()) {
+        (packet @ Packet::Data(_), Some(data_key)) => packet.sign(data_key, session),
+        (packet, _) => packet.seal(state.control_key(), session),
+    }
+}
+
+/// Send a packet to the members of a vrf `admits`.
+pub async fn broadcast_to_vrf(
+    state: &State,
+    vrf: &Vrf,
+    packet: Packet,
+    mut admits: impl FnMut(SwitchId) -> bool,
+) {
+    let client_table = state.client_table.read().await;
+    let draining_peers = state.draining_peers.lock().unwrap().clone();
 
     for member in vrf.members.iter() {
-        if let Some(client) = client_table.get(member) {
-            if let Err(error) = client.send(packet.clone()).await {
+        // draining switches are only reached through the macs already learned for them
+        if draining_peers.contains(member) {
+            continue;
+        }
+
+        if let Some(client) = client_table.get(member).filter(|_| admits(*member)) {
+            if let Err(error) = client.send_data(vrf.id, packet.clone()).await {
+                state.metrics.peer(*member).send_failed();
                 tracing::error!(
-                    "Can't send packet to client {} for vrf {}: {error}",
-                    member,
+                    "Can't send packet to client {member} for vrf {}: {error}",
                     vrf.name
                 )
             }
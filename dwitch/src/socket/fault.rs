@@ -0,0 +1,129 @@
+//! Fault injection on the peer connections, to exercise convergence and failure handling. Only
+//! built with the `fault-injection` feature.
+//!
+//! Faults are set per peer at runtime through the api. Each peer draws them from its own generator,
+//! seeded by its fault, so a run with the same traffic makes the same decisions.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use protocol::Packet;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::config::SwitchId;
+
+static FAULTS: LazyLock<Mutex<HashMap<SwitchId, PeerFault>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Fault {
+    /// Share of the packets dropped, from 0 to 1
+    pub drop: f64,
+    /// Share of the packets sent twice
+    pub duplicate: f64,
+    /// Share of the packets held back and sent after the next one
+    pub reorder: f64,
+    /// Milliseconds waited before each packet, holding back the ones queued behind it
+    pub delay: u64,
+    /// Nothing goes to the peer nor is taken from it, like a cut link
+    pub partition: bool,
+    pub seed: u64,
+}
+
+struct PeerFault {
+    fault: Fault,
+    random: u64,
+}
+
+/// Set the fault of a peer, replacing the previous one and restarting its generator.
+pub fn set(switch_id: SwitchId, fault: Fault) {
+    tracing::warn!("Injecting {fault:?} toward switch id {switch_id}");
+
+    FAULTS.lock().unwrap().insert(
+        switch_id,
+        PeerFault {
+            fault,
+            // a zero xorshift state never leaves zero
+            random: (fault.seed ^ 0x9e37_79b9_7f4a_7c15) | 1,
+        },
+    );
+}
+
+pub fn clear(switch_id: SwitchId) {
+    FAULTS.lock().unwrap().remove(&switch_id);
+}
+
+pub fn list() -> HashMap<SwitchId, Fault> {
+    FAULTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(switch_id, peer_fault)| (*switch_id, peer_fault.fault))
+        .collect()
+}
+
+pub fn is_partitioned(switch_id: SwitchId) -> bool {
+    FAULTS
+        .lock()
+        .unwrap()
+        .get(&switch_id)
+        .is_some_and(|peer_fault| peer_fault.fault.partition)
+}
+
+/// Faults of the packets sent on one connection.
+#[derive(Default)]
+pub struct Injector {
+    held: Option<Packet>,
+}
+
+impl Injector {
+    /// Packets to send in place of this one.
+    pub async fn apply(&mut self, switch_id: SwitchId, packet: Packet) -> Vec<Packet> {
+        let Some((fault, drop, duplicate, reorder)) = draw(switch_id) else {
+            return [packet].into_iter().chain(self.held.take()).collect();
+        };
+
+        if fault.partition || drop < fault.drop {
+            return Vec::new();
+        }
+
+        if fault.delay > 0 {
+            sleep(Duration::from_millis(fault.delay)).await;
+        }
+
+        if reorder < fault.reorder && self.held.is_none() {
+            self.held = Some(packet);
+
+            return Vec::new();
+        }
+
+        let mut packets = vec![packet];
+
+        if duplicate < fault.duplicate {
+            packets.push(packets[0].clone());
+        }
+
+        packets.extend(self.held.take());
+        packets
+    }
+}
+
+// the draws of one packet, in [0, 1)
+fn draw(switch_id: SwitchId) -> Option<(Fault, f64, f64, f64)> {
+    let mut faults = FAULTS.lock().unwrap();
+    let peer_fault = faults.get_mut(&switch_id)?;
+    let mut next = || {
+        peer_fault.random ^= peer_fault.random << 13;
+        peer_fault.random ^= peer_fault.random >> 7;
+        peer_fault.random ^= peer_fault.random << 17;
+
+        (peer_fault.random >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let draws = (next(), next(), next());
+
+    Some((peer_fault.fault, draws.0, draws.1, draws.2))
+}
@@ -1,87 +1,317 @@
-use std::{future::Future, time::Duration};
-
-use protocol::{Packet, PacketSerializer};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+use std::{
+    future::Future,
+    io::{self, IoSlice},
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::RawFd,
+    time::Duration,
 };
 
-use crate::{config::SwitchId, BufferExt, MAX_BUFFER_SIZE};
+use bytes::BytesMut;
+use nix::sys::socket::{getpeername, SockaddrStorage};
+use protocol::{frame, Data, Event, Handshake, Packet, PacketSerializer, Session};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{config::SwitchId, events::publish, state::State, MAX_BUFFER_SIZE};
 
 pub mod client;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod quic;
 pub mod server;
+pub mod tls;
 
 const CONNECTION_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+// failed attempts back off up to this
+const MAX_CONNECTION_RETRY_INTERVAL: Duration = Duration::from_secs(60);
 const PING_INTERVAL: Duration = Duration::from_secs(2);
 const PING_TIMEOUT: Duration = Duration::from_secs(10);
+// a full tap frame plus the room needed by signatures and encryption
+const MAX_PACKET_SIZE: usize = MAX_BUFFER_SIZE + 1024;
+
+async fn exchange_switch_id<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    switch_id: SwitchId,
+    key: Option<&[u8]>,
+) -> Option<(Handshake, Session)> {
+    let handshake = Handshake::new(switch_id);
+
+    send_handshake(stream, &handshake).await?;
+
+    let peer_handshake = recv_handshake::<_, Handshake>(stream, buffer).await?;
 
-async fn exchange_switch_id(stream: &mut TcpStream, switch_id: SwitchId) -> Option<SwitchId> {
-    if let Err(error) = stream.write_all(&switch_id.serialize()).await {
-        tracing::error!("Can't send switch id: {error}");
+    send_handshake(stream, &peer_handshake.prove(key, switch_id)).await?;
+
+    let proof = recv_handshake(stream, buffer).await?;
+
+    if let Err(error) = handshake.verify(key, peer_handshake.switch_id, &proof) {
+        tracing::error!(
+            "Can't authenticate switch id {}: {error}",
+            peer_handshake.switch_id
+        );
         return None;
     }
 
-    let mut buffer = [0u8; MAX_BUFFER_SIZE];
-    let length = match stream.read(&mut buffer).await {
-        Ok(length) => length,
+    let session = Session::new(&handshake, &peer_handshake);
+
+    Some((peer_handshake, session))
+}
+
+/// Whether a peer claims a switch id that's taken, the one of this switch or of a peer connected
+/// from another address. Two switches sharing an id would learn each other's macs as their own.
+fn switch_id_conflict(state: &State, switch_id: SwitchId, address: Option<SocketAddr>) -> bool {
+    let conflict = if switch_id == state.config.switch_id {
+        "it's the id of this switch".to_string()
+    } else {
+        let connected = state
+            .peer_connections
+            .lock()
+            .unwrap()
+            .get(&switch_id)
+            .and_then(|connection| connection.address);
+
+        match (connected, address) {
+            (Some(connected), Some(address))
+                if connected.ip().to_canonical() != address.ip().to_canonical() =>
+            {
+                format!("it's already connected from {connected}")
+            }
+            _ => return false,
+        }
+    };
+    let from = address
+        .map(|address| format!(" from {address}"))
+        .unwrap_or_default();
+
+    tracing::error!("Refused switch id {switch_id}{from}: {conflict}");
+    state.metrics.switch_id_conflict();
+    publish(Event::SwitchIdConflict { switch_id, address });
+
+    true
+}
+
+async fn send_handshake<S: AsyncRead + AsyncWrite + Unpin, T: PacketSerializer>(
+    stream: &mut S,
+    handshake: &T,
+) -> Option<()> {
+    let handshake = match PacketSerializer::serialize(handshake) {
+        Ok(handshake) => handshake,
         Err(error) => {
-            tracing::error!("Read error: {error}");
+            tracing::error!("Can't serialize handshake: {error}");
             return None;
         }
     };
 
-    if length == 0 {
+    if let Err(error) = stream.send_frame(&handshake).await {
+        tracing::error!("Can't send handshake: {error}");
         return None;
     }
 
-    let buffer = buffer[..length].as_mut();
+    Some(())
+}
+
+async fn recv_handshake<S: AsyncRead + AsyncWrite + Unpin, T: PacketSerializer>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+) -> Option<T> {
+    let frame = stream.recv_frame(buffer).await?;
 
-    Some(match SwitchId::deserialize(&buffer) {
-        Ok(switch_id) => switch_id,
+    match <T as PacketSerializer>::deserialize(&frame) {
+        Ok(handshake) => Some(handshake),
         Err(error) => {
-            tracing::error!("Can't deserialize switch id: {error}");
-            return None;
+            tracing::error!("Can't deserialize handshake: {error}");
+            None
         }
+    }
+}
+
+/// Path mtu toward the other end of a tcp connection, as the kernel discovered it.
+#[cfg(target_os = "linux")]
+fn path_mtu(socket: RawFd) -> Option<u32> {
+    [
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        (libc::IPPROTO_IP, libc::IP_MTU),
+    ]
+    .into_iter()
+    .find_map(|(level, name)| {
+        let mut mtu: libc::c_int = 0;
+        let mut length = size_of::<libc::c_int>() as libc::socklen_t;
+
+        (unsafe { libc::getsockopt(socket, level, name, (&raw mut mtu).cast(), &mut length) } == 0
+            && mtu > 0)
+            .then_some(mtu as u32)
     })
 }
 
+// other kernels don't tell it
+#[cfg(not(target_os = "linux"))]
+fn path_mtu(_socket: RawFd) -> Option<u32> {
+    None
+}
+
+/// Address of the other end of a tcp connection.
+fn peer_address(socket: RawFd) -> Option<SocketAddr> {
+    let address = getpeername::<SockaddrStorage>(socket).ok()?;
+
+    match (address.as_sockaddr_in(), address.as_sockaddr_in6()) {
+        (Some(address), _) => Some(SocketAddrV4::from(*address).into()),
+        (_, Some(address)) => Some(SocketAddrV6::from(*address).into()),
+        _ => None,
+    }
+}
+
+// tcp segments frames to the path mtu on its own, it's only surfaced to operators
+fn probe_path_mtu(state: &State, switch_id: SwitchId, socket: Option<RawFd>) {
+    let Some(mtu) = socket.and_then(path_mtu) else {
+        return;
+    };
+
+    if state.path_mtus.lock().unwrap().insert(switch_id, mtu) != Some(mtu) {
+        tracing::info!("Path mtu toward switch id {switch_id} is {mtu}");
+    }
+}
+
+async fn write_slices<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut slices: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    while !slices.is_empty() {
+        let length = stream.write_vectored(slices).await?;
+
+        if length == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        IoSlice::advance_slices(&mut slices, length);
+    }
+
+    Ok(())
+}
+
 pub trait TransmitPacket {
-    fn recv_packet(&mut self, buffer: &mut [u8]) -> impl Future<Output = Option<Packet>>;
+    /// Read one length prefixed frame, bytes past it are kept in `buffer` for the next call.
+    ///
+    /// Cancel safe, so it can be raced against other futures in a select.
+    fn recv_frame(&mut self, buffer: &mut BytesMut) -> impl Future<Output = Option<BytesMut>>;
+
+    fn recv_packet(&mut self, buffer: &mut BytesMut) -> impl Future<Output = Option<Packet>>;
+
+    fn send_frame(&mut self, payload: &[u8]) -> impl Future<Output = io::Result<()>>;
+
+    /// Write a data packet in its own wire format, without copying the frame.
+    fn send_data(&mut self, data: &Data) -> impl Future<Output = io::Result<()>>;
+
+    /// Returns whether the packet was written.
+    fn send_packet<T: Into<Packet>>(&mut self, packet: T) -> impl Future<Output = bool>;
 
-    fn send_packet<T: Into<Packet>>(&mut self, packet: T) -> impl Future<Output = ()>;
+    /// Seal the packet for the peer of the session and send it, dropping it if it can't be.
+    fn send_sealed<T: Into<Packet>>(
+        &mut self,
+        packet: T,
+        key: Option<&[u8]>,
+        session: &mut Session,
+    ) -> impl Future<Output = ()>;
 }
 
-impl TransmitPacket for TcpStream {
-    async fn recv_packet(&mut self, buffer: &mut [u8]) -> Option<Packet> {
-        let length = match self.read(buffer).await {
-            Ok(length) => length,
-            Err(error) => {
-                tracing::error!("Can't read from tcp stream: {error}");
-                return None;
+impl<S: AsyncRead + AsyncWrite + Unpin> TransmitPacket for S {
+    async fn recv_frame(&mut self, buffer: &mut BytesMut) -> Option<BytesMut> {
+        loop {
+            match frame::decode(buffer, MAX_PACKET_SIZE) {
+                Ok(Some(frame)) => return Some(frame),
+                Ok(None) => {}
+                Err(error) => {
+                    tracing::error!("{error}");
+                    return None;
+                }
             }
-        };
 
-        if length == 0 {
-            return None;
+            match self.read_buf(buffer).await {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(error) => {
+                    tracing::error!("Can't read from stream: {error}");
+                    return None;
+                }
+            }
         }
+    }
+
+    async fn recv_packet(&mut self, buffer: &mut BytesMut) -> Option<Packet> {
+        let frame = self.recv_frame(buffer).await?.freeze();
+        let packet = match Data::parse(&frame) {
+            Some(data) => data.map(Packet::Data),
+            None => Packet::deserialize(&frame),
+        };
 
-        let buffer = buffer[..length].as_mut();
-        let packet = match Packet::deserialize(&buffer) {
-            Ok(packet) => packet,
+        match packet {
+            Ok(packet) => Some(packet),
             Err(error) => {
                 tracing::error!("Can't deserialize packet: {error}");
-                return None;
+                None
             }
-        };
+        }
+    }
+
+    // the header and payload go out in one write without being copied together
+    async fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        let header = frame::header(payload);
+        let mut slices = [IoSlice::new(&header), IoSlice::new(payload)];
+
+        write_slices(self, &mut slices).await
+    }
 
-        buffer.clear();
+    // the frame goes out from its own buffer, behind the frame and data headers
+    async fn send_data(&mut self, data: &Data) -> io::Result<()> {
+        let header = data.header();
+        let length = ((header.len() + data.data.len()) as u32).to_be_bytes();
+        let mut slices = [
+            IoSlice::new(&length),
+            IoSlice::new(&header),
+            IoSlice::new(&data.data),
+        ];
 
-        Some(packet)
+        write_slices(self, &mut slices).await
     }
 
-    async fn send_packet<T: Into<Packet>>(&mut self, packet: T) {
-        if let Err(error) = self.write_all(&packet.into().serialize()).await {
+    async fn send_packet<T: Into<Packet>>(&mut self, packet: T) -> bool {
+        let packet = packet.into();
+
+        if let Packet::Data(data) = &packet {
+            if let Err(error) = self.send_data(data).await {
+                tracing::warn!("Can't send packet: {error}");
+                return false;
+            }
+
+            return true;
+        }
+
+        let payload = match packet.serialize() {
+            Ok(payload) => payload,
+            Err(error) => {
+                tracing::error!("Can't serialize packet: {error}");
+                return false;
+            }
+        };
+
+        if let Err(error) = self.send_frame(&payload).await {
             tracing::warn!("Can't send packet: {error}");
+            return false;
+        }
+
+        true
+    }
+
+    async fn send_sealed<T: Into<Packet>>(
+        &mut self,
+        packet: T,
+        key: Option<&[u8]>,
+        session: &mut Session,
+    ) {
+        match packet.into().seal(key, session) {
+            Ok(packet) => {
+                self.send_packet(packet).await;
+            }
+            Err(error) => tracing::error!("Can't seal packet: {error}"),
         }
     }
 }
@@ -0,0 +1,195 @@
+//! Quic transport between switches. The first bidirectional stream of a connection carries what a
+//! tcp connection would, and each vrf sends its frames on a unidirectional stream of its own so a
+//! busy vrf can't hold back the others.
+
+use std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc};
+
+use bytes::BytesMut;
+use common::VrfId;
+use protocol::{Packet, Session};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig,
+};
+use tokio::{
+    io::{empty, join, sink, Join},
+    spawn,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+
+use crate::{
+    socket::{
+        server::{accept_client, handle_peer_packet},
+        tls::{PeerCertificates, Tls, SWITCH_NAME_SUFFIX},
+        TransmitPacket,
+    },
+    state::State,
+};
+
+const STREAM_QUEUE_SIZE: usize = 32;
+
+/// One udp socket on the first listen address, accepting the connections of peers and dialing them.
+pub fn endpoint(tls: &Tls, listen: SocketAddr) -> eyre::Result<Endpoint> {
+    let (server_config, client_config) = tls.configs();
+    let mut endpoint = Endpoint::server(
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_config)?)),
+        listen,
+    )?;
+
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(QuicClientConfig::try_from(
+        client_config,
+    )?)));
+
+    Ok(endpoint)
+}
+
+pub async fn quic_server(state: Arc<State>, endpoint: Endpoint) {
+    while let Some(incoming) = endpoint.accept().await {
+        let state = state.clone();
+        let address = incoming.remote_address();
+
+        tracing::debug!("New quic client from {address}");
+
+        spawn(async move {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    tracing::warn!("Can't establish quic connection with {address}: {error}");
+                    return;
+                }
+            };
+            let (send, recv) = match connection.accept_bi().await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    tracing::warn!("Quic connection with {address} closed: {error}");
+                    return;
+                }
+            };
+            let certificates = peer_certificates(&connection);
+
+            accept_client(
+                state,
+                join(recv, send),
+                address,
+                None,
+                Some(certificates),
+                Some(connection),
+            )
+            .await
+        });
+    }
+}
+
+/// Dial a peer, returning the connection and its control stream.
+pub async fn connect(
+    endpoint: &Endpoint,
+    address: SocketAddr,
+) -> Result<
+    (Connection, Join<RecvStream, SendStream>, PeerCertificates),
+    Box<dyn Error + Send + Sync>,
+> {
+    // the server name is checked against the switch id after the handshake
+    let connection = endpoint.connect(address, SWITCH_NAME_SUFFIX)?.await?;
+    let (send, recv) = connection.open_bi().await?;
+    let certificates = peer_certificates(&connection);
+
+    Ok((connection, join(recv, send), certificates))
+}
+
+fn peer_certificates(connection: &Connection) -> PeerCertificates {
+    connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<PeerCertificates>().ok())
+        .map(|certificates| *certificates)
+        .unwrap_or_default()
+}
+
+/// Frames toward a peer, each vrf on a stream of its own written by its own task.
+pub(super) struct DataStreams {
+    connection: Connection,
+    streams: HashMap<VrfId, Sender<Packet>>,
+}
+
+impl DataStreams {
+    pub(super) fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            streams: HashMap::new(),
+        }
+    }
+
+    // never waits, a vrf whose stream is backed up loses the frame like a congested port would
+    pub(super) fn send(&mut self, vrf_id: VrfId, packet: Packet) -> bool {
+        let sender = self.streams.entry(vrf_id).or_insert_with(|| {
+            let (sender, receiver) = channel(STREAM_QUEUE_SIZE);
+
+            spawn(send_stream(self.connection.clone(), vrf_id, receiver));
+
+            sender
+        });
+
+        match sender.try_send(packet) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            // opened again for the next frame
+            Err(TrySendError::Closed(_)) => {
+                self.streams.remove(&vrf_id);
+                false
+            }
+        }
+    }
+}
+
+async fn send_stream(connection: Connection, vrf_id: VrfId, mut receiver: Receiver<Packet>) {
+    let stream: SendStream = match connection.open_uni().await {
+        Ok(stream) => stream,
+        Err(error) => {
+            tracing::warn!("Can't open quic stream for vrf id {vrf_id}: {error}");
+            return;
+        }
+    };
+    // write only, nothing is ever read from a data stream
+    let mut stream = join(empty(), stream);
+
+    while let Some(packet) = receiver.recv().await {
+        if !stream.send_packet(packet).await {
+            break;
+        }
+    }
+}
+
+/// Hand the frames a peer sends on its data streams to the taps, until the connection closes.
+///
+/// Streams are ordered on their own but not with each other, so `session` has no replay window,
+/// tls already rejects replays.
+pub(super) async fn receive_data(state: Arc<State>, session: Session, connection: Connection) {
+    while let Ok(stream) = connection.accept_uni().await {
+        spawn(receive_stream(state.clone(), session.clone(), stream));
+    }
+}
+
+async fn receive_stream(state: Arc<State>, mut session: Session, stream: RecvStream) {
+    let switch_id = session.peer_switch_id();
+    let metrics = state.metrics.peer(switch_id);
+    let mut stream = join(stream, sink());
+    let mut buffer = BytesMut::new();
+
+    while let Some(packet) = stream.recv_packet(&mut buffer).await {
+        match packet.open(
+            state.control_key(),
+            &mut session,
+            state.data_key().is_some(),
+        ) {
+            Ok(packet @ Packet::Data(_)) => {
+                metrics.received.count_data(&packet);
+                handle_peer_packet(&state, switch_id, packet).await;
+            }
+            Ok(_) => {
+                tracing::warn!("Rejected packet from switch id {switch_id}: not data");
+            }
+            Err(error) => {
+                tracing::warn!("Rejected packet from switch id {switch_id}: {error}");
+            }
+        }
+    }
+}
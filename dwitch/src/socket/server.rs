@@ -1,79 +1,351 @@
-use std::{error::Error, sync::Arc, time::Duration};
+use std::{
+    error::Error,
+    fs::{remove_file, set_permissions, Permissions},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    os::{
+        fd::{AsFd, AsRawFd, RawFd},
+        unix::fs::PermissionsExt,
+    },
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
-use protocol::{Packet, Ping, VrfAction, CONFIGURATION_SWITCH_ID};
+use bytes::BytesMut;
+use nix::unistd::{chown, Group};
+use protocol::{
+    AclAction, Audit, Authenticate, EndpointAction, Events, Goodbye, MacAction, Maintenance,
+    MirrorAction, Packet, PeerAction, Ping, Response, Save, Session, StatsAction, Status, Trace,
+    VrfAction, VrfTest, CONFIGURATION_SWITCH_ID,
+};
+use quinn::Connection;
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
     select, spawn,
-    sync::RwLock,
-    time::sleep,
+    sync::{broadcast::error::RecvError, mpsc::error::TrySendError},
+    time::{sleep, sleep_until, Instant},
 };
 
+#[cfg(feature = "fault-injection")]
+use crate::socket::fault;
 use crate::{
-    cache::{SwitchTable, VrfTable},
+    audit::audit,
     config::{Config, SwitchId},
-    socket::{exchange_switch_id, TransmitPacket, PING_TIMEOUT},
-    tap::{tap, TapTable},
-    MAX_BUFFER_SIZE,
+    events::{publish, subscribe, Event},
+    gossip::learn,
+    management::{
+        add_acl_rule, allocate_vrf, apply_vrf_action, attach_endpoint, configure, detach_endpoint,
+        list_acl, list_vrf_macs, list_vrfs, peer_reports, pin_mac, remove_acl_rule, save,
+        set_maintenance, start_mirror, stats, status, stop_mirror, sync_vrfs, unpin_mac,
+    },
+    socket::{
+        client::{forget_peer, peer_channel, register, seal_for, unregister},
+        exchange_switch_id, probe_path_mtu,
+        quic::{receive_data, DataStreams},
+        switch_id_conflict,
+        tls::{verify_switch_id, PeerCertificates},
+        TransmitPacket, PING_TIMEOUT,
+    },
+    state::{Source, State},
+    token::{authenticate, Access, Permission},
+    trace::trace,
+    vrf_test::{run_for, vrf_test},
 };
 
-use super::client::ClientTable;
+/// Bind a listener for peers and remote clients, or take over the inherited one bound to `listen`
+/// already.
+pub async fn bind(
+    listen: SocketAddr,
+    inherited: &mut Vec<std::net::TcpListener>,
+) -> io::Result<TcpListener> {
+    let position = inherited
+        .iter()
+        .position(|listener| listener.local_addr().is_ok_and(|address| address == listen));
+
+    match position.map(|position| inherited.swap_remove(position)) {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        }
+        None => TcpListener::bind(listen).await,
+    }
+}
+
+/// Accept the peers and remote clients connecting to one of the listen addresses, the listeners of
+/// the others run alongside.
+pub async fn server(state: Arc<State>, listen: SocketAddr, listener: TcpListener) {
+    state.handover_fds.add_listener(listen, listener.as_fd());
 
-pub async fn server(
-    config: Config,
-    tap_table: Arc<RwLock<TapTable>>,
-    vrf_table: Arc<RwLock<VrfTable>>,
-    client_table: Arc<RwLock<ClientTable>>,
-    switch_table: Arc<RwLock<SwitchTable>>,
-) -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind(config.listen).await?;
+    state.listening.store(true, Ordering::Relaxed);
 
     loop {
         match listener.accept().await {
-            Ok((mut stream, address)) => {
+            Ok((stream, address)) => {
                 tracing::debug!("New client from {address}");
 
-                let Some(client_switch_id) =
-                    exchange_switch_id(&mut stream, config.switch_id).await
+                let state = state.clone();
+                let socket = stream.as_raw_fd();
+
+                spawn(async move {
+                    match &state.tls {
+                        Some(tls) => match tls.accept(stream).await {
+                            Ok((stream, certificates)) => {
+                                accept_client(
+                                    state.clone(),
+                                    stream,
+                                    address,
+                                    Some(socket),
+                                    Some(certificates),
+                                    None,
+                                )
+                                .await
+                            }
+                            Err(error) => {
+                                tracing::warn!(
+                                    "Can't establish tls session with {address}: {error}"
+                                );
+                            }
+                        },
+                        None => {
+                            accept_client(state.clone(), stream, address, Some(socket), None, None)
+                                .await
+                        }
+                    }
+                });
+            }
+            Err(error) => {
+                tracing::error!("Can't accept client: {error}");
+                sleep(Duration::from_secs(10)).await;
+            }
+        }
+    }
+}
+
+/// Run a connection accepted from a peer or a remote client, `quic` being the quic connection
+/// `stream` is the first stream of when there's one.
+pub async fn accept_client<S: AsyncRead + AsyncWrite + Unpin>(
+    state: Arc<State>,
+    mut stream: S,
+    address: SocketAddr,
+    socket: Option<RawFd>,
+    certificates: Option<PeerCertificates>,
+    quic: Option<Connection>,
+) {
+    let mut buffer = BytesMut::new();
+    let Some((client_handshake, session)) = exchange_switch_id(
+        &mut stream,
+        &mut buffer,
+        state.config.switch_id,
+        state.control_key(),
+    )
+    .await
+    else {
+        return;
+    };
+    let client_switch_id = client_handshake.switch_id;
+
+    tracing::debug!("Client switch id {client_switch_id}");
+
+    if let Some(certificates) = certificates {
+        if let Err(error) = verify_switch_id(&certificates, client_switch_id) {
+            tracing::error!(
+                "Client certificate from {address} doesn't match switch id {client_switch_id}: {error}"
+            );
+            return;
+        }
+    }
+
+    if client_switch_id != CONFIGURATION_SWITCH_ID
+        && switch_id_conflict(&state, client_switch_id, Some(address))
+    {
+        return;
+    }
+
+    if client_switch_id != CONFIGURATION_SWITCH_ID {
+        state
+            .peer_compression
+            .lock()
+            .unwrap()
+            .insert(client_switch_id, client_handshake.compression);
+    }
+
+    let ip = address.ip().to_canonical();
+    // other configuration clients have to authenticate with a token first
+    let permission = if client_switch_id != CONFIGURATION_SWITCH_ID {
+        None
+    } else if state.config.admins.contains(&ip) {
+        Some(Permission::Admin)
+    } else if !state.config.tokens.is_empty() {
+        None
+    } else {
+        tracing::warn!("Rejected configuration client from untrusted address {address}");
+        return;
+    };
+
+    server_connection(
+        state,
+        session,
+        Source::Remote(ip),
+        permission,
+        stream,
+        socket,
+        quic,
+        buffer,
+    )
+    .await
+}
+
+/// Bind the management socket, before the privileges are dropped.
+pub fn management_listener(config: &Config) -> Result<UnixListener, Box<dyn Error>> {
+    if let Err(error) = remove_file(&config.management_socket) {
+        if error.kind() != ErrorKind::NotFound {
+            return Err(error.into());
+        }
+    }
+
+    let listener = UnixListener::bind(&config.management_socket)?;
+
+    // anyone who can connect is an admin, so only the file permissions keep others out
+    match &config.management_group {
+        Some(group) => {
+            let gid = Group::from_name(group)?
+                .ok_or_else(|| format!("Group {group} doesn't exist"))?
+                .gid;
+
+            chown(&config.management_socket, None, Some(gid))?;
+            set_permissions(&config.management_socket, Permissions::from_mode(0o660))?;
+        }
+        None => set_permissions(&config.management_socket, Permissions::from_mode(0o600))?,
+    }
+
+    Ok(listener)
+}
+
+pub async fn management(listener: UnixListener, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                tracing::debug!("New management client");
+
+                let mut buffer = BytesMut::new();
+                let Some((client_handshake, session)) = exchange_switch_id(
+                    &mut stream,
+                    &mut buffer,
+                    state.config.switch_id,
+                    state.control_key(),
+                )
+                .await
                 else {
                     continue;
                 };
+                let client_switch_id = client_handshake.switch_id;
 
-                tracing::debug!("Client switch id {client_switch_id}");
+                if client_switch_id != CONFIGURATION_SWITCH_ID {
+                    tracing::warn!(
+                        "Rejected switch id {client_switch_id} on the management socket"
+                    );
+                    continue;
+                }
 
                 spawn(server_connection(
-                    config.switch_id,
-                    client_switch_id,
+                    state.clone(),
+                    session,
+                    Source::Management,
+                    Some(Permission::Admin),
                     stream,
-                    tap_table.clone(),
-                    vrf_table.clone(),
-                    client_table.clone(),
-                    switch_table.clone(),
+                    None,
+                    None,
+                    buffer,
                 ));
             }
             Err(error) => {
-                tracing::error!("Can't accept client: {error}");
+                tracing::error!("Can't accept management client: {error}");
                 sleep(Duration::from_secs(10)).await;
             }
         }
     }
 }
 
-async fn server_connection(
-    server_switch_id: SwitchId,
-    client_switch_id: SwitchId,
-    mut stream: TcpStream,
-    tap_table: Arc<RwLock<TapTable>>,
-    vrf_table: Arc<RwLock<VrfTable>>,
-    client_table: Arc<RwLock<ClientTable>>,
-    switch_table: Arc<RwLock<SwitchTable>>,
+#[allow(clippy::too_many_arguments)]
+async fn server_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    state: Arc<State>,
+    mut session: Session,
+    source: Source,
+    mut permission: Option<Permission>,
+    mut stream: S,
+    socket: Option<RawFd>,
+    quic: Option<Connection>,
+    mut buffer: BytesMut,
 ) {
-    let mut buffer = [0u8; MAX_BUFFER_SIZE];
+    let client_switch_id = session.peer_switch_id();
+    let (sender, mut receiver) = peer_channel();
+    // a peer this switch can't dial, behind a nat, is reached back on the connection it made
+    let registered = client_switch_id != CONFIGURATION_SWITCH_ID
+        && register(&state, client_switch_id, &sender, false, socket).await;
+
+    if registered {
+        probe_path_mtu(&state, client_switch_id, socket);
+    }
+
+    let metrics =
+        (client_switch_id != CONFIGURATION_SWITCH_ID).then(|| state.metrics.peer(client_switch_id));
+    let mut ping_timeout = Instant::now() + PING_TIMEOUT;
+    // frames of a connection that didn't register still come in, they go out through the other one
+    let data_task = quic
+        .as_ref()
+        .filter(|_| client_switch_id != CONFIGURATION_SWITCH_ID)
+        .map(|connection| {
+            spawn(receive_data(
+                state.clone(),
+                session.without_replay_window(),
+                connection.clone(),
+            ))
+        });
+    let mut data_streams = quic.map(DataStreams::new);
 
     loop {
         let packet = select! {
-            Some(packet) = stream.recv_packet(&mut buffer) => packet,
-            _ = sleep(PING_TIMEOUT) => {
+            Some(packet) = stream.recv_packet(&mut buffer) => {
+                ping_timeout = Instant::now() + PING_TIMEOUT;
+                packet
+            }
+            Some(packet) = receiver.recv(), if registered => {
+                if let Some(metrics) = &metrics {
+                    metrics.sent.count_data(&packet);
+                }
+
+                let vrf_id = match &packet {
+                    Packet::Data(data) => Some(data.vrf_id),
+                    _ => None,
+                };
+
+                match seal_for(&state, &mut session, packet) {
+                    Ok(packet) => {
+                        let mut sent = match (&mut data_streams, vrf_id) {
+                            (Some(data_streams), Some(vrf_id)) => data_streams.send(vrf_id, packet),
+                            _ => stream.send_packet(packet).await,
+                        };
+
+                        if let Err(error) = stream.flush().await {
+                            tracing::warn!("Can't send packet to switch id {client_switch_id}: {error}");
+                            sent = false;
+                        }
+
+                        if !sent {
+                            if let Some(metrics) = &metrics {
+                                metrics.send_failed();
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        tracing::error!("Can't seal packet for switch id {client_switch_id}: {error}");
+                    }
+                }
+
+                continue;
+            }
+            _ = sleep_until(ping_timeout) => {
                 tracing::warn!("Server connection closed, ping timed out");
                 break
             },
@@ -83,10 +355,42 @@ async fn server_connection(
             },
         };
 
+        #[cfg(feature = "fault-injection")]
+        if fault::is_partitioned(client_switch_id) {
+            continue;
+        }
+
+        let packet = match packet.open(
+            state.control_key(),
+            &mut session,
+            state.data_key().is_some(),
+        ) {
+            Ok(packet) => packet,
+            Err(error) => {
+                tracing::warn!("Rejected packet from switch id {client_switch_id}: {error}");
+                continue;
+            }
+        };
+
         tracing::debug!("{packet:?}");
 
+        if client_switch_id == CONFIGURATION_SWITCH_ID {
+            if let Some((access, request)) = access(&packet) {
+                if let Err(refusal) = state.authorize(source, permission, access, request) {
+                    let response = Response::Error(refusal.to_string());
+
+                    reply(&state, &mut session, &mut stream, [response.into()]).await;
+                    continue;
+                }
+            }
+        }
+
         match packet {
             Packet::Ping(Ping) => {
+                if registered {
+                    probe_path_mtu(&state, client_switch_id, socket);
+                }
+
                 stream.send_packet(Ping).await;
 
                 if let Err(error) = stream.flush().await {
@@ -94,146 +398,433 @@ async fn server_connection(
                 }
             }
             Packet::VrfAction(vrf_action) => {
-                process_vrf_action(
-                    server_switch_id,
-                    client_switch_id,
-                    &mut stream,
-                    tap_table.clone(),
-                    vrf_table.clone(),
-                    client_table.clone(),
-                    switch_table.clone(),
-                    vrf_action,
-                )
-                .await
+                let response =
+                    process_vrf_action(&state, &mut session, &mut stream, vrf_action).await;
+
+                if let (Some(response), CONFIGURATION_SWITCH_ID) = (response, client_switch_id) {
+                    reply(&state, &mut session, &mut stream, [response.into()]).await;
+                }
             }
-            Packet::Data(data) => {
-                let tap_table = tap_table.read().await;
+            Packet::Authenticate(Authenticate { token })
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
+            {
+                let response = if !state.action_limiter.check(source) {
+                    tracing::warn!("Rate limited authentication from {source:?}");
 
-                if let Some(tap) = tap_table.get(&data.vrf_id) {
-                    if let Err(error) = tap.send((client_switch_id, data.data)).await {
-                        tracing::error!(
-                            "Can't send data to tap interface for vrf id {}: {error}",
-                            data.vrf_id
-                        );
+                    Response::Error("Too many attempts, try again later".to_string())
+                } else if let Some(token_permission) = authenticate(&state.config.tokens, &token) {
+                    tracing::info!("Authenticated {source:?} as {token_permission:?}");
+
+                    permission = Some(token_permission);
+                    Response::Ok
+                } else {
+                    tracing::warn!("Invalid token from {source:?}");
+
+                    Response::Error("Invalid token".to_string())
+                };
+
+                reply(&state, &mut session, &mut stream, [response.into()]).await;
+            }
+            Packet::Maintenance(maintenance) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                set_maintenance(&state, maintenance).await;
+                reply(&state, &mut session, &mut stream, [Response::Ok.into()]).await;
+            }
+            Packet::EndpointAction(endpoint_action)
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
+            {
+                let packet = match endpoint_action {
+                    EndpointAction::Attach(endpoint) => {
+                        match attach_endpoint(&state, endpoint).await {
+                            Ok(mac) => EndpointAction::Attached { mac }.into(),
+                            Err(error) => Response::Error(error).into(),
+                        }
                     }
-                }
+                    EndpointAction::Detach { netns, ifname } => {
+                        match detach_endpoint(&netns, &ifname).await {
+                            Ok(()) => Response::Ok.into(),
+                            Err(error) => Response::Error(error).into(),
+                        }
+                    }
+                    EndpointAction::AttachVm { .. } => {
+                        Response::Error("Vms are attached on the vm socket".to_string()).into()
+                    }
+                    EndpointAction::Attached { .. } | EndpointAction::VmAttached { .. } => {
+                        Response::Error("Unexpected endpoint action".to_string()).into()
+                    }
+                };
+
+                reply(&state, &mut session, &mut stream, [packet]).await;
             }
-        }
-    }
-}
+            Packet::Audit(Audit::Start) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let reports = audit(&state).await;
+                let packets = reports
+                    .chunks(10)
+                    .chain([&[][..]])
+                    .map(|reports_chunk| Audit::Report(reports_chunk.to_vec()).into())
+                    .collect::<Vec<Packet>>();
 
-async fn process_vrf_action(
-    server_switch_id: SwitchId,
-    client_switch_id: SwitchId,
-    stream: &mut TcpStream,
-    tap_table: Arc<RwLock<TapTable>>,
-    vrf_table: Arc<RwLock<VrfTable>>,
-    client_table: Arc<RwLock<ClientTable>>,
-    switch_table: Arc<RwLock<SwitchTable>>,
-    vrf_action: VrfAction,
-) {
-    if client_switch_id == CONFIGURATION_SWITCH_ID {
-        match &vrf_action {
-            VrfAction::Create(_)
-            | VrfAction::Delete { .. }
-            | VrfAction::AddMember { .. }
-            | VrfAction::RemoveMember { .. } => {
-                broadcast_packet(client_table.clone(), Packet::from(vrf_action.clone())).await
+                reply(&state, &mut session, &mut stream, packets).await;
             }
-            _ => {}
-        }
-    }
+            Packet::Trace(Trace::Start {
+                vrf_id,
+                destination,
+            }) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let packet = match trace(&state, vrf_id, destination).await {
+                    Ok(hops) => Packet::from(Trace::Report(hops)),
+                    Err(error) => Packet::from(Response::Error(error)),
+                };
 
-    match vrf_action {
-        VrfAction::List(vrf_list) => {
-            let vrf_table = vrf_table.read().await;
+                reply(&state, &mut session, &mut stream, [packet]).await;
+            }
+            Packet::VrfTest(VrfTest::Start { vrf_id, from, to })
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
+            {
+                let packet = match vrf_test(&state, vrf_id, from, to).await {
+                    Ok(report) => Packet::from(VrfTest::Report(report)),
+                    Err(error) => Packet::from(Response::Error(error)),
+                };
 
-            for vrf_list_chunk in vrf_table.values().cloned().collect::<Vec<_>>().chunks(10) {
-                stream
-                    .send_packet(VrfAction::List(Some(vrf_list_chunk.to_vec())))
-                    .await;
+                reply(&state, &mut session, &mut stream, [packet]).await;
             }
+            Packet::Status(Status::Query) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let packet = Status::Report(status(&state).await).into();
 
-            stream.send_packet(VrfAction::List(Some(Vec::new()))).await;
+                reply(&state, &mut session, &mut stream, [packet]).await;
+            }
+            Packet::StatsAction(StatsAction::Query)
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
+            {
+                let packet = StatsAction::Report(stats(&state).await).into();
 
-            if let Err(error) = stream.flush().await {
-                tracing::warn!("Can't send vrf list: {error}");
+                reply(&state, &mut session, &mut stream, [packet]).await;
             }
-        }
-        VrfAction::Create(vrf) => {
-            let mut vrf_table = vrf_table.write().await;
+            Packet::Save(Save::Request) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let packet = match save(&state).await {
+                    Ok((vrfs, macs)) => {
+                        tracing::info!("Saved {vrfs} vrfs and {macs} macs for {source:?}");
 
-            if !vrf_table.contains_key(&vrf.id)
-                && vrf_table
-                    .values()
-                    .find(|vrf_| vrf_.name == vrf.name)
-                    .is_none()
+                        Packet::from(Save::Saved { vrfs, macs })
+                    }
+                    Err(error) => {
+                        tracing::error!("Can't save cache: {error}");
+
+                        Packet::from(Response::Error(format!("Can't save cache: {error}")))
+                    }
+                };
+
+                reply(&state, &mut session, &mut stream, [packet]).await;
+            }
+            Packet::PeerAction(PeerAction::List) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let packet = PeerAction::Report(peer_reports(&state).await).into();
+
+                reply(&state, &mut session, &mut stream, [packet]).await;
+            }
+            Packet::MacAction(MacAction::List { vrf_id })
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
             {
-                if vrf.members.contains(&server_switch_id) {
-                    let mut tap_table = tap_table.write().await;
+                match list_vrf_macs(&state, vrf_id).await {
+                    Ok(entries) => {
+                        let packets = entries
+                            .chunks(1000)
+                            .chain([&[][..]])
+                            .map(|entries_chunk| MacAction::Entries(entries_chunk.to_vec()).into())
+                            .collect::<Vec<Packet>>();
 
-                    tap_table.insert(
-                        vrf.id,
-                        tap(vrf.clone(), client_table.clone(), switch_table.clone()),
-                    );
+                        reply(&state, &mut session, &mut stream, packets).await;
+                    }
+                    Err(error) => {
+                        let packet = Response::Error(error).into();
+
+                        reply(&state, &mut session, &mut stream, [packet]).await;
+                    }
                 }
+            }
+            Packet::MacAction(
+                mac_action @ (MacAction::AddStatic { .. } | MacAction::RemoveStatic { .. }),
+            ) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let result = match mac_action {
+                    MacAction::AddStatic {
+                        vrf_id,
+                        mac,
+                        switch_id,
+                    } => pin_mac(&state, vrf_id, mac, switch_id).await,
+                    MacAction::RemoveStatic { vrf_id, mac } => unpin_mac(&state, vrf_id, mac).await,
+                    _ => Err("Unexpected mac action".to_string()),
+                };
+
+                let response = match result {
+                    Ok(()) => Response::Ok,
+                    Err(error) => Response::Error(error),
+                };
 
-                vrf_table.insert(vrf.id, vrf);
+                reply(&state, &mut session, &mut stream, [response.into()]).await;
+            }
+            Packet::AclAction(AclAction::List { vrf_id })
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
+            {
+                let packet = match list_acl(&state, vrf_id).await {
+                    Ok(rules) => Packet::from(AclAction::Rules(rules)),
+                    Err(error) => Packet::from(Response::Error(error)),
+                };
+
+                reply(&state, &mut session, &mut stream, [packet]).await;
+            }
+            Packet::AclAction(acl_action @ (AclAction::Add { .. } | AclAction::Remove { .. }))
+                if client_switch_id == CONFIGURATION_SWITCH_ID =>
+            {
+                let result = match acl_action {
+                    AclAction::Add {
+                        vrf_id,
+                        rule,
+                        position,
+                    } => add_acl_rule(&state, vrf_id, rule, position).await,
+                    AclAction::Remove { vrf_id, index } => {
+                        remove_acl_rule(&state, vrf_id, index).await
+                    }
+                    _ => Err("Unexpected acl action".to_string()),
+                };
+
+                let response = match result {
+                    Ok(()) => Response::Ok,
+                    Err(error) => Response::Error(error),
+                };
+
+                reply(&state, &mut session, &mut stream, [response.into()]).await;
+            }
+            Packet::MirrorAction(mirror_action) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                let result = match mirror_action {
+                    MirrorAction::Start { vrf_id, target } => {
+                        start_mirror(&state, vrf_id, target).await
+                    }
+                    MirrorAction::Stop { vrf_id } => stop_mirror(&state, vrf_id),
+                };
+
+                let response = match result {
+                    Ok(()) => Response::Ok,
+                    Err(error) => Response::Error(error),
+                };
+
+                reply(&state, &mut session, &mut stream, [response.into()]).await;
+            }
+            Packet::Events(Events::Subscribe) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+                reply(&state, &mut session, &mut stream, [Response::Ok.into()]).await;
+                send_events(&state, &mut session, &mut stream, &mut buffer).await;
+                break;
             }
+            Packet::Goodbye(Goodbye) if client_switch_id != CONFIGURATION_SWITCH_ID => {
+                tracing::info!("Switch id {client_switch_id} is shutting down");
+                forget_peer(&state, client_switch_id).await;
+                break;
+            }
+            packet if client_switch_id != CONFIGURATION_SWITCH_ID => {
+                if let Some(metrics) = &metrics {
+                    metrics.received.count_data(&packet);
+                }
+
+                handle_peer_packet(&state, client_switch_id, packet).await
+            }
+            _ => {}
         }
-        VrfAction::Delete { id } => {
-            let mut vrf_table = vrf_table.write().await;
-            let mut tap_table = tap_table.write().await;
-            let mut switch_table = switch_table.write().await;
+    }
 
-            tap_table.remove(&id);
-            vrf_table.remove(&id);
-            switch_table.remove(&id);
+    if let Some(data_task) = data_task {
+        data_task.abort();
+    }
+
+    if registered {
+        unregister(&state, client_switch_id, &sender).await;
+    }
+}
+
+/// Handle what a peer sends on its own, on the connection it made or on the one made to it.
+pub(super) async fn handle_peer_packet(
+    state: &Arc<State>,
+    peer_switch_id: SwitchId,
+    packet: Packet,
+) {
+    match packet {
+        Packet::VrfAction(vrf_action) => {
+            apply_vrf_action(state, vrf_action).await;
         }
-        VrfAction::AddMember { id, members } => {
-            let mut vrf_table = vrf_table.write().await;
+        Packet::VrfSync(vrf_sync) => sync_vrfs(state, peer_switch_id, vrf_sync).await,
+        Packet::PeerAdvert(peer_advert) => learn(state, peer_switch_id, peer_advert).await,
+        Packet::Maintenance(maintenance) => {
+            let draining = maintenance == Maintenance::Drain;
+            let mut draining_peers = state.draining_peers.lock().unwrap();
 
-            if let Some(vrf) = vrf_table.get_mut(&id) {
-                for new_member in members {
-                    if new_member == server_switch_id {
-                        let mut tap_table = tap_table.write().await;
+            if draining && draining_peers.insert(peer_switch_id) {
+                tracing::info!("Switch id {peer_switch_id} is draining");
+                publish(Event::SwitchDraining {
+                    switch_id: peer_switch_id,
+                });
+            } else if !draining && draining_peers.remove(&peer_switch_id) {
+                tracing::info!("Switch id {peer_switch_id} is active again");
+                publish(Event::SwitchActivated {
+                    switch_id: peer_switch_id,
+                });
+            }
+        }
+        // a switch only reports what it saw itself
+        Packet::Audit(Audit::Sighting(sighting)) if sighting.switch_id == peer_switch_id => {
+            state.audits.record(sighting);
+        }
+        // a switch only reports the hops it took itself
+        Packet::Trace(Trace::Hop(hop)) if hop.switch_id == peer_switch_id => {
+            state.traces.record(hop);
+        }
+        // the echoes come back through the client table, the test can't hold the connection
+        Packet::VrfTest(VrfTest::Run { nonce, vrf_id, to }) => {
+            spawn(run_for(state.clone(), peer_switch_id, nonce, vrf_id, to));
+        }
+        Packet::VrfTest(VrfTest::Result { nonce, result }) => {
+            state.vrf_tests.record(peer_switch_id, nonce, result);
+        }
+        Packet::Data(data) => {
+            let tap_table = state.tap_table.read().await;
 
-                        tap_table.insert(
-                            vrf.id,
-                            tap(vrf.clone(), client_table.clone(), switch_table.clone()),
-                        );
+            // never wait on a busy vrf, it would hold back the other vrfs of this peer
+            if let Some(tap) = tap_table.get(&data.vrf_id) {
+                match tap.try_send((peer_switch_id, data.compression, data.ttl, data.data)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        state.metrics.vrf(data.vrf_id).drop_frame();
+                        tracing::debug!("Dropped packet for vrf id {}, queue full", data.vrf_id);
                     }
-
-                    if !vrf.members.contains(&new_member) {
-                        vrf.members.push(new_member);
+                    Err(TrySendError::Closed(_)) => {
+                        tracing::error!(
+                            "Can't send data to tap interface for vrf id {}: channel closed",
+                            data.vrf_id
+                        );
                     }
                 }
             }
         }
-        VrfAction::RemoveMember { id, members } => {
-            let mut vrf_table = vrf_table.write().await;
+        // endpoints are local to the switch they're attached to, the rest is for configuration
+        // clients
+        _ => {}
+    }
+}
+
+// what a request of a configuration client needs, `None` for the packets that aren't requests
+fn access(packet: &Packet) -> Option<(Access, &'static str)> {
+    Some(match packet {
+        Packet::VrfAction(vrf_action) => (Access::of(vrf_action), "configuration action"),
+        Packet::Maintenance(_) => (Access::Change, "maintenance action"),
+        Packet::EndpointAction(_) => (Access::Change, "endpoint action"),
+        Packet::Audit(Audit::Start) => (Access::Change, "audit"),
+        Packet::Trace(Trace::Start { .. }) => (Access::Change, "trace"),
+        Packet::VrfTest(VrfTest::Start { .. }) => (Access::Change, "vrf test"),
+        Packet::Status(Status::Query) => (Access::Read, "status query"),
+        Packet::StatsAction(StatsAction::Query) => (Access::Read, "stats query"),
+        Packet::Save(Save::Request) => (Access::Change, "save"),
+        Packet::PeerAction(PeerAction::List) => (Access::Read, "peer list"),
+        Packet::MacAction(MacAction::List { .. }) => (Access::Read, "mac list"),
+        Packet::MacAction(MacAction::AddStatic { .. } | MacAction::RemoveStatic { .. }) => {
+            (Access::Change, "static mac")
+        }
+        Packet::AclAction(AclAction::List { .. }) => (Access::Read, "acl list"),
+        Packet::AclAction(AclAction::Add { .. } | AclAction::Remove { .. }) => {
+            (Access::Change, "acl change")
+        }
+        Packet::MirrorAction(_) => (Access::Change, "mirror action"),
+        Packet::Events(Events::Subscribe) => (Access::Read, "event subscription"),
+        _ => return None,
+    })
+}
 
-            if let Some(vrf) = vrf_table.get_mut(&id) {
-                for old_member in members {
-                    if old_member == server_switch_id {
-                        let mut tap_table = tap_table.write().await;
+// a configuration client waits on the reply to each of its requests
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &State,
+    session: &mut Session,
+    stream: &mut S,
+    packets: impl IntoIterator<Item = Packet>,
+) {
+    for packet in packets {
+        stream
+            .send_sealed(packet, state.control_key(), session)
+            .await;
+    }
 
-                        tap_table.remove(&vrf.id);
-                    }
+    if let Err(error) = stream.flush().await {
+        tracing::warn!("Can't send reply: {error}");
+    }
+}
+
+// the connection only carries events from then on, without ping timeout, until the client closes it
+async fn send_events<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &State,
+    session: &mut Session,
+    stream: &mut S,
+    buffer: &mut BytesMut,
+) {
+    let mut events = subscribe();
+
+    loop {
+        select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    stream
+                        .send_sealed(
+                            Events::Event(event),
+                            state.control_key(),
+                            session,
+                        )
+                        .await;
 
-                    vrf.members.retain(|member| *member != old_member);
+                    if let Err(error) = stream.flush().await {
+                        tracing::warn!("Can't send event: {error}");
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(count)) => {
+                    tracing::warn!("Event subscriber lagged, {count} events dropped");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            packet = stream.recv_packet(buffer) => {
+                if packet.is_none() {
+                    tracing::debug!("Event subscriber closed the connection");
+                    break;
                 }
             }
         }
     }
 }
 
-async fn broadcast_packet(client_table: Arc<RwLock<ClientTable>>, packet: Packet) {
-    let client_table = client_table.read().await;
+async fn process_vrf_action<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &Arc<State>,
+    session: &mut Session,
+    stream: &mut S,
+    vrf_action: VrfAction,
+) -> Option<Response> {
+    let client_switch_id = session.peer_switch_id();
+
+    match vrf_action {
+        VrfAction::List(_) => {
+            let vrfs = list_vrfs(state).await;
+            let packets = vrfs
+                .chunks(10)
+                .chain([&[][..]])
+                .map(|vrf_list_chunk| VrfAction::List(Some(vrf_list_chunk.to_vec())).into())
+                .collect::<Vec<Packet>>();
+
+            reply(state, session, stream, packets).await;
+
+            None
+        }
+        VrfAction::Allocate(vrf) if client_switch_id == CONFIGURATION_SWITCH_ID => {
+            let packet = match allocate_vrf(state, *vrf).await {
+                Ok(id) => VrfAction::Allocated { id }.into(),
+                Err(error) => Packet::from(Response::Error(error)),
+            };
+
+            reply(state, session, stream, [packet]).await;
 
-    for (switch_id, client) in client_table.iter() {
-        if let Err(error) = client.send(packet.clone()).await {
-            tracing::error!("Can't broadcast packet to switch id {}: {error}", switch_id);
+            None
+        }
+        vrf_action if client_switch_id == CONFIGURATION_SWITCH_ID => {
+            Some(configure(state, vrf_action).await)
         }
+        vrf_action => Some(apply_vrf_action(state, vrf_action).await),
     }
 }
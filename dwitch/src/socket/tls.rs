@@ -0,0 +1,191 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client,
+    rustls::{
+        self,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            verify_server_cert_signed_by_trust_anchor, verify_server_name,
+        },
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        server::{ParsedCertificate, WebPkiClientVerifier},
+        ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+
+use crate::config::{SwitchId, TlsConfig};
+
+pub(super) const SWITCH_NAME_SUFFIX: &str = "switch.dwitch";
+
+pub type PeerCertificates = Vec<CertificateDer<'static>>;
+
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_config: Arc<ServerConfig>,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Tls {
+    pub fn load(config: &TlsConfig) -> eyre::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let certificates = load_certificates(&config.cert)?;
+        let key = load_key(&config.key)?;
+        let mut roots = RootCertStore::empty();
+
+        for certificate in load_certificates(&config.ca)? {
+            roots.add(certificate)?;
+        }
+
+        let roots = Arc::new(roots);
+        let client_verifier =
+            WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build()?;
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certificates.clone(), key.clone_key())?;
+        let client_config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SwitchCertVerifier { roots, provider }))
+            .with_client_auth_cert(certificates, key)?;
+
+        let server_config = Arc::new(server_config);
+        let client_config = Arc::new(client_config);
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(server_config.clone()),
+            connector: TlsConnector::from(client_config.clone()),
+            server_config,
+            client_config,
+        })
+    }
+
+    /// The configs of both ends, shared with the quic endpoint.
+    pub(super) fn configs(&self) -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        (self.server_config.clone(), self.client_config.clone())
+    }
+
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> std::io::Result<(server::TlsStream<TcpStream>, PeerCertificates)> {
+        let stream = self.acceptor.accept(stream).await?;
+        let certificates = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|certificates| certificates.to_vec())
+            .unwrap_or_default();
+
+        Ok((stream, certificates))
+    }
+
+    pub async fn connect(
+        &self,
+        stream: TcpStream,
+    ) -> std::io::Result<(client::TlsStream<TcpStream>, PeerCertificates)> {
+        // the server name is checked against the switch id after the handshake
+        let server_name = ServerName::try_from(SWITCH_NAME_SUFFIX).expect("Valid server name");
+        let stream = self.connector.connect(server_name, stream).await?;
+        let certificates = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|certificates| certificates.to_vec())
+            .unwrap_or_default();
+
+        Ok((stream, certificates))
+    }
+}
+
+/// Check that the peer certificate was issued for `<switch_id>.switch.dwitch`.
+pub fn verify_switch_id(
+    certificates: &PeerCertificates,
+    switch_id: SwitchId,
+) -> Result<(), rustls::Error> {
+    let certificate = certificates
+        .first()
+        .ok_or(rustls::Error::NoCertificatesPresented)?;
+    let server_name = ServerName::try_from(format!("{switch_id}.{SWITCH_NAME_SUFFIX}"))
+        .map_err(|error| rustls::Error::General(error.to_string()))?;
+
+    verify_server_name(&ParsedCertificate::try_from(certificate)?, &server_name)
+}
+
+fn load_certificates(path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
+    Ok(
+        rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+            .collect::<Result<Vec<_>, _>>()?,
+    )
+}
+
+fn load_key(path: &Path) -> eyre::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| eyre::eyre!("No private key found in {}", path.display()))
+}
+
+#[derive(Debug)]
+struct SwitchCertVerifier {
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for SwitchCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        verify_server_cert_signed_by_trust_anchor(
+            &ParsedCertificate::try_from(end_entity)?,
+            &self.roots,
+            intermediates,
+            now,
+            self.provider.signature_verification_algorithms.all,
+        )?;
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+use common::VrfId;
+use protocol::Compression;
+use quinn::Endpoint;
+
+use tokio::{net::UdpSocket, sync::RwLock};
+
+use crate::{
+    acl::Acls,
+    audit::Audits,
+    cache::{CacheKey, VrfTable},
+    config::{Config, SwitchId},
+    gossip::Gossip,
+    handover::HandoverFds,
+    ip_table::IpTable,
+    metrics::Metrics,
+    mirror::Mirrors,
+    rate_limit::RateLimiter,
+    socket::{
+        client::{ClientTable, PeerConnection},
+        tls::Tls,
+    },
+    switch_table::SwitchTable,
+    tap::TapTable,
+    token::{Access, Permission, Refusal},
+    trace::Traces,
+    vrf_key::VrfKeys,
+    vrf_test::VrfTests,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Management,
+    Dbus,
+    Remote(IpAddr),
+}
+
+pub struct State {
+    pub config: Config,
+    pub tls: Option<Tls>,
+    /// Endpoint peers are dialed and accepted on with the quic transport.
+    pub quic: Option<Endpoint>,
+    /// Socket the frames of vxlan vrfs are sent and received on.
+    pub vxlan: Option<UdpSocket>,
+    pub vrf_keys: VrfKeys,
+    pub cache_key: Option<CacheKey>,
+    pub listening: AtomicBool,
+    pub draining: AtomicBool,
+    pub action_limiter: RateLimiter<Source>,
+    pub tap_table: RwLock<TapTable>,
+    // vrf names by id, for the taps that couldn't be created yet
+    pub degraded_taps: Mutex<HashMap<VrfId, String>>,
+    pub vrf_table: RwLock<VrfTable>,
+    /// Version each deleted vrf was deleted at, so a peer that missed it doesn't bring it back.
+    pub deleted_vrfs: Mutex<HashMap<VrfId, u64>>,
+    /// Vrfs the config file declared, deleted when it stops declaring them.
+    pub declared_vrfs: Mutex<HashSet<VrfId>>,
+    pub client_table: Arc<RwLock<ClientTable>>,
+    pub switch_table: Arc<RwLock<SwitchTable>>,
+    pub acls: Acls,
+    pub mirrors: Mirrors,
+    pub ip_table: Mutex<IpTable>,
+    pub draining_peers: Mutex<HashSet<SwitchId>>,
+    pub gossip: Gossip,
+    /// Connection each connected peer is reached through.
+    pub peer_connections: Mutex<HashMap<SwitchId, PeerConnection>>,
+    /// Path mtu of the underlay toward each connected peer.
+    pub path_mtus: Mutex<HashMap<SwitchId, u32>>,
+    /// Algorithms each peer advertised it can decompress, as of its last handshake.
+    pub peer_compression: Mutex<HashMap<SwitchId, Vec<Compression>>>,
+    // checked by the pipeline of a vrf for each frame, without looking the vrf up
+    pub suspensions: Mutex<HashMap<VrfId, Arc<AtomicBool>>>,
+    pub metrics: Metrics,
+    pub handover_fds: HandoverFds,
+    pub audits: Audits,
+    pub traces: Traces,
+    pub vrf_tests: VrfTests,
+}
+
+impl State {
+    /// The one policy of every management interface: reading needs a permission, changes need the
+    /// admin one and are rate limited per source. `request` only names it in the logs.
+    pub fn authorize(
+        &self,
+        source: Source,
+        permission: Option<Permission>,
+        access: Access,
+        request: &str,
+    ) -> Result<(), Refusal> {
+        if !permission.is_some_and(|permission| permission.allows(access)) {
+            tracing::warn!("Denied {request} from {source:?}");
+
+            return Err(Refusal::Denied);
+        }
+
+        if access == Access::Change && !self.action_limiter.check(source) {
+            tracing::warn!("Rate limited {request} from {source:?}");
+
+            return Err(Refusal::RateLimited);
+        }
+
+        Ok(())
+    }
+
+    pub fn control_key(&self) -> Option<&[u8]> {
+        self.config.control_key()
+    }
+
+    pub fn data_key(&self) -> Option<&[u8]> {
+        self.config.data_key()
+    }
+
+    /// Whether a vrf is suspended, shared with its pipeline.
+    pub fn suspension(&self, vrf_id: VrfId) -> Arc<AtomicBool> {
+        self.suspensions
+            .lock()
+            .unwrap()
+            .entry(vrf_id)
+            .or_default()
+            .clone()
+    }
+}
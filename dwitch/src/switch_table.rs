@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use common::VrfId;
+use lru::LruCache;
+use serde::{
+    de::{SeqAccess, Visitor},
+    ser::SerializeSeq,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::config::SwitchId;
+
+pub type MacAddress = [u8; 6];
+/// Vlan id of a frame, 0 for untagged ones.
+pub type Vlan = u16;
+
+/// Switch a mac address was learned behind, and since when in seconds from the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Learned {
+    pub switch_id: SwitchId,
+    pub since: u64,
+}
+
+/// Learned mac addresses of each vrf and vlan, bounded per vrf by evicting the least recently seen.
+///
+/// Each vrf has its own shard so its pipeline never contends with the others on this table.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct SwitchTable {
+    vrfs: HashMap<VrfId, MacShard>,
+    #[serde(skip)]
+    capacity: Option<NonZeroUsize>,
+}
+
+impl SwitchTable {
+    pub fn set_capacity(&mut self, capacity: NonZeroUsize) {
+        self.capacity = Some(capacity);
+
+        for shard in self.vrfs.values() {
+            shard.0.write().unwrap().resize(capacity);
+        }
+    }
+
+    pub fn shard(&mut self, vrf_id: VrfId) -> MacShard {
+        let capacity = self.capacity;
+
+        self.vrfs
+            .entry(vrf_id)
+            .or_insert_with(|| {
+                MacShard(Arc::new(RwLock::new(MacTable {
+                    entries: match capacity {
+                        Some(capacity) => LruCache::new(capacity),
+                        None => LruCache::unbounded(),
+                    },
+                    evictions: 0,
+                    statics: HashMap::new(),
+                })))
+            })
+            .clone()
+    }
+
+    pub fn remove(&mut self, vrf_id: &VrfId) {
+        self.vrfs.remove(vrf_id);
+    }
+
+    /// Number of learned entries and evictions of each vrf.
+    pub fn usage(&self) -> impl Iterator<Item = (VrfId, usize, u64)> + '_ {
+        self.vrfs.iter().map(|(vrf_id, shard)| {
+            let mac_table = shard.0.read().unwrap();
+
+            (*vrf_id, mac_table.entries.len(), mac_table.evictions)
+        })
+    }
+
+    /// Learned mac addresses of each vrf, most recently seen first.
+    pub fn entries(&self) -> Vec<(VrfId, Vlan, MacAddress, Learned)> {
+        self.vrfs
+            .iter()
+            .flat_map(|(vrf_id, shard)| {
+                shard
+                    .entries()
+                    .into_iter()
+                    .map(|(vlan, mac, learned)| (*vrf_id, vlan, mac, learned))
+            })
+            .collect()
+    }
+
+    /// Learned mac addresses of a vrf, most recently seen first.
+    pub fn vrf_entries(&self, vrf_id: VrfId) -> Vec<(Vlan, MacAddress, Learned)> {
+        self.vrfs
+            .get(&vrf_id)
+            .map(MacShard::entries)
+            .unwrap_or_default()
+    }
+
+    /// Macs pinned behind a switch in each vrf.
+    pub fn statics(&self) -> Vec<(VrfId, MacAddress, SwitchId)> {
+        self.vrfs
+            .iter()
+            .flat_map(|(vrf_id, shard)| {
+                shard
+                    .statics()
+                    .into_iter()
+                    .map(|(mac, switch_id)| (*vrf_id, mac, switch_id))
+            })
+            .collect()
+    }
+
+    /// Macs pinned behind a switch in a vrf.
+    pub fn vrf_statics(&self, vrf_id: VrfId) -> Vec<(MacAddress, SwitchId)> {
+        self.vrfs
+            .get(&vrf_id)
+            .map(MacShard::statics)
+            .unwrap_or_default()
+    }
+
+    pub fn flush(&self, vrf_id: Option<VrfId>) {
+        for (_, shard) in self
+            .vrfs
+            .iter()
+            .filter(|(id, _)| vrf_id.is_none_or(|vrf_id| **id == vrf_id))
+        {
+            shard.clear();
+        }
+    }
+
+    /// Forget the mac addresses learned behind a switch in every vrf, so their frames are
+    /// flooded again instead of sent to a peer that's gone. Returns how many were forgotten, the
+    /// pinned ones stay.
+    pub fn forget(&self, switch_id: SwitchId) -> usize {
+        self.vrfs
+            .values()
+            .map(|shard| shard.forget(switch_id))
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MacShard(Arc<RwLock<MacTable>>);
+
+impl MacShard {
+    /// The switch a mac is pinned behind in any vlan, or learned behind in this one.
+    pub fn get(&self, vlan: Vlan, mac: &MacAddress) -> Option<SwitchId> {
+        let mac_table = self.0.read().unwrap();
+
+        mac_table.statics.get(mac).copied().or_else(|| {
+            mac_table
+                .entries
+                .peek(&(vlan, *mac))
+                .map(|learned| learned.switch_id)
+        })
+    }
+
+    pub fn learn(&self, vlan: Vlan, mac: MacAddress, switch_id: SwitchId) {
+        let mut mac_table = self.0.write().unwrap();
+
+        // a pinned mac stays where it was pinned
+        if mac_table.statics.contains_key(&mac) {
+            return;
+        }
+
+        // a mac seen again behind the same switch keeps the time it was learned
+        let since = match mac_table.entries.peek(&(vlan, mac)) {
+            Some(learned) if learned.switch_id == switch_id => learned.since,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+        };
+
+        if let Some((evicted, _)) = mac_table
+            .entries
+            .push((vlan, mac), Learned { switch_id, since })
+        {
+            if evicted != (vlan, mac) {
+                mac_table.evictions += 1;
+
+                tracing::debug!("Evicted mac address {:?} of vlan {}", evicted.1, evicted.0);
+            }
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(Vlan, MacAddress, Learned)> {
+        self.0
+            .read()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|((vlan, mac), learned)| (*vlan, *mac, *learned))
+            .collect()
+    }
+
+    /// Pin a mac behind a switch, it's neither learned elsewhere nor evicted until it's unpinned.
+    pub fn pin(&self, mac: MacAddress, switch_id: SwitchId) {
+        let mut mac_table = self.0.write().unwrap();
+
+        mac_table.statics.insert(mac, switch_id);
+        mac_table
+            .entries
+            .retain(|(_, learned_mac), _| *learned_mac != mac);
+    }
+
+    /// The switch a mac is pinned behind, if it is.
+    pub fn pinned(&self, mac: &MacAddress) -> Option<SwitchId> {
+        self.0.read().unwrap().statics.get(mac).copied()
+    }
+
+    /// Returns whether the mac was pinned.
+    pub fn unpin(&self, mac: &MacAddress) -> bool {
+        self.0.write().unwrap().statics.remove(mac).is_some()
+    }
+
+    pub fn statics(&self) -> Vec<(MacAddress, SwitchId)> {
+        self.0
+            .read()
+            .unwrap()
+            .statics
+            .iter()
+            .map(|(mac, switch_id)| (*mac, *switch_id))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().entries.clear();
+    }
+
+    pub fn forget(&self, switch_id: SwitchId) -> usize {
+        let mut mac_table = self.0.write().unwrap();
+        let macs = mac_table
+            .entries
+            .iter()
+            .filter(|(_, learned)| learned.switch_id == switch_id)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in &macs {
+            mac_table.entries.pop(key);
+        }
+
+        macs.len()
+    }
+
+    /// Bound this vrf apart from the switch wide capacity.
+    pub fn set_capacity(&self, capacity: NonZeroUsize) {
+        self.0.write().unwrap().resize(capacity);
+    }
+}
+
+impl Serialize for MacShard {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.read().unwrap().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacShard {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(Arc::new(RwLock::new(MacTable::deserialize(
+            deserializer,
+        )?))))
+    }
+}
+
+#[derive(Debug)]
+struct MacTable {
+    entries: LruCache<(Vlan, MacAddress), Learned>,
+    evictions: u64,
+    // kept by the cache apart from the learned entries
+    statics: HashMap<MacAddress, SwitchId>,
+}
+
+impl MacTable {
+    fn resize(&mut self, capacity: NonZeroUsize) {
+        let length = self.entries.len();
+
+        self.entries.resize(capacity);
+        self.evictions += length.saturating_sub(capacity.get()) as u64;
+    }
+}
+
+// stored least recently seen first, with the same layout as a plain map
+impl Serialize for MacTable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.entries.len()))?;
+
+        for entry in self.entries.iter().rev() {
+            seq.serialize_element(&entry)?;
+        }
+
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for MacTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MacTableVisitor;
+
+        impl<'de> Visitor<'de> for MacTableVisitor {
+            type Value = MacTable;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a list of vlans, mac addresses and where they were learned")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut entries = LruCache::unbounded();
+
+                while let Some((key, learned)) =
+                    seq.next_element::<((Vlan, MacAddress), Learned)>()?
+                {
+                    entries.push(key, learned);
+                }
+
+                Ok(MacTable {
+                    entries,
+                    evictions: 0,
+                    statics: HashMap::new(),
+                })
+            }
+        }
+
+        deserializer.deserialize_seq(MacTableVisitor)
+    }
+}
@@ -1,124 +1,450 @@
-use std::{collections::HashMap, error::Error, io, sync::Arc};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    future::Future,
+    io::{self, Read, Write},
+    net::IpAddr,
+    num::NonZeroUsize,
+    os::fd::{AsFd, OwnedFd},
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
 
 use common::VrfId;
+#[cfg(all(target_os = "linux", feature = "netns"))]
 use netns::Netns;
-use protocol::{Data, Packet, Vrf};
-use tappers::{tokio::AsyncTap, DeviceState};
+use protocol::{
+    AclDirection, Bpdu, Compression, Data, Decision, DropReason, Learning, Packet, Vrf, VrfMode,
+    DATA_TTL,
+};
+use tappers::{DeviceState, Interface};
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use tokio::task::spawn_blocking;
 use tokio::{
-    spawn,
+    io::{unix::AsyncFd, Interest},
+    select, spawn,
     sync::{
         mpsc::{channel, Receiver, Sender},
-        RwLock,
+        Mutex, Semaphore, SemaphorePermit,
     },
+    task::JoinSet,
+    time::{sleep, timeout},
 };
 
 use crate::{
-    cache::{SwitchTable, VrfTable},
+    audit,
     config::SwitchId,
-    socket::client::{broadcast_to_vrf, ClientTable},
-    BufferExt, MAX_BUFFER_SIZE,
+    events::{publish, Event},
+    link::{self, Dataplane},
+    metrics::VrfMetrics,
+    mirror::Mirror,
+    networkd,
+    openflow::{
+        self, Datapath, PacketOut, PORT_ALL, PORT_CONTROLLER, PORT_FLOOD, PORT_IN_PORT, PORT_LOCAL,
+        PORT_NORMAL,
+    },
+    rate_limit::{RateLimitConfig, RateLimiter},
+    runtime::{enter_data_plane, spawn_data_plane},
+    shaper::Shaper,
+    socket::client::broadcast_to_vrf,
+    state::State,
+    switch_table::{MacAddress, MacShard, Vlan},
+    trace,
+    vrf_key::VrfKey,
+    vrf_test, vxlan, BufferExt, MAX_BUFFER_SIZE,
 };
 
-pub type TapTable = HashMap<VrfId, Sender<(SwitchId, Vec<u8>)>>;
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+const BPDU_GUARD_HOLD: Duration = Duration::from_secs(60);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+const TAP_QUEUE_DEPTH: usize = 32;
+/// Deepest queue of frames a vrf can ask for.
+pub const MAX_TAP_QUEUE_DEPTH: u32 = 65536;
 
-pub fn initiate_tap_table(
-    switch_id: SwitchId,
-    vrf_table: &VrfTable,
-    client_table: Arc<RwLock<ClientTable>>,
-    switch_table: Arc<RwLock<SwitchTable>>,
-) -> TapTable {
-    let mut tap_table = HashMap::new();
-
-    for (id, vrf) in vrf_table.iter() {
-        if vrf.members.contains(&switch_id) {
-            tap_table.insert(
-                *id,
-                tap(vrf.clone(), client_table.clone(), switch_table.clone()),
-            );
+// each tap holds a permit until its netns or links are deleted, closing them waits on all of them
+static OPEN_TAPS: Semaphore = Semaphore::const_new(u32::MAX as usize);
+
+/// A frame for the tap of a vrf, with the switch it comes from, how it was compressed and the hops
+/// it has left.
+pub type Inbound = (SwitchId, Option<Compression>, u8, Bytes);
+pub type TapTable = HashMap<VrfId, Sender<Inbound>>;
+
+pub async fn initiate_tap_table(state: &Arc<State>, mut inherited: HashMap<VrfId, OwnedFd>) {
+    let semaphore = Arc::new(Semaphore::new(state.config.tap_setup_parallelism.max(1)));
+    let mut setups = JoinSet::new();
+
+    for vrf in state.vrf_table.read().await.values() {
+        if vrf.members.contains(&state.config.switch_id) {
+            let vrf = vrf.clone();
+            let state = state.clone();
+            let inherited = inherited.remove(&vrf.id);
+            let semaphore = semaphore.clone();
+            // the taps have to be created in the runtime that will drive them
+            let _data_plane = enter_data_plane();
+
+            setups.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let tap = match inherited {
+                    Some(fd) => {
+                        link::set_tap_name(&vrf);
+
+                        Tap::new(fd, isolation(&state, &vrf)).map_err(Into::into)
+                    }
+                    None => create_tap(&state, &vrf).await,
+                };
+
+                (vrf, tap)
+            });
         }
     }
 
-    tap_table
+    // taps of vrfs this switch left meanwhile
+    drop(inherited);
+
+    let mut tap_table = state.tap_table.write().await;
+    let mut created = 0;
+
+    while let Some(setup) = setups.join_next().await {
+        let (vrf, tap) = match setup {
+            Ok(setup) => setup,
+            Err(error) => {
+                tracing::error!("Can't set up a vrf tap: {error}");
+                continue;
+            }
+        };
+
+        if tap.is_ok() {
+            created += 1;
+            tracing::info!("Created the tap for the vrf {}", vrf.name);
+        }
+
+        tap_table.insert(vrf.id, start_tap(vrf, tap, state.clone()));
+    }
+
+    tracing::info!("Set up {created} of {} vrf taps", tap_table.len());
 }
 
-pub fn tap(
-    vrf: Vrf,
-    client_table: Arc<RwLock<ClientTable>>,
-    switch_table: Arc<RwLock<SwitchTable>>,
-) -> Sender<(SwitchId, Vec<u8>)> {
-    let (sender, receiver) = channel::<(SwitchId, Vec<u8>)>(32);
+pub async fn tap(vrf: Vrf, state: Arc<State>) -> Sender<Inbound> {
+    let tap = create_data_plane_tap(&vrf, &state).await;
 
-    match setup_tap(&vrf.name) {
+    start_tap(vrf, tap, state)
+}
+
+/// Like `tap`, failing instead of recovering a tap that can't be created.
+pub async fn try_tap(vrf: Vrf, state: Arc<State>) -> Result<Sender<Inbound>, SetupError> {
+    let tap = create_data_plane_tap(&vrf, &state).await?;
+
+    Ok(start_tap(vrf, Ok(tap), state))
+}
+
+/// Tear down every tap with its netns or links, for the daemon to exit without leaving them behind.
+pub async fn close_taps(state: &State) {
+    // each pipeline ends once its sender is gone, dropping its tap
+    state.tap_table.write().await.clear();
+
+    match timeout(CLOSE_TIMEOUT, OPEN_TAPS.acquire_many(u32::MAX)).await {
+        Ok(_) => tracing::info!("Closed the taps"),
+        Err(_) => tracing::warn!("Can't close the taps in time, some may be left behind"),
+    }
+}
+
+// the tap has to be created in the runtime that will drive it
+async fn create_data_plane_tap(vrf: &Vrf, state: &Arc<State>) -> Result<Tap, SetupError> {
+    match spawn_data_plane({
+        let vrf = vrf.clone();
+        let state = state.clone();
+
+        async move { create_tap(&state, &vrf).await }
+    })
+    .await
+    {
+        Ok(tap) => tap,
+        Err(error) => Err(error.to_string().into()),
+    }
+}
+
+fn start_tap(vrf: Vrf, tap: Result<Tap, SetupError>, state: Arc<State>) -> Sender<Inbound> {
+    let (sender, receiver) = channel::<Inbound>(queue_depth(&vrf));
+
+    match tap {
         Ok(tap) => {
-            spawn(tap_connection(
-                tap,
-                vrf,
-                receiver,
-                client_table.clone(),
-                switch_table.clone(),
-            ));
+            state.handover_fds.add_tap(vrf.id, tap.0.as_fd());
+            spawn_data_plane(vrf_connection(tap, vrf, receiver, state));
         }
         Err(error) => {
-            tracing::error!("Error creating the tap for the vrf {}: {error}", vrf.name);
+            spawn_data_plane(recover_tap(vrf, error, receiver, state));
         }
     }
 
     sender
 }
 
-async fn tap_connection(
-    tap: Tap,
+// the vrf keeps its sender meanwhile, frames for it are dropped until the tap exists
+async fn recover_tap(
     vrf: Vrf,
-    mut receiver: Receiver<(SwitchId, Vec<u8>)>,
-    client_table: Arc<RwLock<ClientTable>>,
-    switch_table: Arc<RwLock<SwitchTable>>,
+    mut error: SetupError,
+    receiver: Receiver<Inbound>,
+    state: Arc<State>,
+) {
+    let mut delay = RETRY_MIN_DELAY;
+
+    tracing::error!("Vrf {} degraded: tap missing, {error}", vrf.name);
+    state
+        .degraded_taps
+        .lock()
+        .unwrap()
+        .insert(vrf.id, vrf.name.clone());
+    publish(Event::TapDegraded {
+        id: vrf.id,
+        name: vrf.name.clone(),
+        error: error.to_string(),
+    });
+
+    let tap = loop {
+        sleep(delay).await;
+
+        // the vrf was deleted or its tap replaced
+        if receiver.is_closed() {
+            return;
+        }
+
+        match create_tap(&state, &vrf).await {
+            Ok(tap) => break tap,
+            Err(error_) => error = error_,
+        }
+
+        delay = (delay * 2).min(RETRY_MAX_DELAY);
+        tracing::warn!(
+            "Can't create the tap for the vrf {}, retrying in {delay:?}: {error}",
+            vrf.name
+        );
+    };
+
+    tracing::info!("Recovered the tap for the vrf {}", vrf.name);
+    state.degraded_taps.lock().unwrap().remove(&vrf.id);
+    state.handover_fds.add_tap(vrf.id, tap.0.as_fd());
+    publish(Event::TapRecovered {
+        id: vrf.id,
+        name: vrf.name.clone(),
+    });
+
+    vrf_connection(tap, vrf, receiver, state).await
+}
+
+// frames from the peers a vrf queues for its tap
+fn queue_depth(vrf: &Vrf) -> usize {
+    vrf.settings
+        .queue_depth
+        .map_or(TAP_QUEUE_DEPTH, |queue_depth| queue_depth as usize)
+}
+
+/// Frames written to and read from a virtual tap, for wiring instances together in memory.
+pub struct VirtualWire {
+    pub sender: Sender<Bytes>,
+    pub receiver: Receiver<Bytes>,
+}
+
+pub fn virtual_tap(vrf: Vrf, state: Arc<State>) -> (Sender<Inbound>, VirtualWire) {
+    let (sender, receiver) = channel::<Inbound>(queue_depth(&vrf));
+    let (inbound_sender, inbound_receiver) = channel(32);
+    let (outbound_sender, outbound_receiver) = channel(32);
+
+    spawn_data_plane(tap_connection(
+        VirtualTap {
+            inbound: Mutex::new(inbound_receiver),
+            outbound: outbound_sender,
+        },
+        vrf,
+        receiver,
+        state,
+    ));
+
+    (
+        sender,
+        VirtualWire {
+            sender: inbound_sender,
+            receiver: outbound_receiver,
+        },
+    )
+}
+
+trait TapDevice: Send + Sync + 'static {
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+async fn vrf_connection<D: TapDevice>(
+    tap: D,
+    vrf: Vrf,
+    receiver: Receiver<Inbound>,
+    state: Arc<State>,
+) {
+    let tap = Mirrored {
+        device: tap,
+        mirror: state.mirrors.vrf(vrf.id),
+    };
+
+    match vrf.settings.mode.unwrap_or_default() {
+        VrfMode::Tap => tap_connection(tap, vrf, receiver, state).await,
+        VrfMode::Tun => tun_connection(tap, vrf, receiver, state).await,
+    }
+}
+
+async fn tap_connection<D: TapDevice>(
+    tap: D,
+    vrf: Vrf,
+    mut receiver: Receiver<Inbound>,
+    state: Arc<State>,
 ) {
     let tap = Arc::new(tap);
+    let suspended = state.suspension(vrf.id);
+    let metrics = state.metrics.vrf(vrf.id);
+    let shaper = Arc::new(Shaper::new(&vrf.settings, metrics.clone()));
+    // vxlan frames cross in the clear
+    let key = state
+        .vrf_keys
+        .get(&vrf.name)
+        .cloned()
+        .filter(|_| vrf.settings.vxlan.is_none());
+    let mac_shard = state.switch_table.write().await.shard(vrf.id);
+    let acl = state.acls.vrf(vrf.id);
+    let frame_limiter = vrf.settings.frame_rate.map(|rate| {
+        RateLimiter::new(RateLimitConfig {
+            rate: rate as f64,
+            burst: rate,
+        })
+    });
+
+    let bpdu_guard = Arc::new(BpduGuard::default());
+    let max_frame_size = vrf
+        .settings
+        .max_frame_size()
+        .map_or(MAX_BUFFER_SIZE, |max_frame_size| max_frame_size as usize);
+
+    if let Some(max_macs) = vrf
+        .settings
+        .max_macs
+        .and_then(|max_macs| NonZeroUsize::new(max_macs as usize))
+    {
+        mac_shard.set_capacity(max_macs);
+    }
+
+    if vrf.settings.vxlan.is_some() && state.vxlan.is_none() {
+        tracing::warn!(
+            "Vrf {} uses vxlan but the switch has no vxlan listen address, its frames stay local",
+            vrf.name
+        );
+    }
+
+    if vrf.settings.gateway.is_some() && !link::owns_netns(state.config.dataplane, &vrf) {
+        tracing::warn!(
+            "Vrf {} isn't in a netns of its own, its gateway isn't served",
+            vrf.name
+        );
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if vrf.settings.gateway.is_some() {
+        tracing::warn!("Vrf {} has a gateway, it's only served on linux", vrf.name);
+    }
+
+    // evpn advertises the macs behind the local taps, they're only learned for it
+    let learn_local = state.config.evpn.is_some();
+    let (datapath, mut packet_outs, datapath_task) = match state.config.openflow.clone() {
+        Some(config) => {
+            let (datapath, packet_outs, task) =
+                openflow::datapath(config, vrf.clone(), state.clone());
+
+            (Some(datapath), Some(packet_outs), Some(task))
+        }
+        None => (None, None, None),
+    };
 
     let receiver_task = spawn({
         let tap = tap.clone();
         let vrf = vrf.clone();
-        let switch_table = switch_table.clone();
+        let key = key.clone();
+        let mac_shard = mac_shard.clone();
+        let acl = acl.clone();
+        let shaper = shaper.clone();
+        let datapath = datapath.clone();
+        let bpdu_guard = bpdu_guard.clone();
+        let suspended = suspended.clone();
+        let metrics = metrics.clone();
+        let state = state.clone();
 
         async move {
-            let mut buffer = [0u8; MAX_BUFFER_SIZE];
+            let mut buffer = vec![0u8; max_frame_size];
 
             loop {
                 if let Ok(length) = tap.recv(&mut buffer).await {
-                    if length == 0 {
+                    if length == 0 || suspended.load(Ordering::Relaxed) {
                         continue;
                     }
 
+                    metrics.received.count(length);
+
                     let buffer = &mut buffer[..length];
 
-                    if length >= 14 {
-                        let packet = Packet::from(Data {
-                            vrf_id: vrf.id,
-                            data: buffer.to_vec(),
-                        });
-                        let destination_mac = get_destination_mac(&buffer);
-
-                        tracing::debug!("Destination mac address {destination_mac:?}");
-
-                        if let Some(switch_id) = {
-                            let switch_table = switch_table.read().await;
-
-                            switch_table.get(&vrf.id).and_then(|vrf_switch_table| {
-                                vrf_switch_table.get(&destination_mac).copied()
-                            })
-                        } {
-                            let client_table = client_table.read().await;
-
-                            if let Some(client) = client_table.get(&switch_id) {
-                                if let Err(error) = client.send(packet).await {
-                                    tracing::error!(
-                                        "Can't send packet to client {switch_id} for vrf {}: {error}",
-                                        vrf.name
+                    if length >= 14
+                        && !audit::observe(&state, vrf.id, buffer, true).await
+                        && !is_denied(&vrf, buffer)
+                        && is_vlan_allowed(&vrf, buffer)
+                        && acl.permits_frame(AclDirection::Ingress, buffer)
+                        && bpdu_guard.pass(&vrf, buffer, true)
+                        && frame_limiter
+                            .as_ref()
+                            .is_none_or(|frame_limiter| frame_limiter.check(()))
+                    {
+                        // the flow table comes first, its normal action falls back to the mac table
+                        let ports = match &datapath {
+                            Some(datapath) => {
+                                let ports = datapath.flow_table.lookup(PORT_LOCAL, buffer);
+
+                                if let Some(ports) = &ports {
+                                    output(
+                                        &state,
+                                        &vrf,
+                                        key.as_ref(),
+                                        &shaper,
+                                        &*tap,
+                                        &metrics,
+                                        datapath,
+                                        PORT_LOCAL,
+                                        DATA_TTL,
+                                        ports,
+                                        buffer,
                                     )
+                                    .await;
                                 }
+
+                                ports
+                            }
+                            None => None,
+                        };
+
+                        if ports.is_none_or(|ports| ports.contains(&PORT_NORMAL)) {
+                            let decision = forward(
+                                &state,
+                                &vrf,
+                                key.as_ref(),
+                                &shaper,
+                                &mac_shard,
+                                learn_local,
+                                buffer,
+                                false,
+                            )
+                            .await;
+
+                            if matches!(decision, Decision::Flood) {
+                                metrics.flood();
                             }
-                        } else {
-                            broadcast_to_vrf(&vrf, packet, client_table.clone()).await;
                         }
                     }
 
@@ -128,76 +454,1020 @@ async fn tap_connection(
         }
     });
 
-    while let Some((switch_id, data)) = receiver.recv().await {
+    loop {
+        let (switch_id, compression, ttl, data) = select! {
+            received = receiver.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            Some(packet_out) = recv_packet_out(&mut packet_outs) => {
+                if let Some(datapath) = &datapath {
+                    output(
+                        &state,
+                        &vrf,
+                        key.as_ref(),
+                        &shaper,
+                        &*tap,
+                        &metrics,
+                        datapath,
+                        packet_out.in_port,
+                        DATA_TTL,
+                        &packet_out.ports,
+                        &packet_out.frame,
+                    )
+                    .await;
+                }
+
+                continue;
+            }
+        };
+        let Some(data) = open_frame(
+            &vrf,
+            key.as_ref(),
+            switch_id,
+            compression,
+            data,
+            max_frame_size,
+        ) else {
+            continue;
+        };
+
+        // frames injected by a trace on this switch take the way of the frames of the tap
+        if let Some(traced) = trace::parse(&data) {
+            let local = switch_id == state.config.switch_id;
+            let in_port = if local { PORT_LOCAL } else { switch_id };
+            let decision = if suspended.load(Ordering::Relaxed) {
+                Decision::Dropped(DropReason::Suspended)
+            } else if is_denied(&vrf, &data) {
+                Decision::Dropped(DropReason::DeniedEthertype)
+            } else if !is_vlan_allowed(&vrf, &data) {
+                Decision::Dropped(DropReason::DeniedVlan)
+            } else if !acl.permits_frame(
+                if local {
+                    AclDirection::Ingress
+                } else {
+                    AclDirection::Egress
+                },
+                &data,
+            ) {
+                Decision::Dropped(DropReason::Acl)
+            } else if !local && state.draining.load(Ordering::Relaxed) && is_flooded(&data) {
+                Decision::Dropped(DropReason::Draining)
+            } else if datapath
+                .as_ref()
+                .and_then(|datapath| datapath.flow_table.lookup(in_port, &data))
+                .is_some_and(|ports| !ports.contains(&PORT_NORMAL))
+            {
+                Decision::Openflow
+            } else if local {
+                forward(
+                    &state,
+                    &vrf,
+                    key.as_ref(),
+                    &shaper,
+                    &mac_shard,
+                    learn_local,
+                    &data,
+                    true,
+                )
+                .await
+            } else {
+                Decision::Delivered
+            };
+
+            trace::report(&state, traced, (!local).then_some(switch_id), decision).await;
+            continue;
+        }
+
+        if suspended.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        // test frames go through the tap before they're echoed or counted
+        if let Some(echo) = vrf_test::parse(&data) {
+            if tap.send(&data).await.is_ok() {
+                vrf_test::answer(&state, &vrf, key.as_ref(), switch_id, echo, &data).await;
+            }
+
+            continue;
+        }
+
+        // probes go on to the tap, a leak in the kernel shows them out of another one
+        if audit::observe(&state, vrf.id, &data, false).await {
+            send_to_tap(&vrf, &*tap, &metrics, &data).await;
+            continue;
+        }
+
+        // a draining switch only keeps serving the macs peers already know it for
+        if state.draining.load(Ordering::Relaxed) && is_flooded(&data) {
+            continue;
+        }
+
+        if data.len() >= 14
+            && (is_denied(&vrf, &data)
+                || !is_vlan_allowed(&vrf, &data)
+                || !acl.permits_frame(AclDirection::Egress, &data)
+                || !bpdu_guard.pass(&vrf, &data, false))
+        {
+            continue;
+        }
+
+        let learning = vrf.settings.learning.unwrap_or_default();
+
+        if learning == Learning::Static
+            && pinned(&vrf, &mac_shard, &get_source_mac(&data)) != Some(switch_id)
+        {
+            continue;
+        }
+
+        let ports = match &datapath {
+            Some(datapath) if data.len() >= 14 => {
+                let ports = datapath.flow_table.lookup(switch_id, &data);
+
+                if let Some(ports) = &ports {
+                    output(
+                        &state,
+                        &vrf,
+                        key.as_ref(),
+                        &shaper,
+                        &*tap,
+                        &metrics,
+                        datapath,
+                        switch_id,
+                        // what this switch sends on has one hop less
+                        ttl.saturating_sub(1),
+                        ports,
+                        &data,
+                    )
+                    .await;
+                }
+
+                ports
+            }
+            _ => None,
+        };
+
+        if ports.is_some_and(|ports| !ports.contains(&PORT_NORMAL)) {
+            continue;
+        }
+
         let source_mac = get_source_mac(&data);
 
         tracing::debug!("Source mac address {source_mac:?}");
 
+        if learning == Learning::Dynamic {
+            mac_shard.learn(get_vlan(&data), source_mac, switch_id);
+        }
+
+        send_to_tap(&vrf, &*tap, &metrics, &data).await;
+    }
+
+    receiver_task.abort();
+
+    if let Some(datapath_task) = datapath_task {
+        datapath_task.abort();
+    }
+}
+
+// a frame from a peer as it was read from its tap, `None` when it has to be dropped
+fn open_frame(
+    vrf: &Vrf,
+    key: Option<&VrfKey>,
+    switch_id: SwitchId,
+    compression: Option<Compression>,
+    data: Bytes,
+    max_frame_size: usize,
+) -> Option<Bytes> {
+    let data = match key {
+        Some(key) => match key.decrypt(vrf.id, &data) {
+            Some(frame) => Bytes::from(frame),
+            None => {
+                tracing::warn!(
+                    "Dropped frame from switch id {switch_id} for vrf {}, can't decrypt it",
+                    vrf.name
+                );
+                return None;
+            }
+        },
+        None => data,
+    };
+    let data = match compression {
+        // a frame that would grow past the largest one is dropped before it's decompressed
+        Some(compression) => match compression.decompress(&data, max_frame_size) {
+            Some(frame) => Bytes::from(frame),
+            None => {
+                tracing::warn!(
+                    "Dropped frame from switch id {switch_id} for vrf {}, can't decompress it",
+                    vrf.name
+                );
+                return None;
+            }
+        },
+        None => data,
+    };
+
+    if data.len() > max_frame_size {
+        tracing::debug!(
+            "Dropped a frame of {} bytes from switch id {switch_id} for vrf {}",
+            data.len(),
+            vrf.name
+        );
+        return None;
+    }
+
+    Some(data)
+}
+
+/// Pipeline of a vrf in tun mode, its ip packets go to the switch their destination was learned
+/// behind or to every member.
+async fn tun_connection<D: TapDevice>(
+    tun: D,
+    vrf: Vrf,
+    mut receiver: Receiver<Inbound>,
+    state: Arc<State>,
+) {
+    let tun = Arc::new(tun);
+    let suspended = state.suspension(vrf.id);
+    let metrics = state.metrics.vrf(vrf.id);
+    let shaper = Shaper::new(&vrf.settings, metrics.clone());
+    let key = state.vrf_keys.get(&vrf.name).cloned();
+    let acl = state.acls.vrf(vrf.id);
+    let ip_shard = state.ip_table.lock().unwrap().shard(
+        vrf.id,
+        vrf.settings
+            .max_macs
+            .and_then(|max_macs| NonZeroUsize::new(max_macs as usize))
+            .unwrap_or(state.config.max_macs_per_vrf),
+    );
+    let packet_limiter = vrf.settings.frame_rate.map(|rate| {
+        RateLimiter::new(RateLimitConfig {
+            rate: rate as f64,
+            burst: rate,
+        })
+    });
+    let max_packet_size = vrf.settings.mtu.map_or(MAX_BUFFER_SIZE, |mtu| mtu as usize);
+
+    let receiver_task = spawn({
+        let tun = tun.clone();
+        let vrf = vrf.clone();
+        let key = key.clone();
+        let ip_shard = ip_shard.clone();
+        let acl = acl.clone();
+        let suspended = suspended.clone();
+        let metrics = metrics.clone();
+        let state = state.clone();
+
+        async move {
+            let mut buffer = vec![0u8; max_packet_size];
+
+            loop {
+                let Ok(length) = tun.recv(&mut buffer).await else {
+                    continue;
+                };
+
+                if length == 0 || suspended.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                metrics.received.count(length);
+
+                let packet = &buffer[..length];
+                let Some((_, destination)) = ip_addresses(packet) else {
+                    continue;
+                };
+
+                if !acl.permits_packet(AclDirection::Ingress, packet)
+                    || packet_limiter
+                        .as_ref()
+                        .is_some_and(|packet_limiter| !packet_limiter.check(()))
+                {
+                    continue;
+                }
+
+                // broadcast and multicast are flooded like unknown destinations
+                let switch_id = ip_shard.get(&destination);
+
+                if switch_id.is_none() {
+                    metrics.flood();
+                }
+
+                send_frame(
+                    &state,
+                    &vrf,
+                    key.as_ref(),
+                    &shaper,
+                    switch_id,
+                    packet,
+                    DATA_TTL,
+                )
+                .await;
+            }
+        }
+    });
+
+    // packets from peers are never sent on, their ttl doesn't matter
+    while let Some((switch_id, compression, _, data)) = receiver.recv().await {
+        let Some(data) = open_frame(
+            &vrf,
+            key.as_ref(),
+            switch_id,
+            compression,
+            data,
+            max_packet_size,
+        ) else {
+            continue;
+        };
+
+        if suspended.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let Some((source, _)) = ip_addresses(&data) else {
+            tracing::debug!(
+                "Dropped a packet from switch id {switch_id} for vrf {}, it isn't ip",
+                vrf.name
+            );
+            continue;
+        };
+
+        if !acl.permits_packet(AclDirection::Egress, &data) {
+            continue;
+        }
+
+        ip_shard.learn(source, switch_id);
+        send_to_tap(&vrf, &*tun, &metrics, &data).await;
+    }
+
+    receiver_task.abort();
+}
+
+// source and destination of an ipv4 or ipv6 packet
+fn ip_addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => Some((
+            IpAddr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()),
+            IpAddr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()),
+        )),
+        6 if packet.len() >= 40 => Some((
+            IpAddr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap()),
+            IpAddr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap()),
+        )),
+        _ => None,
+    }
+}
+
+async fn recv_packet_out(packet_outs: &mut Option<Receiver<PacketOut>>) -> Option<PacketOut> {
+    match packet_outs {
+        Some(packet_outs) => packet_outs.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// built-in forwarding of a frame read from the tap, through the pinned then the learned macs, a
+// traced frame isn't checked nor learned as a source
+#[allow(clippy::too_many_arguments)]
+async fn forward(
+    state: &State,
+    vrf: &Vrf,
+    key: Option<&VrfKey>,
+    shaper: &Shaper,
+    mac_shard: &MacShard,
+    learn_local: bool,
+    frame: &[u8],
+    traced: bool,
+) -> Decision {
+    let source_mac = get_source_mac(frame);
+    let destination_mac = get_destination_mac(frame);
+    let vlan = get_vlan(frame);
+    let learning = vrf.settings.learning.unwrap_or_default();
+
+    tracing::debug!("Destination mac address {destination_mac:?}");
+
+    if !traced {
+        if learning == Learning::Static
+            && pinned(vrf, mac_shard, &source_mac) != Some(state.config.switch_id)
         {
-            let mut switch_table = switch_table.write().await;
+            return Decision::Dropped(DropReason::UnknownDestination);
+        }
+
+        if learning == Learning::Dynamic && learn_local {
+            mac_shard.learn(vlan, source_mac, state.config.switch_id);
+        }
+    }
+
+    let switch_id = pinned(vrf, mac_shard, &destination_mac).or_else(|| match learning {
+        Learning::Dynamic => mac_shard.get(vlan, &destination_mac),
+        Learning::Flood | Learning::Static => None,
+    });
 
-            switch_table
-                .entry(vrf.id)
-                .or_default()
-                .insert(source_mac, switch_id);
+    match switch_id {
+        None if learning == Learning::Static && !is_flooded(frame) => {
+            Decision::Dropped(DropReason::UnknownDestination)
         }
+        switch_id => {
+            if !send_frame(state, vrf, key, shaper, switch_id, frame, DATA_TTL).await {
+                return Decision::Dropped(DropReason::Encryption);
+            }
 
-        if let Err(error) = tap.send(&data).await {
+            switch_id.map_or(Decision::Flood, Decision::Unicast)
+        }
+    }
+}
+
+/// Send a frame out of the openflow ports chosen for it, the normal port is left to the caller.
+#[allow(clippy::too_many_arguments)]
+async fn output<D: TapDevice>(
+    state: &State,
+    vrf: &Vrf,
+    key: Option<&VrfKey>,
+    shaper: &Shaper,
+    tap: &D,
+    metrics: &VrfMetrics,
+    datapath: &Datapath,
+    in_port: u32,
+    ttl: u8,
+    ports: &[u32],
+    frame: &[u8],
+) {
+    for port in ports {
+        let port = match *port {
+            PORT_IN_PORT => in_port,
+            port => port,
+        };
+
+        match port {
+            PORT_NORMAL => {}
+            PORT_CONTROLLER => datapath.packet_in(in_port, frame),
+            PORT_LOCAL => send_to_tap(vrf, tap, metrics, frame).await,
+            PORT_FLOOD | PORT_ALL => {
+                if in_port != PORT_LOCAL {
+                    send_to_tap(vrf, tap, metrics, frame).await;
+                }
+
+                // peers are a full mesh, the others already got what a peer flooded
+                if !openflow::is_peer_port(in_port) {
+                    metrics.flood();
+                    send_frame(state, vrf, key, shaper, None, frame, ttl).await;
+                }
+            }
+            // split horizon, a frame never goes back to the switch it came from
+            switch_id if openflow::is_peer_port(switch_id) && switch_id != in_port => {
+                if ttl == 0 {
+                    tracing::debug!(
+                        "Dropped frame toward switch id {switch_id} for vrf {}, no hops left",
+                        vrf.name
+                    );
+                    continue;
+                }
+
+                send_frame(state, vrf, key, shaper, Some(switch_id), frame, ttl).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Send a frame to a member, or flood it to all of them when `switch_id` is none, within the
+/// bandwidth limits of the vrf. Returns whether it could be encrypted.
+async fn send_frame(
+    state: &State,
+    vrf: &Vrf,
+    key: Option<&VrfKey>,
+    shaper: &Shaper,
+    switch_id: Option<SwitchId>,
+    frame: &[u8],
+    ttl: u8,
+) -> bool {
+    // no data packet, the frame goes out as it is
+    if vrf.settings.vxlan.is_some() {
+        if shaper.admits(switch_id, frame.len()) {
+            vxlan::send(state, vrf, switch_id, frame).await;
+        }
+
+        return true;
+    }
+
+    // a frame over the limits isn't worth compressing and encrypting
+    if switch_id.is_some_and(|switch_id| !shaper.admits(Some(switch_id), frame.len())) {
+        return true;
+    }
+
+    let Some(packet) = data_packet(state, vrf, key, frame, ttl) else {
+        return false;
+    };
+
+    match switch_id {
+        Some(switch_id) => send_to_peer(state, vrf, switch_id, packet).await,
+        None => {
+            broadcast_to_vrf(state, vrf, packet, |member| {
+                shaper.admits(Some(member), frame.len())
+            })
+            .await
+        }
+    }
+
+    true
+}
+
+pub(crate) fn data_packet(
+    state: &State,
+    vrf: &Vrf,
+    key: Option<&VrfKey>,
+    frame: &[u8],
+    ttl: u8,
+) -> Option<Packet> {
+    // compressed before it's encrypted, nothing is left to gain after
+    let compressed = compression(state, vrf)
+        .and_then(|compression| Some((compression, compression.compress(frame)?)));
+    let (compression, frame) = match &compressed {
+        Some((compression, compressed)) => (Some(*compression), compressed.as_slice()),
+        None => (None, frame),
+    };
+    let data = match key {
+        Some(key) => match key.encrypt(vrf.id, frame) {
+            Some(data) => Bytes::from(data),
+            None => {
+                tracing::error!("Can't encrypt frame for vrf {}", vrf.name);
+                return None;
+            }
+        },
+        // the only copy of the frame, packet clones share it
+        None => Bytes::copy_from_slice(frame),
+    };
+
+    Some(Packet::from(Data {
+        vrf_id: vrf.id,
+        data,
+        compression,
+        ttl,
+    }))
+}
+
+// frames can be flooded to every member, so only if all the connected ones can decompress them
+fn compression(state: &State, vrf: &Vrf) -> Option<Compression> {
+    let compression = vrf.settings.compression?;
+    let peer_compression = state.peer_compression.lock().unwrap();
+
+    vrf.members
+        .iter()
+        .filter(|member| **member != state.config.switch_id)
+        .filter_map(|member| peer_compression.get(member))
+        .all(|supported| supported.contains(&compression))
+        .then_some(compression)
+}
+
+pub(crate) async fn send_to_peer(state: &State, vrf: &Vrf, switch_id: SwitchId, packet: Packet) {
+    let client_table = state.client_table.read().await;
+
+    if let Some(client) = client_table.get(&switch_id) {
+        if let Err(error) = client.send_data(vrf.id, packet).await {
+            state.metrics.peer(switch_id).send_failed();
+            tracing::error!(
+                "Can't send packet to client {switch_id} for vrf {}: {error}",
+                vrf.name
+            )
+        }
+    }
+}
+
+async fn send_to_tap<D: TapDevice>(vrf: &Vrf, tap: &D, metrics: &VrfMetrics, frame: &[u8]) {
+    match tap.send(frame).await {
+        Ok(_) => metrics.sent.count(frame.len()),
+        Err(error) => {
             tracing::error!(
                 "Can't send data through tap iterface for vrf {}: {error}",
                 vrf.name
             );
         }
     }
-
-    receiver_task.abort();
 }
 
-fn get_destination_mac(buffer: &[u8]) -> [u8; 6] {
+fn get_destination_mac(buffer: &[u8]) -> MacAddress {
     let mut mac = [0u8; 6];
 
     mac.copy_from_slice(&buffer[0..6]);
     mac
 }
 
-fn get_source_mac(buffer: &[u8]) -> [u8; 6] {
+fn is_flooded(buffer: &[u8]) -> bool {
+    // the group bit of the destination mac, set for broadcast and multicast
+    buffer[0] & 1 == 1
+}
+
+/// Keeps a vrf tap shut for a while after a bpdu came out of it, with the bpdu guard.
+#[derive(Default)]
+struct BpduGuard(std::sync::Mutex<Option<Instant>>);
+
+impl BpduGuard {
+    // bpdus never pass a filter or a guard, `local` frames come out of the tap
+    fn pass(&self, vrf: &Vrf, buffer: &[u8], local: bool) -> bool {
+        let bpdu = vrf.settings.bpdu.unwrap_or_default();
+
+        if bpdu == Bpdu::Forward {
+            return true;
+        }
+
+        let mut shut_until = self.0.lock().unwrap();
+
+        if is_bpdu(buffer) {
+            if bpdu == Bpdu::Guard && local && shut_until.is_none() {
+                tracing::warn!(
+                    "Shut the tap of the vrf {} for {}s, a bpdu came out of it",
+                    vrf.name,
+                    BPDU_GUARD_HOLD.as_secs()
+                );
+                publish(Event::BpduGuardTripped {
+                    id: vrf.id,
+                    name: vrf.name.clone(),
+                });
+                *shut_until = Some(Instant::now() + BPDU_GUARD_HOLD);
+            }
+
+            return false;
+        }
+
+        match *shut_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                tracing::info!("Opened the tap of the vrf {} again", vrf.name);
+                *shut_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+// the ieee and the per vlan spanning tree group addresses
+fn is_bpdu(buffer: &[u8]) -> bool {
+    buffer[..6] == [0x01, 0x80, 0xc2, 0x00, 0x00, 0x00]
+        || buffer[..6] == [0x01, 0x00, 0x0c, 0xcc, 0xcc, 0xcd]
+}
+
+// the macs of the vrf settings, then the ones pinned through the management protocol
+fn pinned(vrf: &Vrf, mac_shard: &MacShard, mac: &MacAddress) -> Option<SwitchId> {
+    vrf.settings
+        .static_macs
+        .iter()
+        .find(|static_mac| static_mac.mac == *mac)
+        .map(|static_mac| static_mac.switch_id)
+        .or_else(|| mac_shard.pinned(mac))
+}
+
+fn is_denied(vrf: &Vrf, buffer: &[u8]) -> bool {
+    let ethertype = u16::from_be_bytes([buffer[12], buffer[13]]);
+
+    vrf.settings.deny_ethertypes.contains(&ethertype)
+}
+
+fn is_vlan_allowed(vrf: &Vrf, buffer: &[u8]) -> bool {
+    let vlan = get_vlan(buffer);
+
+    vlan == 0 || vrf.settings.vlans.is_empty() || vrf.settings.vlans.contains(&vlan)
+}
+
+// the outer tag, a c-tag or the s-tag of a double tagged frame
+fn get_vlan(buffer: &[u8]) -> Vlan {
+    match buffer.get(12..16) {
+        Some(&[0x81, 0x00, high, low] | &[0x88, 0xa8, high, low]) => {
+            u16::from_be_bytes([high, low]) & 0x0fff
+        }
+        _ => 0,
+    }
+}
+
+fn get_source_mac(buffer: &[u8]) -> MacAddress {
     let mut mac = [0u8; 6];
 
     mac.copy_from_slice(&buffer[6..12]);
     mac
 }
 
-fn setup_tap(netns_name: &str) -> Result<Tap, Box<dyn Error>> {
-    let netns = Netns::named(netns_name);
+type SetupError = Box<dyn Error + Send + Sync>;
+
+async fn create_tap(state: &State, vrf: &Vrf) -> Result<Tap, SetupError> {
+    let dataplane = state.config.dataplane;
+
+    link::set_tap_name(vrf);
+
+    let name = link::tap_name(vrf.id);
+    let tap_altname = link::altname(&vrf.name, "tap");
+
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    if dataplane == Dataplane::Netns {
+        let netns = link::vrf_netns(vrf);
+        let owned = link::owns_netns(dataplane, vrf);
+        let mode = vrf.settings.mode.unwrap_or_default();
+        let tap = match spawn_blocking({
+            let netns = netns.clone();
+            let name = name.clone();
+
+            move || setup_tap(netns, owned, &name, mode)
+        })
+        .await
+        {
+            Ok(tap) => tap?,
+            Err(error) => return Err(error.to_string().into()),
+        };
+
+        let netns_path = netns.map(|netns| netns.path());
+
+        if let Some(mac) = vrf.settings.mac {
+            link::set_mac(netns_path.as_deref(), &name, mac).await?;
+        }
+
+        if let Some(mtu) = vrf.settings.mtu {
+            link::set_mtu(netns_path.as_deref(), &name, mtu).await?;
+        }
+
+        link::add_addresses(
+            netns_path.as_deref(),
+            &name,
+            &vrf.settings.addresses,
+            &vrf.settings.routes,
+        )
+        .await?;
+
+        add_altname(netns_path.as_deref(), &name, tap_altname).await;
+        attach_uplink(state, vrf).await;
+
+        return Ok(tap);
+    }
+
+    // closing it on error removes the tap again
+    let fd = open_tap(&name, vrf.settings.mode.unwrap_or_default())?;
+
+    if let Some(mac) = vrf.settings.mac {
+        link::set_mac(None, &name, mac).await?;
+    }
+
+    if let Some(mtu) = vrf.settings.mtu {
+        link::set_mtu(None, &name, mtu).await?;
+    }
+
+    link::add_addresses(None, &name, &vrf.settings.addresses, &vrf.settings.routes).await?;
+
+    if dataplane == Dataplane::Tap {
+        add_altname(None, &name, tap_altname).await;
+        attach_uplink(state, vrf).await;
+
+        return Ok(Tap::new(fd, Isolation::Host)?);
+    }
+
+    let master = link::attach(dataplane, vrf.id, &name).await?;
+    let master_altname = link::altname(
+        &vrf.name,
+        match dataplane {
+            Dataplane::Bridge => "bridge",
+            _ => "vrf",
+        },
+    );
+
+    add_altname(None, &name, tap_altname).await;
+    add_altname(None, &master, master_altname).await;
+    attach_uplink(state, vrf).await;
+
+    if let Some(networkd) = &state.config.networkd {
+        if let Err(error) = networkd::write_vrf(networkd, dataplane, vrf.id, &vrf.name).await {
+            tracing::warn!(
+                "Can't write the networkd files of the vrf {}: {error}",
+                vrf.name
+            );
+        }
+    }
+
+    Ok(Tap::new(fd, isolation(state, vrf))?)
+}
+
+// only a convenience for udev rules and networkd, the vrf works without it
+async fn add_altname(netns: Option<&Path>, name: &str, altname: String) {
+    if let Err(error) = link::add_altname(netns, name, &altname).await {
+        tracing::warn!("Can't add the altname {altname} to {name}: {error}");
+    }
+}
+
+// the vrf keeps reaching the other members without its uplink
+async fn attach_uplink(state: &State, vrf: &Vrf) {
+    let Some(uplink) = state.config.uplinks.get(&vrf.name) else {
+        return;
+    };
 
-    netns.create()?;
+    if !link::owns_netns(state.config.dataplane, vrf) {
+        tracing::warn!(
+            "Can't attach the vrf {} to {}: it isn't in a netns of its own",
+            vrf.name,
+            uplink.parent
+        );
+        return;
+    }
 
-    let netns_handle = netns.enter()?;
-    let mut tap = AsyncTap::new()?;
+    match link::attach_uplink(
+        state.config.dataplane,
+        vrf.id,
+        &vrf.name,
+        vrf.settings.mtu,
+        uplink,
+    )
+    .await
+    {
+        Ok(name) => tracing::info!(
+            "Attached the vrf {} to {} through {name}",
+            vrf.name,
+            uplink.parent
+        ),
+        Err(error) => tracing::error!(
+            "Can't attach the vrf {} to {}: {error}",
+            vrf.name,
+            uplink.parent
+        ),
+    }
+}
 
-    tap.set_state(DeviceState::Up)?;
+fn isolation(state: &State, vrf: &Vrf) -> Isolation {
+    match state.config.dataplane {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        Dataplane::Netns => match link::owns_netns(Dataplane::Netns, vrf) {
+            true => Isolation::Netns(Netns::named(link::netns_name(&vrf.name))),
+            false => Isolation::Host,
+        },
+        Dataplane::Tap => Isolation::Host,
+        dataplane => Isolation::Master {
+            master: link::master_name(dataplane, vrf.id),
+            uplink: state
+                .config
+                .uplinks
+                .contains_key(&vrf.name)
+                .then(|| link::uplink_name(vrf.id)),
+        },
+    }
+}
 
-    netns_handle.close()?;
+pub(crate) fn open_tap(name: &str, mode: VrfMode) -> io::Result<OwnedFd> {
+    // owned apart from the device so it can be handed over to a new daemon
+    match mode {
+        VrfMode::Tap => {
+            let mut tap = tappers::Tap::new_named(Interface::new(name)?)?;
 
-    Ok(Tap(tap, netns))
+            tap.set_state(DeviceState::Up)?;
+            tap.set_nonblocking(true)?;
+            tap.as_fd().try_clone_to_owned()
+        }
+        VrfMode::Tun => {
+            let mut tun = tappers::Tun::new_named(Interface::new(name)?)?;
+
+            tun.set_state(DeviceState::Up)?;
+            tun.set_nonblocking(true)?;
+            tun.as_fd().try_clone_to_owned()
+        }
+    }
 }
 
-struct Tap(AsyncTap, Netns);
+// a netns the tap is only plugged into is left behind with the tap, `None` is the host one
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn setup_tap(
+    netns: Option<Netns>,
+    owned: bool,
+    name: &str,
+    mode: VrfMode,
+) -> Result<Tap, SetupError> {
+    let Some(netns) = netns else {
+        return Ok(Tap::new(open_tap(name, mode)?, Isolation::Host)?);
+    };
+
+    if owned {
+        netns.create()?;
+    } else if !netns.exists() {
+        return Err(format!("Can't find the netns {netns}").into());
+    }
+
+    let fd = netns.run(|| open_tap(name, mode))??;
+
+    Ok(Tap::new(
+        fd,
+        match owned {
+            true => Isolation::Netns(netns),
+            false => Isolation::Host,
+        },
+    )?)
+}
+
+/// What keeps a vrf tap apart from the others, torn down with the tap.
+enum Isolation {
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    Netns(Netns),
+    Master {
+        master: String,
+        uplink: Option<String>,
+    },
+    /// Nothing besides the tap, gone once it's closed.
+    Host,
+}
+
+struct Tap(AsyncFd<File>, Isolation, Option<SemaphorePermit<'static>>);
 
 impl Tap {
+    // inherited descriptors share the non blocking flag set by the previous daemon
+    fn new(fd: OwnedFd, isolation: Isolation) -> io::Result<Self> {
+        Ok(Self(
+            AsyncFd::new(File::from(fd))?,
+            isolation,
+            OPEN_TAPS.try_acquire().ok(),
+        ))
+    }
+}
+
+impl TapDevice for Tap {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .async_io(Interest::WRITABLE, |mut file| file.write(buf))
+            .await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0
+            .async_io(Interest::READABLE, |mut file| file.read(buf))
+            .await
+    }
+}
+
+/// A tap whose frames are copied to the mirror of its vrf, the ones read and the ones written.
+struct Mirrored<D> {
+    device: D,
+    mirror: Mirror,
+}
+
+impl<D: TapDevice> TapDevice for Mirrored<D> {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.device.send(buf).await?;
+
+        self.mirror.copy(buf);
+
+        Ok(length)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.device.recv(buf).await?;
+
+        self.mirror.copy(&buf[..length]);
+
+        Ok(length)
+    }
+}
+
+struct VirtualTap {
+    inbound: Mutex<Receiver<Bytes>>,
+    outbound: Sender<Bytes>,
+}
+
+impl TapDevice for VirtualTap {
     async fn send(&self, buf: &[u8]) -> io::Result<usize> {
-        self.0.send(buf).await
+        self.outbound
+            .send(Bytes::copy_from_slice(buf))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
     }
 
     async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.recv(buf).await
+        match self.inbound.lock().await.recv().await {
+            Some(frame) => {
+                let length = frame.len().min(buf.len());
+
+                buf[..length].copy_from_slice(&frame[..length]);
+
+                Ok(length)
+            }
+            // like an idle device once the wire is gone
+            None => std::future::pending().await,
+        }
     }
 }
 
 impl Drop for Tap {
     fn drop(&mut self) {
-        if let Err(error) = self.1.delete() {
-            tracing::error!("Can't delete the netns {}: {error}", self.1);
+        match &self.1 {
+            #[cfg(all(target_os = "linux", feature = "netns"))]
+            Isolation::Netns(netns) => {
+                if let Err(error) = netns.delete() {
+                    tracing::error!("Can't delete the netns {netns}: {error}");
+                }
+            }
+            Isolation::Master { master, uplink } => {
+                let links = [Some(master.clone()), uplink.clone()];
+                let permit = self.2.take();
+
+                spawn(async move {
+                    let _permit = permit;
+
+                    for link in links.into_iter().flatten() {
+                        if let Err(error) = link::delete(&link).await {
+                            tracing::error!("Can't delete the link {link}: {error}");
+                        }
+                    }
+                });
+            }
+            Isolation::Host => {}
         }
     }
 }
@@ -0,0 +1,78 @@
+use std::fmt::{self, Display, Formatter};
+
+use protocol::VrfAction;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ReadOnly,
+    Admin,
+}
+
+impl Permission {
+    pub fn allows(&self, access: Access) -> bool {
+        match self {
+            Permission::Admin => true,
+            Permission::ReadOnly => access == Access::Read,
+        }
+    }
+}
+
+/// What a request of a management interface does to the switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Change,
+}
+
+impl Access {
+    pub fn of(vrf_action: &VrfAction) -> Access {
+        match vrf_action {
+            VrfAction::List(_) => Access::Read,
+            _ => Access::Change,
+        }
+    }
+}
+
+/// Why a request was refused, the same whatever interface it came through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Denied,
+    RateLimited,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Denied => write!(f, "Permission denied"),
+            Refusal::RateLimited => write!(f, "Too many configuration actions, try again later"),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TokenConfig {
+    pub token: String,
+    pub permission: Permission,
+}
+
+// keep the tokens out of the logged config
+impl std::fmt::Debug for TokenConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("permission", &self.permission)
+            .finish_non_exhaustive()
+    }
+}
+
+pub fn authenticate(tokens: &[TokenConfig], token: &str) -> Option<Permission> {
+    // comparing digests doesn't leak how much of a token matched
+    let digest = Sha256::digest(token);
+
+    tokens
+        .iter()
+        .find(|token_config| Sha256::digest(&token_config.token) == digest)
+        .map(|token_config| token_config.permission)
+}
@@ -0,0 +1,127 @@
+//! Flow trace. A marked frame goes into a vrf through the pipeline of its local tap, and each switch
+//! handling it reports the decision it took back to the switch that started the trace.
+//!
+//! The traced frames are never learned nor written to a tap, they only show the path a frame for
+//! the destination would take.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use common::VrfId;
+use protocol::{Decision, Hop, Packet, Trace, DATA_TTL};
+use tokio::time::sleep;
+
+use crate::{config::SwitchId, state::State, switch_table::MacAddress};
+
+// the local experimental ethertype, like the audit probes
+const TRACE_ETHERTYPE: u16 = 0x88b5;
+const TRACE_MAGIC: &[u8; 12] = b"dwitch-trace";
+const TRACE_LENGTH: usize = 14 + TRACE_MAGIC.len() + 4 + 8;
+// leaves the frame the time to go through the overlay
+const TRACE_WAIT: Duration = Duration::from_secs(2);
+
+/// Hops of the running traces, by nonce.
+#[derive(Default)]
+pub struct Traces(Mutex<HashMap<u64, Vec<Hop>>>);
+
+impl Traces {
+    pub fn record(&self, hop: Hop) {
+        if let Some(hops) = self.0.lock().unwrap().get_mut(&hop.nonce) {
+            hops.push(hop);
+        }
+    }
+}
+
+pub async fn trace(
+    state: &Arc<State>,
+    vrf_id: VrfId,
+    destination: MacAddress,
+) -> Result<Vec<Hop>, String> {
+    let switch_id = state.config.switch_id;
+    let nonce = OsRng.next_u64();
+    let frame = frame(destination, switch_id, nonce);
+
+    state.traces.0.lock().unwrap().insert(nonce, Vec::new());
+
+    // handed to the tap pipeline as if read out of the tap
+    let injected = match state.tap_table.read().await.get(&vrf_id) {
+        Some(tap) => tap
+            .try_send((switch_id, None, DATA_TTL, frame.into()))
+            .is_ok(),
+        None => false,
+    };
+
+    if injected {
+        sleep(TRACE_WAIT).await;
+    }
+
+    let hops = state
+        .traces
+        .0
+        .lock()
+        .unwrap()
+        .remove(&nonce)
+        .unwrap_or_default();
+
+    if !injected {
+        return Err(format!("Vrf id {vrf_id} has no tap on this switch"));
+    }
+
+    Ok(hops)
+}
+
+/// Origin and nonce of a traced frame.
+pub fn parse(frame: &[u8]) -> Option<(SwitchId, u64)> {
+    let payload = frame.get(14..TRACE_LENGTH)?;
+
+    if frame[12..14] != TRACE_ETHERTYPE.to_be_bytes() || !payload.starts_with(TRACE_MAGIC) {
+        return None;
+    }
+
+    let payload = &payload[TRACE_MAGIC.len()..];
+
+    Some((
+        SwitchId::from_be_bytes(payload[..4].try_into().ok()?),
+        u64::from_be_bytes(payload[4..].try_into().ok()?),
+    ))
+}
+
+/// Send the origin of a trace the decision this switch took for its frame.
+pub async fn report(
+    state: &State,
+    (origin, nonce): (SwitchId, u64),
+    ingress: Option<SwitchId>,
+    decision: Decision,
+) {
+    let hop = Hop {
+        nonce,
+        switch_id: state.config.switch_id,
+        ingress,
+        decision,
+    };
+
+    if origin == state.config.switch_id {
+        state.traces.record(hop);
+    } else if let Some(client) = state.client_table.read().await.get(&origin) {
+        if let Err(error) = client.send(Packet::from(Trace::Hop(hop))).await {
+            tracing::warn!("Can't report a trace hop to switch id {origin}: {error}");
+        }
+    }
+}
+
+fn frame(destination: MacAddress, switch_id: SwitchId, nonce: u64) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(TRACE_LENGTH);
+
+    frame.extend_from_slice(&destination);
+    // locally administered, never a real host
+    frame.extend_from_slice(&[0x02, 0xd7, 0x00, 0x00, 0x00, 0x01]);
+    frame.extend_from_slice(&TRACE_ETHERTYPE.to_be_bytes());
+    frame.extend_from_slice(TRACE_MAGIC);
+    frame.extend_from_slice(&switch_id.to_be_bytes());
+    frame.extend_from_slice(&nonce.to_be_bytes());
+    frame
+}
@@ -0,0 +1,163 @@
+//! Tap handoff for qemu guests.
+//!
+//! A client asks for a vm port of a local vrf on the vm socket, the daemon creates a tap with a
+//! virtio net header in the default namespace, bridges it into the vrf and sends its descriptor
+//! back with the reply. The daemon doesn't keep the tap open, so it goes away with the emulator.
+//!
+//! `dwitch-cli /run/dwitch-vm.sock vm run --vrf <vrf> --name <vm> -- qemu-system-x86_64 ...
+//! -netdev tap,id=net0,fd={fd},vhost=on -device virtio-net-pci,netdev=net0` wraps an emulator
+//! this way, libvirt can use it as the `<emulator>` of a domain through a small script.
+
+use std::{
+    error::Error,
+    fs::{remove_file, set_permissions, OpenOptions, Permissions},
+    io::{self, ErrorKind, IoSlice},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
+    sync::Arc,
+};
+
+use bytes::BytesMut;
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use protocol::{frame, EndpointAction, Packet, PacketSerializer, Response};
+use tokio::{net::UnixListener, spawn, task::spawn_blocking};
+
+use crate::{config::VmConfig, link, management::local_vrf, socket::TransmitPacket, state::State};
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+/// Bind the vm socket, before the privileges are dropped.
+pub fn vm_listener(config: &VmConfig) -> Result<UnixListener, Box<dyn Error>> {
+    if let Err(error) = remove_file(&config.socket) {
+        if error.kind() != ErrorKind::NotFound {
+            return Err(error.into());
+        }
+    }
+
+    let listener = UnixListener::bind(&config.socket)?;
+
+    set_permissions(&config.socket, Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+pub async fn vm(listener: UnixListener, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                spawn(vm_connection(stream, state.clone()));
+            }
+            Err(error) => {
+                tracing::error!("Can't accept vm client: {error}");
+            }
+        }
+    }
+}
+
+async fn vm_connection(mut stream: tokio::net::UnixStream, state: Arc<State>) {
+    let mut buffer = BytesMut::new();
+    let Some(packet) = stream.recv_packet(&mut buffer).await else {
+        return;
+    };
+    let (reply, tap) = match packet {
+        Packet::EndpointAction(EndpointAction::AttachVm { vrf, name }) => {
+            match attach_vm(&state, &vrf, &name).await {
+                Ok((ifname, tap)) => (EndpointAction::VmAttached { ifname }.into(), Some(tap)),
+                Err(error) => (Packet::from(Response::Error(error)), None),
+            }
+        }
+        _ => (
+            Packet::from(Response::Error("Unexpected packet".to_string())),
+            None,
+        ),
+    };
+    let result = async {
+        let stream = stream.into_std()?;
+
+        spawn_blocking(move || {
+            stream.set_nonblocking(false)?;
+            send_reply(&stream, &reply, tap.as_ref().map(AsFd::as_fd))
+        })
+        .await?
+    }
+    .await;
+
+    // the emulator is left as the only owner of the tap
+    if let Err(error) = result {
+        tracing::warn!("Can't hand the tap over to the vm client: {error}");
+    }
+}
+
+/// Name and descriptor of a new tap bridged into a local vrf.
+async fn attach_vm(state: &State, vrf: &str, name: &str) -> Result<(String, OwnedFd), String> {
+    let vrf_info = local_vrf(state, vrf).await?;
+    let ifname = link::vm_tap_name(vrf, name);
+    // closing it on error removes the tap again
+    let tap = open_vm_tap(&ifname)
+        .map_err(|error| format!("Can't create the tap of the vm {name}: {error}"))?;
+
+    link::attach_vm_tap(
+        state.config.dataplane,
+        vrf_info.id,
+        vrf,
+        vrf_info.settings.mtu,
+        &ifname,
+    )
+    .await
+    .map_err(|error| format!("Can't attach the vm {name} to vrf {vrf}: {error}"))?;
+
+    tracing::info!("Attached the vm {name} to vrf {vrf} through {ifname}");
+
+    Ok((ifname, tap))
+}
+
+// qemu and vhost-net use the virtio net header for checksum and segmentation offloads
+fn open_vm_tap(name: &str) -> io::Result<OwnedFd> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Tap name too long"));
+    }
+
+    let tun = OpenOptions::new().read(true).write(true).open(TUN_PATH)?;
+    // an all zero ifreq is valid, only the name and flags are set
+    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+
+    for (byte, char) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+        *byte = char as libc::c_char;
+    }
+
+    ifreq.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as _;
+
+    // the request only reads the ifreq it's given
+    if unsafe { libc::ioctl(tun.as_raw_fd(), libc::TUNSETIFF, &ifreq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(tun.into())
+}
+
+// the descriptor goes along with the first byte of the length prefixed reply
+fn send_reply(stream: &UnixStream, reply: &Packet, fd: Option<BorrowedFd>) -> io::Result<()> {
+    let payload = reply.serialize().map_err(io::Error::other)?;
+    let header = frame::header(&payload);
+    let fds = fd.map(|fd| [fd.as_raw_fd()]);
+    let cmsgs = fds
+        .iter()
+        .map(|fds| ControlMessage::ScmRights(fds))
+        .collect::<Vec<_>>();
+
+    let length = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&header), IoSlice::new(&payload)],
+        &cmsgs,
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    if length != header.len() + payload.len() {
+        return Err(ErrorKind::WriteZero.into());
+    }
+
+    Ok(())
+}
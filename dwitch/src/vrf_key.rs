@@ -0,0 +1,69 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use common::VrfId;
+use sha2::{Digest, Sha256};
+use tokio::fs::read;
+
+const NONCE_SIZE: usize = 24;
+
+pub type VrfKeys = HashMap<String, VrfKey>;
+
+#[derive(Clone)]
+pub struct VrfKey(XChaCha20Poly1305);
+
+impl VrfKey {
+    pub async fn load_all(paths: &HashMap<String, PathBuf>) -> io::Result<VrfKeys> {
+        let mut vrf_keys = HashMap::new();
+
+        for (name, path) in paths.iter() {
+            let key_material = read(path).await?;
+            let key = Sha256::digest(&key_material);
+
+            vrf_keys.insert(
+                name.clone(),
+                Self(XChaCha20Poly1305::new(Key::from_slice(&key))),
+            );
+        }
+
+        Ok(vrf_keys)
+    }
+
+    // the vrf id is authenticated so a frame can't be moved into another vrf sharing the key
+    pub fn encrypt(&self, vrf_id: VrfId, frame: &[u8]) -> Option<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: frame,
+                    aad: &vrf_id.to_be_bytes(),
+                },
+            )
+            .ok()?;
+
+        Some([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn decrypt(&self, vrf_id: VrfId, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+
+        self.0
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &vrf_id.to_be_bytes(),
+                },
+            )
+            .ok()
+    }
+}
@@ -0,0 +1,318 @@
+//! Connectivity check between two members of a vrf. The `from` member sends test frames to the
+//! `to` member through the overlay, which writes them into its tap and echoes them back, written
+//! into the tap of `from` in turn.
+//!
+//! Small frames give the round trip, then one frame per common mtu gives the largest mtu both ways
+//! carry.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use common::VrfId;
+use protocol::{Packet, Vrf, VrfTest, VrfTestReport, DATA_TTL};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::timeout,
+};
+
+use crate::{
+    config::SwitchId,
+    state::State,
+    tap::{data_packet, send_to_peer},
+    vrf_key::VrfKey,
+};
+
+// the local experimental ethertype, like the audit probes
+const TEST_ETHERTYPE: u16 = 0x88b5;
+const TEST_MAGIC: &[u8; 12] = b"dwitch-check";
+// then the origin, the nonce, the sequence and the echo flag
+const TEST_LENGTH: usize = 14 + TEST_MAGIC.len() + 4 + 8 + 4 + 1;
+const ECHO_COUNT: u32 = 5;
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+const MTU_WAIT: Duration = Duration::from_secs(2);
+const MTUS: &[u32] = &[576, 1280, 1400, 1450, 1500, 4000, 9000];
+// the echoes and the mtu frames of the `from` member, with some margin
+const RUN_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TestResult = Result<VrfTestReport, String>;
+
+/// Running tests of this switch.
+#[derive(Default)]
+pub struct VrfTests {
+    // sequences echoed back, by nonce
+    echoes: Mutex<HashMap<u64, UnboundedSender<u32>>>,
+    // tests handed over to their `from` member, by nonce
+    relayed: Mutex<HashMap<u64, (SwitchId, oneshot::Sender<TestResult>)>>,
+}
+
+impl VrfTests {
+    pub fn record(&self, switch_id: SwitchId, nonce: u64, result: TestResult) {
+        let mut relayed = self.relayed.lock().unwrap();
+
+        // only the member a test was handed over to reports it
+        if relayed
+            .get(&nonce)
+            .is_some_and(|(from, _)| *from == switch_id)
+        {
+            if let Some((_, sender)) = relayed.remove(&nonce) {
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+pub struct Echo {
+    origin: SwitchId,
+    nonce: u64,
+    sequence: u32,
+    reply: bool,
+}
+
+/// Test a vrf from the `from` member to the `to` member, handing it over when `from` is a peer.
+pub async fn vrf_test(
+    state: &Arc<State>,
+    vrf_id: VrfId,
+    from: SwitchId,
+    to: SwitchId,
+) -> TestResult {
+    if from == state.config.switch_id {
+        return run(state, vrf_id, to).await;
+    }
+
+    let nonce = OsRng.next_u64();
+    let (sender, receiver) = oneshot::channel();
+
+    state
+        .vrf_tests
+        .relayed
+        .lock()
+        .unwrap()
+        .insert(nonce, (from, sender));
+
+    let sent = match state.client_table.read().await.get(&from) {
+        Some(client) => client
+            .send(Packet::from(VrfTest::Run { nonce, vrf_id, to }))
+            .await
+            .is_ok(),
+        None => false,
+    };
+    let result = if sent {
+        match timeout(RUN_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => Err(format!("Switch id {from} didn't report the test in time")),
+        }
+    } else {
+        Err(format!("Switch id {from} isn't connected"))
+    };
+
+    state.vrf_tests.relayed.lock().unwrap().remove(&nonce);
+
+    result
+}
+
+/// Run a test handed over by a peer and send it the result.
+pub async fn run_for(
+    state: Arc<State>,
+    switch_id: SwitchId,
+    nonce: u64,
+    vrf_id: VrfId,
+    to: SwitchId,
+) {
+    let result = run(&state, vrf_id, to).await;
+
+    if let Some(client) = state.client_table.read().await.get(&switch_id) {
+        if let Err(error) = client
+            .send(Packet::from(VrfTest::Result { nonce, result }))
+            .await
+        {
+            tracing::warn!("Can't report a vrf test to switch id {switch_id}: {error}");
+        }
+    }
+}
+
+/// Handle a test frame from a peer, once written into the tap.
+pub async fn answer(
+    state: &State,
+    vrf: &Vrf,
+    key: Option<&VrfKey>,
+    switch_id: SwitchId,
+    echo: Echo,
+    frame: &[u8],
+) {
+    if echo.reply {
+        if echo.origin == state.config.switch_id {
+            if let Some(echoes) = state.vrf_tests.echoes.lock().unwrap().get(&echo.nonce) {
+                let _ = echoes.send(echo.sequence);
+            }
+        }
+    } else if echo.origin == switch_id {
+        let mut reply = frame.to_vec();
+
+        reply[TEST_LENGTH - 1] = 1;
+
+        if let Some(packet) = data_packet(state, vrf, key, &reply, DATA_TTL) {
+            send_to_peer(state, vrf, switch_id, packet).await;
+        }
+    }
+}
+
+pub fn parse(frame: &[u8]) -> Option<Echo> {
+    let payload = frame.get(14..TEST_LENGTH)?;
+
+    if frame[12..14] != TEST_ETHERTYPE.to_be_bytes() || !payload.starts_with(TEST_MAGIC) {
+        return None;
+    }
+
+    let payload = &payload[TEST_MAGIC.len()..];
+
+    Some(Echo {
+        origin: SwitchId::from_be_bytes(payload[..4].try_into().ok()?),
+        nonce: u64::from_be_bytes(payload[4..12].try_into().ok()?),
+        sequence: u32::from_be_bytes(payload[12..16].try_into().ok()?),
+        reply: payload[16] != 0,
+    })
+}
+
+async fn run(state: &State, vrf_id: VrfId, to: SwitchId) -> TestResult {
+    let switch_id = state.config.switch_id;
+    let vrf = state
+        .vrf_table
+        .read()
+        .await
+        .get(&vrf_id)
+        .cloned()
+        .ok_or_else(|| format!("Can't find vrf id {vrf_id}"))?;
+
+    if to == switch_id {
+        return Err("Can't test a switch against itself".to_string());
+    }
+
+    if !vrf.members.contains(&switch_id) || !vrf.members.contains(&to) {
+        return Err(format!(
+            "Switch ids {switch_id} and {to} aren't both members of vrf {}",
+            vrf.name
+        ));
+    }
+
+    if !state.tap_table.read().await.contains_key(&vrf_id) {
+        return Err(format!(
+            "Vrf {} has no tap on switch id {switch_id}",
+            vrf.name
+        ));
+    }
+
+    let nonce = OsRng.next_u64();
+    let (sender, mut echoes) = unbounded_channel();
+
+    state.vrf_tests.echoes.lock().unwrap().insert(nonce, sender);
+
+    let result = exchange(state, &vrf, to, nonce, &mut echoes).await;
+
+    state.vrf_tests.echoes.lock().unwrap().remove(&nonce);
+
+    result
+}
+
+async fn exchange(
+    state: &State,
+    vrf: &Vrf,
+    to: SwitchId,
+    nonce: u64,
+    echoes: &mut UnboundedReceiver<u32>,
+) -> TestResult {
+    let mut round_trips = Vec::new();
+
+    for sequence in 0..ECHO_COUNT {
+        let start = Instant::now();
+
+        send(state, vrf, to, nonce, sequence, TEST_LENGTH).await;
+
+        // a late echo of an earlier frame isn't this one's
+        let echoed = timeout(ECHO_TIMEOUT, async {
+            while let Some(echoed) = echoes.recv().await {
+                if echoed == sequence {
+                    return true;
+                }
+            }
+
+            false
+        })
+        .await;
+
+        if echoed == Ok(true) {
+            round_trips.push(start.elapsed());
+        }
+    }
+
+    if round_trips.is_empty() {
+        return Err(format!("No test frame came back from switch id {to}"));
+    }
+
+    let mtus = MTUS
+        .iter()
+        .copied()
+        .chain(vrf.settings.mtu)
+        .collect::<BTreeSet<_>>();
+
+    for (index, mtu) in mtus.iter().enumerate() {
+        let length = (*mtu as usize + 14).max(TEST_LENGTH);
+
+        send(state, vrf, to, nonce, ECHO_COUNT + index as u32, length).await;
+    }
+
+    let mut mtu = None;
+    let mut echoed = 0;
+
+    let _ = timeout(MTU_WAIT, async {
+        while let Some(sequence) = echoes.recv().await {
+            let Some(echoed_mtu) = sequence
+                .checked_sub(ECHO_COUNT)
+                .and_then(|index| mtus.iter().nth(index as usize))
+            else {
+                continue;
+            };
+
+            mtu = mtu.max(Some(*echoed_mtu));
+            echoed += 1;
+
+            if echoed == mtus.len() {
+                break;
+            }
+        }
+    })
+    .await;
+
+    Ok(VrfTestReport {
+        sent: ECHO_COUNT,
+        received: round_trips.len() as u32,
+        round_trip: (round_trips.iter().sum::<Duration>() / round_trips.len() as u32).as_micros()
+            as u64,
+        mtu,
+    })
+}
+
+async fn send(state: &State, vrf: &Vrf, to: SwitchId, nonce: u64, sequence: u32, length: usize) {
+    let mut frame = Vec::with_capacity(length);
+
+    // locally administered, never a real host
+    frame.extend_from_slice(&[0x02, 0xd7, 0x00, 0x00, 0x00, 0x02]);
+    frame.extend_from_slice(&[0x02, 0xd7, 0x00, 0x00, 0x00, 0x02]);
+    frame.extend_from_slice(&TEST_ETHERTYPE.to_be_bytes());
+    frame.extend_from_slice(TEST_MAGIC);
+    frame.extend_from_slice(&state.config.switch_id.to_be_bytes());
+    frame.extend_from_slice(&nonce.to_be_bytes());
+    frame.extend_from_slice(&sequence.to_be_bytes());
+    frame.push(0);
+    frame.resize(length, 0);
+
+    if let Some(packet) = data_packet(state, vrf, state.vrf_keys.get(&vrf.name), &frame, DATA_TTL) {
+        send_to_peer(state, vrf, to, packet).await;
+    }
+}
@@ -0,0 +1,185 @@
+//! Vxlan encapsulation of the vrfs set to it, their frames cross as the udp datagrams any vtep
+//! understands instead of data packets on the peer connections.
+//!
+//! Member switches are reached on the vxlan port at the address of their peer connection, other
+//! vteps at the address they're configured with. A frame is only taken from an address known for
+//! the vrf.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use common::VrfId;
+use protocol::{Vrf, DATA_TTL};
+use serde::Deserialize;
+use tokio::{net::UdpSocket, sync::mpsc::error::TrySendError, time::sleep};
+
+use crate::{config::SwitchId, state::State, MAX_BUFFER_SIZE};
+
+const HEADER_SIZE: usize = 8;
+// the i flag, set when the vni is valid
+const FLAG_VNI: u8 = 0x08;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VxlanConfig {
+    /// Udp address vxlan frames are received on, every switch of the fabric uses the same port.
+    pub listen: SocketAddr,
+}
+
+pub async fn bind(config: &VxlanConfig) -> io::Result<UdpSocket> {
+    UdpSocket::bind(config.listen).await
+}
+
+/// Hand the frames the vxlan socket receives to the taps of their vrfs.
+pub async fn vxlan(state: Arc<State>) {
+    let Some(socket) = &state.vxlan else {
+        return;
+    };
+    let mut buffer = vec![0u8; HEADER_SIZE + MAX_BUFFER_SIZE];
+
+    loop {
+        let (length, address) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(error) => {
+                tracing::error!("Can't receive vxlan frame: {error}");
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let Some((vni, frame)) = parse(&buffer[..length]) else {
+            tracing::debug!("Dropped invalid vxlan frame from {address}");
+            continue;
+        };
+        let Some((vrf_id, switch_id)) = source(&state, vni, address.ip()).await else {
+            tracing::debug!("Dropped vxlan frame for vni {vni} from {address}, it isn't a member");
+            continue;
+        };
+        let tap_table = state.tap_table.read().await;
+
+        // never wait on a busy vrf, it would hold back the others
+        if let Some(tap) = tap_table.get(&vrf_id) {
+            match tap.try_send((switch_id, None, DATA_TTL, Bytes::copy_from_slice(frame))) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    state.metrics.vrf(vrf_id).drop_frame();
+                    tracing::debug!("Dropped vxlan frame for vrf id {vrf_id}, queue full");
+                }
+                Err(TrySendError::Closed(_)) => {
+                    tracing::error!(
+                        "Can't send data to tap interface for vrf id {vrf_id}: channel closed"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Send a frame of a vxlan vrf to a member or a vtep, or flood it to all of them.
+pub async fn send(state: &State, vrf: &Vrf, switch_id: Option<SwitchId>, frame: &[u8]) {
+    let (Some(socket), Some(config), Some(vxlan)) =
+        (&state.vxlan, &state.config.vxlan, &vrf.settings.vxlan)
+    else {
+        return;
+    };
+    let Some(vni) = vxlan.vni(vrf.id) else {
+        tracing::debug!(
+            "Dropped frame of vrf {}, its id doesn't fit in a vni",
+            vrf.name
+        );
+        return;
+    };
+    let addresses = {
+        let peer_connections = state.peer_connections.lock().unwrap();
+        let draining_peers = state.draining_peers.lock().unwrap();
+        let member = |member: &SwitchId| {
+            peer_connections
+                .get(member)
+                .and_then(|connection| connection.address)
+                .map(|address| SocketAddr::new(address.ip(), config.listen.port()))
+        };
+
+        match switch_id {
+            Some(switch_id) => vxlan
+                .vteps
+                .iter()
+                .find(|vtep| vtep.switch_id == switch_id)
+                .map(|vtep| vtep.address)
+                .or_else(|| member(&switch_id))
+                .into_iter()
+                .collect::<Vec<_>>(),
+            // draining switches are only reached through the macs already learned for them
+            None => vrf
+                .members
+                .iter()
+                .filter(|member| !draining_peers.contains(member))
+                .filter_map(member)
+                .chain(vxlan.vteps.iter().map(|vtep| vtep.address))
+                .collect(),
+        }
+    };
+    let mut datagram = Vec::with_capacity(HEADER_SIZE + frame.len());
+
+    datagram.extend_from_slice(&header(vni));
+    datagram.extend_from_slice(frame);
+
+    for address in addresses {
+        if let Err(error) = socket.send_to(&datagram, address).await {
+            tracing::warn!("Can't send vxlan frame to {address}: {error}");
+        }
+    }
+}
+
+fn header(vni: u32) -> [u8; HEADER_SIZE] {
+    let vni = vni.to_be_bytes();
+
+    [FLAG_VNI, 0, 0, 0, vni[1], vni[2], vni[3], 0]
+}
+
+fn parse(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    if datagram.len() < HEADER_SIZE || datagram[0] & FLAG_VNI == 0 {
+        return None;
+    }
+
+    let vni = u32::from_be_bytes([0, datagram[4], datagram[5], datagram[6]]);
+
+    Some((vni, &datagram[HEADER_SIZE..]))
+}
+
+// the vrf of a vni and who sent the frame in it, a vtep or a member switch
+async fn source(state: &State, vni: u32, ip: IpAddr) -> Option<(VrfId, SwitchId)> {
+    let ip = ip.to_canonical();
+    let vrf_table = state.vrf_table.read().await;
+    let vrf = vrf_table.values().find(|vrf| {
+        vrf.settings
+            .vxlan
+            .as_ref()
+            .and_then(|vxlan| vxlan.vni(vrf.id))
+            == Some(vni)
+    })?;
+    let vxlan = vrf.settings.vxlan.as_ref()?;
+
+    if let Some(vtep) = vxlan
+        .vteps
+        .iter()
+        .find(|vtep| vtep.address.ip().to_canonical() == ip)
+    {
+        return Some((vrf.id, vtep.switch_id));
+    }
+
+    let peer_connections = state.peer_connections.lock().unwrap();
+
+    vrf.members
+        .iter()
+        .filter(|member| **member != state.config.switch_id)
+        .find(|member| {
+            peer_connections
+                .get(member)
+                .and_then(|connection| connection.address)
+                .is_some_and(|address| address.ip().to_canonical() == ip)
+        })
+        .map(|member| (vrf.id, *member))
+}
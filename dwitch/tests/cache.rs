@@ -0,0 +1,44 @@
+use std::{env, fs, process};
+
+use dwitch::cache::{Cache, CacheConfig};
+
+// written by the releases before the versioned header: the vrf 1 named red between the switches 1
+// and 2, with 02:00:00:00:00:01 learned behind the switch 2
+const BASELINE_CACHE: &[u8] = &[
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x72, 0x65, 0x64, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+];
+
+#[tokio::test]
+async fn baseline_cache_loads() {
+    let path = env::temp_dir().join(format!("dwitch-baseline-{}.cache", process::id()));
+
+    fs::write(&path, BASELINE_CACHE).unwrap();
+
+    let cache = Cache::load(
+        &CacheConfig {
+            persist: true,
+            path: path.clone(),
+            interval: 1,
+        },
+        None,
+    )
+    .await
+    .map_err(|error| error.to_string());
+
+    fs::remove_file(&path).unwrap();
+
+    let cache = cache.unwrap();
+    let vrf = &cache.vrf_table[&1];
+    let entries = cache.switch_table.vrf_entries(1);
+
+    assert_eq!(vrf.name, "red");
+    assert_eq!(vrf.members, [1, 2]);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, 0);
+    assert_eq!(entries[0].1, [2, 0, 0, 0, 0, 1]);
+    assert_eq!(entries[0].2.switch_id, 2);
+}
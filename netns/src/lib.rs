@@ -1,14 +1,19 @@
+pub mod link;
+
 use std::{
     error::Error,
     fmt::{self, Display, Formatter},
     fs::File,
-    os::fd::BorrowedFd,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+    panic::resume_unwind,
     path::{Path, PathBuf},
     process::exit,
+    thread,
 };
 
 use nix::{
     dir::Dir,
+    errno::Errno,
     fcntl::{open, OFlag},
     libc::IN_ISDIR,
     mount::{mount, umount2, MntFlags, MsFlags},
@@ -19,6 +24,7 @@ use nix::{
     },
     unistd::{close, fork, mkdir, unlink, ForkResult},
 };
+use tokio::task::spawn_blocking;
 
 const SELF_NETNS_PATH: &str = "/proc/self/ns/net";
 const DEAULT_NETNS_PATH: &str = "/proc/1/ns/net";
@@ -29,6 +35,10 @@ pub enum Netns {
     #[default]
     Default,
     Named(String),
+    /// The netns of a process, like a container, gone with it.
+    Pid(i32),
+    /// A netns file opened by the caller, who keeps it open while it's used.
+    Fd(RawFd),
 }
 
 impl Netns {
@@ -36,6 +46,14 @@ impl Netns {
         Self::Named(netns_name.as_ref().to_string())
     }
 
+    pub fn from_pid(pid: i32) -> Self {
+        Self::Pid(pid)
+    }
+
+    pub fn from_fd(fd: BorrowedFd) -> Self {
+        Self::Fd(fd.as_raw_fd())
+    }
+
     pub fn list() -> Vec<Netns> {
         let mut netns = vec![Netns::Default];
         let Ok(default_stat) = stat(DEAULT_NETNS_PATH) else {
@@ -79,6 +97,8 @@ impl Netns {
         match self {
             Netns::Default => Path::new(DEAULT_NETNS_PATH).to_path_buf(),
             Netns::Named(name) => Path::new(NETNS_PATH).join(name),
+            Netns::Pid(pid) => PathBuf::from(format!("/proc/{pid}/ns/net")),
+            Netns::Fd(fd) => PathBuf::from(format!("/proc/self/fd/{fd}")),
         }
     }
 
@@ -86,11 +106,16 @@ impl Netns {
         self.path().exists()
     }
 
+    /// Only named netns are created, the others have to exist already.
     pub fn create(&self) -> nix::Result<()> {
         if self.exists() {
             return Ok(());
         }
 
+        if !matches!(self, Netns::Named(_)) {
+            return Err(Errno::ENOENT);
+        }
+
         match unsafe { fork() }? {
             ForkResult::Parent { child, .. } => {
                 waitpid(child, None)?;
@@ -193,8 +218,9 @@ impl Netns {
         Ok(())
     }
 
+    /// Only named netns are deleted, the others belong to their process.
     pub fn delete(&self) -> nix::Result<()> {
-        if !self.exists() {
+        if !self.exists() || !matches!(self, Netns::Named(_)) {
             return Ok(());
         }
 
@@ -206,20 +232,55 @@ impl Netns {
 
     pub fn enter(&self) -> Result<NetnsHandle, Box<dyn Error>> {
         let initial_netns = File::open(SELF_NETNS_PATH)?;
-        let target_netns = File::open(&self.path())?;
+        let target_netns = File::open(self.path())?;
 
         unshare(CloneFlags::CLONE_NEWNET)?;
         setns(target_netns, CloneFlags::CLONE_NEWNET)?;
 
         Ok(NetnsHandle(initial_netns))
     }
+
+    /// Run `f` on a thread of its own inside the netns, the calling thread never leaves its netns.
+    ///
+    /// Sockets and links made by `f` stay in the netns, the thread ends with the call.
+    pub fn run<F, T>(&self, f: F) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        F: FnOnce() -> T + Send,
+        T: Send,
+    {
+        let target_netns = File::open(self.path())?;
+
+        thread::scope(|scope| {
+            scope
+                .spawn(move || {
+                    setns(target_netns, CloneFlags::CLONE_NEWNET)?;
+
+                    Ok(f())
+                })
+                .join()
+                .unwrap_or_else(|panic| resume_unwind(panic))
+        })
+    }
+
+    /// `run` from async code, without blocking the runtime.
+    pub async fn run_async<F, T>(&self, f: F) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let netns = self.clone();
+
+        spawn_blocking(move || netns.run(f)).await?
+    }
 }
 
 impl Display for Netns {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Netns::Default => f.write_str("default"),
-            Netns::Named(name) => f.write_str(&name),
+            Netns::Named(name) => f.write_str(name),
+            Netns::Pid(pid) => write!(f, "pid {pid}"),
+            Netns::Fd(fd) => write!(f, "fd {fd}"),
         }
     }
 }
@@ -0,0 +1,111 @@
+//! Veth pairs and link settings through netlink, in any netns without moving the calling thread.
+
+use std::{error::Error, fs::File, os::fd::AsRawFd};
+
+use rtnetlink::{new_connection, Handle, LinkUnspec, LinkVeth};
+use tokio::{runtime, spawn};
+use tokio_stream::StreamExt;
+
+use crate::Netns;
+
+type LinkError = Box<dyn Error + Send + Sync>;
+
+// a netlink socket stays in the namespace it was opened in
+async fn connect(netns: &Netns) -> Result<Handle, LinkError> {
+    let runtime = runtime::Handle::current();
+    let (connection, handle, _) = netns
+        .run_async(move || {
+            // the socket registers with the runtime of the caller
+            let _guard = runtime.enter();
+
+            new_connection()
+        })
+        .await??;
+
+    // the connection ends with its last handle
+    spawn(connection);
+
+    Ok(handle)
+}
+
+async fn index(handle: &Handle, name: &str) -> Result<u32, LinkError> {
+    match handle
+        .link()
+        .get()
+        .match_name(name.to_string())
+        .execute()
+        .next()
+        .await
+    {
+        Some(link) => Ok(link?.header.index),
+        None => Err(format!("Can't find the link {name}").into()),
+    }
+}
+
+/// Create a veth pair in `netns`, both ends down.
+pub async fn create_veth(netns: &Netns, name: &str, peer_name: &str) -> Result<(), LinkError> {
+    let handle = connect(netns).await?;
+
+    handle
+        .link()
+        .add(LinkVeth::new(name, peer_name).build())
+        .execute()
+        .await?;
+
+    Ok(())
+}
+
+/// Move a link of `netns` into `target`, where it's down and has lost its addresses.
+pub async fn move_link(netns: &Netns, name: &str, target: &Netns) -> Result<(), LinkError> {
+    let handle = connect(netns).await?;
+    let index = index(&handle, name).await?;
+    let target_file = File::open(target.path())?;
+
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(index)
+                .setns_by_fd(target_file.as_raw_fd())
+                .build(),
+        )
+        .execute()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_up(netns: &Netns, name: &str, up: bool) -> Result<(), LinkError> {
+    let handle = connect(netns).await?;
+    let index = index(&handle, name).await?;
+    let message = match up {
+        true => LinkUnspec::new_with_index(index).up().build(),
+        false => LinkUnspec::new_with_index(index).down().build(),
+    };
+
+    handle.link().set(message).execute().await?;
+
+    Ok(())
+}
+
+pub async fn set_mtu(netns: &Netns, name: &str, mtu: u32) -> Result<(), LinkError> {
+    let handle = connect(netns).await?;
+    let index = index(&handle, name).await?;
+
+    handle
+        .link()
+        .set(LinkUnspec::new_with_index(index).mtu(mtu).build())
+        .execute()
+        .await?;
+
+    Ok(())
+}
+
+/// Remove a link of `netns`, the other end goes with it for a veth.
+pub async fn delete(netns: &Netns, name: &str) -> Result<(), LinkError> {
+    let handle = connect(netns).await?;
+    let index = index(&handle, name).await?;
+
+    handle.link().del(index).execute().await?;
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! `cargo fuzz run packet` from the protocol directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use protocol::{Handshake, Packet, PacketSerializer};
+
+fuzz_target!(|bytes: &[u8]| {
+    let _ = Handshake::deserialize(bytes);
+
+    let Ok(packet) = Packet::deserialize(bytes) else {
+        return;
+    };
+
+    // maps may drop duplicate keys, so only parsing again is checked rather than the bytes
+    assert!(packet
+        .serialize()
+        .is_ok_and(|bytes| Packet::deserialize(&bytes).is_ok()));
+
+    // the payload of a signed packet is parsed again once its tag is checked
+    if let Packet::Signed(signed) = packet {
+        let _ = Packet::deserialize(&signed.payload);
+    }
+});
@@ -0,0 +1,77 @@
+//! Acl rules a switch applies to the frames of a vrf, on its own taps only.
+
+use common::VrfId;
+use serde::{Deserialize, Serialize};
+
+use crate::{mac, prefix, IpPrefix};
+
+/// Rules of a vrf, asked with `List` by a configuration client and answered with `Rules`. `Add`
+/// inserts a rule at a position, at the end when there's none, and `Remove` takes out the rule at
+/// an index, both answered with a `Response`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum AclAction {
+    List {
+        vrf_id: VrfId,
+    },
+    Rules(Vec<AclRule>),
+    Add {
+        vrf_id: VrfId,
+        rule: AclRule,
+        position: Option<u32>,
+    },
+    Remove {
+        vrf_id: VrfId,
+        index: u32,
+    },
+}
+
+/// A frame matches a rule when it matches each of the criteria set, the first rule it matches
+/// decides and a frame matching none is permitted.
+///
+/// The ethertype is the one after the vlan tags and the vlan the outer tag, 0 for untagged
+/// frames. Ports are only found in tcp, udp and sctp packets that aren't later fragments, and in
+/// ipv6 packets without extension headers. Mac, ethertype and vlan criteria never match the
+/// packets of a tun vrf.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AclRule {
+    pub verdict: AclVerdict,
+    pub direction: AclDirection,
+    #[serde(with = "mac::option")]
+    pub source_mac: Option<[u8; 6]>,
+    #[serde(with = "mac::option")]
+    pub destination_mac: Option<[u8; 6]>,
+    pub ethertype: Option<u16>,
+    pub vlan: Option<u16>,
+    #[serde(with = "prefix::option")]
+    pub source_ip: Option<IpPrefix>,
+    #[serde(with = "prefix::option")]
+    pub destination_ip: Option<IpPrefix>,
+    pub protocol: Option<u8>,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclVerdict {
+    #[default]
+    Permit,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclDirection {
+    /// Frames read from the tap, before they're sent to the peers.
+    Ingress,
+    /// Frames from the peers, before they're written to the tap.
+    Egress,
+    #[default]
+    Both,
+}
+
+impl AclDirection {
+    pub fn includes(self, direction: AclDirection) -> bool {
+        self == AclDirection::Both || self == direction
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt::{self, Display, Formatter};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use common::SwitchId;
+
+use crate::{
+    Compression, Handshake, HandshakeProof, Packet, PacketSerializer, ReplayWindow, Signed,
+    CHALLENGE_SIZE,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const HANDSHAKE_CONTEXT: &[u8] = b"dwitch-handshake";
+const PACKET_CONTEXT: &[u8] = b"dwitch-packet";
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unsigned,
+    InvalidSignature,
+    UnexpectedSignature,
+    Replayed(u64),
+    Malformed(bincode::Error),
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unsigned => f.write_str("packet isn't signed"),
+            AuthError::InvalidSignature => f.write_str("invalid signature"),
+            AuthError::UnexpectedSignature => f.write_str("signed packet without a key"),
+            AuthError::Replayed(sequence) => write!(f, "replayed packet with sequence {sequence}"),
+            AuthError::Malformed(error) => write!(f, "malformed signed packet: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// One end of an authenticated connection, bound to the challenges both ends sent in the
+/// handshake so its packets can't be replayed on another connection.
+#[derive(Debug, Clone)]
+pub struct Session {
+    switch_id: SwitchId,
+    challenge: [u8; CHALLENGE_SIZE],
+    peer_switch_id: SwitchId,
+    peer_challenge: [u8; CHALLENGE_SIZE],
+    sequence: u64,
+    // none when the transport rejects replays on its own
+    replay_window: Option<ReplayWindow>,
+}
+
+impl Session {
+    /// Session of the side that sent `handshake` and received `peer_handshake`.
+    pub fn new(handshake: &Handshake, peer_handshake: &Handshake) -> Self {
+        Self {
+            switch_id: handshake.switch_id,
+            challenge: handshake.challenge,
+            peer_switch_id: peer_handshake.switch_id,
+            peer_challenge: peer_handshake.challenge,
+            sequence: 0,
+            replay_window: Some(ReplayWindow::default()),
+        }
+    }
+
+    pub fn peer_switch_id(&self) -> SwitchId {
+        self.peer_switch_id
+    }
+
+    /// The same session for packets received out of order, over a transport rejecting replays.
+    pub fn without_replay_window(&self) -> Self {
+        Self {
+            replay_window: None,
+            ..self.clone()
+        }
+    }
+
+    fn mac(&self, key: &[u8], sent: bool, sequence: u64) -> HmacSha256 {
+        let (from, to) = if sent {
+            (
+                (self.switch_id, self.challenge),
+                (self.peer_switch_id, self.peer_challenge),
+            )
+        } else {
+            (
+                (self.peer_switch_id, self.peer_challenge),
+                (self.switch_id, self.challenge),
+            )
+        };
+        let mut mac = mac(key, PACKET_CONTEXT, from.0, to.0);
+
+        mac.update(&from.1);
+        mac.update(&to.1);
+        mac.update(&sequence.to_be_bytes());
+        mac
+    }
+}
+
+fn mac(key: &[u8], context: &[u8], from: SwitchId, to: SwitchId) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("Hmac accepts keys of any size");
+
+    mac.update(context);
+    mac.update(&from.to_be_bytes());
+    mac.update(&to.to_be_bytes());
+    mac
+}
+
+impl Packet {
+    /// Whether the packet changes or reveals state, and must be signed when a key is set.
+    ///
+    /// Every variant is listed so a new packet can't skip the signature by default.
+    pub fn is_control(&self) -> bool {
+        match self {
+            Packet::Ping(_) | Packet::Data(_) | Packet::Signed(_) => false,
+            Packet::VrfAction(_)
+            | Packet::Response(_)
+            | Packet::Authenticate(_)
+            | Packet::Maintenance(_)
+            | Packet::EndpointAction(_)
+            | Packet::Audit(_)
+            | Packet::Trace(_)
+            | Packet::VrfTest(_)
+            | Packet::Status(_)
+            | Packet::Events(_)
+            | Packet::Save(_)
+            | Packet::MacAction(_)
+            | Packet::PeerAction(_)
+            | Packet::Goodbye(_)
+            | Packet::VrfSync(_)
+            | Packet::StatsAction(_)
+            | Packet::PeerAdvert(_)
+            | Packet::AclAction(_)
+            | Packet::MirrorAction(_) => true,
+        }
+    }
+
+    /// Sign the packet for the peer of the session if it's a control packet and a key is set.
+    pub fn seal(self, key: Option<&[u8]>, session: &mut Session) -> bincode::Result<Packet> {
+        match key {
+            Some(key) if self.is_control() => self.sign(key, session),
+            _ => Ok(self),
+        }
+    }
+
+    /// Sign any packet with the next sequence number of the session, which the receiver accepts
+    /// only once.
+    pub fn sign(self, key: &[u8], session: &mut Session) -> bincode::Result<Packet> {
+        let payload = self.serialize()?;
+
+        session.sequence += 1;
+
+        let sequence = session.sequence;
+        let mut mac = session.mac(key, true, sequence);
+
+        mac.update(&payload);
+
+        Ok(Packet::Signed(Signed {
+            sequence,
+            payload,
+            tag: mac.finalize().into_bytes().to_vec(),
+        }))
+    }
+
+    /// Verify and unwrap a packet sent by the peer of the session, rejecting unsigned control
+    /// packets when a key is set, and unsigned data packets too when `signed_data` is.
+    ///
+    /// Each sequence number is only accepted once.
+    pub fn open(
+        self,
+        key: Option<&[u8]>,
+        session: &mut Session,
+        signed_data: bool,
+    ) -> Result<Packet, AuthError> {
+        match (self, key) {
+            (Packet::Signed(signed), Some(key)) => {
+                let mut mac = session.mac(key, false, signed.sequence);
+
+                mac.update(&signed.payload);
+                mac.verify_slice(&signed.tag)
+                    .map_err(|_| AuthError::InvalidSignature)?;
+
+                if session
+                    .replay_window
+                    .as_mut()
+                    .is_some_and(|replay_window| !replay_window.check(signed.sequence))
+                {
+                    return Err(AuthError::Replayed(signed.sequence));
+                }
+
+                match Packet::deserialize(&signed.payload).map_err(AuthError::Malformed)? {
+                    Packet::Signed(_) => Err(AuthError::InvalidSignature),
+                    packet => Ok(packet),
+                }
+            }
+            (Packet::Signed(_), None) => Err(AuthError::UnexpectedSignature),
+            (packet, Some(_)) if packet.is_control() => Err(AuthError::Unsigned),
+            (Packet::Data(_), Some(_)) if signed_data => Err(AuthError::Unsigned),
+            (packet, _) => Ok(packet),
+        }
+    }
+}
+
+// a fresh challenge for each connection, so a recorded proof can't be replayed
+impl Handshake {
+    pub fn new(switch_id: SwitchId) -> Self {
+        let mut challenge = [0u8; CHALLENGE_SIZE];
+
+        getrandom::getrandom(&mut challenge).expect("The system has a random number generator");
+
+        Self {
+            switch_id,
+            challenge,
+            compression: Compression::supported(),
+        }
+    }
+
+    /// Answer the challenge of this handshake, received from a peer, as `switch_id`.
+    pub fn prove(&self, key: Option<&[u8]>, switch_id: SwitchId) -> HandshakeProof {
+        HandshakeProof {
+            tag: key.map(|key| {
+                let mut mac = mac(key, HANDSHAKE_CONTEXT, switch_id, self.switch_id);
+
+                mac.update(&self.challenge);
+                mac.finalize().into_bytes().to_vec()
+            }),
+        }
+    }
+
+    /// Check the answer of `peer` to the challenge of this handshake, the one sent to it.
+    pub fn verify(
+        &self,
+        key: Option<&[u8]>,
+        peer: SwitchId,
+        proof: &HandshakeProof,
+    ) -> Result<(), AuthError> {
+        match (key, &proof.tag) {
+            (Some(key), Some(tag)) => {
+                let mut mac = mac(key, HANDSHAKE_CONTEXT, peer, self.switch_id);
+
+                mac.update(&self.challenge);
+                mac.verify_slice(tag)
+                    .map_err(|_| AuthError::InvalidSignature)
+            }
+            (Some(_), None) => Err(AuthError::Unsigned),
+            (None, _) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 1;
+
+#[cfg(feature = "zstd")]
+thread_local! {
+    // contexts are costly to set up, each thread keeps its own
+    static ZSTD_COMPRESSOR: std::cell::RefCell<Option<zstd::bulk::Compressor<'static>>> =
+        const { std::cell::RefCell::new(None) };
+    static ZSTD_DECOMPRESSOR: std::cell::RefCell<Option<zstd::bulk::Decompressor<'static>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// How the frames of a vrf are compressed between switches, only done when every member connected
+/// can decompress them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Fast, for frames that only compress a little.
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Algorithms this build can compress and decompress with, advertised in the handshake.
+    pub fn supported() -> Vec<Compression> {
+        [
+            (Compression::Lz4, cfg!(feature = "lz4")),
+            (Compression::Zstd, cfg!(feature = "zstd")),
+        ]
+        .into_iter()
+        .filter_map(|(compression, supported)| supported.then_some(compression))
+        .collect()
+    }
+
+    /// `None` if this build can't compress with the algorithm.
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ZSTD_COMPRESSOR.with_borrow_mut(|compressor| {
+                let compressor = match compressor {
+                    Some(compressor) => compressor,
+                    None => compressor.insert(zstd::bulk::Compressor::new(ZSTD_LEVEL).ok()?),
+                };
+
+                compressor.compress(data).ok()
+            }),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// `None` if the data is invalid or would decompress to more than `limit` bytes.
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub fn decompress(self, data: &[u8], limit: usize) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let (size, data) = lz4_flex::block::uncompressed_size(data).ok()?;
+
+                if size > limit {
+                    return None;
+                }
+
+                lz4_flex::decompress(data, size).ok()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ZSTD_DECOMPRESSOR.with_borrow_mut(|decompressor| {
+                let decompressor = match decompressor {
+                    Some(decompressor) => decompressor,
+                    None => decompressor.insert(zstd::bulk::Decompressor::new().ok()?),
+                };
+
+                decompressor.decompress(data, limit).ok()
+            }),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Wire format of the data packets, kept out of bincode so a frame is written straight from its
+//! buffer and read without being copied: a tag no bincode packet starts with, the vrf id as a big
+//! endian u32, the compression, the ttl, then the frame as is.
+//!
+//! Signed data packets stay bincode, inside the payload their signature covers.
+
+use std::mem::size_of;
+
+use bytes::Bytes;
+
+use crate::{Compression, Data};
+
+// bincode starts a packet with its variant index, a u32 that never gets this high
+const TAG: [u8; 4] = [0xff; 4];
+pub const HEADER_SIZE: usize = TAG.len() + size_of::<u32>() + 2;
+
+impl Data {
+    /// Goes on the wire right before the frame.
+    pub fn header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+
+        header[..4].copy_from_slice(&TAG);
+        header[4..8].copy_from_slice(&self.vrf_id.to_be_bytes());
+        header[8] = match self.compression {
+            None => 0,
+            Some(Compression::Lz4) => 1,
+            Some(Compression::Zstd) => 2,
+        };
+        header[9] = self.ttl;
+
+        header
+    }
+
+    /// The data packet a frame holds, sharing its buffer, `None` if it holds another packet.
+    pub fn parse(frame: &Bytes) -> Option<bincode::Result<Data>> {
+        if frame.len() < HEADER_SIZE || frame[..4] != TAG {
+            return None;
+        }
+
+        let compression = match frame[8] {
+            0 => None,
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            compression => {
+                return Some(Err(bincode::ErrorKind::Custom(format!(
+                    "Unknown compression {compression}"
+                ))
+                .into()))
+            }
+        };
+
+        Some(Ok(Data {
+            vrf_id: u32::from_be_bytes(frame[4..8].try_into().unwrap()),
+            data: frame.slice(HEADER_SIZE..),
+            compression,
+            ttl: frame[9],
+        }))
+    }
+}
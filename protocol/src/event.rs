@@ -0,0 +1,95 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use common::{SwitchId, VrfId};
+
+/// Change on a switch, published to its subscribers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    PeerUp {
+        switch_id: SwitchId,
+    },
+    PeerDown {
+        switch_id: SwitchId,
+    },
+    SwitchDraining {
+        switch_id: SwitchId,
+    },
+    SwitchActivated {
+        switch_id: SwitchId,
+    },
+    VrfCreated {
+        id: VrfId,
+        name: String,
+    },
+    VrfDeleted {
+        id: VrfId,
+    },
+    VrfMembersAdded {
+        id: VrfId,
+        members: Vec<SwitchId>,
+    },
+    VrfMembersRemoved {
+        id: VrfId,
+        members: Vec<SwitchId>,
+    },
+    VrfSuspended {
+        id: VrfId,
+    },
+    VrfResumed {
+        id: VrfId,
+    },
+    PeersBelowThreshold {
+        connected: usize,
+        min_peers: usize,
+    },
+    TapDegraded {
+        id: VrfId,
+        name: String,
+        error: String,
+    },
+    TapRecovered {
+        id: VrfId,
+        name: String,
+    },
+    BpduGuardTripped {
+        id: VrfId,
+        name: String,
+    },
+    /// A connection was refused for claiming a switch id that's taken.
+    SwitchIdConflict {
+        switch_id: SwitchId,
+        address: Option<SocketAddr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Peer,
+    Vrf,
+    Alert,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::PeerUp { .. }
+            | Event::PeerDown { .. }
+            | Event::SwitchDraining { .. }
+            | Event::SwitchActivated { .. } => EventKind::Peer,
+            Event::VrfCreated { .. }
+            | Event::VrfDeleted { .. }
+            | Event::VrfMembersAdded { .. }
+            | Event::VrfMembersRemoved { .. }
+            | Event::VrfSuspended { .. }
+            | Event::VrfResumed { .. }
+            | Event::TapRecovered { .. } => EventKind::Vrf,
+            Event::PeersBelowThreshold { .. }
+            | Event::TapDegraded { .. }
+            | Event::BpduGuardTripped { .. }
+            | Event::SwitchIdConflict { .. } => EventKind::Alert,
+        }
+    }
+}
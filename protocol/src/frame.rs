@@ -0,0 +1,85 @@
+//! Framing of the packets on every stream, between switches and between the daemon and its
+//! clients. Each packet is prefixed by its length as a big endian u32.
+
+use std::{
+    io::{self, IoSlice, Read, Write},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+
+use crate::MAX_PACKET_SIZE;
+
+pub const HEADER_SIZE: usize = 4;
+/// A daemon that stays silent this long in the middle of a reply is considered gone by clients.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn header(payload: &[u8]) -> [u8; HEADER_SIZE] {
+    (payload.len() as u32).to_be_bytes()
+}
+
+/// Length of the payload announced by a header, rejecting it past `max_size`.
+pub fn length(header: [u8; HEADER_SIZE], max_size: usize) -> io::Result<usize> {
+    let length = u32::from_be_bytes(header) as usize;
+
+    if length > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Packet of {length} bytes is too large"),
+        ));
+    }
+
+    Ok(length)
+}
+
+/// Split the first whole frame off the buffer, bytes past it are kept for the next call.
+///
+/// Without a whole frame, room is reserved in the buffer for the rest of it.
+pub fn decode(buffer: &mut BytesMut, max_size: usize) -> io::Result<Option<BytesMut>> {
+    if buffer.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let length = length(buffer[..HEADER_SIZE].try_into().unwrap(), max_size)?;
+
+    if buffer.len() < HEADER_SIZE + length {
+        buffer.reserve(HEADER_SIZE + length - buffer.len());
+        return Ok(None);
+    }
+
+    buffer.advance(HEADER_SIZE);
+
+    Ok(Some(buffer.split_to(length)))
+}
+
+// the header and payload go out in one write without being copied together
+pub fn write_frame(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let header = header(payload);
+    let mut slices = [IoSlice::new(&header), IoSlice::new(payload)];
+    let mut slices = &mut slices[..];
+
+    while !slices.is_empty() {
+        let length = stream.write_vectored(slices)?;
+
+        if length == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        IoSlice::advance_slices(&mut slices, length);
+    }
+
+    Ok(())
+}
+
+/// Read exactly one frame, so nothing past it is taken from the stream.
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; HEADER_SIZE];
+
+    stream.read_exact(&mut header)?;
+
+    let mut payload = vec![0u8; length(header, MAX_PACKET_SIZE)?];
+
+    stream.read_exact(&mut payload)?;
+
+    Ok(payload)
+}
@@ -1,8 +1,43 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use bincode::Options;
+use bytes::Bytes;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use common::{SwitchId, VrfId};
 
+mod acl;
+mod auth;
+mod compression;
+pub mod data;
+mod event;
+pub mod frame;
+pub mod mac;
+pub mod prefix;
+mod replay;
+
+pub use acl::{AclAction, AclDirection, AclRule, AclVerdict};
+pub use auth::{AuthError, Session};
+pub use compression::Compression;
+pub use event::{Event, EventKind};
+pub use replay::ReplayWindow;
+
 pub const CONFIGURATION_SWITCH_ID: SwitchId = 0;
+pub const CHALLENGE_SIZE: usize = 16;
+/// Largest serialized packet, anything longer is rejected before being read.
+pub const MAX_PACKET_SIZE: usize = 1 << 20;
+/// Hops a frame read from a tap can take between switches.
+pub const DATA_TTL: u8 = 8;
+// an ethernet header with an s-tag and a c-tag
+const FRAME_OVERHEAD: u32 = 22;
+// vnis are 24 bits
+const MAX_VNI: u32 = (1 << 24) - 1;
 
 macro_rules! packets {
     ($($packet_name:ident),*) => {
@@ -21,42 +56,733 @@ macro_rules! packets {
     };
 }
 
-packets!(Ping, VrfAction, Data);
+packets!(
+    Ping,
+    VrfAction,
+    Response,
+    Data,
+    Signed,
+    Authenticate,
+    Maintenance,
+    EndpointAction,
+    Audit,
+    Trace,
+    VrfTest,
+    Status,
+    Events,
+    Save,
+    MacAction,
+    PeerAction,
+    Goodbye,
+    VrfSync,
+    StatsAction,
+    PeerAdvert,
+    AclAction,
+    MirrorAction
+);
+
+// the encoding of `bincode::serialize`, so the wire format doesn't change
+fn options() -> impl Options {
+    bincode::options().with_fixint_encoding()
+}
 
 pub trait PacketSerializer: Sized + Serialize + DeserializeOwned {
-    fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("Can't serialize packet")
+    fn serialize(&self) -> bincode::Result<Vec<u8>> {
+        options().serialize(self)
     }
 
+    /// Length fields are checked against the packet size before anything is allocated, and bytes
+    /// left after the packet make it invalid.
     fn deserialize(bytes: &[u8]) -> bincode::Result<Self> {
-        bincode::deserialize(bytes)
+        if bytes.len() > MAX_PACKET_SIZE {
+            return Err(bincode::ErrorKind::SizeLimit.into());
+        }
+
+        options()
+            .with_limit(bytes.len() as u64)
+            .reject_trailing_bytes()
+            .deserialize(bytes)
     }
 }
 
 impl PacketSerializer for Packet {}
-impl PacketSerializer for SwitchId {}
+impl PacketSerializer for Handshake {}
+impl PacketSerializer for HandshakeProof {}
+
+/// First frame each side of a connection sends, the other side answers its challenge with a
+/// `HandshakeProof`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Handshake {
+    pub switch_id: SwitchId,
+    pub challenge: [u8; CHALLENGE_SIZE],
+    /// Algorithms the switch can decompress frames with.
+    pub compression: Vec<Compression>,
+}
+
+/// Signature of the challenge of the other side with the control key, when one is set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HandshakeProof {
+    pub tag: Option<Vec<u8>>,
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Ping;
 
+/// Sent by a switch shutting down, its peers close the connection instead of waiting for it to
+/// time out.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Goodbye;
+
+/// The vrfs a switch knows and the versions of the ones it deleted, sent to each peer it connects
+/// with so a switch that was away catches up, over several packets when there are many vrfs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VrfSync {
+    pub vrfs: Vec<Vrf>,
+    pub deleted: Vec<(VrfId, u64)>,
+}
+
+/// Where a switch and the peers it's connected to listen, so its peers dial them too. An
+/// unspecified ip stands for the address the switch is reached at.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PeerAdvert {
+    pub peers: Vec<(SwitchId, SocketAddr)>,
+}
+
+/// `Allocate` creates a vrf with an id picked by the switch the client is connected to, its own id
+/// is ignored, answered with `Allocated` or an error. Peers only ever see the resulting `Create`.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum VrfAction {
     List(Option<Vec<Vrf>>),
-    Create(Vrf),
-    Delete { id: VrfId },
-    AddMember { id: VrfId, members: Vec<SwitchId> },
-    RemoveMember { id: VrfId, members: Vec<SwitchId> },
+    Create(Box<Vrf>),
+    Delete {
+        id: VrfId,
+    },
+    AddMember {
+        id: VrfId,
+        members: Vec<SwitchId>,
+    },
+    RemoveMember {
+        id: VrfId,
+        members: Vec<SwitchId>,
+    },
+    Allocate(Box<Vrf>),
+    Allocated {
+        id: VrfId,
+    },
+    Describe {
+        id: VrfId,
+        metadata: VrfMetadata,
+    },
+    /// Stop forwarding the frames of a vrf on every member, keeping its configuration and macs.
+    Suspend {
+        id: VrfId,
+    },
+    Resume {
+        id: VrfId,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Maintenance {
+    Drain,
+    Activate,
+}
+
+/// Plugs a container network namespace into a local vrf, answered with `Attached` or an error.
+///
+/// `AttachVm` is only served on the vm socket, answered with `VmAttached` and the descriptor of a
+/// tap bridged into the vrf, which goes away once the emulator holding it exits.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum EndpointAction {
+    Attach(Endpoint),
+    Detach { netns: PathBuf, ifname: String },
+    Attached { mac: [u8; 6] },
+    AttachVm { vrf: String, name: String },
+    VmAttached { ifname: String },
 }
 
+/// Isolation audit asked with `Start` by a configuration client. The switch sends a probe frame
+/// into each of its vrfs, the members send it back a `Sighting` of each probe they see, and the
+/// client gets the reports in chunks of `Report` ending with an empty one.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Audit {
+    Start,
+    Sighting(Sighting),
+    Report(Vec<AuditReport>),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Sighting {
+    pub nonce: u64,
+    pub switch_id: SwitchId,
+    pub vrf_id: VrfId,
+    /// Read out of a tap rather than received from the overlay.
+    pub from_tap: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditReport {
+    pub vrf_id: VrfId,
+    /// Switches the probe reached in its vrf.
+    pub seen_by: Vec<SwitchId>,
+    /// Where the probe showed up outside of its vrf.
+    pub leaks: Vec<Sighting>,
+}
+
+/// Flow trace. A client sends `Start` to a member of the vrf, which injects a marked frame for the
+/// destination into it like its tap would. Every switch handling the frame sends the origin a
+/// `Hop`, and the client gets them all in one `Report`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Trace {
+    Start {
+        vrf_id: VrfId,
+        #[serde(with = "mac")]
+        destination: [u8; 6],
+    },
+    Hop(Hop),
+    Report(Vec<Hop>),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Hop {
+    pub nonce: u64,
+    pub switch_id: SwitchId,
+    /// Peer the frame came from, none when it was read out of the local tap.
+    pub ingress: Option<SwitchId>,
+    pub decision: Decision,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Decision {
+    /// Sent to the member the destination is pinned or learned behind.
+    Unicast(SwitchId),
+    /// Sent to every active member.
+    Flood,
+    /// Written to the local tap.
+    Delivered,
+    /// Left to the openflow flow table, which didn't hand it back to the mac table.
+    Openflow,
+    Dropped(DropReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DropReason {
+    DeniedEthertype,
+    /// Flooded to a draining switch.
+    Draining,
+    /// No pinned mac for the destination in static learning.
+    UnknownDestination,
+    Encryption,
+    /// The vrf is suspended.
+    Suspended,
+    /// Tagged with a vlan the vrf doesn't allow.
+    DeniedVlan,
+    /// Denied by an acl rule of the vrf.
+    Acl,
+}
+
+/// Connectivity check between two members of a vrf asked with `Start` by a configuration client.
+/// A switch that isn't the `from` member hands it over with `Run` and gets back its `Result`. The
+/// `from` member echoes test frames off the `to` member through the overlay and both taps, and the
+/// client gets a `Report`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum VrfTest {
+    Start {
+        vrf_id: VrfId,
+        from: SwitchId,
+        to: SwitchId,
+    },
+    Run {
+        nonce: u64,
+        vrf_id: VrfId,
+        to: SwitchId,
+    },
+    Result {
+        nonce: u64,
+        result: Result<VrfTestReport, String>,
+    },
+    Report(VrfTestReport),
+}
+
+/// Copies of the frames going through the tap of a vrf on a switch, both ways, started with `Start`
+/// by a configuration client and stopped with `Stop`, both answered with a `Response`. A new mirror
+/// of a vrf replaces the previous one, and mirrors aren't kept across restarts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum MirrorAction {
+    Start { vrf_id: VrfId, target: MirrorTarget },
+    Stop { vrf_id: VrfId },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum MirrorTarget {
+    /// File of the mirror directory of the switch, rotated once it reaches `max_size` bytes, with
+    /// `max_files` files kept in all.
+    Pcap {
+        name: String,
+        max_size: u64,
+        max_files: u32,
+    },
+    /// Tap created on the host for the copies, gone once the mirror stops.
+    Tap { ifname: String },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct VrfTestReport {
+    /// Small frames sent and echoed back.
+    pub sent: u32,
+    pub received: u32,
+    /// Average round trip of the echoed frames, in microseconds.
+    pub round_trip: u64,
+    /// Largest mtu an echoed frame was sent with.
+    pub mtu: Option<u32>,
+}
+
+/// State of a switch asked with `Query` by a configuration client, answered with a `Report`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Status {
+    Query,
+    Report(StatusReport),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatusReport {
+    pub switch_id: SwitchId,
+    pub draining: bool,
+    /// Peers with a connection from this switch.
+    pub peers: Vec<SwitchId>,
+    pub draining_peers: Vec<SwitchId>,
+    /// Path mtu of the underlay toward peers, for those the kernel knows it.
+    pub path_mtus: Vec<(SwitchId, u32)>,
+    /// Vrfs with a tap on this switch.
+    pub vrfs: Vec<VrfId>,
+    /// Vrfs whose tap is missing, by name.
+    pub degraded_vrfs: Vec<String>,
+    /// Frames from peers each vrf dropped with its tap queue full, for those that dropped any.
+    pub dropped_frames: Vec<(VrfId, u64)>,
+}
+
+/// Persistence of the vrf and mac tables right away, asked with `Request` by a configuration
+/// client and answered with `Saved` or an error.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Save {
+    Request,
+    Saved { vrfs: usize, macs: usize },
+}
+
+/// Macs of a vrf, asked with `List` by a configuration client and answered in chunks of `Entries`
+/// ending with an empty one, the pinned ones then the learned ones most recently seen first.
+///
+/// `AddStatic` pins a mac behind a switch in the table of the switch it's sent to, in every vlan,
+/// before the learned macs and never aged out. It and `RemoveStatic` are answered with a
+/// `Response`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum MacAction {
+    List {
+        vrf_id: VrfId,
+    },
+    Entries(Vec<MacEntry>),
+    AddStatic {
+        vrf_id: VrfId,
+        #[serde(with = "mac")]
+        mac: [u8; 6],
+        switch_id: SwitchId,
+    },
+    RemoveStatic {
+        vrf_id: VrfId,
+        #[serde(with = "mac")]
+        mac: [u8; 6],
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MacEntry {
+    /// 0 for untagged frames.
+    pub vlan: u16,
+    #[serde(with = "mac")]
+    pub mac: [u8; 6],
+    pub switch_id: SwitchId,
+    /// When the mac was learned behind this switch, in seconds from the unix epoch, 0 for the
+    /// pinned ones.
+    pub learned: u64,
+    /// Pinned with `AddStatic` rather than learned, in every vlan.
+    pub pinned: bool,
+}
+
+/// Peers of a switch, asked with `List` by a configuration client and answered with `Report`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum PeerAction {
+    List,
+    Report(Vec<PeerReport>),
+}
+
+/// Traffic counters of a switch, asked with `Query` by a configuration client and answered with
+/// `Report`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum StatsAction {
+    Query,
+    Report(StatsReport),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StatsReport {
+    pub vrfs: Vec<VrfStats>,
+    pub peers: Vec<PeerStats>,
+}
+
+/// Counters of a vrf with a tap on the switch, since the daemon started.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VrfStats {
+    pub vrf_id: VrfId,
+    /// Frames read from the tap.
+    pub received: TrafficStats,
+    /// Frames written to the tap.
+    pub sent: TrafficStats,
+    /// Frames from the tap sent to every member, their destination unknown.
+    pub flooded: u64,
+    /// Frames for the tap dropped because its queue was full.
+    pub dropped: u64,
+    /// Frames toward the peers dropped by the bandwidth limits of the vrf.
+    pub shaped: u64,
+    pub learned_macs: usize,
+}
+
+/// Counters of the data packets exchanged with a peer, across reconnects.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PeerStats {
+    pub switch_id: SwitchId,
+    pub received: TrafficStats,
+    pub sent: TrafficStats,
+    /// Packets that couldn't be queued or written toward the peer.
+    pub send_failures: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct TrafficStats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// A connected peer, or a member of a vrf of the switch that isn't connected.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PeerReport {
+    pub switch_id: SwitchId,
+    pub state: PeerState,
+    /// Other end of the connection the peer is reached through.
+    pub address: Option<SocketAddr>,
+    /// Round trip time of the last ping, only known toward the peers the switch dials.
+    pub ping_rtt: Option<Duration>,
+    /// Time since the connection came up.
+    pub uptime: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PeerState {
+    Connected,
+    Draining,
+    Disconnected,
+}
+
+/// Event stream asked with `Subscribe` by a configuration client. The switch then sends it each of
+/// its events as an `Event` until the connection closes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Events {
+    Subscribe,
+    Event(Event),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Endpoint {
+    pub vrf: String,
+    pub netns: PathBuf,
+    pub ifname: String,
+    pub addresses: Vec<IpPrefix>,
+    pub routes: Vec<IpRoute>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IpPrefix {
+    pub address: IpAddr,
+    pub prefix_length: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IpRoute {
+    #[serde(with = "prefix")]
+    pub destination: IpPrefix,
+    pub gateway: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Response {
+    Ok,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Vrf {
     pub id: VrfId,
     pub name: String,
     pub members: Vec<SwitchId>,
+    pub template: Option<String>,
+    pub settings: VrfSettings,
+    pub metadata: VrfMetadata,
+    /// Suspended by an operator, its members drop its frames until it's resumed.
+    pub suspended: bool,
+    /// Bumped by each change, the highest one wins when switches reconcile their vrfs.
+    pub version: u64,
+}
+
+/// What a vrf is for, only kept for the operators.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VrfMetadata {
+    pub description: Option<String>,
+    pub owner: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Applied by every member of a vrf, the unset ones are filled from the template of the vrf by the
+/// switch it's created on.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VrfSettings {
+    pub mode: Option<VrfMode>,
+    /// Name of the tap instead of the generated one.
+    pub ifname: Option<String>,
+    /// Mac of the tap instead of a random one.
+    #[serde(with = "mac::option")]
+    pub mac: Option<[u8; 6]>,
+    pub mtu: Option<u32>,
+    /// Addresses of the tap, with the routes through it, set when the tap is created.
+    #[serde(with = "prefix::vec")]
+    pub addresses: Vec<IpPrefix>,
+    pub routes: Vec<IpRoute>,
+    /// Largest frame carried, by default the mtu with room for an ethernet header and two tags.
+    pub max_frame_size: Option<u32>,
+    /// Overrides the switch wide limit of learned macs.
+    pub max_macs: Option<u32>,
+    pub learning: Option<Learning>,
+    /// Macs pinned to the switch they're behind, used before the learned ones.
+    pub static_macs: Vec<StaticMac>,
+    /// Frames per second read from the tap, bursts of up to a second are let through.
+    pub frame_rate: Option<u32>,
+    /// Bytes per second of frames sent to the peers, each copy of a flooded frame counted, bursts
+    /// of up to a second are let through.
+    pub bandwidth: Option<u64>,
+    /// Bytes per second of frames sent to each peer, within `bandwidth`.
+    pub peer_bandwidth: Option<u64>,
+    /// Frames from peers waiting for the tap, the ones that don't fit are dropped.
+    pub queue_depth: Option<u32>,
+    /// Frames of these ethertypes are dropped in both directions.
+    pub deny_ethertypes: Vec<u16>,
+    /// Vlan ids tagged frames can carry, any of them when empty. Untagged frames always pass.
+    pub vlans: Vec<u16>,
+    pub bpdu: Option<Bpdu>,
+    pub gateway: Option<Gateway>,
+    pub compression: Option<Compression>,
+    pub vxlan: Option<Vxlan>,
+    pub netns: Option<VrfNetns>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VrfMode {
+    /// Ethernet frames through a tap, switched with the learned macs.
+    #[default]
+    Tap,
+    /// Ip packets through a tun, routed with the learned ip addresses, for workloads that don't
+    /// need ethernet.
+    Tun,
+}
+
+/// Where the tap of a vrf is created with the netns dataplane, written `create`, `default` or
+/// `existing:<name>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum VrfNetns {
+    /// A netns named after the vrf, deleted with the tap.
+    #[default]
+    Create,
+    /// The host netns, the tap is left alone like with the tap dataplane.
+    Default,
+    /// A netns of `/run/netns` the workloads already live in, dwitch only plugs the tap into it.
+    Existing(String),
+}
+
+impl Display for VrfNetns {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VrfNetns::Create => f.write_str("create"),
+            VrfNetns::Default => f.write_str("default"),
+            VrfNetns::Existing(name) => write!(f, "existing:{name}"),
+        }
+    }
+}
+
+impl TryFrom<String> for VrfNetns {
+    type Error = String;
+
+    fn try_from(netns: String) -> Result<Self, Self::Error> {
+        match netns.as_str() {
+            "create" => Ok(VrfNetns::Create),
+            "default" => Ok(VrfNetns::Default),
+            // a name that can't escape /run/netns
+            _ => match netns.strip_prefix("existing:") {
+                Some(name)
+                    if !name.is_empty() && !name.contains('/') && name != "." && name != ".." =>
+                {
+                    Ok(VrfNetns::Existing(name.to_string()))
+                }
+                _ => Err("Expected create, default or existing:<name>".to_string()),
+            },
+        }
+    }
+}
+
+impl From<VrfNetns> for String {
+    fn from(netns: VrfNetns) -> Self {
+        netns.to_string()
+    }
+}
+
+/// Frames of the vrf cross as standard vxlan instead of data packets, so a vtep like a linux vxlan
+/// device can take part. They're neither encrypted nor compressed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Vxlan {
+    /// The vrf id by default.
+    pub vni: Option<u32>,
+    /// Vteps outside dwitch, frames are flooded to them too.
+    pub vteps: Vec<Vtep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Vtep {
+    /// Macs behind the vtep are learned as behind this switch id, one no switch has.
+    pub switch_id: SwitchId,
+    pub address: SocketAddr,
+}
+
+impl Vxlan {
+    /// Vni of the frames of a vrf, `None` when its id doesn't fit in one and no vni was set.
+    pub fn vni(&self, vrf_id: VrfId) -> Option<u32> {
+        Some(self.vni.unwrap_or(vrf_id)).filter(|vni| *vni <= MAX_VNI)
+    }
+}
+
+/// Address a member of a vrf answers for and routes into its host network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Gateway {
+    #[serde(with = "prefix")]
+    pub address: IpPrefix,
+    /// Only the lowest active member reachable from a switch serves it, instead of all of them.
+    #[serde(default)]
+    pub elect: bool,
+    /// What the gateway routes into the host network leaves with the address of the switch.
+    #[serde(default)]
+    pub masquerade: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Learning {
+    /// Where macs are is learned from the frames of the members.
+    #[default]
+    Dynamic,
+    /// Nothing is learned, frames to macs that aren't pinned are flooded to every member.
+    Flood,
+    /// Only pinned macs can send frames, to other pinned macs or flooded ones.
+    Static,
+}
+
+/// What a switch does with the spanning tree bpdus of a vrf, a loop behind one switch would
+/// otherwise take part in the spanning tree of the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bpdu {
+    #[default]
+    Forward,
+    /// Dropped in both directions.
+    Filter,
+    /// Dropped, and a bpdu coming out of the local tap shuts it for a while.
+    Guard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StaticMac {
+    #[serde(with = "mac")]
+    pub mac: [u8; 6],
+    pub switch_id: SwitchId,
+}
+
+impl VrfSettings {
+    pub fn max_frame_size(&self) -> Option<u32> {
+        self.max_frame_size
+            .or(self.mtu.map(|mtu| mtu.saturating_add(FRAME_OVERHEAD)))
+    }
+
+    pub fn or(self, template: &VrfSettings) -> VrfSettings {
+        VrfSettings {
+            mode: self.mode.or(template.mode),
+            // they belong to a single tap, never shared through a template
+            ifname: self.ifname,
+            mac: self.mac,
+            addresses: self.addresses,
+            mtu: self.mtu.or(template.mtu),
+            routes: if self.routes.is_empty() {
+                template.routes.clone()
+            } else {
+                self.routes
+            },
+            max_frame_size: self.max_frame_size.or(template.max_frame_size),
+            max_macs: self.max_macs.or(template.max_macs),
+            learning: self.learning.or(template.learning),
+            static_macs: if self.static_macs.is_empty() {
+                template.static_macs.clone()
+            } else {
+                self.static_macs
+            },
+            frame_rate: self.frame_rate.or(template.frame_rate),
+            bandwidth: self.bandwidth.or(template.bandwidth),
+            peer_bandwidth: self.peer_bandwidth.or(template.peer_bandwidth),
+            queue_depth: self.queue_depth.or(template.queue_depth),
+            bpdu: self.bpdu.or(template.bpdu),
+            gateway: self.gateway.or(template.gateway),
+            compression: self.compression.or(template.compression),
+            vxlan: self.vxlan.or_else(|| template.vxlan.clone()),
+            netns: self.netns.or_else(|| template.netns.clone()),
+            deny_ethertypes: if self.deny_ethertypes.is_empty() {
+                template.deny_ethertypes.clone()
+            } else {
+                self.deny_ethertypes
+            },
+            vlans: if self.vlans.is_empty() {
+                template.vlans.clone()
+            } else {
+                self.vlans
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Data {
     pub vrf_id: VrfId,
-    pub data: Vec<u8>,
+    pub data: Bytes,
+    /// How the frame was compressed, before it was encrypted.
+    pub compression: Option<Compression>,
+    /// Hops left, a switch sends a frame on with one less and only delivers it once none are left.
+    pub ttl: u8,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Authenticate {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Signed {
+    pub sequence: u64,
+    pub payload: Vec<u8>,
+    pub tag: Vec<u8>,
 }
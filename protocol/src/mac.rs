@@ -0,0 +1,73 @@
+//! Mac addresses written as `02:00:00:00:00:01` in human readable formats and as bytes otherwise.
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn parse(mac: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0u8; 6];
+    let mut parts = mac.split(':');
+
+    for byte in &mut bytes {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    parts.next().is_none().then_some(bytes)
+}
+
+pub fn format(mac: &[u8; 6]) -> String {
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+pub fn serialize<S: Serializer>(mac: &[u8; 6], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.collect_str(&format(mac))
+    } else {
+        mac.serialize(serializer)
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 6], D::Error> {
+    if deserializer.is_human_readable() {
+        let mac = String::deserialize(deserializer)?;
+
+        parse(&mac).ok_or_else(|| D::Error::custom(format!("Invalid mac address {mac}")))
+    } else {
+        <[u8; 6]>::deserialize(deserializer)
+    }
+}
+
+/// The same for an optional mac address.
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    struct Mac<'a>(&'a [u8; 6]);
+
+    impl Serialize for Mac<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(self.0, serializer)
+        }
+    }
+
+    struct OwnedMac([u8; 6]);
+
+    impl<'de> Deserialize<'de> for OwnedMac {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(OwnedMac)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        mac: &Option<[u8; 6]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        mac.as_ref().map(Mac).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 6]>, D::Error> {
+        Ok(Option::<OwnedMac>::deserialize(deserializer)?.map(|OwnedMac(mac)| mac))
+    }
+}
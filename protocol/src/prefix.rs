@@ -0,0 +1,115 @@
+//! Ip prefixes written as `10.0.0.1/24` in human readable formats and as a struct otherwise.
+
+use std::net::IpAddr;
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::IpPrefix;
+
+pub fn parse(prefix: &str) -> Option<IpPrefix> {
+    let (address, prefix_length) = prefix.split_once('/')?;
+    let address = address.parse::<IpAddr>().ok()?;
+    let prefix_length = prefix_length.parse::<u8>().ok()?;
+    let max_length = match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+
+    (prefix_length <= max_length).then_some(IpPrefix {
+        address,
+        prefix_length,
+    })
+}
+
+pub fn serialize<S: Serializer>(prefix: &IpPrefix, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.collect_str(&format_args!("{}/{}", prefix.address, prefix.prefix_length))
+    } else {
+        prefix.serialize(serializer)
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpPrefix, D::Error> {
+    if deserializer.is_human_readable() {
+        let prefix = String::deserialize(deserializer)?;
+
+        parse(&prefix).ok_or_else(|| D::Error::custom(format!("Invalid ip prefix {prefix}")))
+    } else {
+        IpPrefix::deserialize(deserializer)
+    }
+}
+
+/// The same for a list of ip prefixes.
+pub mod vec {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::IpPrefix;
+
+    struct Prefix<'a>(&'a IpPrefix);
+
+    impl Serialize for Prefix<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(self.0, serializer)
+        }
+    }
+
+    struct OwnedPrefix(IpPrefix);
+
+    impl<'de> Deserialize<'de> for OwnedPrefix {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(OwnedPrefix)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        prefixes: &[IpPrefix],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(prefixes.iter().map(Prefix))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<IpPrefix>, D::Error> {
+        Ok(Vec::<OwnedPrefix>::deserialize(deserializer)?
+            .into_iter()
+            .map(|OwnedPrefix(prefix)| prefix)
+            .collect())
+    }
+}
+
+/// The same for an optional ip prefix.
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::IpPrefix;
+
+    struct Prefix<'a>(&'a IpPrefix);
+
+    impl Serialize for Prefix<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(self.0, serializer)
+        }
+    }
+
+    struct OwnedPrefix(IpPrefix);
+
+    impl<'de> Deserialize<'de> for OwnedPrefix {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(OwnedPrefix)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        prefix: &Option<IpPrefix>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        prefix.as_ref().map(Prefix).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<IpPrefix>, D::Error> {
+        Ok(Option::<OwnedPrefix>::deserialize(deserializer)?.map(|OwnedPrefix(prefix)| prefix))
+    }
+}
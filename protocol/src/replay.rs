@@ -0,0 +1,37 @@
+const WINDOW_SIZE: u64 = 64;
+
+/// Sliding window over the sequence numbers received from one peer.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    /// Accept each sequence number once, rejecting anything older than the window.
+    pub fn check(&mut self, sequence: u64) -> bool {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+
+            self.bitmap = if shift >= WINDOW_SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.highest = sequence;
+
+            return true;
+        }
+
+        let offset = self.highest - sequence;
+
+        if offset >= WINDOW_SIZE || self.bitmap & (1 << offset) != 0 {
+            return false;
+        }
+
+        self.bitmap |= 1 << offset;
+
+        true
+    }
+}
@@ -0,0 +1,38 @@
+use protocol::{AuthError, Goodbye, Handshake, Packet, Session};
+
+const KEY: &[u8] = b"shared key";
+
+// both ends of a connection between the switches 1 and 2
+fn sessions() -> (Session, Session) {
+    let first = Handshake::new(1);
+    let second = Handshake::new(2);
+
+    (Session::new(&first, &second), Session::new(&second, &first))
+}
+
+#[test]
+fn replayed_control_packet_is_rejected() {
+    let (mut sender, mut receiver) = sessions();
+    let packet = Packet::from(Goodbye).seal(Some(KEY), &mut sender).unwrap();
+
+    assert!(matches!(
+        packet.clone().open(Some(KEY), &mut receiver, false),
+        Ok(Packet::Goodbye(Goodbye))
+    ));
+    assert!(matches!(
+        packet.open(Some(KEY), &mut receiver, false),
+        Err(AuthError::Replayed(_))
+    ));
+}
+
+#[test]
+fn control_packet_of_another_connection_is_rejected() {
+    let (mut sender, _) = sessions();
+    let (_, mut receiver) = sessions();
+    let packet = Packet::from(Goodbye).seal(Some(KEY), &mut sender).unwrap();
+
+    assert!(matches!(
+        packet.open(Some(KEY), &mut receiver, false),
+        Err(AuthError::InvalidSignature)
+    ));
+}